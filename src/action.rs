@@ -0,0 +1,237 @@
+use new_string_template::template::Template;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+/// Built-in actions that can be run instead of a shell command
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Action {
+    /// Copy the changed file to the destination
+    Copy { dest: String },
+    /// Copy the changed file, and delete the destination when the
+    /// file is removed
+    DeleteDest { dest: String },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Copy { dest } => write!(f, "copy to {}", dest),
+            Self::DeleteDest { dest } => write!(f, "copy to {} (mirror deletes)", dest),
+        }
+    }
+}
+
+impl Action {
+    /// Render the destination template for the changed file, the
+    /// destination ending in separator is taken as directory
+    pub fn render(&self, map: &HashMap<String, String>) -> RenderedAction {
+        let (dest, mirror_delete) = match self {
+            Self::Copy { dest } => (dest, false),
+            Self::DeleteDest { dest } => (dest, true),
+        };
+        let src = PathBuf::from(&map["path"]);
+        let dest_str = Template::new(dest).render_nofail_string(map);
+        let mut dest = PathBuf::from(&dest_str);
+        if dest_str.ends_with(MAIN_SEPARATOR) || dest_str.ends_with('/') || dest.is_dir() {
+            dest.push(&map["name.ext"]);
+        }
+        RenderedAction {
+            delete: mirror_delete && !src.exists(),
+            src,
+            dest,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RenderedAction {
+    src: PathBuf,
+    dest: PathBuf,
+    /// Source is removed, so delete the destination
+    delete: bool,
+}
+
+impl fmt::Display for RenderedAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.delete {
+            write!(f, "delete {:?}", self.dest)
+        } else {
+            write!(f, "copy {:?} → {:?}", self.src, self.dest)
+        }
+    }
+}
+
+impl RenderedAction {
+    pub fn run(&self) -> Result<(), String> {
+        if self.delete {
+            return match fs::remove_file(&self.dest) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(format!("{:?}: {}", self.dest, e))
+                }
+                _ => Ok(()),
+            };
+        }
+        copy_atomic(&self.src, &self.dest).map_err(|e| format!("{} ({})", e, self))
+    }
+}
+
+/// Copy the file keeping its mtime, the file is copied to a temporary
+/// file next to the destination first and then renamed
+fn copy_atomic(src: &Path, dest: &Path) -> std::io::Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = dest.with_file_name(format!(
+        ".{}.onchange-tmp",
+        dest.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    fs::copy(src, &tmp)?;
+    let mtime = filetime::FileTime::from_last_modification_time(&src.metadata()?);
+    filetime::set_file_mtime(&tmp, mtime)?;
+    fs::rename(&tmp, dest).inspect_err(|_| {
+        fs::remove_file(&tmp).ok();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("onchange-action-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn vars(src: &Path, dir: &Path) -> HashMap<String, String> {
+        HashMap::from([
+            ("path".to_string(), src.to_string_lossy().to_string()),
+            (
+                "name.ext".to_string(),
+                src.file_name().unwrap().to_string_lossy().to_string(),
+            ),
+            (
+                "out".to_string(),
+                dir.join("out").to_string_lossy().to_string(),
+            ),
+        ])
+    }
+
+    fn copy(dest: &str) -> Action {
+        Action::Copy {
+            dest: dest.to_string(),
+        }
+    }
+
+    #[test]
+    fn destination_directories() {
+        let dir = temp_dir("render");
+        let src = dir.join("a.md");
+        fs::write(&src, "").unwrap();
+        let map = vars(&src, &dir);
+        // the separator at the end makes it a directory
+        assert_eq!(copy("{out}/x/").render(&map).dest, dir.join("out/x/a.md"));
+        assert_eq!(copy("{out}/x").render(&map).dest, dir.join("out/x"));
+        // so does an existing directory without it
+        fs::create_dir_all(dir.join("out/x")).unwrap();
+        assert_eq!(copy("{out}/x").render(&map).dest, dir.join("out/x/a.md"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copies_to_nested_destinations() {
+        let dir = temp_dir("nested");
+        let src = dir.join("a.md");
+        fs::write(&src, "new").unwrap();
+        let mtime = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+        filetime::set_file_mtime(&src, mtime).unwrap();
+        let action = copy("{out}/deep/er/still/").render(&vars(&src, &dir));
+        action.run().unwrap();
+        let dest = dir.join("out/deep/er/still/a.md");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&dest.metadata().unwrap()),
+            mtime
+        );
+        // no temporary file is left next to it
+        assert_eq!(fs::read_dir(dest.parent().unwrap()).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copy_replaces_the_destination() {
+        let dir = temp_dir("atomic");
+        let (src, dest) = (dir.join("a.md"), dir.join("b.md"));
+        fs::write(&src, "new").unwrap();
+        fs::write(&dest, "old").unwrap();
+        copy_atomic(&src, &dest).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+        assert!(!dir.join(".b.md.onchange-tmp").exists());
+        // a missing source leaves the destination as it was
+        fs::write(&dest, "old").unwrap();
+        assert!(copy_atomic(&dir.join("gone.md"), &dest).is_err());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "old");
+        assert!(!dir.join(".b.md.onchange-tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deletes_are_mirrored() {
+        let dir = temp_dir("delete");
+        let src = dir.join("a.md");
+        fs::write(&src, "").unwrap();
+        let mirror = Action::DeleteDest {
+            dest: "{out}/".to_string(),
+        };
+        let map = vars(&src, &dir);
+        mirror.render(&map).run().unwrap();
+        let dest = dir.join("out/a.md");
+        assert!(dest.exists());
+        fs::remove_file(&src).unwrap();
+        let action = mirror.render(&map);
+        assert_eq!(action.to_string(), format!("delete {:?}", dest));
+        action.run().unwrap();
+        assert!(!dest.exists());
+        // already gone is fine
+        action.run().unwrap();
+        // only copied by the plain copy, so it's an error for the file
+        let err = copy("{out}/").render(&map).run().unwrap_err();
+        assert!(err.contains("a.md"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn errors_name_the_file() {
+        let dir = temp_dir("denied");
+        let src = dir.join("a.md");
+        fs::write(&src, "").unwrap();
+        // a file in the way of the destination directory
+        fs::write(dir.join("out"), "").unwrap();
+        let err = copy("{out}/x/")
+            .render(&vars(&src, &dir))
+            .run()
+            .unwrap_err();
+        assert!(err.contains(&format!("{:?}", src)), "{}", err);
+        fs::remove_file(dir.join("out")).unwrap();
+
+        let locked = dir.join("out");
+        fs::create_dir(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o555)).unwrap();
+        let result = copy("{out}/").render(&vars(&src, &dir)).run();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        // root can write there anyway
+        if unsafe { libc::geteuid() } != 0 {
+            let err = result.unwrap_err();
+            assert!(err.contains("ermission denied"), "{}", err);
+            assert!(err.contains(&format!("{:?}", src)), "{}", err);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Interval the waits check for the cancellation at
+const SLICE: Duration = Duration::from_millis(50);
+
+/// Time the timed out (or cancelled) process has after the SIGTERM
+/// before it's killed
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// Set by the SIGINT/SIGTERM handler to stop watching
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// The stop signal is handled instead of killing everything
+static HANDLED: AtomicBool = AtomicBool::new(false);
+
+/// Files to remove at SIGINT or SIGTERM, as the drops may not run then
+static SIGNAL_FILES: OnceLock<Vec<CString>> = OnceLock::new();
+
+/// Stop watching at the first SIGINT or SIGTERM instead of dying, the
+/// next one gets the default action
+pub fn stop_on_signal() {
+    HANDLED.store(true, Ordering::SeqCst);
+    handle_signals();
+}
+
+/// Remove the files at SIGINT or SIGTERM too, whether it stops
+/// watching or dies from the signal
+pub fn remove_on_signal(files: &[&Path]) {
+    let files = files
+        .iter()
+        .filter_map(|f| CString::new(f.as_os_str().as_bytes()).ok())
+        .collect();
+    if SIGNAL_FILES.set(files).is_ok() {
+        handle_signals();
+    }
+}
+
+/// The one handler for both, so neither replaces the other
+fn handle_signals() {
+    extern "C" fn handler(sig: libc::c_int) {
+        for file in SIGNAL_FILES.get().into_iter().flatten() {
+            unsafe { libc::unlink(file.as_ptr()) };
+        }
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+        }
+        if HANDLED.load(Ordering::SeqCst) {
+            STOP.store(true, Ordering::SeqCst);
+        } else {
+            // die from the same signal as if there was no handler
+            unsafe { libc::raise(sig) };
+        }
+    }
+    unsafe {
+        libc::signal(libc::SIGINT, handler as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as *const () as libc::sighandler_t);
+    }
+}
+
+/// The stop signal is handled, then the commands need to be stopped
+/// by onchange on shutdown
+pub fn signal_handled() -> bool {
+    HANDLED.load(Ordering::SeqCst)
+}
+
+/// Stop watching like the stop signal does, e.g. when the reader of
+/// the JSON reports is gone
+pub fn stop() {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+/// Stop was asked for with a signal
+pub fn stopped() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
+
+/// Cancels the waits it's passed to, it's also cancelled by the stop
+/// signal so the shutdown doesn't wait for the running stages
+#[derive(Clone, Default)]
+pub struct Token(Arc<AtomicBool>);
+
+impl Token {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst) || stopped()
+    }
+
+    /// Sleep for the duration, false if it was cancelled meanwhile
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let end = Instant::now() + duration;
+        loop {
+            if self.is_cancelled() {
+                return false;
+            }
+            let left = end.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+            thread::sleep(left.min(SLICE));
+        }
+    }
+
+    /// Run `wait` (that waits for the process), terminating the
+    /// process (or its group) if cancelled before it returns, and
+    /// killing it if it's still running after a grace period
+    pub fn guard<T>(&self, pid: Option<u32>, group: bool, wait: impl FnOnce() -> T) -> T {
+        self.guard_timeout(pid, group, None, wait).0
+    }
+
+    /// Like `guard`, the process is also terminated after the timeout
+    /// (and killed if it's still running after a grace period), true
+    /// if it timed out
+    pub fn guard_timeout<T>(
+        &self,
+        pid: Option<u32>,
+        group: bool,
+        timeout: Option<Duration>,
+        wait: impl FnOnce() -> T,
+    ) -> (T, bool) {
+        let Some(pid) = pid else {
+            return (wait(), false);
+        };
+        let target = if group { -(pid as i32) } else { pid as i32 };
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let timed_out = AtomicBool::new(false);
+        let done = (Mutex::new(false), Condvar::new());
+        let out = thread::scope(|s| {
+            s.spawn(|| {
+                let mut finished = done.0.lock().unwrap();
+                let mut terminated: Option<Instant> = None;
+                while !*finished {
+                    if let Some(at) = terminated {
+                        if at.elapsed() > KILL_GRACE {
+                            unsafe { libc::kill(target, libc::SIGKILL) };
+                            return;
+                        }
+                    } else if self.is_cancelled() {
+                        unsafe { libc::kill(target, libc::SIGTERM) };
+                        terminated = Some(Instant::now());
+                    } else if deadline.is_some_and(|d| Instant::now() >= d) {
+                        timed_out.store(true, Ordering::SeqCst);
+                        unsafe { libc::kill(target, libc::SIGTERM) };
+                        terminated = Some(Instant::now());
+                    }
+                    finished = done.1.wait_timeout(finished, SLICE).unwrap().0;
+                }
+            });
+            let out = wait();
+            *done.0.lock().unwrap() = true;
+            done.1.notify_one();
+            out
+        });
+        (out, timed_out.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn cancelled_sleep_returns_early() {
+        let token = Token::default();
+        let t = token.clone();
+        let start = Instant::now();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            t.cancel();
+        });
+        assert!(!token.sleep(Duration::from_secs(10)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn cancel_kills_the_process_ignoring_sigterm() {
+        let mut child = Command::new("sh")
+            .args(["-c", "trap '' TERM; sleep 30"])
+            .spawn()
+            .unwrap();
+        let token = Token::default();
+        let t = token.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            t.cancel();
+        });
+        let start = Instant::now();
+        let (status, timed_out) =
+            token.guard_timeout(Some(child.id()), false, None, || child.wait().unwrap());
+        assert!(start.elapsed() < KILL_GRACE + Duration::from_secs(2));
+        assert!(!status.success());
+        assert!(!timed_out);
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn timeout_terminates_the_process() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let start = Instant::now();
+        let (_, timed_out) = Token::default().guard_timeout(
+            Some(child.id()),
+            false,
+            Some(Duration::from_millis(100)),
+            || child.wait().unwrap(),
+        );
+        assert!(timed_out);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,1319 @@
+use crate::action::Action;
+use crate::filter::EventType;
+use crate::output::infoln;
+use colored::Colorize;
+use new_string_template::template::{Template, DEFAULT_TEMPLATE};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+use std::time::Duration;
+
+/// A rule from the config file, applied to the files with the given
+/// extensions
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Rule {
+    /// Name of the rule, the table name in the config file
+    #[serde(skip_deserializing)]
+    pub name: String,
+    /// Space separated list of extensions this rule applies to
+    #[serde(default)]
+    pub extensions: String,
+    /// Path pattern (relative to the current directory) the rule
+    /// applies to, checked before the extensions of the other rules
+    #[serde(default, with = "pattern_opt")]
+    pub pattern: Option<glob::Pattern>,
+    /// Regex the file name has to match for the rule to apply, its
+    /// named groups are variables
+    #[serde(default, with = "regex_opt")]
+    pub regex: Option<Regex>,
+    /// Command template to run, or a table of them by the kind of
+    /// change; no command means ignore rule
+    pub command: Option<Command>,
+    /// Command templates to run one after the other instead of
+    /// `command`, stopping at the first one that fails
+    pub commands: Option<Vec<String>>,
+    /// Run the rest of `commands` even after one fails
+    pub continue_on_error: Option<bool>,
+    /// Command templates to try in order instead of `command`, until
+    /// one succeeds; the ones that can't be run are skipped
+    pub command_fallbacks: Option<Vec<String>>,
+    /// Command template to run instead of command the first time the
+    /// rule runs in the session
+    pub first_command: Option<String>,
+    /// Command template whose output gives extra variables
+    pub extra_variables: Option<String>,
+    /// User facing description of what the rule does
+    pub description: Option<String>,
+    /// Run the same rendered command only once per batch of changes
+    pub dedupe: Option<bool>,
+    /// Split the batch into chunks of at most this many files, the
+    /// deduplicated command runs once per chunk
+    pub batch_size: Option<usize>,
+    /// Ask for confirmation before running on new files, when
+    /// `--confirm-new-files` is used
+    pub confirm_new: Option<bool>,
+    /// Remote hosts to run the command on through ssh
+    pub ssh: Option<Vec<String>>,
+    /// Built-in action to run instead of the command
+    pub action: Option<Action>,
+    /// Apply the rule to the dotfiles (names starting with `.`)
+    pub dotfiles: Option<bool>,
+    /// Don't apply the rule to the paths with a hidden file or
+    /// directory in them, like `--ignore-hidden`
+    pub ignore_hidden: Option<bool>,
+    /// Command template that must succeed for the command to run
+    pub check: Option<String>,
+    /// Run the command when the check fails instead
+    pub check_invert: Option<bool>,
+    /// Which events of the direct children of the watched directories
+    /// the rule runs for, overrides `--dir-events`
+    pub dir_events: Option<crate::filter::DirEvents>,
+    /// Run the command per file or once for all the files of the
+    /// batch, `--batch` is the default
+    pub mode: Option<RunMode>,
+    /// Kinds of changes the rule runs for, `--events` overrides it
+    pub events: Option<Vec<crate::filter::EventType>>,
+    /// Quiet period the rule waits for after the changes of a file,
+    /// on top of the global debounce
+    #[serde(default, with = "duration_opt")]
+    pub debounce: Option<Duration>,
+    /// Debounce duration for the rule's files instead of `--duration`,
+    /// the changes within it from the first one run once
+    #[serde(default, with = "duration_opt")]
+    pub duration: Option<Duration>,
+    /// Delay before running the command instead of `--delay`
+    #[serde(default, with = "duration_opt")]
+    pub delay: Option<Duration>,
+    /// Time the command can run for before it's terminated, instead
+    /// of `--timeout`
+    #[serde(default, with = "duration_opt")]
+    pub timeout: Option<Duration>,
+    /// Time the check can take before it counts as failed
+    #[serde(default, with = "duration_opt")]
+    pub check_timeout: Option<Duration>,
+    /// Path templates of the files that have to arrive with the changed
+    /// one before the command runs, like "{dir}/{name}.schema.json"
+    pub await_set: Option<Vec<String>>,
+    /// Time to wait for the `await_set` files
+    #[serde(default, with = "duration_opt")]
+    pub await_timeout: Option<Duration>,
+    /// Whether the command runs or is skipped when the `await_set`
+    /// files don't all arrive in time
+    pub await_policy: Option<AwaitPolicy>,
+    /// Delimiters of the variables in the rule's templates instead of
+    /// `{}`, overrides the one in `[global]`
+    pub template_delimiters: Option<Delimiters>,
+    /// Environment variables for the command, the values are
+    /// templates like the command
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Directory to run the command in, a template like `{dir}`,
+    /// overrides `--cwd`
+    pub cwd: Option<String>,
+    /// File marking the package root to run the command in, the
+    /// nearest directory of the changed file that has it
+    pub workdir_marker: Option<String>,
+    /// Container to run the command in
+    pub container: Option<Container>,
+    /// Path patterns the rule is limited to, along with the extensions
+    #[serde(default, with = "patterns")]
+    pub only: Vec<glob::Pattern>,
+}
+
+impl Rule {
+    /// Extensions of the rule, with a leading dot removed so ".rs"
+    /// works the same as "rs"
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.extensions
+            .split_whitespace()
+            .map(|e| e.strip_prefix('.').unwrap_or(e))
+    }
+
+    /// Variables from the named groups of the rule's regex for the
+    /// file name, the unmatched groups are empty
+    pub fn captures(&self, name: &str) -> Vec<(String, String)> {
+        let Some(regex) = &self.regex else {
+            return vec![];
+        };
+        let Some(caps) = regex.captures(name) else {
+            return vec![];
+        };
+        regex
+            .capture_names()
+            .flatten()
+            .map(|group| {
+                let value = caps.name(group).map_or("", |m| m.as_str());
+                (group.to_string(), value.to_string())
+            })
+            .collect()
+    }
+
+    /// Rule for the files no other rule applies to: `extensions =
+    /// "*"`, or the `[default]` table without extensions
+    pub fn is_catch_all(&self) -> bool {
+        self.extensions.trim() == "*"
+            || (self.name == "default"
+                && self.extensions.trim().is_empty()
+                && self.pattern.is_none())
+    }
+
+    /// What the rule is matched by: its pattern, and the extensions
+    pub fn selector(&self) -> String {
+        if self.is_catch_all() {
+            return "*".to_string();
+        }
+        match (&self.pattern, self.extensions.trim()) {
+            (Some(p), "") => p.to_string(),
+            (Some(p), exts) => format!("{}, {}", p, exts),
+            (None, exts) => exts.to_string(),
+        }
+    }
+
+    /// Debounce duration for the rule's files, the global debounce
+    /// runs first so it can't be shorter than that
+    pub fn duration(&self, global: Duration) -> Duration {
+        self.duration.map_or(global, |d| d.max(global))
+    }
+
+    /// Delay before running the rule's command
+    pub fn delay(&self, global: Duration) -> Duration {
+        self.delay.unwrap_or(global)
+    }
+
+    /// Steps of `commands` (or the `command_fallbacks`) to run
+    /// instead of the command, unless it's the first run with a
+    /// `first_command`
+    pub fn steps(&self, first_run: bool) -> Option<&[String]> {
+        match &self.first_command {
+            Some(_) if first_run => None,
+            _ => self
+                .commands
+                .as_deref()
+                .or(self.command_fallbacks.as_deref()),
+        }
+    }
+
+    /// The steps are fallbacks, only run until one succeeds
+    pub fn fallbacks(&self) -> bool {
+        self.command_fallbacks.is_some()
+    }
+
+    pub fn continue_on_error(&self) -> bool {
+        self.continue_on_error.unwrap_or(false)
+    }
+
+    /// Command template to run for the kind of change (`None` for
+    /// unknown), `first_command` on the first run
+    pub fn command(&self, first_run: bool, kind: Option<EventType>) -> Option<&str> {
+        match &self.first_command {
+            Some(cmd) if first_run => Some(cmd),
+            _ => self.command.as_ref()?.get(kind),
+        }
+    }
+
+    pub fn extra_variables(&self) -> Option<Template> {
+        self.extra_variables.as_ref().map(|c| self.template(c))
+    }
+
+    /// Template with the rule's delimiters for the variables
+    pub fn template(&self, templ: &str) -> Template {
+        match &self.template_delimiters {
+            Some(d) => Template::new_regex(templ, &d.regex()),
+            None => Template::new(templ),
+        }
+    }
+
+    /// Command (and the directory and environment it runs in) doesn't
+    /// reference any variable that changes with the changed file, so
+    /// every event renders the same command
+    pub fn is_static(&self) -> bool {
+        let templates = match &self.command {
+            Some(cmd) => cmd.templates(),
+            None => vec![],
+        };
+        !templates.iter().all(|c| c.is_empty())
+            && templates
+                .into_iter()
+                .chain(&self.cwd)
+                .chain(self.env.values())
+                .all(|templ| {
+                    template_variables(templ, self.template_delimiters.as_ref())
+                        .iter()
+                        .all(|v| {
+                            SESSION_VARIABLES.contains(&v.as_str())
+                                || BATCH_VARIABLES.contains(&v.as_str())
+                        })
+                })
+    }
+
+    pub fn check(&self) -> Option<Template> {
+        self.check.as_ref().map(|c| self.template(c))
+    }
+
+    pub fn check_timeout(&self) -> Duration {
+        self.check_timeout.unwrap_or(CHECK_TIMEOUT)
+    }
+
+    pub fn await_timeout(&self) -> Duration {
+        self.await_timeout.unwrap_or(AWAIT_TIMEOUT)
+    }
+
+    pub fn dedupe(&self) -> bool {
+        self.dedupe.unwrap_or_else(|| self.is_static())
+    }
+}
+
+/// Container a rule's command runs in, through docker or podman
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Container {
+    pub image: String,
+    /// `host:container` volume mounts, can use the variables
+    #[serde(default)]
+    pub mounts: Vec<String>,
+    /// Directory the command runs in, in the container; can use the
+    /// variables
+    pub workdir: Option<String>,
+}
+
+/// Command of a rule, one template for all the changes or a table of
+/// them by the kind of change
+#[derive(Serialize, Clone)]
+#[serde(untagged)]
+pub enum Command {
+    All(String),
+    ByKind(KindCommands),
+}
+
+impl<'de> Deserialize<'de> for Command {
+    // by hand instead of untagged, to keep the errors of the table
+    fn deserialize<D: serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Command;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(
+                    f,
+                    "a command template or a table of them by the kind of change"
+                )
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Command, E> {
+                Ok(Command::All(v.to_string()))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Command, A::Error> {
+                KindCommands::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                    .map(Command::ByKind)
+            }
+        }
+        de.deserialize_any(Visitor)
+    }
+}
+
+/// Command templates by the kind of change, `any` is used for the
+/// kinds without their own
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KindCommands {
+    pub create: Option<String>,
+    pub modify: Option<String>,
+    pub remove: Option<String>,
+    pub rename: Option<String>,
+    pub any: Option<String>,
+}
+
+impl Command {
+    /// Template for the kind of change, the most specific one there is
+    pub fn get(&self, kind: Option<EventType>) -> Option<&str> {
+        match self {
+            Self::All(cmd) => Some(cmd),
+            Self::ByKind(k) => {
+                let specific = match kind {
+                    Some(EventType::Create) => &k.create,
+                    Some(EventType::Modify) => &k.modify,
+                    Some(EventType::Remove) => &k.remove,
+                    Some(EventType::Rename) => &k.rename,
+                    None => &None,
+                };
+                specific.as_deref().or(k.any.as_deref())
+            }
+        }
+    }
+
+    /// All the templates, with the kind they're for
+    pub fn by_kind(&self) -> Vec<(&'static str, &String)> {
+        match self {
+            Self::All(cmd) => vec![("any", cmd)],
+            Self::ByKind(k) => [
+                ("create", &k.create),
+                ("modify", &k.modify),
+                ("remove", &k.remove),
+                ("rename", &k.rename),
+                ("any", &k.any),
+            ]
+            .into_iter()
+            .filter_map(|(kind, cmd)| Some((kind, cmd.as_ref()?)))
+            .collect(),
+        }
+    }
+
+    pub fn templates(&self) -> Vec<&String> {
+        self.by_kind().into_iter().map(|(_, cmd)| cmd).collect()
+    }
+
+    pub fn templates_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Self::All(cmd) => vec![cmd],
+            Self::ByKind(k) => [
+                &mut k.create,
+                &mut k.modify,
+                &mut k.remove,
+                &mut k.rename,
+                &mut k.any,
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::All(cmd) => write!(f, "{}", cmd),
+            Self::ByKind(_) => write!(
+                f,
+                "{}",
+                self.by_kind()
+                    .iter()
+                    .map(|(kind, cmd)| format!("{}: {}", kind, cmd))
+                    .collect::<Vec<String>>()
+                    .join("; ")
+            ),
+        }
+    }
+}
+
+/// How the rule runs for the files changed together
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunMode {
+    PerFile,
+    Batch,
+}
+
+/// What's done when the `await_set` of a rule times out
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AwaitPolicy {
+    Run,
+    Skip,
+}
+
+/// Default time the check command can take
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default time to wait for the `await_set` files
+const AWAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Variables that stay the same for the whole session
+pub const SESSION_VARIABLES: [&str; 5] = [
+    "pwd",
+    "rundir",
+    "onchange_version",
+    "config_hash",
+    "session_start",
+];
+
+/// Variables that stay the same for the whole batch (or its chunk)
+pub const BATCH_VARIABLES: [&str; 9] = [
+    "batch.count",
+    "batch.created",
+    "batch.modified",
+    "batch.removed",
+    "batch.renamed",
+    "batch.exts",
+    "batch.roots",
+    "chunk_index",
+    "chunk_total",
+];
+
+/// Names of the variables used in the template string, with the
+/// default `{}` delimiters if none are given
+pub fn template_variables(templ: &str, delimiters: Option<&Delimiters>) -> Vec<String> {
+    variable_regex(delimiters)
+        .captures_iter(templ)
+        .map(|c| c[1].to_string())
+        .collect()
+}
+
+/// Regex matching the variables with the delimiters, `{}` by default
+pub fn variable_regex(delimiters: Option<&Delimiters>) -> Regex {
+    delimiters
+        .map(|d| d.regex())
+        .unwrap_or_else(|| DEFAULT_TEMPLATE.clone())
+}
+
+/// Shell to run the commands with, a string split like the shell
+/// does (`"fish -c"`) or the list of its arguments
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Shell {
+    Line(String),
+    Argv(Vec<String>),
+}
+
+impl Shell {
+    /// The program and its arguments, the command goes after them
+    pub fn argv(&self) -> Result<Vec<String>, String> {
+        match self {
+            Shell::Line(line) => crate::exec::split_shell(line),
+            Shell::Argv(argv) => Ok(argv.clone()),
+        }
+    }
+}
+
+/// Opening and closing delimiters of the variables, for the commands
+/// that need the `{}` for themselves, e.g. `["<%", "%>"]`
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct Delimiters(pub String, pub String);
+
+impl Delimiters {
+    pub fn regex(&self) -> Regex {
+        Regex::new(&format!(
+            r"(?mi){}\s*(\S+?)\s*{}",
+            regex::escape(&self.0),
+            regex::escape(&self.1)
+        ))
+        .unwrap()
+    }
+}
+
+/// Path prefix to swap for the `{rewrite.<name>}` variables, e.g. from
+/// a build output mirror to its source directory
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Rewrite {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl Rewrite {
+    /// The path with `from` prefix replaced by `to`, relative `from`
+    /// is matched against the path relative to the current directory
+    pub fn apply(&self, path: &Path, rpath: &Path) -> Option<PathBuf> {
+        let path = if self.from.is_absolute() { path } else { rpath };
+        let rest = path.strip_prefix(&self.from).ok()?;
+        if rest.as_os_str().is_empty() {
+            Some(self.to.clone())
+        } else {
+            Some(self.to.join(rest))
+        }
+    }
+}
+
+/// Rules from the config file, with lookup by extension
+#[derive(Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    by_ext: HashMap<String, usize>,
+    /// Rule for the files no other rule applies to
+    catch_all: Option<usize>,
+    /// Delimiters of the variables from `[global]`, for the default
+    /// command
+    pub delimiters: Option<Delimiters>,
+    /// Other names for the variables from the config, `alias -> name`
+    pub var_aliases: BTreeMap<String, String>,
+    /// Path rewrites from the config file, by name
+    pub rewrites: BTreeMap<String, Rewrite>,
+    /// Variables from the `[variables]` table, templates rendered
+    /// for each event
+    pub variables: BTreeMap<String, String>,
+    /// Command template for the files no rule applies to
+    pub default_command: Option<String>,
+    /// Paths to watch from the config, can use the session variables
+    pub watch: Vec<String>,
+    /// Ignore patterns from the config, used with the ones from the CLI
+    pub ignore: Vec<glob::Pattern>,
+    /// Patterns of more editor files, ignored with the built-in ones
+    pub editor_ignores: Vec<glob::Pattern>,
+    /// Command template to run for the runs interrupted in the
+    /// earlier session, found from the journal
+    pub on_recover: Option<String>,
+    /// Shell to run the commands with instead of `sh -c`
+    pub shell: Option<Shell>,
+    /// Entries of the config that were normalized, as `(given, used)`
+    pub normalized: Vec<(String, String)>,
+    /// Extensions are matched regardless of their case
+    pub ignore_case: bool,
+    /// Hash of the loaded config, the same for the same settings
+    /// however the file is formatted; empty without a config
+    pub hash: String,
+}
+
+impl Rules {
+    pub fn new(conf: HashMap<String, Rule>) -> Self {
+        let mut rules: Vec<Rule> = conf
+            .into_iter()
+            .map(|(name, mut rule)| {
+                rule.name = name;
+                rule
+            })
+            .collect();
+        rules.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut by_ext = HashMap::new();
+        let mut normalized = Vec::new();
+        let catch_all = rules.iter().position(|r| r.is_catch_all());
+        // the rules with a pattern are only found through it
+        for (i, rule) in rules.iter().enumerate() {
+            if rule.pattern.is_some() || rule.is_catch_all() {
+                continue;
+            }
+            for (given, ext) in rule.extensions.split_whitespace().zip(rule.extensions()) {
+                if given != ext {
+                    normalized.push((given.to_string(), ext.to_string()));
+                }
+                by_ext.insert(ext.to_string(), i);
+            }
+        }
+        Self {
+            rules,
+            by_ext,
+            catch_all,
+            delimiters: None,
+            var_aliases: BTreeMap::new(),
+            rewrites: BTreeMap::new(),
+            variables: BTreeMap::new(),
+            default_command: None,
+            watch: vec![],
+            ignore: vec![],
+            editor_ignores: vec![],
+            on_recover: None,
+            shell: None,
+            normalized,
+            ignore_case: false,
+            hash: String::new(),
+        }
+    }
+
+    /// Match the extensions regardless of their case, the ones of the
+    /// rules are made lowercase
+    pub fn ignore_case(&mut self) {
+        self.ignore_case = true;
+        let mut by_ext: Vec<(String, usize)> =
+            std::mem::take(&mut self.by_ext).into_iter().collect();
+        by_ext.sort();
+        for (ext, i) in by_ext {
+            let lower = ext.to_lowercase();
+            if lower != ext {
+                self.normalized.push((ext, lower.clone()));
+            }
+            self.by_ext.insert(lower, i);
+        }
+    }
+
+    /// Rule for the changed file from its variables: the first one
+    /// (by name) whose pattern matches, else the one for the extension
+    pub fn find(&self, map: &HashMap<String, String>) -> Option<&Rule> {
+        let ext = match self.ignore_case {
+            true => map["ext"].to_lowercase(),
+            false => map["ext"].clone(),
+        };
+        let path = Path::new(&map["path"]);
+        let pwd = Path::new(&map["pwd"]);
+        let by_pattern = self.rules.iter().find(|r| {
+            r.pattern
+                .as_ref()
+                .is_some_and(|p| crate::filter::matches_any_form(p, path, pwd, None))
+                && (r.extensions.trim().is_empty()
+                    || r.extensions().any(|e| match self.ignore_case {
+                        true => e.to_lowercase() == ext,
+                        false => e == ext,
+                    }))
+        });
+        let rule = match by_pattern {
+            Some(rule) => rule,
+            None => self
+                .by_ext
+                .get(&ext)
+                .or(self.catch_all.as_ref())
+                .map(|&i| &self.rules[i])?,
+        };
+        if map["name.ext"].starts_with('.') && !rule.dotfiles.unwrap_or(true) {
+            return None;
+        }
+        if rule
+            .regex
+            .as_ref()
+            .is_some_and(|r| !r.is_match(&map["name.ext"]))
+        {
+            return None;
+        }
+        if !rule.only.is_empty()
+            && !rule
+                .only
+                .iter()
+                .any(|p| crate::filter::matches_any_form(p, path, pwd, None))
+        {
+            return None;
+        }
+        // the rule's `only` patterns already name what it's for
+        if rule.ignore_hidden.unwrap_or(false)
+            && rule.only.is_empty()
+            && crate::filter::hidden(path, Some(pwd))
+        {
+            return None;
+        }
+        Some(rule)
+    }
+
+    /// Some template of the config uses the variable
+    pub fn uses_variable(&self, var: &str) -> bool {
+        let uses = |templ: &String, delimiters: Option<&Delimiters>| {
+            template_variables(templ, delimiters)
+                .iter()
+                .any(|v| v == var)
+        };
+        self.rules.iter().any(|r| {
+            let delimiters = r.template_delimiters.as_ref();
+            r.command
+                .iter()
+                .flat_map(|c| c.templates())
+                .chain(&r.first_command)
+                .chain(&r.extra_variables)
+                .chain(&r.check)
+                .chain(r.await_set.iter().flatten())
+                .chain(&r.cwd)
+                .chain(r.env.values())
+                .any(|t| uses(t, delimiters))
+        }) || self
+            .default_command
+            .iter()
+            .chain(self.variables.values())
+            .any(|t| uses(t, self.delimiters.as_ref()))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+}
+
+/// Parse the duration like "500ms", "2s" or "1m30s", bare numbers
+/// are taken as milliseconds for backward compatibility
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    static NOTE: Once = Once::new();
+    if let Ok(ms) = s.trim().parse::<u64>() {
+        NOTE.call_once(|| {
+            infoln!(
+                "{}: duration without unit ({1}) is taken as milliseconds, use \"{1}ms\" instead",
+                "Note".dimmed(),
+                s.trim()
+            )
+        });
+        return Ok(Duration::from_millis(ms));
+    }
+    humantime::parse_duration(s).map_err(|e| {
+        format!(
+            "invalid duration {:?} ({}), use something like \"500ms\" or \"1m30s\"",
+            s, e
+        )
+    })
+}
+
+/// Optional durations in the config file, written like "500ms" or "2s"
+mod duration_opt {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(de)?
+            .map(|s| super::parse_duration(&s).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+
+    pub fn serialize<S: Serializer>(d: &Option<Duration>, ser: S) -> Result<S::Ok, S::Error> {
+        match d {
+            Some(d) => ser.serialize_str(&humantime::format_duration(*d).to_string()),
+            None => ser.serialize_none(),
+        }
+    }
+}
+
+mod pattern_opt {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        de: D,
+    ) -> Result<Option<glob::Pattern>, D::Error> {
+        Option::<String>::deserialize(de)?
+            .map(|p| {
+                glob::Pattern::new(&p)
+                    .map_err(|e| serde::de::Error::custom(format!("pattern {:?}: {}", p, e)))
+            })
+            .transpose()
+    }
+
+    pub fn serialize<S: Serializer>(p: &Option<glob::Pattern>, ser: S) -> Result<S::Ok, S::Error> {
+        match p {
+            Some(p) => ser.serialize_str(p.as_str()),
+            None => ser.serialize_none(),
+        }
+    }
+}
+
+mod regex_opt {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Option<regex::Regex>, D::Error> {
+        Option::<String>::deserialize(de)?
+            .map(|r| {
+                regex::Regex::new(&r)
+                    .map_err(|e| serde::de::Error::custom(format!("regex {:?}: {}", r, e)))
+            })
+            .transpose()
+    }
+
+    pub fn serialize<S: Serializer>(r: &Option<regex::Regex>, ser: S) -> Result<S::Ok, S::Error> {
+        match r {
+            Some(r) => ser.serialize_str(r.as_str()),
+            None => ser.serialize_none(),
+        }
+    }
+}
+
+mod patterns {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Vec<glob::Pattern>, D::Error> {
+        Vec::<String>::deserialize(de)?
+            .iter()
+            .map(|p| {
+                glob::Pattern::new(p)
+                    .map_err(|e| serde::de::Error::custom(format!("pattern {:?}: {}", p, e)))
+            })
+            .collect()
+    }
+
+    pub fn serialize<S: Serializer>(patterns: &[glob::Pattern], ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_seq(patterns.iter().map(|p| p.as_str()))
+    }
+}
+
+pub fn get_config(conf: &Option<String>) -> Result<config::Config, String> {
+    if let Some(conf_file) = conf {
+        return config::Config::builder()
+            .add_source(config::File::with_name(conf_file))
+            .build()
+            .map_err(|e| e.to_string());
+    }
+    config::Config::builder()
+        .add_source(
+            config_files()
+                .iter()
+                .map(|f| config::File::from(f.as_path()))
+                .collect::<Vec<config::File<_, _>>>(),
+        )
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Config files found in the search path, the later ones override
+/// the earlier ones
+pub fn config_files() -> Vec<PathBuf> {
+    [
+        PathBuf::from("/etc/onchange.toml"),
+        PathBuf::from(format!(
+            "{}/.config/onchange.toml",
+            std::env::var("HOME").unwrap_or_default()
+        )),
+        PathBuf::from(".onchange.toml"),
+    ]
+    .into_iter()
+    .filter(|f| f.exists())
+    .collect()
+}
+
+/// Contents of the config file, special tables and the rules
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    aliases: Aliases,
+    /// Path prefixes to swap for the `{rewrite.<name>}` variables
+    #[serde(default)]
+    rewrites: BTreeMap<String, Rewrite>,
+    /// Variables for all the templates, `[variables]`
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+    #[serde(default)]
+    global: Global,
+    #[serde(flatten)]
+    rules: HashMap<String, Rule>,
+}
+
+/// The `[aliases]` table
+#[derive(Deserialize, Default)]
+struct Aliases {
+    /// Other names for the variables, `[aliases.vars]`
+    #[serde(default)]
+    vars: BTreeMap<String, String>,
+    /// Command snippets usable as `{@name}` in the rule commands
+    #[serde(flatten)]
+    commands: HashMap<String, String>,
+}
+
+/// Settings in the `[global]` table
+#[derive(Deserialize, Default)]
+struct Global {
+    /// Command template for the files no rule applies to
+    default_command: Option<String>,
+    /// Paths to watch in addition to the ones given in the CLI
+    #[serde(default)]
+    watch: Vec<String>,
+    /// Ignore patterns in addition to the ones given in the CLI
+    #[serde(default)]
+    ignore: Vec<String>,
+    /// Patterns of the temporary files of other editors, ignored like
+    /// the built-in ones
+    #[serde(default)]
+    extra_editor_ignores: Vec<String>,
+    /// Command template to clean up after the interrupted runs
+    on_recover: Option<String>,
+    /// Shell and its arguments to run the commands with, like "fish -c"
+    /// or `["pwsh", "-Command"]`
+    shell: Option<Shell>,
+    /// Delimiters of the variables for all the rules
+    template_delimiters: Option<Delimiters>,
+    /// Match the extensions of the rules regardless of their case
+    #[serde(default)]
+    ignore_case: bool,
+}
+
+pub fn load_rules(conf: &Option<String>) -> Result<Rules, String> {
+    get_config(conf).and_then(rules_from)
+}
+
+/// Rules from the config given as the TOML text, for the tests
+#[cfg(test)]
+pub fn rules_from_toml(text: &str) -> Result<Rules, String> {
+    config::Config::builder()
+        .add_source(config::File::from_str(text, config::FileFormat::Toml))
+        .build()
+        .map_err(|e| e.to_string())
+        .and_then(rules_from)
+}
+
+fn rules_from(conf: config::Config) -> Result<Rules, String> {
+    let mut conf: ConfigFile = conf.try_deserialize().map_err(|e| e.to_string())?;
+    for (name, rule) in conf.rules.iter_mut() {
+        rule.name = name.clone();
+        let mut templates: Vec<&mut String> = [
+            &mut rule.first_command,
+            &mut rule.extra_variables,
+            &mut rule.check,
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        templates.extend(rule.await_set.iter_mut().flatten());
+        if let Some(cmd) = &mut rule.command {
+            templates.extend(cmd.templates_mut());
+        }
+        if let Some(steps) = &mut rule.commands {
+            templates.extend(steps.iter_mut());
+        }
+        if let Some(fallbacks) = &mut rule.command_fallbacks {
+            templates.extend(fallbacks.iter_mut());
+        }
+        for templ in templates {
+            *templ = expand_aliases(templ, &conf.aliases.commands, &mut vec![])
+                .map_err(|e| format!("rule {}: {}", name, e))?;
+        }
+        for group in rule.regex.iter().flat_map(|r| r.capture_names().flatten()) {
+            if crate::vars::builtin_target(group).is_some() {
+                return Err(format!(
+                    "rule {}: regex group {:?} would shadow the built-in variable {{{}}}, rename it",
+                    name, group, group
+                ));
+            }
+        }
+        if rule.extensions.trim().is_empty() && rule.pattern.is_none() && name != "default" {
+            return Err(format!(
+                "rule {}: has neither extensions nor a pattern, it would never apply",
+                name
+            ));
+        }
+        if let Some(fallbacks) = &rule.command_fallbacks {
+            if rule.command.is_some() || rule.commands.is_some() {
+                return Err(format!(
+                    "rule {}: has command_fallbacks with command or commands, use one of them",
+                    name
+                ));
+            }
+            rule.command = Some(Command::All(fallbacks.join(" || ")));
+        }
+        if let Some(steps) = &rule.commands {
+            if rule.command.is_some() {
+                return Err(format!(
+                    "rule {}: has both command and commands, use one of them",
+                    name
+                ));
+            }
+            // the whole sequence as one command, for showing it and
+            // everything else that looks at the command
+            let sep = if rule.continue_on_error() {
+                "; "
+            } else {
+                " && "
+            };
+            rule.command = Some(Command::All(steps.join(sep)));
+        }
+    }
+    let mut catch_all: Vec<&String> = conf
+        .rules
+        .iter()
+        .filter(|(_, r)| r.is_catch_all())
+        .map(|(name, _)| name)
+        .collect();
+    catch_all.sort();
+    match (&catch_all[..], &conf.global.default_command) {
+        ([_, _, ..], _) => {
+            return Err(format!(
+                "rules {} are all for the files without a rule, keep one of them",
+                catch_all
+                    .iter()
+                    .map(|n| n.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ))
+        }
+        ([name], Some(_)) => {
+            return Err(format!(
+                "rule {} and default_command in [global] are both for the files without a rule, keep one of them",
+                name
+            ))
+        }
+        _ => (),
+    }
+    if let Some(cmd) = &mut conf.global.default_command {
+        *cmd = expand_aliases(cmd, &conf.aliases.commands, &mut vec![])
+            .map_err(|e| format!("default_command: {}", e))?;
+    }
+    for (alias, var) in &conf.aliases.vars {
+        match crate::vars::builtin_target(alias) {
+            Some(target) if target != var => {
+                return Err(format!(
+                    "variable alias {:?} would shadow the built-in variable {{{}}}",
+                    alias, alias
+                ));
+            }
+            _ => (),
+        }
+    }
+    if let Some(d) = &conf.global.template_delimiters {
+        for rule in conf.rules.values_mut() {
+            rule.template_delimiters.get_or_insert_with(|| d.clone());
+        }
+    }
+    for (name, rule) in &conf.rules {
+        if let Some(Delimiters(open, close)) = &rule.template_delimiters {
+            if open.is_empty() || close.is_empty() {
+                return Err(format!("rule {}: template_delimiters can't be empty", name));
+            }
+        }
+    }
+    let mut rules = Rules::new(conf.rules);
+    if conf.global.ignore_case {
+        rules.ignore_case();
+    }
+    rules.delimiters = conf.global.template_delimiters;
+    rules.var_aliases = conf.aliases.vars;
+    rules.rewrites = conf.rewrites;
+    rules.variables = conf.variables;
+    rules.default_command = conf.global.default_command;
+    rules.watch = conf.global.watch;
+    rules.on_recover = conf.global.on_recover;
+    rules.shell = conf.global.shell;
+    let mut ignore_pattern = |p: &String| {
+        let pattern = match crate::filter::bare_extension(p) {
+            Some(pattern) => {
+                rules.normalized.push((p.clone(), pattern.clone()));
+                pattern
+            }
+            None => p.clone(),
+        };
+        glob::Pattern::new(&pattern).map_err(|e| format!("ignore pattern {:?}: {}", p, e))
+    };
+    let ignore = conf
+        .global
+        .ignore
+        .iter()
+        .map(&mut ignore_pattern)
+        .collect::<Result<Vec<_>, String>>()?;
+    let editor_ignores = conf
+        .global
+        .extra_editor_ignores
+        .iter()
+        .map(&mut ignore_pattern)
+        .collect::<Result<Vec<_>, String>>()?;
+    rules.ignore = ignore;
+    rules.editor_ignores = editor_ignores;
+    rules.hash = config_hash(&rules);
+    Ok(rules)
+}
+
+/// Hash of the settings as loaded, not of the file's text: the json
+/// of the values has its keys sorted, so the order and formatting in
+/// the file don't change it
+fn config_hash(rules: &Rules) -> String {
+    let settings = serde_json::json!({
+        "rules": rules.rules,
+        "delimiters": rules.delimiters,
+        "aliases": rules.var_aliases,
+        "rewrites": rules.rewrites,
+        "variables": rules.variables,
+        "default_command": rules.default_command,
+        "watch": rules.watch,
+        "ignore": rules.ignore.iter().map(|p| p.as_str()).collect::<Vec<&str>>(),
+        "extra_editor_ignores": rules
+            .editor_ignores
+            .iter()
+            .map(|p| p.as_str())
+            .collect::<Vec<&str>>(),
+        "on_recover": rules.on_recover,
+        "shell": rules.shell,
+        "ignore_case": rules.ignore_case,
+    });
+    crate::manifest::hash_bytes(settings.to_string().as_bytes())
+}
+
+/// Replace the `{@name}` aliases in the template with their values,
+/// `stack` has the aliases being expanded to detect cycles
+fn expand_aliases(
+    templ: &str,
+    aliases: &HashMap<String, String>,
+    stack: &mut Vec<String>,
+) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = templ;
+    while let Some(start) = rest.find("{@") {
+        let end = match rest[start..].find('}') {
+            Some(e) => start + e,
+            None => break,
+        };
+        let name = rest[start + 2..end].trim();
+        if stack.iter().any(|a| a == name) {
+            return Err(format!("alias cycle: {} -> {}", stack.join(" -> "), name));
+        }
+        let value = aliases
+            .get(name)
+            .ok_or_else(|| format!("unknown alias {{@{}}}", name))?;
+        stack.push(name.to_string());
+        let value = expand_aliases(value, aliases, stack)?;
+        stack.pop();
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Name of the rule for the file in /project
+    fn rule_for(rules: &Rules, file: &str) -> Option<String> {
+        let mut map =
+            crate::vars::path_vars(&Path::new("/project").join(file), Path::new("/project"));
+        map.insert("pwd".to_string(), "/project".to_string());
+        rules.find(&map).map(|r| r.name.clone())
+    }
+
+    #[test]
+    fn dotfiles_find_their_rules() {
+        let rules = rules_from_toml(
+            "[local]\nextensions = \"local\"\ncommand = \"x\"\n\
+             [gz]\nextensions = \"gz\"\ncommand = \"x\"\ndotfiles = false\n\
+             [other]\nextensions = \"*\"\ncommand = \"x\"\n",
+        )
+        .unwrap();
+        let cases = [
+            (".gitignore", Some("other")),
+            (".env.local", Some("local")),
+            ("archive.tar.gz", Some("gz")),
+            (".backup.tar.gz", None),
+            ("README", Some("other")),
+        ];
+        for (file, rule) in cases {
+            assert_eq!(rule_for(&rules, file).as_deref(), rule, "{}", file);
+        }
+    }
+
+    #[test]
+    fn uppercase_extensions() {
+        let config = "[rs]\nextensions = \"RS TOML\"\ncommand = \"cargo check\"\n\
+                      [md]\nextensions = \"md\"\ncommand = \"pandoc {name.ext}\"\n";
+        let rules = rules_from_toml(config).unwrap();
+        // as written, only the files with the same case match
+        assert_eq!(rule_for(&rules, "main.RS").as_deref(), Some("rs"));
+        assert_eq!(rule_for(&rules, "main.rs"), None);
+        assert_eq!(rule_for(&rules, "a.MD"), None);
+        assert!(rules.normalized.is_empty());
+
+        let rules = rules_from_toml(&format!("[global]\nignore_case = true\n{}", config)).unwrap();
+        for (file, rule) in [
+            ("main.rs", "rs"),
+            ("main.RS", "rs"),
+            ("Cargo.toml", "rs"),
+            ("Cargo.Toml", "rs"),
+            ("a.MD", "md"),
+        ] {
+            assert_eq!(rule_for(&rules, file).as_deref(), Some(rule), "{}", file);
+        }
+        assert_eq!(
+            rules.normalized,
+            [
+                ("RS".to_string(), "rs".to_string()),
+                ("TOML".to_string(), "toml".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn static_rules_are_deduped() {
+        let rules = rules_from_toml(
+            "[rs]\nextensions = \"rs\"\ncommand = \"cargo build\"\n\
+             [md]\nextensions = \"md\"\ncommand = \"pandoc {name.ext}\"\n\
+             [mk]\nextensions = \"mk\"\ncommand = \"make -C {pwd} all\"\n\
+             [c]\nextensions = \"c\"\ncommand = \"make\"\ndedupe = false\n\
+             [h]\nextensions = \"h\"\ncommand = \"make\"\ncwd = \"{dir}\"\n\
+             [sh]\nextensions = \"sh\"\ncommand = \"shellcheck <%rpath%> {x}\"\n\
+             template_delimiters = [\"<%\", \"%>\"]\n\
+             [py]\nextensions = \"py\"\ncommand = \"echo {batch.count} changed\"\n\
+             [txt]\nextensions = \"txt\"\n",
+        )
+        .unwrap();
+        let flags: Vec<(&str, bool, bool)> = rules
+            .iter()
+            .map(|r| (r.name.as_str(), r.is_static(), r.dedupe()))
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                ("c", true, false),
+                ("h", false, false),
+                ("md", false, false),
+                ("mk", true, true),
+                ("py", true, true),
+                ("rs", true, true),
+                ("sh", false, false),
+                ("txt", false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_aliases_are_expanded() {
+        let rules = rules_from_toml(
+            "[aliases]\nlatex = \"latexmk {@pdf} {name.ext}\"\npdf = \"-pdf -outdir={@out}\"\n\
+             out = \"build\"\n\
+             [tex]\nextensions = \"tex\"\ncommand = \"{@latex} && echo done\"\n",
+        )
+        .unwrap();
+        let rule = rules.iter().next().unwrap();
+        assert_eq!(
+            rule.command.as_ref().unwrap().to_string(),
+            "latexmk -pdf -outdir=build {name.ext} && echo done"
+        );
+    }
+
+    #[test]
+    fn alias_errors() {
+        let err = |text: &str| rules_from_toml(text).err().unwrap();
+        assert_eq!(
+            err("[tex]\nextensions = \"tex\"\ncommand = \"{@latex}\"\n"),
+            "rule tex: unknown alias {@latex}"
+        );
+        assert_eq!(
+            err("[aliases]\na = \"x {@b}\"\nb = \"{@a}\"\n\
+                 [tex]\nextensions = \"tex\"\ncommand = \"{@a}\"\n"),
+            "rule tex: alias cycle: a -> b -> a"
+        );
+        assert_eq!(
+            err("[global]\ndefault_command = \"{@nope}\"\n"),
+            "default_command: unknown alias {@nope}"
+        );
+    }
+
+    #[test]
+    fn durations() {
+        let cases = [
+            ("500", 500),
+            (" 500 ", 500),
+            ("500ms", 500),
+            ("2s", 2_000),
+            ("1m30s", 90_000),
+            ("1m 30s", 90_000),
+        ];
+        for (text, ms) in cases {
+            assert_eq!(
+                parse_duration(text),
+                Ok(Duration::from_millis(ms)),
+                "{}",
+                text
+            );
+        }
+        for text in ["", "abc", "5 parsecs", "-1s", "1.5.2s"] {
+            assert!(parse_duration(text).is_err(), "{}", text);
+        }
+        let err = rules_from_toml(
+            "[tex]\nextensions = \"tex\"\ncommand = \"x\"\ncheck_timeout = \"soon\"\n",
+        )
+        .err()
+        .unwrap();
+        assert!(err.starts_with("invalid duration \"soon\""), "{}", err);
+    }
+
+    #[test]
+    fn config_hash_ignores_the_formatting() {
+        let hash = |text: &str| rules_from_toml(text).unwrap().hash;
+        let base = hash(
+            "[global]\nignore = [\"*.tmp\"]\n\
+             [md]\nextensions = \"md\"\ncommand = \"pandoc {name.ext}\"\n\
+             [tex]\nextensions = \"tex\"\ncommand = \"latexmk\"\n",
+        );
+        // reordered, with comments, blank lines and other quotes
+        let reformatted = hash(
+            "# the documents\n\n[tex]\ncommand = 'latexmk'\nextensions = \"tex\"\n\n\
+             [md]\n  command   =   \"pandoc {name.ext}\"  # render\nextensions = 'md'\n\
+             [global]\nignore = [ \"*.tmp\", ]\n",
+        );
+        assert_eq!(base, reformatted);
+        let edits = [
+            "[global]\nignore = [\"*.bak\"]\n\
+             [md]\nextensions = \"md\"\ncommand = \"pandoc {name.ext}\"\n\
+             [tex]\nextensions = \"tex\"\ncommand = \"latexmk\"\n",
+            "[global]\nignore = [\"*.tmp\"]\n\
+             [md]\nextensions = \"md\"\ncommand = \"pandoc {rpath}\"\n\
+             [tex]\nextensions = \"tex\"\ncommand = \"latexmk\"\n",
+            "[global]\nignore = [\"*.tmp\"]\n\
+             [md]\nextensions = \"md markdown\"\ncommand = \"pandoc {name.ext}\"\n\
+             [tex]\nextensions = \"tex\"\ncommand = \"latexmk\"\n",
+            "[global]\nignore = [\"*.tmp\"]\n\
+             [md]\nextensions = \"md\"\ncommand = \"pandoc {name.ext}\"\n\
+             [latex]\nextensions = \"tex\"\ncommand = \"latexmk\"\n",
+        ];
+        for edit in edits {
+            assert_ne!(hash(edit), base, "{}", edit);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::debounce::Kind;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::Permissions;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Time to wait for the other side of the socket
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Marks the watcher events sent for `onchange simulate`
+const SIMULATED: &str = "onchange-simulate";
+
+/// Requests to the instance running with --single-instance, sent as a
+/// JSON object on a line like `{"cmd": "vars-dump"}`
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    /// Variables of the latest event
+    VarsDump,
+    /// Run the commands for a made up change of the file
+    Simulate { path: PathBuf, kind: Kind },
+}
+
+/// Watcher event for the simulated change, sent to the watcher's
+/// channel so it wakes the event loop up like a real one
+pub fn simulated_event(path: PathBuf, kind: Kind) -> notify::Event {
+    notify::Event::new(kind.event_kind())
+        .add_path(path)
+        .set_info(SIMULATED)
+}
+
+/// The event is of `simulated_event`, not from the watcher
+pub fn is_simulated(event: &notify::Event) -> bool {
+    event.info() == Some(SIMULATED)
+}
+
+/// Control socket of the instance, removed when dropped
+pub struct Server {
+    path: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+impl Server {
+    /// Listen on the socket, the requests are answered by `handle` on
+    /// a thread of its own
+    pub fn start(
+        path: &Path,
+        handle: impl Fn(Request) -> Result<Value, String> + Send + 'static,
+    ) -> Result<Self, String> {
+        let err = |e: std::io::Error| format!("{}: {}", path.to_string_lossy(), e);
+        // the instance lock is held, so it's left by an earlier one
+        std::fs::remove_file(path).ok();
+        let listener = UnixListener::bind(path).map_err(err)?;
+        let server = Self {
+            path: path.to_path_buf(),
+        };
+        std::fs::set_permissions(path, Permissions::from_mode(0o600)).map_err(err)?;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                serve(stream, &handle).ok();
+            }
+        });
+        Ok(server)
+    }
+}
+
+/// Answer the request on the connection, `{"ok": true, "result": ...}`
+/// or `{"ok": false, "error": "..."}`
+fn serve(
+    stream: UnixStream,
+    handle: &impl Fn(Request) -> Result<Value, String>,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let reply = match serde_json::from_str(&line) {
+        Ok(request) => match handle(request) {
+            Ok(result) => json!({"ok": true, "result": result}),
+            Err(e) => json!({"ok": false, "error": e}),
+        },
+        Err(e) => json!({"ok": false, "error": format!("bad request: {}", e)}),
+    };
+    writeln!(&stream, "{}", reply)
+}
+
+/// Send the request to the instance listening on the socket, the
+/// result it answered with
+pub fn request(path: &Path, request: &Request) -> Result<Value, String> {
+    let stream = UnixStream::connect(path).map_err(|e| {
+        format!(
+            "no instance is listening on {:?} ({}), it has to be started with --single-instance",
+            path, e
+        )
+    })?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    writeln!(&stream, "{}", serde_json::to_string(request).unwrap()).map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("no answer from the instance ({})", e))?;
+    let reply: Value =
+        serde_json::from_str(&line).map_err(|_| format!("unexpected answer {:?}", line))?;
+    match reply["ok"].as_bool() {
+        Some(true) => Ok(reply["result"].clone()),
+        _ => Err(reply["error"].as_str().unwrap_or("failed").to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "onchange-test-{}-{}.sock",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn request_is_answered() {
+        let path = socket("answer");
+        let _server = Server::start(&path, |request| match request {
+            Request::VarsDump => Ok(json!({"name": "a"})),
+            Request::Simulate { .. } => Ok(Value::Null),
+        })
+        .unwrap();
+        assert_eq!(
+            request(&path, &Request::VarsDump).unwrap(),
+            json!({"name": "a"})
+        );
+    }
+
+    #[test]
+    fn simulate_reaches_the_event_loop() {
+        let path = socket("simulate");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _server = Server::start(&path, move |request| match request {
+            Request::Simulate { path, kind } => {
+                tx.send(simulated_event(path, kind)).unwrap();
+                Ok(Value::Null)
+            }
+            _ => Err("unexpected".to_string()),
+        })
+        .unwrap();
+        let file = PathBuf::from("/project/notes.md");
+        let simulate = Request::Simulate {
+            path: file.clone(),
+            kind: Kind::Create,
+        };
+        request(&path, &simulate).unwrap();
+        let event = rx.recv_timeout(TIMEOUT).unwrap();
+        assert!(is_simulated(&event));
+        assert_eq!(event.paths, [file]);
+        assert_eq!(event.kind, Kind::Create.event_kind());
+    }
+
+    #[test]
+    fn watcher_events_are_not_simulated() {
+        let event = notify::Event::new(Kind::Modify.event_kind()).add_path("/a".into());
+        assert!(!is_simulated(&event));
+    }
+
+    #[test]
+    fn errors_are_passed_on() {
+        let path = socket("error");
+        let _server = Server::start(&path, |_| Err("nothing yet".to_string())).unwrap();
+        assert_eq!(
+            request(&path, &Request::VarsDump).unwrap_err(),
+            "nothing yet"
+        );
+    }
+
+    #[test]
+    fn socket_is_removed_when_dropped() {
+        let path = socket("drop");
+        drop(Server::start(&path, |_| Ok(Value::Null)).unwrap());
+        assert!(!path.exists());
+        assert!(request(&path, &Request::VarsDump).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use crate::filter::EventType;
+use notify::EventKind;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How the debounce window is decided
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Mode {
+    /// Fire after the debounce duration from the first event
+    Fixed,
+    /// Restart the window on each new event, but never wait longer
+    /// than the max duration from the first event
+    Adaptive,
+}
+
+/// What the new events restart the window of, in adaptive mode
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Scope {
+    /// Only the path of the event
+    Path,
+    /// All the pending paths
+    Global,
+}
+
+/// What to do with the events received while a command runs
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OnBusy {
+    /// Debounce and run for them as usual
+    Queue,
+    /// Ignore them
+    Drop,
+    /// Run once for all of them together
+    Coalesce,
+}
+
+/// Change to a path after the debounce
+#[derive(Debug, Clone)]
+pub struct DebouncedEvent {
+    pub path: PathBuf,
+    /// Kinds of the events merged into this one, in the order seen
+    pub kinds: Vec<EventKind>,
+    /// Made up by the simulate subcommand, not from the watcher
+    pub synthetic: bool,
+}
+
+impl DebouncedEvent {
+    /// Made up change of the path for the simulate subcommand, it
+    /// isn't debounced
+    pub fn simulated(path: PathBuf, kind: EventKind) -> Self {
+        Self {
+            path,
+            kinds: vec![kind],
+            synthetic: true,
+        }
+    }
+}
+
+/// Kind of the synthetic event for the simulate subcommand
+#[derive(Clone, Copy, PartialEq, Debug, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Create,
+    Modify,
+    Remove,
+    Rename,
+}
+
+impl Kind {
+    pub fn event_kind(self) -> EventKind {
+        use notify::event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode};
+        match self {
+            Self::Create => EventKind::Create(CreateKind::File),
+            Self::Modify => EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+            Self::Remove => EventKind::Remove(RemoveKind::File),
+            Self::Rename => EventKind::Modify(ModifyKind::Name(RenameMode::Any)),
+        }
+    }
+
+    /// The overall change of the event kinds to the path, a modify if
+    /// there's nothing else
+    pub fn net(path: &Path, kinds: &[EventKind]) -> Self {
+        match EventType::net(path, kinds) {
+            Some(EventType::Create) => Self::Create,
+            Some(EventType::Remove) => Self::Remove,
+            Some(EventType::Rename) => Self::Rename,
+            Some(EventType::Modify) | None => Self::Modify,
+        }
+    }
+}
+
+/// Events of a path waiting for the debounce window to end
+struct Pending {
+    path: PathBuf,
+    kinds: Vec<EventKind>,
+    first: Instant,
+    last: Instant,
+}
+
+/// Debounces the raw events from the watcher. It doesn't look at the
+/// clock itself, the time is always passed in, so it can be driven by
+/// any clock.
+pub struct Debouncer {
+    mode: Mode,
+    scope: Scope,
+    duration: Duration,
+    /// No max if `None`, waits for the quiet however long it takes
+    max: Option<Duration>,
+    /// Pending events in the order of their first event
+    pending: Vec<Pending>,
+    index: HashMap<PathBuf, usize>,
+    /// Time of the last event of any path
+    last: Option<Instant>,
+}
+
+impl Debouncer {
+    pub fn new(mode: Mode, scope: Scope, duration: Duration, max: Option<Duration>) -> Self {
+        Self {
+            mode,
+            scope,
+            duration,
+            max,
+            pending: Vec::new(),
+            index: HashMap::new(),
+            last: None,
+        }
+    }
+
+    pub fn add(&mut self, path: PathBuf, kind: EventKind, now: Instant) {
+        self.last = Some(now);
+        match self.index.get(&path) {
+            Some(&i) => {
+                let pending = &mut self.pending[i];
+                pending.last = now;
+                if !pending.kinds.contains(&kind) {
+                    pending.kinds.push(kind);
+                }
+            }
+            None => {
+                self.index.insert(path.clone(), self.pending.len());
+                self.pending.push(Pending {
+                    path,
+                    kinds: vec![kind],
+                    first: now,
+                    last: now,
+                });
+            }
+        }
+    }
+
+    fn deadline(&self, pending: &Pending) -> Instant {
+        match self.mode {
+            Mode::Fixed => pending.first + self.duration,
+            Mode::Adaptive => {
+                let last = match self.scope {
+                    Scope::Path => pending.last,
+                    Scope::Global => self.last.unwrap_or(pending.last),
+                };
+                let quiet = last + self.duration;
+                match self.max {
+                    Some(max) => quiet.min(pending.first + max),
+                    None => quiet,
+                }
+            }
+        }
+    }
+
+    /// Time the next pending event is ready at
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|p| self.deadline(p)).min()
+    }
+
+    /// Remove and return the events whose debounce window has ended,
+    /// in fixed mode all the events within the window of the first one
+    /// are taken together
+    pub fn take_ready(&mut self, now: Instant) -> Vec<DebouncedEvent> {
+        if self.next_deadline().is_none_or(|d| d > now) {
+            return Vec::new();
+        }
+        let (ready, waiting): (Vec<Pending>, Vec<Pending>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| self.mode == Mode::Fixed || self.deadline(p) <= now);
+        self.pending = waiting;
+        self.index = self
+            .pending
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.path.clone(), i))
+            .collect();
+        ready
+            .into_iter()
+            .map(|p| DebouncedEvent {
+                path: p.path,
+                kinds: p.kinds,
+                synthetic: false,
+            })
+            .collect()
+    }
+}
+
+/// Merge the events into one per path, to run for together
+pub fn coalesce(events: Vec<notify::Event>) -> Vec<DebouncedEvent> {
+    let now = Instant::now();
+    let mut debouncer = Debouncer::new(Mode::Fixed, Scope::Path, Duration::ZERO, None);
+    for event in events {
+        for path in event.paths {
+            debouncer.add(path, event.kind, now);
+        }
+    }
+    debouncer.take_ready(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clock for the tests, the time is moved by hand
+    struct Clock(Instant);
+
+    impl Clock {
+        fn at(&self, ms: u64) -> Instant {
+            self.0 + Duration::from_millis(ms)
+        }
+    }
+
+    fn names(events: &[DebouncedEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| e.path.to_string_lossy().to_string())
+            .collect()
+    }
+
+    fn adaptive(scope: Scope) -> Debouncer {
+        Debouncer::new(
+            Mode::Adaptive,
+            scope,
+            Duration::from_millis(500),
+            Some(Duration::from_secs(5)),
+        )
+    }
+
+    #[test]
+    fn quiet_ends_the_window() {
+        let clock = Clock(Instant::now());
+        let modify = Kind::Modify.event_kind();
+        let mut debouncer = adaptive(Scope::Path);
+        debouncer.add("a".into(), modify, clock.at(0));
+        debouncer.add("a".into(), modify, clock.at(400));
+        assert!(debouncer.take_ready(clock.at(600)).is_empty());
+        assert_eq!(debouncer.next_deadline(), Some(clock.at(900)));
+        let ready = debouncer.take_ready(clock.at(900));
+        assert_eq!(names(&ready), ["a"]);
+        assert_eq!(ready[0].kinds, [modify]);
+        assert_eq!(debouncer.next_deadline(), None);
+    }
+
+    #[test]
+    fn max_caps_the_window() {
+        let clock = Clock(Instant::now());
+        let mut debouncer = adaptive(Scope::Path);
+        // a long checkout, an event every 300ms
+        for t in (0..=6000).step_by(300) {
+            debouncer.add("a".into(), Kind::Modify.event_kind(), clock.at(t));
+            if t < 5000 {
+                assert!(debouncer.take_ready(clock.at(t)).is_empty(), "{}", t);
+            }
+        }
+        assert_eq!(debouncer.next_deadline(), Some(clock.at(5000)));
+        assert_eq!(names(&debouncer.take_ready(clock.at(6000))), ["a"]);
+    }
+
+    #[test]
+    fn scope_of_the_restart() {
+        let clock = Clock(Instant::now());
+        let modify = Kind::Modify.event_kind();
+        let mut by_path = adaptive(Scope::Path);
+        let mut global = adaptive(Scope::Global);
+        for debouncer in [&mut by_path, &mut global] {
+            debouncer.add("a".into(), modify, clock.at(0));
+            debouncer.add("b".into(), modify, clock.at(300));
+        }
+        // b's event restarts a's window only in the global scope
+        assert_eq!(names(&by_path.take_ready(clock.at(500))), ["a"]);
+        assert!(global.take_ready(clock.at(500)).is_empty());
+        assert_eq!(names(&global.take_ready(clock.at(800))), ["a", "b"]);
+    }
+
+    #[test]
+    fn fixed_window_from_the_first_event() {
+        let clock = Clock(Instant::now());
+        let modify = Kind::Modify.event_kind();
+        let mut debouncer =
+            Debouncer::new(Mode::Fixed, Scope::Path, Duration::from_millis(500), None);
+        debouncer.add("a".into(), modify, clock.at(0));
+        debouncer.add("a".into(), Kind::Create.event_kind(), clock.at(450));
+        debouncer.add("b".into(), modify, clock.at(480));
+        let ready = debouncer.take_ready(clock.at(500));
+        assert_eq!(names(&ready), ["a", "b"]);
+        assert_eq!(ready[0].kinds, [modify, Kind::Create.event_kind()]);
+    }
+}
@@ -0,0 +1,164 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Interval to check for the end of the window (and the flush
+/// request) at
+pub const POLL: Duration = Duration::from_secs(1);
+
+/// Set by the SIGUSR1 handler, from `onchange ctl flush`
+static FLUSH: AtomicBool = AtomicBool::new(false);
+
+/// Time range of the day in minutes, the end is excluded; it goes over
+/// midnight if the end is before the start
+#[derive(Clone, Copy)]
+struct Range {
+    start: u32,
+    end: u32,
+}
+
+impl Range {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Ranges of the local time the events are deferred in, like
+/// "09:00-12:00,13:00-18:00"
+#[derive(Clone)]
+pub struct Window(Vec<Range>);
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let minutes = |t: &str| {
+            let (h, m) = t.trim().split_once(':')?;
+            let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+            (h <= 24 && m < 60 && h * 60 + m <= 24 * 60).then_some(h * 60 + m)
+        };
+        s.split(',')
+            .map(|range| {
+                range
+                    .split_once('-')
+                    .and_then(|(start, end)| {
+                        Some(Range {
+                            start: minutes(start)?,
+                            end: minutes(end)?,
+                        })
+                    })
+                    .ok_or_else(|| format!("{:?} isn't a time range like 09:00-18:00", range))
+            })
+            .collect::<Result<Vec<Range>, String>>()
+            .map(Self)
+    }
+}
+
+impl Window {
+    /// The local time is in one of the ranges
+    pub fn contains(&self, time: SystemTime) -> bool {
+        self.contains_minute(local_minute(time))
+    }
+
+    /// The minute of the day is in one of the ranges
+    fn contains_minute(&self, minute: u32) -> bool {
+        self.0.iter().any(|r| r.contains(minute))
+    }
+}
+
+/// Minute of the day in the local time zone
+fn local_minute(time: SystemTime) -> u32 {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as libc::time_t)
+        .unwrap_or(0);
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+/// Ask for the deferred events to be run at SIGUSR1, instead of dying
+pub fn flush_on_signal() {
+    extern "C" fn handler(_: libc::c_int) {
+        FLUSH.store(true, Ordering::SeqCst);
+    }
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler as *const () as libc::sighandler_t);
+    }
+}
+
+/// A flush was asked for since the last call
+pub fn flush_requested() -> bool {
+    FLUSH.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> u32 {
+        let (h, m) = time.split_once(':').unwrap();
+        h.parse::<u32>().unwrap() * 60 + m.parse::<u32>().unwrap()
+    }
+
+    #[test]
+    fn windows_are_parsed() {
+        let window: Window = "09:00-12:00, 13:00-18:00".parse().unwrap();
+        let inside = ["09:00", "11:59", "13:00", "17:59"];
+        let outside = ["08:59", "12:00", "12:30", "18:00", "00:00"];
+        for t in inside {
+            assert!(window.contains_minute(at(t)), "{}", t);
+        }
+        for t in outside {
+            assert!(!window.contains_minute(at(t)), "{}", t);
+        }
+        // over midnight, and the whole day
+        let night: Window = "22:00-06:00".parse().unwrap();
+        assert!(night.contains_minute(at("23:30")) && night.contains_minute(at("05:59")));
+        assert!(!night.contains_minute(at("06:00")) && !night.contains_minute(at("21:59")));
+        let day: Window = "00:00-24:00".parse().unwrap();
+        assert!(day.contains_minute(at("00:00")) && day.contains_minute(at("23:59")));
+    }
+
+    #[test]
+    fn bad_windows() {
+        for s in [
+            "",
+            "9-18",
+            "09:00",
+            "09:00-25:00",
+            "09:60-10:00",
+            "24:01-01:00",
+            "a:b-c:d",
+        ] {
+            assert!(s.parse::<Window>().is_err(), "{}", s);
+        }
+        assert_eq!(
+            "09:00-18:00,x".parse::<Window>().err().unwrap(),
+            "\"x\" isn't a time range like 09:00-18:00"
+        );
+    }
+
+    #[test]
+    fn local_time_is_used() {
+        let window: Window = "00:00-24:00".parse().unwrap();
+        assert!(window.contains(SystemTime::now()));
+        // the minute of the epoch in the local time zone
+        let epoch = local_minute(UNIX_EPOCH);
+        let single: Window = format!(
+            "{:02}:{:02}-{:02}:{:02}",
+            epoch / 60,
+            epoch % 60,
+            (epoch + 1) / 60 % 24,
+            (epoch + 1) % 60
+        )
+        .parse()
+        .unwrap();
+        assert!(single.contains(UNIX_EPOCH));
+        assert!(!single.contains(UNIX_EPOCH + Duration::from_secs(120)));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::output::infoln;
+use colored::Colorize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Value written for the redacted variables
+const REDACTED: &str = "<redacted>";
+
+/// The variables sorted by name, with the values of the ones matching
+/// the patterns hidden
+pub fn redacted(
+    map: &HashMap<String, String>,
+    redact: &[glob::Pattern],
+) -> BTreeMap<String, String> {
+    map.iter()
+        .map(|(k, v)| {
+            let v = if redact.iter().any(|p| p.matches(k)) {
+                REDACTED
+            } else {
+                v
+            };
+            (k.clone(), v.to_string())
+        })
+        .collect()
+}
+
+/// Writes the final variables of each event to a directory, as
+/// `<seq>-<name.ext>.toml`, keeping only the latest files
+pub struct VarsDump {
+    dir: PathBuf,
+    keep: usize,
+    seq: AtomicU64,
+}
+
+impl VarsDump {
+    pub fn new(dir: &Path, keep: usize) -> Result<Self, String> {
+        std::fs::create_dir_all(dir).map_err(|e| format!("{:?}: {}", dir, e))?;
+        // continue after the dumps of the earlier sessions, so the
+        // order of the names stays the order they're written in
+        let last = dumps(dir).last().map(|(seq, _)| *seq).unwrap_or(0);
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            keep,
+            seq: AtomicU64::new(last + 1),
+        })
+    }
+
+    /// Write the (redacted) variables of a new event, the file they're
+    /// written to
+    pub fn write(&self, vars: &BTreeMap<String, String>) -> PathBuf {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let name = vars.get("name.ext").map(String::as_str).unwrap_or_default();
+        let file = self.dir.join(format!("{:06}-{}.toml", seq, name));
+        write_vars(&file, vars);
+        self.prune();
+        file
+    }
+
+    /// Write the variables again with the ones added after the run, if
+    /// the event's file is still kept
+    pub fn update(&self, file: &Path, vars: &BTreeMap<String, String>) {
+        if file.exists() {
+            write_vars(file, vars);
+        }
+    }
+
+    /// Remove the oldest dumps beyond the ones to keep
+    fn prune(&self) {
+        let dumps = dumps(&self.dir);
+        let extra = dumps.len().saturating_sub(self.keep);
+        for (_, file) in &dumps[..extra] {
+            std::fs::remove_file(file).ok();
+        }
+    }
+}
+
+/// Write the variables as TOML, warning if it can't be written
+fn write_vars(file: &Path, vars: &BTreeMap<String, String>) {
+    let mut text = String::new();
+    for (k, v) in vars {
+        // JSON strings are valid TOML basic strings
+        text.push_str(&format!(
+            "{} = {}\n",
+            serde_json::to_string(k).unwrap(),
+            serde_json::to_string(v).unwrap()
+        ));
+    }
+    if let Err(e) = std::fs::write(file, text) {
+        infoln!(
+            "{}: writing the variables to {:?}: {}",
+            "Warning".yellow().bold(),
+            file,
+            e
+        );
+    }
+}
+
+/// Dump files in the directory with their sequence number, oldest first
+fn dumps(dir: &Path) -> Vec<(u64, PathBuf)> {
+    let mut dumps: Vec<(u64, PathBuf)> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| {
+            let path = e.ok()?.path();
+            let name = path.file_name()?.to_string_lossy().to_string();
+            if !name.ends_with(".toml") {
+                return None;
+            }
+            let seq = name.split_once('-')?.0.parse().ok()?;
+            Some((seq, path))
+        })
+        .collect();
+    dumps.sort();
+    dumps
+}
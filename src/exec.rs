@@ -0,0 +1,1042 @@
+use crate::cancel;
+use crate::output::{self, infoln};
+use colored::Colorize;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use subprocess::{Exec, ExitStatus, NullFile, Popen, PopenConfig, Redirection};
+
+/// Captured output is sent to the printer in lines of at most this size
+const MAX_LINE: usize = 8192;
+
+/// Shell program and its arguments the commands are given to, set by
+/// `--shell` (or `shell` in the config); `sh -c` if it's not set
+static SHELL: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Split the shell given as a string into its arguments, the quotes
+/// work like in the posix shell: `"C:/Program Files/bash" -c`
+pub fn split_shell(shell: &str) -> Result<Vec<String>, String> {
+    shlex::split(shell).ok_or_else(|| format!("unbalanced quotes in the shell {:?}", shell))
+}
+
+/// Run the commands with the shell, like `["fish", "-c"]`, instead of
+/// `sh -c`
+pub fn set_shell(argv: Vec<String>) -> Result<(), String> {
+    if argv.is_empty() {
+        return Err("the shell to run the commands with is empty".to_string());
+    }
+    SHELL.set(argv).ok();
+    Ok(())
+}
+
+/// The shell and its arguments, the command goes after them
+fn shell() -> Vec<String> {
+    SHELL
+        .get()
+        .cloned()
+        .unwrap_or_else(|| vec!["sh".to_string(), "-c".to_string()])
+}
+
+/// Everything about how the rendered command is run, so what's shown
+/// is what gets executed
+pub struct ExecutionPlan {
+    /// Directory the command runs in
+    pub cwd: PathBuf,
+    /// Program and its arguments
+    pub argv: Vec<String>,
+    /// Remote host the command runs on through ssh
+    pub host: Option<String>,
+    /// Environment variables added for the command
+    pub env: Vec<(String, String)>,
+    /// The string handed to the shell
+    pub shell_command: String,
+    /// Run in its own process group, so it can be stopped along with
+    /// the processes it starts
+    pub group: bool,
+    /// Container the command runs in
+    pub container: Option<ContainerRun>,
+    /// Time the command can run for before it's terminated
+    pub timeout: Option<Duration>,
+}
+
+/// Container to run the command in, with the rule's `container`
+/// rendered for the event
+#[derive(Clone)]
+pub struct ContainerRun {
+    /// docker or podman
+    pub engine: String,
+    /// Name of the container, to kill it by
+    pub name: String,
+    pub image: String,
+    /// `host:container` volume mounts
+    pub mounts: Vec<String>,
+    pub workdir: Option<String>,
+}
+
+/// Number of the containers started, for their names
+static CONTAINERS: AtomicU64 = AtomicU64::new(0);
+
+impl ContainerRun {
+    pub fn new(engine: &str, image: &str, mounts: Vec<String>, workdir: Option<String>) -> Self {
+        Self {
+            engine: engine.to_string(),
+            name: format!(
+                "onchange-{}-{}",
+                std::process::id(),
+                CONTAINERS.fetch_add(1, Ordering::SeqCst)
+            ),
+            image: image.to_string(),
+            mounts,
+            workdir,
+        }
+    }
+
+    /// Engine invocation running the command, the environment
+    /// variables named in `env` are passed through
+    fn argv(&self, cmd: &str, env: &[(String, String)]) -> Vec<String> {
+        let mut argv = vec![
+            self.engine.clone(),
+            "run".to_string(),
+            "--rm".to_string(),
+            "--name".to_string(),
+            self.name.clone(),
+        ];
+        for mount in &self.mounts {
+            argv.extend(["-v".to_string(), mount.clone()]);
+        }
+        if let Some(dir) = &self.workdir {
+            argv.extend(["-w".to_string(), dir.clone()]);
+        }
+        for (k, _) in env {
+            argv.extend(["-e".to_string(), k.clone()]);
+        }
+        argv.extend([
+            self.image.clone(),
+            "sh".to_string(),
+            "-c".to_string(),
+            cmd.to_string(),
+        ]);
+        argv
+    }
+
+    /// Kill the container, stopping the client doesn't always stop it
+    pub fn kill(&self) {
+        Exec::cmd(&self.engine)
+            .args(&["kill", &self.name])
+            .stdout(NullFile)
+            .stderr(NullFile)
+            .join()
+            .ok();
+    }
+}
+
+/// Container engine to use: the given one, or docker or podman,
+/// whichever is found first in the PATH
+pub fn container_engine(given: Option<&str>) -> Result<String, String> {
+    if let Some(engine) = given {
+        return Ok(engine.to_string());
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    ["docker", "podman"]
+        .into_iter()
+        .find(|engine| std::env::split_paths(&path).any(|dir| dir.join(engine).is_file()))
+        .map(String::from)
+        .ok_or_else(|| "no container engine (docker or podman) found in PATH".to_string())
+}
+
+impl ExecutionPlan {
+    /// Plan to run the rendered command in the shell, locally or on
+    /// the remote host through ssh
+    pub fn new(cmd: &str, host: Option<&str>) -> Self {
+        let argv = match host {
+            // ssh joins the arguments for the remote shell, so the
+            // command needs to be quoted once. The remote host gets
+            // `sh -c`, the local `--shell` may not exist there
+            Some(host) => vec![
+                "ssh".to_string(),
+                "-o".to_string(),
+                "BatchMode=yes".to_string(),
+                host.to_string(),
+                "--".to_string(),
+                format!("sh -c {}", shell_quote(cmd)),
+            ],
+            None => {
+                let mut argv = shell();
+                argv.push(cmd.to_string());
+                argv
+            }
+        };
+        Self {
+            cwd: std::env::current_dir().unwrap_or_default(),
+            argv,
+            host: host.map(String::from),
+            env: Vec::new(),
+            shell_command: cmd.to_string(),
+            // the signal doesn't reach the commands started by the
+            // shell then, they're stopped through the group instead
+            group: cancel::signal_handled(),
+            container: None,
+            timeout: None,
+        }
+    }
+
+    /// Run the command in the container instead, on the remote host
+    /// too if there's one
+    pub fn in_container(mut self, container: ContainerRun) -> Self {
+        let argv = container.argv(&self.shell_command, &self.env);
+        self.argv = match &self.host {
+            Some(_) => {
+                let mut ssh = self.argv[..self.argv.len() - 1].to_vec();
+                ssh.push(
+                    argv.iter()
+                        .map(|a| shell_quote(a))
+                        .collect::<Vec<String>>()
+                        .join(" "),
+                );
+                ssh
+            }
+            None => argv,
+        };
+        self.container = Some(container);
+        self
+    }
+
+    /// Error if the arguments are too large to be passed to the
+    /// program, instead of the confusing E2BIG from the spawn
+    pub fn check_size(&self) -> Result<(), String> {
+        let (arg_max, arg_strlen) = arg_limits();
+        let env: usize = std::env::vars_os()
+            .map(|(k, v)| k.len() + v.len() + 2)
+            .chain(self.env.iter().map(|(k, v)| k.len() + v.len() + 2))
+            .sum();
+        let args: usize = self.argv.iter().map(|a| a.len() + 1).sum();
+        let longest = self.argv.iter().map(|a| a.len() + 1).max().unwrap_or(0);
+        if args + env > arg_max || longest > arg_strlen {
+            return Err(format!(
+                "command is {} bytes, over the limit of {} bytes for the arguments{}; pass the large values through a file or an environment variable instead",
+                args,
+                arg_strlen.min(arg_max.saturating_sub(env)),
+                if env > arg_max / 2 {
+                    " (the environment takes a lot of it)"
+                } else {
+                    ""
+                }
+            ));
+        }
+        Ok(())
+    }
+
+    /// Start the command, with its output captured or to the terminal
+    pub fn popen(&self, capture: bool) -> Result<Popen, String> {
+        if capture {
+            self.spawn(Redirection::Pipe, Redirection::Merge)
+        } else if output::stdout_reserved() {
+            self.spawn(Redirection::Merge, Redirection::None)
+        } else {
+            self.spawn(Redirection::None, Redirection::None)
+        }
+    }
+
+    /// Start the command with the given redirections, the error is
+    /// about the program that couldn't be started (not the command)
+    pub fn spawn(&self, stdout: Redirection, stderr: Redirection) -> Result<Popen, String> {
+        let mut env = PopenConfig::current_env();
+        env.retain(|(k, _)| !self.env.iter().any(|(name, _)| k == name.as_str()));
+        env.extend(self.env.iter().map(|(k, v)| (k.into(), v.into())));
+        Popen::create(
+            &self.argv,
+            PopenConfig {
+                stdout,
+                stderr,
+                cwd: Some(self.cwd.clone().into()),
+                env: Some(env),
+                setpgid: self.group,
+                ..Default::default()
+            },
+        )
+        .map_err(|e| format!("can't start {:?} ({})", self.argv[0], e))
+    }
+
+    /// Lines describing the plan for `--echo full`
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![format!("cwd: {}", self.cwd.to_string_lossy())];
+        if let Some(host) = &self.host {
+            lines.push(format!("host: {}", host));
+        }
+        lines.push(format!(
+            "argv: [{}]",
+            self.argv
+                .iter()
+                .map(|a| format!("{:?}", a))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ));
+        for (k, v) in &self.env {
+            lines.push(format!("env: {}={}", k, v));
+        }
+        lines.push(format!("shell: {}", self.shell_command));
+        lines
+    }
+}
+
+/// Limits on the size of the arguments and environment of a new
+/// program, and of a single argument
+fn arg_limits() -> (usize, usize) {
+    let arg_max = match unsafe { libc::sysconf(libc::_SC_ARG_MAX) } {
+        n if n > 0 => n as usize,
+        // the smallest POSIX allows
+        _ => 4096,
+    };
+    // linux limits a single argument to 32 pages as well
+    let arg_strlen = if cfg!(target_os = "linux") {
+        32 * 4096
+    } else {
+        arg_max
+    };
+    (arg_max, arg_strlen)
+}
+
+/// What to do with the variable values with shell special characters
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum AutoQuote {
+    /// Warn about them
+    Warn,
+    /// Quote them
+    Fix,
+    /// Leave them as they are
+    Off,
+}
+
+/// Characters the shell treats specially
+const SHELL_SPECIAL: &str = " \t\n'\"`$;&|<>()\\*?[]#!{}~";
+
+/// Variables with values already quoted for the shell
+const QUOTED_VARIABLES: [&str; 3] = ["watched", "paths", "rpaths"];
+
+/// Render the command template for the shell. The values of the
+/// variables with the shell special characters are quoted (or warned
+/// about) unless the template already has them inside quotes, so only
+/// the substituted parts are touched. Unknown variables are left as is.
+pub fn render_shell(
+    templ: &str,
+    regex: &regex::Regex,
+    map: &HashMap<String, String>,
+    mode: AutoQuote,
+) -> String {
+    let mut out = String::new();
+    let mut last = 0;
+    for cap in regex.captures_iter(templ) {
+        let (whole, var) = (cap.get(0).unwrap(), &cap[1]);
+        let Some(value) = map.get(var) else {
+            continue;
+        };
+        out.push_str(&templ[last..whole.start()]);
+        last = whole.end();
+        let around = (
+            templ[..whole.start()].chars().last(),
+            templ[whole.end()..].chars().next(),
+        );
+        if mode == AutoQuote::Off
+            || QUOTED_VARIABLES.contains(&var)
+            || !value.contains(|c| SHELL_SPECIAL.contains(c))
+            || quoted_by(around, value)
+        {
+            out.push_str(value);
+        } else if mode == AutoQuote::Fix {
+            out.push_str(&match around {
+                (Some('\''), Some('\'')) => value.replace('\'', r"'\''"),
+                (Some('"'), Some('"')) => value
+                    .chars()
+                    .flat_map(|c| match c {
+                        '"' | '$' | '`' | '\\' => vec!['\\', c],
+                        c => vec![c],
+                    })
+                    .collect(),
+                _ => shell_quote(value),
+            });
+        } else {
+            infoln!(
+                "{}: value of {{{}}} has shell special characters, quote it in the template or use --auto-quote=fix: {:?}",
+                "Warning".yellow().bold(),
+                var,
+                value
+            );
+            out.push_str(value);
+        }
+    }
+    out.push_str(&templ[last..]);
+    out
+}
+
+/// The quotes around the variable keep the value as it is
+fn quoted_by(around: (Option<char>, Option<char>), value: &str) -> bool {
+    match around {
+        (Some('\''), Some('\'')) => !value.contains('\''),
+        (Some('"'), Some('"')) => !value.contains(['"', '$', '`', '\\']),
+        _ => false,
+    }
+}
+
+/// Stop the command run in its own process group with SIGTERM, and
+/// SIGKILL (and kill its container) if it's still `running` after the
+/// grace period
+pub fn terminate(
+    pid: u32,
+    container: Option<&ContainerRun>,
+    grace: Duration,
+    running: impl Fn() -> bool,
+) {
+    let group = -(pid as i32);
+    // the engine's client passes the SIGTERM on to the container
+    unsafe { libc::kill(group, libc::SIGTERM) };
+    let start = Instant::now();
+    while running() {
+        if start.elapsed() > grace {
+            if let Some(container) = container {
+                container.kill();
+            }
+            unsafe { libc::kill(group, libc::SIGKILL) };
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Quote the string for the posix shell, so it's passed as a single
+/// argument without any expansion
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Run the check command with its output hidden, the error has the
+/// reason to skip the run
+pub fn check(
+    cmd: &str,
+    timeout: Duration,
+    invert: bool,
+    cancel: &cancel::Token,
+) -> Result<(), String> {
+    let plan = ExecutionPlan::new(cmd, None);
+    let null = || {
+        std::fs::File::create("/dev/null")
+            .map(Redirection::File)
+            .map_err(|e| format!("check failed ({})", e))
+    };
+    let mut p = plan.spawn(null()?, null()?)?;
+    let pid = p.pid();
+    let status = match cancel.guard(pid, plan.group, || p.wait_timeout(timeout)) {
+        _ if cancel.is_cancelled() => {
+            p.kill().ok();
+            p.wait().ok();
+            return Err("check cancelled".to_string());
+        }
+        Ok(Some(status)) => status,
+        _ => {
+            p.kill().ok();
+            p.wait().ok();
+            return Err(format!(
+                "check timed out after {}",
+                humantime::format_duration(timeout)
+            ));
+        }
+    };
+    if status.success() != invert {
+        return Ok(());
+    }
+    let status = match status {
+        ExitStatus::Exited(c) => format!("exit {}", c),
+        s => exit_name(&s.into()),
+    };
+    if invert {
+        Err(format!("check passed ({}, inverted)", status))
+    } else {
+        Err(format!("check failed ({})", status))
+    }
+}
+
+/// Exit status of a run, whether it was stopped for running longer
+/// than its timeout, and whether its output was cut at `--max-output`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RunStatus {
+    pub status: ExitStatus,
+    pub timed_out: bool,
+    pub output_truncated: bool,
+}
+
+impl RunStatus {
+    pub fn success(&self) -> bool {
+        self.status.success() && !self.timed_out
+    }
+}
+
+impl From<ExitStatus> for RunStatus {
+    fn from(status: ExitStatus) -> Self {
+        Self {
+            status,
+            timed_out: false,
+            output_truncated: false,
+        }
+    }
+}
+
+/// Run the command, if `max_output` is given the output is captured
+/// and only that many bytes of it are shown. `on_spawn` gets the
+/// process id once it's started. The command is terminated if the
+/// `cancel` token is cancelled while it runs, or after its timeout.
+/// The error is for the command that couldn't be started at all
+pub fn run(
+    plan: &ExecutionPlan,
+    max_output: Option<usize>,
+    cancel: &cancel::Token,
+    on_spawn: impl FnOnce(u32),
+) -> Result<RunStatus, String> {
+    if max_output.is_none() {
+        // the command writes to the terminal directly
+        output::flush();
+    }
+    let mut p = plan.popen(max_output.is_some())?;
+    if let Some(pid) = p.pid() {
+        on_spawn(pid);
+    }
+    let pid = p.pid();
+    let ((status, output_truncated), timed_out) =
+        cancel.guard_timeout(pid, plan.group, plan.timeout, || match max_output {
+            Some(limit) => show_output(&mut p, limit),
+            None => (p.wait().unwrap(), false),
+        });
+    if timed_out {
+        if let Some(container) = &plan.container {
+            container.kill();
+        }
+        infoln!(
+            "{}: {} (after {})",
+            "Timeout".bold().yellow(),
+            plan.shell_command,
+            humantime::format_duration(plan.timeout.unwrap_or_default())
+        );
+    }
+    Ok(RunStatus {
+        status,
+        timed_out,
+        output_truncated,
+    })
+}
+
+/// Show the captured output of the command up to `limit` bytes, and
+/// wait for it; whether the output was truncated
+fn show_output(p: &mut Popen, limit: usize) -> (ExitStatus, bool) {
+    let child_out = p.stdout.take().unwrap();
+    let truncated = copy_output(child_out, limit, output::print_bytes);
+    (p.wait().unwrap(), truncated)
+}
+
+/// Pass the output in lines to `print` up to `limit` bytes, reading
+/// it all; whether it was truncated
+fn copy_output(mut child_out: impl Read, limit: usize, mut print: impl FnMut(&[u8])) -> bool {
+    let mut buf = [0; 8192];
+    let mut line = Vec::with_capacity(MAX_LINE);
+    let mut shown = 0;
+    let mut truncated = false;
+    loop {
+        let n = match child_out.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        // keep reading after the limit so the command doesn't block
+        // on a full pipe
+        if truncated {
+            continue;
+        }
+        for &b in &buf[..n] {
+            if shown == limit {
+                truncated = true;
+                break;
+            }
+            line.push(b);
+            shown += 1;
+            if b == b'\n' || line.len() == MAX_LINE {
+                print(&line);
+                line.clear();
+            }
+        }
+        if truncated {
+            if !line.is_empty() {
+                line.push(b'\n');
+                print(&line);
+                line.clear();
+            }
+            infoln!(
+                "{}: output is larger than {} bytes, rest of it is not shown",
+                "Truncated".bold().yellow(),
+                limit
+            );
+        }
+    }
+    if !line.is_empty() {
+        print(&line);
+    }
+    truncated
+}
+
+/// Parse the size like "10MB", "512K" or "100" (bytes)
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: usize = num
+        .parse()
+        .map_err(|_| format!("invalid size {:?}, use something like 10MB", s))?;
+    let mult = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        u => return Err(format!("invalid size unit {:?}", u)),
+    };
+    Ok(num * mult)
+}
+
+/// Parse the `KEY=VALUE` environment variable for the command
+pub fn parse_env(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() && !k.contains(char::is_whitespace) => {
+            Ok((k.to_string(), v.to_string()))
+        }
+        _ => Err(format!("invalid variable {:?}, use KEY=VALUE", s)),
+    }
+}
+
+/// Add the variables about the finished run to the map
+pub fn post_run_vars(map: &mut HashMap<String, String>, status: &RunStatus, elapsed: Duration) {
+    map.insert("exit_code".to_string(), exit_code(&status.status));
+    map.insert("exit_name".to_string(), exit_name(status));
+    map.insert("duration_ms".to_string(), elapsed.as_millis().to_string());
+    map.insert("duration".to_string(), humanize_duration(elapsed));
+}
+
+/// Exit code like the shell reports it, signals are 128 + signal
+pub fn exit_code(status: &ExitStatus) -> String {
+    match status {
+        ExitStatus::Exited(c) => c.to_string(),
+        ExitStatus::Signaled(s) => (128 + *s as u32).to_string(),
+        ExitStatus::Other(c) => c.to_string(),
+        ExitStatus::Undetermined => String::new(),
+    }
+}
+
+/// Readable name for the exit status: "ok", "failed(2)",
+/// "signal(SIGKILL)", or "timeout" when it was stopped for its timeout
+pub fn exit_name(status: &RunStatus) -> String {
+    if status.timed_out {
+        return "timeout".to_string();
+    }
+    match status.status {
+        ExitStatus::Exited(0) => "ok".to_string(),
+        ExitStatus::Exited(c) => format!("failed({})", c),
+        ExitStatus::Signaled(s) => format!("signal({})", signal_name(s as i32)),
+        ExitStatus::Other(c) => format!("other({})", c),
+        ExitStatus::Undetermined => "unknown".to_string(),
+    }
+}
+
+/// The shell couldn't find (or execute) the program of the command
+pub fn not_found(status: &ExitStatus) -> bool {
+    matches!(status, ExitStatus::Exited(126 | 127))
+}
+
+#[cfg(unix)]
+fn signal_name(sig: i32) -> String {
+    let name = match sig {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        _ => return sig.to_string(),
+    };
+    name.to_string()
+}
+
+#[cfg(not(unix))]
+fn signal_name(sig: i32) -> String {
+    sig.to_string()
+}
+
+/// Duration rounded for humans: "350ms", "12s", "1m 23s"
+pub fn humanize_duration(d: Duration) -> String {
+    if d < Duration::from_secs(1) {
+        format!("{}ms", d.as_millis())
+    } else {
+        humantime::format_duration(Duration::from_secs(d.as_secs())).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_is_split_with_quotes() {
+        assert_eq!(split_shell("fish -c").unwrap(), ["fish", "-c"]);
+        assert_eq!(
+            split_shell("'/opt/my shell/bash' --norc -c").unwrap(),
+            ["/opt/my shell/bash", "--norc", "-c"]
+        );
+        assert!(split_shell("\"bash -c").is_err());
+        assert!(set_shell(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn exit_outcomes() {
+        let cases = [
+            (ExitStatus::Exited(0), false, "ok", "0", true),
+            (ExitStatus::Exited(2), false, "failed(2)", "2", false),
+            (ExitStatus::Exited(127), false, "failed(127)", "127", false),
+            (
+                ExitStatus::Signaled(libc::SIGKILL as u8),
+                false,
+                "signal(SIGKILL)",
+                "137",
+                false,
+            ),
+            (
+                ExitStatus::Signaled(libc::SIGTERM as u8),
+                true,
+                "timeout",
+                "143",
+                false,
+            ),
+            (ExitStatus::Exited(0), true, "timeout", "0", false),
+            (ExitStatus::Other(3), false, "other(3)", "3", false),
+            (ExitStatus::Undetermined, false, "unknown", "", false),
+        ];
+        for (status, timed_out, name, code, success) in cases {
+            let run = RunStatus {
+                status,
+                timed_out,
+                output_truncated: false,
+            };
+            assert_eq!(exit_name(&run), name);
+            assert_eq!(exit_code(&status), code);
+            assert_eq!(run.success(), success, "{}", name);
+            let mut map = HashMap::new();
+            post_run_vars(&mut map, &run, Duration::from_millis(83_400));
+            assert_eq!(map["exit_name"], name);
+            assert_eq!(map["exit_code"], code);
+            assert_eq!(map["duration_ms"], "83400");
+            assert_eq!(map["duration"], "1m 23s");
+        }
+    }
+
+    #[test]
+    fn durations_are_humanized() {
+        let cases = [
+            (Duration::ZERO, "0ms"),
+            (Duration::from_micros(999_900), "999ms"),
+            (Duration::from_millis(1_500), "1s"),
+            (Duration::from_secs(90), "1m 30s"),
+            (Duration::from_secs(3_725), "1h 2m 5s"),
+        ];
+        for (d, text) in cases {
+            assert_eq!(humanize_duration(d), text);
+        }
+    }
+
+    #[test]
+    fn large_output_is_truncated() {
+        // the output of `yes`, then a line longer than MAX_LINE
+        let output = "y\n".repeat(100_000) + &"x".repeat(3 * MAX_LINE);
+        let mut lines = Vec::new();
+        let truncated = copy_output(output.as_bytes(), 1001, |l| lines.push(l.to_vec()));
+        assert!(truncated);
+        assert_eq!(lines.len(), 501);
+        // the cut line gets its newline
+        assert_eq!(lines[500], b"y\n");
+        let truncated = copy_output(&output.as_bytes()[200_000..], usize::MAX, |l| {
+            lines.push(l.to_vec())
+        });
+        assert!(!truncated);
+        // the long line comes in pieces
+        assert_eq!(
+            lines[501..].iter().map(Vec::len).collect::<Vec<_>>(),
+            [MAX_LINE; 3]
+        );
+    }
+
+    #[test]
+    fn endless_output_doesnt_block() {
+        let mut plan = ExecutionPlan::new("yes", None);
+        plan.timeout = Some(Duration::from_millis(300));
+        plan.group = true;
+        let finished = run(&plan, Some(1024), &cancel::Token::default(), |_| ()).unwrap();
+        assert!(finished.timed_out);
+        assert!(finished.output_truncated);
+        // the pipe is drained, so a large finite output finishes
+        let plan = ExecutionPlan::new("yes | head -c 5000000", None);
+        let finished = run(&plan, Some(1024), &cancel::Token::default(), |_| ()).unwrap();
+        assert!(finished.success());
+        assert!(finished.output_truncated);
+        let plan = ExecutionPlan::new("echo short", None);
+        let finished = run(&plan, Some(1024), &cancel::Token::default(), |_| ()).unwrap();
+        assert!(!finished.output_truncated);
+    }
+
+    /// Gives the text a few bytes at a time, like a slow command
+    struct Trickle {
+        text: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.text.len() - self.pos);
+            buf[..n].copy_from_slice(&self.text[self.pos..self.pos + n]);
+            self.pos += n;
+            std::thread::yield_now();
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn concurrent_runs_dont_mix_lines() {
+        let captured = output::capture(output::NotifyStream::Stdout, || {
+            let runs: Vec<_> = (0..20)
+                .map(|i| {
+                    output::spawn(move || {
+                        let prefix = format!("[run-{:02}]", i);
+                        infoln!("{} Run", prefix);
+                        let text: String = (0..100)
+                            .map(|k| format!("{} line {}\n", prefix, k))
+                            .collect();
+                        let out = Trickle {
+                            text: text.into_bytes(),
+                            pos: 0,
+                            chunk: 3 + i,
+                        };
+                        copy_output(out, usize::MAX, output::print_bytes);
+                        infoln!("{} Done", prefix);
+                    })
+                })
+                .collect();
+            for run in runs {
+                run.join().unwrap();
+            }
+        });
+        let lines: Vec<&str> = captured.stdout.lines().collect();
+        assert_eq!(lines.len(), 20 * 102);
+        for line in &lines {
+            assert_eq!(line.matches("[run-").count(), 1, "{:?}", line);
+        }
+        for i in 0..20 {
+            let prefix = format!("[run-{:02}] ", i);
+            let run: Vec<&str> = lines
+                .iter()
+                .filter_map(|l| l.strip_prefix(&prefix))
+                .collect();
+            assert_eq!(run.len(), 102);
+            assert_eq!(run[0], "Run");
+            assert_eq!(run[101], "Done");
+            for (k, line) in run[1..101].iter().enumerate() {
+                assert_eq!(*line, format!("line {}", k));
+            }
+        }
+    }
+
+    #[test]
+    fn check_commands() {
+        let check = |cmd: &str, invert: bool| {
+            super::check(
+                cmd,
+                Duration::from_secs(5),
+                invert,
+                &cancel::Token::default(),
+            )
+        };
+        assert_eq!(check("true", false), Ok(()));
+        assert_eq!(
+            check("exit 3", false),
+            Err("check failed (exit 3)".to_string())
+        );
+        assert_eq!(check("exit 1", true), Ok(()));
+        assert_eq!(
+            check("true", true),
+            Err("check passed (exit 0, inverted)".to_string())
+        );
+        // the output doesn't show
+        assert_eq!(check("echo noise; echo more >&2", false), Ok(()));
+        let slow = super::check(
+            "sleep 5",
+            Duration::from_millis(100),
+            false,
+            &cancel::Token::default(),
+        );
+        assert_eq!(slow, Err("check timed out after 100ms".to_string()));
+    }
+
+    #[test]
+    fn special_characters_by_quoting_mode() {
+        let regex = crate::config::variable_regex(None);
+        let render = |templ: &str, value: &str, mode: AutoQuote| {
+            let map = HashMap::from([("v".to_string(), value.to_string())]);
+            let mut out = String::new();
+            let captured = output::capture(output::NotifyStream::Stderr, || {
+                out = render_shell(templ, &regex, &map, mode);
+            });
+            (
+                out,
+                captured.stderr.contains("has shell special characters"),
+            )
+        };
+        // what the shell makes of the rendered command
+        let shell = |cmd: &str| {
+            let out = std::process::Command::new("sh")
+                .args(["-c", &format!("printf %s {cmd}")])
+                .output()
+                .unwrap();
+            String::from_utf8(out.stdout).unwrap()
+        };
+        for c in SHELL_SPECIAL.chars() {
+            let value = format!("a{c}b");
+            for (templ, open) in [("{v}", ""), ("'{v}'", "'"), ("\"{v}\"", "\"")] {
+                let raw = format!("{open}{value}{open}");
+                let safe = match open {
+                    "" => false,
+                    "'" => c != '\'',
+                    _ => !"\"$`\\".contains(c),
+                };
+                assert_eq!(render(templ, &value, AutoQuote::Off), (raw.clone(), false));
+                assert_eq!(render(templ, &value, AutoQuote::Warn), (raw.clone(), !safe));
+                let (fixed, warned) = render(templ, &value, AutoQuote::Fix);
+                assert!(!warned);
+                if safe {
+                    assert_eq!(fixed, raw);
+                }
+                assert_eq!(shell(&fixed), value, "{c:?} in {templ} as {fixed}");
+            }
+        }
+        // plain values and the ones quoted already are left alone
+        assert_eq!(
+            render("{v}", "a.b-c_d", AutoQuote::Warn),
+            ("a.b-c_d".into(), false)
+        );
+        let map = HashMap::from([("paths".to_string(), "'a b' 'c'".to_string())]);
+        assert_eq!(
+            render_shell("{paths}", &regex, &map, AutoQuote::Fix),
+            "'a b' 'c'"
+        );
+    }
+
+    #[test]
+    fn timed_out_run_is_reported() {
+        let mut plan = ExecutionPlan::new("sleep 30", None);
+        plan.timeout = Some(Duration::from_millis(100));
+        // like with the timeouts, so the sleep goes with the shell
+        plan.group = true;
+        let run = run(&plan, Some(1024), &cancel::Token::default(), |_| ()).unwrap();
+        assert!(run.timed_out);
+        assert_eq!(exit_name(&run), "timeout");
+    }
+
+    #[test]
+    fn container_commands() {
+        let container = || {
+            ContainerRun::new(
+                "podman",
+                "texlive:latest",
+                vec!["/home/me/paper:/work".into(), "/tmp/fonts:/fonts".into()],
+                Some("/work".into()),
+            )
+        };
+        let env = vec![
+            (
+                "ONCHANGE_FILE".to_string(),
+                "/home/me/paper/a b.tex".to_string(),
+            ),
+            ("ONCHANGE_RULE".to_string(), "tex".to_string()),
+        ];
+        let c = container();
+        // the values of the variables come from the engine's
+        // environment, not the arguments
+        assert_eq!(
+            c.argv("latexmk 'a b.tex'", &env),
+            [
+                "podman",
+                "run",
+                "--rm",
+                "--name",
+                &c.name,
+                "-v",
+                "/home/me/paper:/work",
+                "-v",
+                "/tmp/fonts:/fonts",
+                "-w",
+                "/work",
+                "-e",
+                "ONCHANGE_FILE",
+                "-e",
+                "ONCHANGE_RULE",
+                "texlive:latest",
+                "sh",
+                "-c",
+                "latexmk 'a b.tex'"
+            ]
+        );
+        // each run gets its own name to be killed by
+        assert_ne!(container().name, c.name);
+        let bare = ContainerRun::new("docker", "alpine", vec![], None);
+        assert_eq!(
+            bare.argv("true", &[]),
+            ["docker", "run", "--rm", "--name", &bare.name, "alpine", "sh", "-c", "true"]
+        );
+
+        let mut plan = ExecutionPlan::new("latexmk 'a b.tex'", None);
+        plan.env = env.clone();
+        let local = plan.in_container(c.clone());
+        assert_eq!(local.argv, c.argv("latexmk 'a b.tex'", &env));
+        assert_eq!(local.container.as_ref().unwrap().name, c.name);
+        // on the remote host, ssh's shell splits it back to the same
+        // engine invocation
+        let mut plan = ExecutionPlan::new("latexmk 'a b.tex'", Some("build"));
+        plan.env = env.clone();
+        let remote = plan.in_container(c.clone());
+        assert_eq!(
+            remote.argv[..5],
+            ["ssh", "-o", "BatchMode=yes", "build", "--"]
+        );
+        assert_eq!(remote.argv.len(), 6);
+        assert_eq!(
+            split_shell(&remote.argv[5]).unwrap(),
+            c.argv("latexmk 'a b.tex'", &env)
+        );
+    }
+
+    #[test]
+    fn remote_commands_use_sh() {
+        let plan = ExecutionPlan::new("make 'a b'", Some("build"));
+        assert_eq!(
+            plan.argv,
+            [
+                "ssh",
+                "-o",
+                "BatchMode=yes",
+                "build",
+                "--",
+                "sh -c 'make '\\''a b'\\'''"
+            ]
+        );
+    }
+}
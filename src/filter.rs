@@ -0,0 +1,663 @@
+use crate::report::Outcome;
+use notify::EventKind;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Reason for skipping the event
+pub enum Skip {
+    /// Didn't match any of the `--only` patterns
+    NotOnly,
+    /// Matched an ignore pattern
+    Ignored,
+    /// Has a hidden file or directory below the watched path
+    Hidden,
+    /// File was last modified before the freshness window
+    Stale(SystemTime),
+    /// Doesn't match the glob pattern its directory is watched for
+    NotMatching,
+    /// Temporary or backup file of an editor
+    EditorFile,
+}
+
+impl Skip {
+    /// `{action}` of the events it skips
+    pub fn outcome(&self) -> Outcome {
+        match self {
+            Self::Stale(_) => Outcome::Stale,
+            _ => Outcome::Ignored,
+        }
+    }
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotOnly => write!(f, "doesn't match --only"),
+            Self::Ignored => write!(f, "ignored"),
+            Self::Hidden => write!(f, "hidden"),
+            Self::Stale(mtime) => write!(
+                f,
+                "stale, last modified at {}",
+                humantime::format_rfc3339_seconds(*mtime)
+            ),
+            Self::NotMatching => write!(f, "doesn't match the watched patterns"),
+            Self::EditorFile => write!(f, "editor's temporary file"),
+        }
+    }
+}
+
+/// Temporary, swap and backup files of the editors: vim's swap files
+/// and the file it checks it can write with, emacs' backups, autosaves
+/// and locks, JetBrains' safe write files and kate's swap files
+pub const EDITOR_FILES: [&str; 8] = [
+    "**/.*.sw?",
+    "**/4913",
+    "**/*~",
+    "**/#*#",
+    "**/.#*",
+    "**/*___jb_tmp___",
+    "**/*___jb_old___",
+    "**/*.kate-swp",
+];
+
+/// Decides which events are to be skipped before doing anything else
+pub struct Filter {
+    /// Only the paths matching one of these pass, if any
+    pub only: Vec<glob::Pattern>,
+    /// Skip the paths with a hidden component below their watch root
+    pub ignore_hidden: bool,
+    pub ignore: Vec<glob::Pattern>,
+    /// Patterns of the editors' temporary files, skipped unless
+    /// `--no-ignore-editor-files`
+    pub editor_files: Vec<glob::Pattern>,
+    pub fresh_within: Option<Duration>,
+    pub cwd: PathBuf,
+    /// Absolute paths of the watched paths
+    pub roots: Vec<PathBuf>,
+    /// Glob patterns given as the watched paths
+    pub globs: Vec<crate::watch::GlobWatch>,
+}
+
+impl Filter {
+    pub fn check(&self, path: &Path, now: SystemTime) -> Option<Skip> {
+        let root = crate::watch::watch_root(path, &self.roots);
+        let only: Vec<&glob::Pattern> = self
+            .only
+            .iter()
+            .filter(|p| matches_any_form(p, path, &self.cwd, root))
+            .collect();
+        if !self.only.is_empty() && only.is_empty() {
+            return Some(Skip::NotOnly);
+        }
+        // asking for a hidden file by name wins over hiding them
+        if self.ignore_hidden
+            && hidden(path, root)
+            && !only.iter().any(|p| names_hidden(p.as_str()))
+        {
+            return Some(Skip::Hidden);
+        }
+        if self
+            .ignore
+            .iter()
+            .any(|p| matches_any_form(p, path, &self.cwd, root))
+        {
+            return Some(Skip::Ignored);
+        }
+        if self
+            .editor_files
+            .iter()
+            .any(|p| matches_any_form(p, path, &self.cwd, root))
+        {
+            return Some(Skip::EditorFile);
+        }
+        // paths under a glob's base only pass if they match one of the
+        // globs with that base
+        let globs: Vec<&crate::watch::GlobWatch> = self
+            .globs
+            .iter()
+            .filter(|g| root == Some(g.root.as_path()))
+            .collect();
+        if !globs.is_empty() && !globs.iter().any(|g| g.matches(path)) {
+            return Some(Skip::NotMatching);
+        }
+        if let Some(within) = self.fresh_within {
+            // deleted files have no mtime, and are never stale
+            if let Ok(mtime) = path.metadata().and_then(|m| m.modified()) {
+                // files with mtime in future are fresh
+                if now.duration_since(mtime).is_ok_and(|age| age > within) {
+                    return Some(Skip::Stale(mtime));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Path has a component starting with `.` below the root, so a hidden
+/// watched directory is fine; only its name counts without a root
+pub fn hidden(path: &Path, root: Option<&Path>) -> bool {
+    let below = root
+        .and_then(|r| path.strip_prefix(r).ok())
+        .filter(|p| !p.as_os_str().is_empty())
+        .or_else(|| path.file_name().map(Path::new));
+    below.is_some_and(|p| {
+        p.components()
+            .any(|c| matches!(c, Component::Normal(n) if n.to_string_lossy().starts_with('.')))
+    })
+}
+
+/// Pattern names a hidden file or directory, like ".env" or
+/// "**/.github/**"
+fn names_hidden(pattern: &str) -> bool {
+    pattern
+        .split('/')
+        .any(|part| part.starts_with('.') && part != "." && part != "..")
+}
+
+/// Pattern matching the path exactly, the glob characters in it (like
+/// the brackets of "photos [2024]") are taken literally
+pub fn literal_pattern(path: &Path) -> glob::Pattern {
+    glob::Pattern::new(&glob::Pattern::escape(&path.to_string_lossy())).unwrap()
+}
+
+/// Pattern matching everything in the directory, its path taken
+/// literally
+pub fn literal_contents(dir: &Path) -> glob::Pattern {
+    let dir = glob::Pattern::escape(&dir.to_string_lossy());
+    glob::Pattern::new(&format!("{}/**", dir.trim_end_matches('/'))).unwrap()
+}
+
+/// Suffix pattern for an ignore entry that's only an extension with
+/// the dot (like ".swp"), so it ignores the files ending in it anywhere
+pub fn bare_extension(pattern: &str) -> Option<String> {
+    let ext = pattern.strip_prefix('.')?;
+    if ext.is_empty() || ext.contains(['*', '?', '[', ']', '/']) {
+        return None;
+    }
+    Some(format!("**/*{}", pattern))
+}
+
+/// Pattern matches the path in any of its forms: absolute, relative
+/// to the current directory, or relative to the watch root it's from
+pub fn matches_any_form(
+    pattern: &glob::Pattern,
+    path: &Path,
+    cwd: &Path,
+    root: Option<&Path>,
+) -> bool {
+    // without the `.` components the watch paths like "." leave
+    let mut forms: Vec<PathBuf> = vec![path.components().collect()];
+    if let Some(rel) = pathdiff::diff_paths(path, cwd) {
+        forms.push(rel);
+    }
+    if let Some(root) = root {
+        match path.strip_prefix(root) {
+            // the watched path is the file itself
+            Ok(rel) if rel.as_os_str().is_empty() => {
+                forms.push(PathBuf::from(path.file_name().unwrap_or_default()))
+            }
+            Ok(rel) => forms.push(rel.to_path_buf()),
+            Err(_) => (),
+        }
+    }
+    forms
+        .iter()
+        .filter(|f| !f.as_os_str().is_empty())
+        .any(|f| pattern.matches(&slashed(f, std::path::MAIN_SEPARATOR)))
+}
+
+/// Path with `/` for the separators, as the patterns are written
+fn slashed(path: &Path, separator: char) -> String {
+    path.to_string_lossy().replace(separator, "/")
+}
+
+/// Directories the representative files are put in for the
+/// reachability check, relative to the current directory
+const SAMPLE_DIRS: [&str; 3] = ["", "dir", "dir/sub"];
+
+/// Literal path the glob pattern matches, with its wildcards filled
+/// in with plain names, e.g. `dir/file.md` for `**/*.md`
+pub fn pattern_sample(pattern: &str) -> String {
+    let mut sample = String::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                sample.push_str("dir");
+            }
+            '*' => sample.push_str("file"),
+            '?' => sample.push('x'),
+            '[' => {
+                let class: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                match class.strip_prefix('!') {
+                    // a character that's not excluded
+                    Some(excluded) => {
+                        sample.push(('a'..='z').find(|c| !excluded.contains(*c)).unwrap_or('_'))
+                    }
+                    None => sample.extend(class.chars().next()),
+                }
+            }
+            c => sample.push(c),
+        }
+    }
+    sample
+}
+
+/// What the rule is found by (its pattern, or each of its extensions)
+/// with the representative paths of the files for it
+fn rule_samples(rule: &crate::config::Rule, cwd: &Path) -> Vec<(String, Vec<PathBuf>)> {
+    match &rule.pattern {
+        // the rules with a pattern are only found through it
+        Some(p) => {
+            let sample = cwd.join(pattern_sample(p.as_str()));
+            // can't tell without a path it matches
+            if !matches_any_form(p, &sample, cwd, None) {
+                return Vec::new();
+            }
+            vec![(format!("pattern {}", p), vec![sample])]
+        }
+        None => rule
+            .extensions()
+            .map(|ext| {
+                let paths = SAMPLE_DIRS
+                    .iter()
+                    .map(|dir| cwd.join(dir).join(format!("file.{}", ext)))
+                    .collect();
+                (ext.to_string(), paths)
+            })
+            .collect(),
+    }
+}
+
+/// What the rule is found by whose files are always skipped by the
+/// ignore patterns or the `--only` ones, with the number of the ways
+/// it's found by. It's checked with representative paths so it's a
+/// heuristic
+pub fn unreachable_selectors(
+    rule: &crate::config::Rule,
+    ignore: &[glob::Pattern],
+    only: &[glob::Pattern],
+    cwd: &Path,
+) -> (Vec<String>, usize) {
+    let skipped = |path: &PathBuf| {
+        let matches = |p: &glob::Pattern| matches_any_form(p, path, cwd, Some(cwd));
+        ignore.iter().any(matches) || (!only.is_empty() && !only.iter().any(matches))
+    };
+    let samples = rule_samples(rule, cwd);
+    let total = samples.len();
+    let unreachable = samples
+        .into_iter()
+        .filter(|(_, paths)| paths.iter().all(skipped))
+        .map(|(selector, _)| selector)
+        .collect();
+    (unreachable, total)
+}
+
+/// Which events of the direct children of a watched directory are used
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DirEvents {
+    /// Creating, removing or renaming the children
+    Entries,
+    /// Modifying the existing children
+    Writes,
+    All,
+}
+
+impl DirEvents {
+    /// Event passes the setting, the events of the paths other than the
+    /// direct children of a watched directory only pass with `all`
+    pub fn allows(&self, path: &Path, kinds: &[EventKind], roots: &[PathBuf]) -> bool {
+        if *self == Self::All {
+            return true;
+        }
+        // watched files aren't affected, watched directories themselves
+        // aren't their children
+        if roots.iter().any(|r| r == path) {
+            return !path.is_dir();
+        }
+        let child = path
+            .parent()
+            .is_some_and(|p| roots.iter().any(|r| r == p && r.is_dir()));
+        if !child {
+            return false;
+        }
+        let entry = kinds.iter().any(|k| {
+            matches!(
+                k,
+                EventKind::Create(_)
+                    | EventKind::Remove(_)
+                    | EventKind::Modify(notify::event::ModifyKind::Name(_))
+            )
+        });
+        match self {
+            Self::Entries => entry,
+            // created files are written too, those are entries
+            _ => !entry,
+        }
+    }
+}
+
+/// Kind of change for `--events` and the rule's `events`
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum EventType {
+    Create,
+    Modify,
+    Remove,
+    Rename,
+}
+
+impl EventType {
+    /// Kinds of change in the debounced event. A remove and a create
+    /// (or rename into place) of a file that exists now is an atomic
+    /// save, so a modify.
+    pub fn of(path: &Path, kinds: &[EventKind]) -> Vec<Self> {
+        use notify::event::ModifyKind;
+        let removed = kinds.iter().any(|k| matches!(k, EventKind::Remove(_)));
+        let created = kinds.iter().any(|k| {
+            matches!(
+                k,
+                EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+            )
+        });
+        if removed && created && path.exists() {
+            return vec![Self::Modify];
+        }
+        let mut types = Vec::new();
+        for kind in kinds {
+            let t = match kind {
+                EventKind::Create(_) => Self::Create,
+                EventKind::Remove(_) => Self::Remove,
+                EventKind::Modify(ModifyKind::Name(_)) => Self::Rename,
+                EventKind::Access(_) => continue,
+                _ => Self::Modify,
+            };
+            if !types.contains(&t) {
+                types.push(t);
+            }
+        }
+        types
+    }
+
+    /// The overall change the event made to the file: removed if it's
+    /// gone, else created, renamed or modified in that order
+    pub fn net(path: &Path, kinds: &[EventKind]) -> Option<Self> {
+        let types = Self::of(path, kinds);
+        if types.is_empty() {
+            return None;
+        }
+        if !path.exists() {
+            return Some(if types.contains(&Self::Remove) {
+                Self::Remove
+            } else {
+                types[types.len() - 1]
+            });
+        }
+        [Self::Create, Self::Rename, Self::Modify]
+            .into_iter()
+            .find(|t| types.contains(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{rules_from_toml, Rules};
+
+    fn patterns(ps: &[&str]) -> Vec<glob::Pattern> {
+        ps.iter().map(|p| glob::Pattern::new(p).unwrap()).collect()
+    }
+
+    fn unreachable(rules: &Rules, name: &str, ignore: &[&str], only: &[&str]) -> Vec<String> {
+        let rule = rules.iter().find(|r| r.name == name).unwrap();
+        let cwd = Path::new("/project");
+        unreachable_selectors(rule, &patterns(ignore), &patterns(only), cwd).0
+    }
+
+    #[test]
+    fn literal_patterns_of_the_paths() {
+        let dir = Path::new("/p/photos [2024]");
+        let exact = literal_pattern(dir);
+        assert!(exact.matches_path(dir));
+        // the brackets would match one of the characters
+        assert!(!exact.matches_path(Path::new("/p/photos 2")));
+        let contents = literal_contents(Path::new("/p/raw*/"));
+        assert!(contents.matches_path(Path::new("/p/raw*/a/b.cr2")));
+        assert!(!contents.matches_path(Path::new("/p/raw-2/b.cr2")));
+        assert!(literal_contents(dir).matches_path(Path::new("/p/photos [2024]/a.jpg")));
+    }
+
+    #[test]
+    fn samples_of_patterns() {
+        assert_eq!(pattern_sample("**/*.md"), "dir/file.md");
+        assert_eq!(pattern_sample("docs/ch?.tex"), "docs/chx.tex");
+        assert_eq!(pattern_sample("src/[abc]*.rs"), "src/afile.rs");
+        assert_eq!(pattern_sample("[!a]*"), "bfile");
+    }
+
+    #[test]
+    fn reachable_rules() {
+        let rules = rules_from_toml(
+            "[md]\nextensions = \"md\"\ncommand = \"x\"\n\
+             [docs]\npattern = \"docs/*.tex\"\ncommand = \"x\"\n",
+        )
+        .unwrap();
+        assert!(unreachable(&rules, "md", &["**/*.tmp", "target/**"], &[]).is_empty());
+        assert!(unreachable(&rules, "docs", &["**/*.tmp"], &["docs/**"]).is_empty());
+    }
+
+    #[test]
+    fn unreachable_rules() {
+        let rules = rules_from_toml(
+            "[md]\nextensions = \"md\"\ncommand = \"x\"\n\
+             [docs]\npattern = \"docs/*.tex\"\ncommand = \"x\"\n",
+        )
+        .unwrap();
+        assert_eq!(unreachable(&rules, "md", &["**/*.md"], &[]), ["md"]);
+        assert_eq!(unreachable(&rules, "md", &["*.md"], &[]), ["md"]);
+        assert_eq!(
+            unreachable(&rules, "docs", &["docs/**"], &[]),
+            ["pattern docs/*.tex"]
+        );
+        // the only patterns leave them out too
+        assert_eq!(unreachable(&rules, "md", &[], &["**/*.rs"]), ["md"]);
+        assert_eq!(
+            unreachable(&rules, "docs", &[], &["src/**"]),
+            ["pattern docs/*.tex"]
+        );
+    }
+
+    #[test]
+    fn partially_reachable_rules() {
+        let rules =
+            rules_from_toml("[text]\nextensions = \"md txt org\"\ncommand = \"x\"\n").unwrap();
+        let rule = rules.iter().next().unwrap();
+        let (skipped, total) = unreachable_selectors(
+            rule,
+            &patterns(&["**/*.md", "**/*.org"]),
+            &[],
+            Path::new("/project"),
+        );
+        assert_eq!(skipped, ["md", "org"]);
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn bare_extensions() {
+        assert_eq!(bare_extension(".swp").unwrap(), "**/*.swp");
+        assert_eq!(bare_extension(".tar.gz").unwrap(), "**/*.tar.gz");
+        for p in ["swp", ".", ".*", ".sw?", "./build", "*.swp"] {
+            assert_eq!(bare_extension(p), None, "{}", p);
+        }
+        let pattern = glob::Pattern::new(&bare_extension(".swp").unwrap()).unwrap();
+        let cwd = Path::new("/project");
+        assert!(matches_any_form(
+            &pattern,
+            Path::new("/project/a/b/.x.swp"),
+            cwd,
+            None
+        ));
+        assert!(matches_any_form(
+            &pattern,
+            Path::new("/project/x.swp"),
+            cwd,
+            None
+        ));
+        assert!(!matches_any_form(
+            &pattern,
+            Path::new("/project/x.swpx"),
+            cwd,
+            None
+        ));
+    }
+
+    #[test]
+    fn hidden_paths() {
+        let root = Some(Path::new("/home/me/.config"));
+        assert!(!hidden(Path::new("/home/me/.config/nvim/init.lua"), root));
+        assert!(hidden(Path::new("/home/me/.config/.git/HEAD"), root));
+        assert!(hidden(Path::new("/home/me/.config/nvim/.netrwhist"), root));
+        // only the name counts for the watched file itself, or without a root
+        assert!(hidden(
+            Path::new("/home/me/.bashrc"),
+            Some(Path::new("/home/me/.bashrc"))
+        ));
+        assert!(!hidden(
+            Path::new("/home/.me/notes.md"),
+            Some(Path::new("/home/.me/notes.md"))
+        ));
+        assert!(hidden(Path::new("/home/me/.bashrc"), None));
+        assert!(!hidden(Path::new("/home/.me/notes.md"), None));
+    }
+
+    #[test]
+    fn hidden_files_asked_for_pass() {
+        let filter = Filter {
+            only: patterns(&["**/.env"]),
+            ignore_hidden: true,
+            ignore: Vec::new(),
+            editor_files: Vec::new(),
+            fresh_within: None,
+            cwd: PathBuf::from("/project"),
+            roots: vec![PathBuf::from("/project")],
+            globs: Vec::new(),
+        };
+        let now = SystemTime::now();
+        assert!(filter.check(Path::new("/project/app/.env"), now).is_none());
+        assert!(matches!(
+            filter.check(Path::new("/project/.git/config"), now),
+            Some(Skip::NotOnly)
+        ));
+        let filter = Filter {
+            only: Vec::new(),
+            ..filter
+        };
+        assert!(matches!(
+            filter.check(Path::new("/project/app/.env"), now),
+            Some(Skip::Hidden)
+        ));
+    }
+
+    #[test]
+    fn stale_files_by_the_clock() {
+        let file = std::env::temp_dir().join(format!("onchange-stale-{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        filetime::set_file_mtime(&file, filetime::FileTime::from_system_time(mtime)).unwrap();
+        let within = Duration::from_secs(3600);
+        let filter = Filter {
+            only: Vec::new(),
+            ignore_hidden: false,
+            ignore: Vec::new(),
+            editor_files: Vec::new(),
+            fresh_within: Some(within),
+            cwd: std::env::temp_dir(),
+            roots: Vec::new(),
+            globs: Vec::new(),
+        };
+        // exactly as old as the window is still fresh
+        assert!(filter.check(&file, mtime + within).is_none());
+        assert!(matches!(
+            filter.check(&file, mtime + within + Duration::from_secs(1)),
+            Some(Skip::Stale(t)) if t == mtime
+        ));
+        // modified in the future by the clock
+        assert!(filter.check(&file, mtime - within).is_none());
+        assert!(filter.check(&file, mtime).is_none());
+        // deleted ones have no mtime to be stale by
+        std::fs::remove_file(&file).unwrap();
+        assert!(filter.check(&file, mtime + within * 24).is_none());
+    }
+
+    #[test]
+    fn net_event_types() {
+        use crate::debounce::Kind;
+        use notify::event::{AccessKind, RemoveKind};
+        let [create, modify, remove, rename] =
+            [Kind::Create, Kind::Modify, Kind::Remove, Kind::Rename].map(Kind::event_kind);
+        let access = EventKind::Access(AccessKind::Any);
+        let file = std::env::temp_dir().join(format!("onchange-net-{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let net = |kinds: &[EventKind]| EventType::net(&file, kinds);
+        assert_eq!(net(&[modify]), Some(EventType::Modify));
+        assert_eq!(net(&[create, modify]), Some(EventType::Create));
+        assert_eq!(net(&[modify, rename]), Some(EventType::Rename));
+        // an atomic save
+        assert_eq!(net(&[remove, rename]), Some(EventType::Modify));
+        assert_eq!(net(&[access]), None);
+        assert_eq!(net(&[]), None);
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(net(&[create, modify, remove]), Some(EventType::Remove));
+        assert_eq!(
+            net(&[EventKind::Remove(RemoveKind::File)]),
+            Some(EventType::Remove)
+        );
+        // gone without a remove, like a rename away
+        assert_eq!(net(&[modify, rename]), Some(EventType::Rename));
+    }
+
+    #[test]
+    fn ignore_matches_any_form() {
+        let cwd = Path::new("/home/me");
+        let root = Some(Path::new("/home/me/project/src"));
+        let path = Path::new("/home/me/project/src/gen/out.rs");
+        let matches = |p: &str| matches_any_form(&glob::Pattern::new(p).unwrap(), path, cwd, root);
+        // absolute, relative to the current directory and to the root
+        assert!(matches("/home/me/project/src/gen/*.rs"));
+        assert!(matches("project/src/gen/out.rs"));
+        assert!(matches("gen/out.rs"));
+        assert!(matches("gen/*"));
+        assert!(matches("**/out.rs"));
+        assert!(!matches("out.rs"));
+        assert!(!matches("src/gen/out.rs"));
+        assert!(!matches("other/**"));
+        // the watched file itself is matched by its name
+        let file = Path::new("/home/me/notes.md");
+        assert!(matches_any_form(
+            &glob::Pattern::new("notes.md").unwrap(),
+            file,
+            Path::new("/tmp"),
+            Some(file)
+        ));
+        // with the `.` components of the watch paths left out
+        assert!(matches_any_form(
+            &glob::Pattern::new("/home/me/a.txt").unwrap(),
+            Path::new("/home/me/./a.txt"),
+            cwd,
+            Some(Path::new("/home/me/."))
+        ));
+    }
+
+    #[test]
+    fn windows_separators() {
+        let pattern = glob::Pattern::new("build/**/*.o").unwrap();
+        let path = slashed(Path::new(r"build\obj\out.o"), '\\');
+        assert_eq!(path, "build/obj/out.o");
+        assert!(pattern.matches(&path));
+        assert_eq!(slashed(Path::new("build/out.o"), '/'), "build/out.o");
+    }
+}
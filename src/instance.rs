@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Exit code when another instance holds the lock
+pub const ALREADY_RUNNING: i32 = 3;
+
+/// How long to wait for the replaced instance to exit
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do when another instance is running
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SingleInstance {
+    /// Exit without doing anything
+    Exit,
+    /// Stop the other instance and take over
+    Replace,
+}
+
+/// Details of the running instance, kept next to the lock
+#[derive(Serialize, Deserialize)]
+struct Info {
+    pid: u32,
+    started_at: SystemTime,
+    watch: Vec<PathBuf>,
+}
+
+/// Lock held by this instance, the info file is removed when dropped.
+/// The lock file is left, removing it could let a waiting instance
+/// lock the removed file while another one locks a new one.
+pub struct InstanceLock {
+    _file: File,
+    info: PathBuf,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.info).ok();
+    }
+}
+
+/// Take the lock for the config file (or the directory) given as the
+/// key, the error has the details of the instance holding it
+pub fn acquire(
+    key: &Path,
+    mode: SingleInstance,
+    watch: &[PathBuf],
+) -> Result<InstanceLock, String> {
+    let (lock, info, socket) = files(key);
+    let err = |e: std::io::Error| format!("{}: {}", lock.to_string_lossy(), e);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock)
+        .map_err(err)?;
+    if !try_lock(&file) {
+        let holder = read_info(&info);
+        match (mode, &holder) {
+            // the lock is released when the process exits, so a dead
+            // pid means it just exited
+            (SingleInstance::Replace, Some(holder)) if pid_alive(holder.pid) => {
+                unsafe { libc::kill(holder.pid as i32, libc::SIGTERM) };
+            }
+            (SingleInstance::Replace, _) => (),
+            (SingleInstance::Exit, _) => return Err(describe(key, holder.as_ref())),
+        }
+        let start = Instant::now();
+        while !try_lock(&file) {
+            if start.elapsed() > REPLACE_TIMEOUT {
+                return Err(format!(
+                    "{} didn't exit in {}",
+                    describe(key, holder.as_ref()),
+                    humantime::format_duration(REPLACE_TIMEOUT)
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+    let details = Info {
+        pid: std::process::id(),
+        started_at: SystemTime::now(),
+        watch: watch.to_vec(),
+    };
+    std::fs::write(&info, serde_json::to_string(&details).unwrap())
+        .map_err(|e| format!("{}: {}", info.to_string_lossy(), e))?;
+    // the drop doesn't run when killed by the signal
+    crate::cancel::remove_on_signal(&[&info, &socket]);
+    Ok(InstanceLock { _file: file, info })
+}
+
+/// Send the signal to the instance running for the key, returns its
+/// pid
+pub fn signal(key: &Path, sig: libc::c_int) -> Result<u32, String> {
+    let (_, info, _) = files(key);
+    match read_info(&info) {
+        Some(holder) if pid_alive(holder.pid) => {
+            unsafe { libc::kill(holder.pid as i32, sig) };
+            Ok(holder.pid)
+        }
+        _ => Err(format!(
+            "no instance is running for {:?}, it has to be started with --single-instance",
+            key
+        )),
+    }
+}
+
+/// Control socket of the instance running for the key
+pub fn socket(key: &Path) -> PathBuf {
+    files(key).2
+}
+
+/// Lock and info files, and the control socket, for the key
+fn files(key: &Path) -> (PathBuf, PathBuf, PathBuf) {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let name = format!("onchange-{:016x}", hasher.finish());
+    (
+        dir.join(format!("{}.lock", name)),
+        dir.join(format!("{}.info", name)),
+        dir.join(format!("{}.sock", name)),
+    )
+}
+
+fn try_lock(file: &File) -> bool {
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+}
+
+fn pid_alive(pid: u32) -> bool {
+    unsafe { libc::kill(pid as i32, 0) == 0 }
+}
+
+fn read_info(info: &Path) -> Option<Info> {
+    serde_json::from_str(&std::fs::read_to_string(info).ok()?).ok()
+}
+
+fn describe(key: &Path, holder: Option<&Info>) -> String {
+    match holder {
+        Some(h) => format!(
+            "another instance is running for {:?} (pid {}, started at {}, watching {})",
+            key,
+            h.pid,
+            humantime::format_rfc3339_seconds(h.started_at),
+            h.watch
+                .iter()
+                .map(|p| format!("{:?}", p))
+                .collect::<Vec<String>>()
+                .join(" ")
+        ),
+        None => format!("another instance is running for {:?}", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("onchange-instance-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn second_instance_is_refused() {
+        let key = key("refused");
+        let watch = [PathBuf::from("/project/src")];
+        let lock = acquire(&key, SingleInstance::Exit, &watch).unwrap();
+        // another open of the lock file, like another process does
+        let err = acquire(&key, SingleInstance::Exit, &[]).err().unwrap();
+        assert!(
+            err.starts_with(&format!(
+                "another instance is running for {:?} (pid {},",
+                key,
+                std::process::id()
+            )),
+            "{}",
+            err
+        );
+        assert!(err.ends_with("watching \"/project/src\")"), "{}", err);
+        let (_, info, _) = files(&key);
+        drop(lock);
+        assert!(!info.exists());
+        // free again
+        drop(acquire(&key, SingleInstance::Exit, &[]).unwrap());
+    }
+
+    #[test]
+    fn stale_info_is_replaced() {
+        let key = key("stale");
+        let (_, info, _) = files(&key);
+        // left by an instance that was killed with SIGKILL
+        let mut dead = std::process::Command::new("true").spawn().unwrap();
+        dead.wait().unwrap();
+        let stale = Info {
+            pid: dead.id(),
+            started_at: SystemTime::UNIX_EPOCH,
+            watch: vec![],
+        };
+        std::fs::write(&info, serde_json::to_string(&stale).unwrap()).unwrap();
+        assert!(!pid_alive(dead.id()));
+        assert!(signal(&key, 0).is_err());
+        let lock = acquire(&key, SingleInstance::Exit, &[]).unwrap();
+        assert_eq!(read_info(&info).unwrap().pid, std::process::id());
+        assert_eq!(signal(&key, 0), Ok(std::process::id()));
+        drop(lock);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::output::infoln;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// A line in the journal file
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record {
+    /// Written (and synced) before the run starts
+    Start {
+        id: u64,
+        path: PathBuf,
+        rule: String,
+        command: String,
+        started_at: SystemTime,
+        /// Version of onchange and hash of the config the run is from
+        #[serde(default)]
+        onchange_version: String,
+        #[serde(default)]
+        config_hash: String,
+    },
+    /// Process id of the run once it's spawned
+    Pid { id: u64, pid: u32 },
+    /// Written after the run finishes
+    End {
+        id: u64,
+        status: String,
+        /// Output of the run was cut at `--max-output`
+        #[serde(default)]
+        output_truncated: bool,
+    },
+}
+
+/// A run that started in an earlier session but never finished
+pub struct Interrupted {
+    pub path: PathBuf,
+    pub rule: String,
+    pub command: String,
+    pub started_at: SystemTime,
+    pub pid: Option<u32>,
+}
+
+/// Journal of the runs, to find the ones interrupted by a crash
+pub struct Journal {
+    file: Mutex<File>,
+    /// Started runs without an end record
+    open: Mutex<HashSet<u64>>,
+    next_id: Mutex<u64>,
+    /// Hash of the config of the session, for the start records
+    config_hash: String,
+}
+
+impl Journal {
+    /// Open the journal, the runs from the earlier session that never
+    /// finished are returned and the journal is started fresh
+    pub fn open(path: &Path, config_hash: &str) -> Result<(Self, Vec<Interrupted>), String> {
+        let err = |e: std::io::Error| format!("{}: {}", path.to_string_lossy(), e);
+        let interrupted = if path.exists() {
+            read_interrupted(File::open(path).map_err(err)?)
+        } else {
+            vec![]
+        };
+        // appending, so the writes go to the end even after compaction
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(err)?;
+        // nothing else in the old journal is needed anymore
+        file.set_len(0).map_err(err)?;
+        let journal = Self {
+            file: Mutex::new(file),
+            open: Mutex::new(HashSet::new()),
+            next_id: Mutex::new(1),
+            config_hash: config_hash.to_string(),
+        };
+        Ok((journal, interrupted))
+    }
+
+    /// Record the start of a run, returns its id for the other records
+    pub fn start(&self, path: &Path, rule: &str, command: &str) -> u64 {
+        let id = {
+            let mut next = self.next_id.lock().unwrap();
+            *next += 1;
+            *next - 1
+        };
+        self.open.lock().unwrap().insert(id);
+        self.write(
+            &Record::Start {
+                id,
+                path: path.to_path_buf(),
+                rule: rule.to_string(),
+                command: command.to_string(),
+                started_at: SystemTime::now(),
+                onchange_version: env!("CARGO_PKG_VERSION").to_string(),
+                config_hash: self.config_hash.clone(),
+            },
+            true,
+        );
+        id
+    }
+
+    pub fn pid(&self, id: u64, pid: u32) {
+        self.write(&Record::Pid { id, pid }, false);
+    }
+
+    /// Record the end of the run, the journal is emptied before it when
+    /// there are no running commands left
+    pub fn end(&self, id: u64, status: &str, output_truncated: bool) {
+        let mut open = self.open.lock().unwrap();
+        open.remove(&id);
+        if open.is_empty() {
+            let file = self.file.lock().unwrap();
+            if let Err(e) = file.set_len(0) {
+                infoln!("{}: compacting the journal: {}", "Error".bold().red(), e);
+            }
+        }
+        self.write(
+            &Record::End {
+                id,
+                status: status.to_string(),
+                output_truncated,
+            },
+            false,
+        );
+    }
+
+    fn write(&self, record: &Record, sync: bool) {
+        let mut line = serde_json::to_string(record).unwrap();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        let res =
+            file.write_all(line.as_bytes())
+                .and_then(|_| if sync { file.sync_data() } else { Ok(()) });
+        if let Err(e) = res {
+            infoln!("{}: writing the journal: {}", "Error".bold().red(), e);
+        }
+    }
+}
+
+/// Starts without an end from the journal, lines that can't be parsed
+/// (e.g. partly written ones) are skipped
+fn read_interrupted(file: File) -> Vec<Interrupted> {
+    let mut runs: BTreeMap<u64, Interrupted> = BTreeMap::new();
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        match serde_json::from_str(&line) {
+            Ok(Record::Start {
+                id,
+                path,
+                rule,
+                command,
+                started_at,
+                ..
+            }) => {
+                runs.insert(
+                    id,
+                    Interrupted {
+                        path,
+                        rule,
+                        command,
+                        started_at,
+                        pid: None,
+                    },
+                );
+            }
+            Ok(Record::Pid { id, pid }) => {
+                if let Some(run) = runs.get_mut(&id) {
+                    run.pid = Some(pid);
+                }
+            }
+            Ok(Record::End { id, .. }) => {
+                runs.remove(&id);
+            }
+            Err(_) => (),
+        }
+    }
+    runs.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn killed_runs_are_found_and_finished_ones_compacted() {
+        let path = std::env::temp_dir().join(format!("onchange-journal-{}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let (journal, interrupted) = Journal::open(&path, "abc").unwrap();
+        assert!(interrupted.is_empty());
+        let id = journal.start(Path::new("/project/a.md"), "md", "sleep 30");
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        journal.pid(id, child.id());
+        // the session dies with its command mid-run
+        child.kill().unwrap();
+        child.wait().unwrap();
+        drop(journal);
+
+        let (journal, interrupted) = Journal::open(&path, "abc").unwrap();
+        assert_eq!(interrupted.len(), 1);
+        let run = &interrupted[0];
+        assert_eq!(
+            (run.path.as_path(), run.rule.as_str(), run.command.as_str()),
+            (Path::new("/project/a.md"), "md", "sleep 30")
+        );
+        assert_eq!(run.pid, Some(child.id()));
+        // it's reported once, the journal starts fresh
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        let a = journal.start(Path::new("a"), "md", "x");
+        let b = journal.start(Path::new("b"), "md", "x");
+        journal.end(a, "0", false);
+        let types: Vec<String> = records(&path)
+            .iter()
+            .map(|r| r["type"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(types, ["start", "start", "end"]);
+        // the last one to end compacts it, but it's still recorded
+        journal.end(b, "1", true);
+        let records = records(&path);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["type"], "end");
+        assert_eq!(records[0]["id"], b);
+        assert_eq!(records[0]["status"], "1");
+        assert_eq!(records[0]["output_truncated"], true);
+        drop(journal);
+        let (_, interrupted) = Journal::open(&path, "abc").unwrap();
+        assert!(interrupted.is_empty());
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,265 @@
+use crate::config::{template_variables, variable_regex, Rule, Rules};
+use colored::Colorize;
+use regex::Regex;
+use std::fmt;
+
+/// How bad a finding is, errors always fail `onchange check`
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in a rule, with how to fix it
+pub struct Finding {
+    pub code: &'static str,
+    pub severity: Severity,
+    pub rule: String,
+    pub message: String,
+    pub help: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self.severity {
+            Severity::Warning => format!("warning[{}]", self.code).yellow().bold(),
+            Severity::Error => format!("error[{}]", self.code).red().bold(),
+        };
+        writeln!(f, "{}: {}", level, self.message.bold())?;
+        writeln!(f, "  {} rule {}", "-->".blue().bold(), self.rule)?;
+        write!(f, "   {} help: {}", "=".blue().bold(), self.help)
+    }
+}
+
+/// Lints for the rules, each gets the rule and adds its findings
+const LINTS: &[fn(&Rule, &Rules, &mut Vec<Finding>)] = &[
+    unquoted_redirect,
+    dotted_extension,
+    cd_without_and,
+    circular_variables,
+];
+
+/// Run all the lints over the rules
+pub fn lint(rules: &Rules) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for rule in rules.iter() {
+        for lint in LINTS {
+            lint(rule, rules, &mut findings);
+        }
+    }
+    findings
+}
+
+/// Command templates of the rule, with the field they're from
+fn commands(rule: &Rule) -> Vec<(String, &String)> {
+    let mut templates: Vec<(String, &String)> = match &rule.command {
+        Some(cmd) => cmd
+            .by_kind()
+            .into_iter()
+            .map(|(kind, c)| {
+                let field = match &rule.command {
+                    Some(crate::config::Command::ByKind(_)) => format!("command.{}", kind),
+                    _ => "command".to_string(),
+                };
+                (field, c)
+            })
+            .collect(),
+        None => vec![],
+    };
+    if let Some(cmd) = &rule.first_command {
+        templates.push(("first_command".to_string(), cmd));
+    }
+    templates
+}
+
+/// L001: a path variable as the target of a redirection without
+/// quotes, the file names with spaces break it
+fn unquoted_redirect(rule: &Rule, _: &Rules, findings: &mut Vec<Finding>) {
+    let regex = variable_regex(rule.template_delimiters.as_ref());
+    for (field, templ) in commands(rule) {
+        for cap in regex.captures_iter(templ) {
+            let whole = cap.get(0).unwrap();
+            let var = &cap[1];
+            let is_path = crate::vars::builtin_target(var).is_some_and(|v| {
+                crate::vars::VARIABLES
+                    .iter()
+                    .any(|b| b.name == v && b.value.is_some())
+            });
+            let before = templ[..whole.start()].trim_end_matches(' ');
+            let quoted = templ[..whole.start()].ends_with(['\'', '"']);
+            if is_path && !quoted && before.ends_with(['<', '>']) {
+                findings.push(Finding {
+                    code: "L001",
+                    severity: Severity::Warning,
+                    rule: rule.name.clone(),
+                    message: format!("{} redirects to {} without quotes", field, whole.as_str()),
+                    help: format!(
+                        "quote it as \"{}\", the paths with spaces break the redirection",
+                        whole.as_str()
+                    ),
+                });
+            }
+        }
+    }
+}
+
+/// L002: extensions are matched without the dot, the leading one is
+/// removed but a second one (like "..rs") never matches
+fn dotted_extension(rule: &Rule, _: &Rules, findings: &mut Vec<Finding>) {
+    for ext in rule
+        .extensions
+        .split_whitespace()
+        .filter(|e| e.starts_with('.'))
+    {
+        let bare = ext.trim_start_matches('.');
+        findings.push(Finding {
+            code: "L002",
+            severity: Severity::Warning,
+            rule: rule.name.clone(),
+            message: format!("extension {:?} has a leading dot", ext),
+            help: format!(
+                "write it as {:?}, the extensions don't include the dot",
+                bare
+            ),
+        });
+    }
+}
+
+/// L003: a `cd` followed by `;` (or nothing) runs the rest of the
+/// command in the wrong directory when it fails, or does nothing
+fn cd_without_and(rule: &Rule, _: &Rules, findings: &mut Vec<Finding>) {
+    let regex = Regex::new(r"(?:^|[;&|(]\s*)cd\s+[^;&|]*(;|\|\||$)").unwrap();
+    for (field, templ) in commands(rule) {
+        for cap in regex.captures_iter(templ.trim()) {
+            let help = if cap[1].is_empty() {
+                "the directory change is lost when the command ends, run the command after it with `&&`"
+            } else {
+                "use `cd DIR && ...` so the rest doesn't run in the wrong directory if it fails"
+            };
+            findings.push(Finding {
+                code: "L003",
+                severity: Severity::Warning,
+                rule: rule.name.clone(),
+                message: format!("{} has `cd` without `&&`", field),
+                help: help.to_string(),
+            });
+        }
+    }
+}
+
+/// L004: the variables command can only use the variables there are
+/// before it runs, not the ones it gives itself
+fn circular_variables(rule: &Rule, rules: &Rules, findings: &mut Vec<Finding>) {
+    let Some(templ) = &rule.extra_variables else {
+        return;
+    };
+    for var in template_variables(templ, rule.template_delimiters.as_ref()) {
+        let target = rules
+            .var_aliases
+            .get(&var)
+            .map(String::as_str)
+            .or(crate::vars::builtin_target(&var));
+        // the variables of the path and the event are set before it
+        let known = target.is_some_and(|t| {
+            ["srcpath", "event", "event_raw"].contains(&t)
+                || crate::vars::VARIABLES
+                    .iter()
+                    .any(|v| v.name == t && v.value.is_some())
+        }) || var.starts_with("rewrite.")
+            || rule
+                .regex
+                .as_ref()
+                .is_some_and(|r| r.capture_names().flatten().any(|g| g == var));
+        if !known {
+            findings.push(Finding {
+                code: "L004",
+                severity: Severity::Error,
+                rule: rule.name.clone(),
+                message: format!("extra_variables uses {{{}}}, which it would have to give itself", var),
+                help: "use only the built-in variables in extra_variables, the ones it prints are for the command".to_string(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::rules_from_toml;
+
+    /// Codes of the findings for the rule `r` with the given fields
+    fn codes(fields: &str) -> Vec<&'static str> {
+        let rules = rules_from_toml(&format!("[r]\nextensions = \"txt\"\n{}", fields)).unwrap();
+        lint(&rules).iter().map(|f| f.code).collect()
+    }
+
+    #[test]
+    fn unquoted_redirects() {
+        assert_eq!(codes("command = \"make > {path}.log\""), ["L001"]);
+        assert_eq!(codes("command = \"sort < {rpath}\""), ["L001"]);
+        assert_eq!(codes("first_command = \"echo x >{dir}/log\""), ["L001"]);
+        assert!(codes("command = \"make > '{path}.log'\"").is_empty());
+        assert!(codes("command = \"make > \\\"{path}\\\"\"").is_empty());
+        assert!(codes("command = \"cat {path} > out.log\"").is_empty());
+        // not a path
+        assert!(codes("command = \"echo > {env.HOME}\"").is_empty());
+    }
+
+    #[test]
+    fn dotted_extensions() {
+        let rules = rules_from_toml("[r]\nextensions = \".rs md\"\ncommand = \"x\"\n").unwrap();
+        let findings = lint(&rules);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].code, "L002");
+        assert!(findings[0].help.contains("\"rs\""), "{}", findings[0].help);
+    }
+
+    #[test]
+    fn cd_without_and() {
+        assert_eq!(codes("command = \"cd {dir}; make\""), ["L003"]);
+        assert_eq!(codes("command = \"cd build\""), ["L003"]);
+        assert_eq!(codes("command = \"make && cd out || true\""), ["L003"]);
+        assert!(codes("command = \"cd {dir} && make\"").is_empty());
+        assert!(codes("command = \"make -C {dir}\"").is_empty());
+        assert!(codes("command = \"abcd x; y\"").is_empty());
+    }
+
+    #[test]
+    fn circular_variables() {
+        let findings = |fields: &str| {
+            let rules = rules_from_toml(&format!(
+                "[r]\nextensions = \"txt\"\ncommand = \"x {{version}}\"\n{}",
+                fields
+            ))
+            .unwrap();
+            lint(&rules)
+                .into_iter()
+                .map(|f| (f.code, f.severity == Severity::Error))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            findings("extra_variables = \"get-version {version}\""),
+            [("L004", true)]
+        );
+        assert!(findings("extra_variables = \"get-version {path} {event}\"").is_empty());
+        assert!(
+            findings("regex = \"(?P<part>.*)\\\\.txt\"\nextra_variables = \"v {part}\"").is_empty()
+        );
+    }
+
+    #[test]
+    fn findings_look_like_rustc() {
+        colored::control::set_override(false);
+        let finding = Finding {
+            code: "L003",
+            severity: Severity::Warning,
+            rule: "build".to_string(),
+            message: "command has `cd` without `&&`".to_string(),
+            help: "use `cd DIR && ...`".to_string(),
+        };
+        assert_eq!(
+            finding.to_string(),
+            "warning[L003]: command has `cd` without `&&`\n  --> rule build\n   = help: use `cd DIR && ...`"
+        );
+    }
+}
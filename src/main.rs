@@ -1,20 +1,50 @@
-use clap::Parser;
+mod action;
+mod cancel;
+mod config;
+mod control;
+mod debounce;
+mod defer;
+mod dump;
+mod exec;
+mod filter;
+mod instance;
+mod journal;
+mod lint;
+mod manifest;
+mod output;
+mod report;
+mod state;
+mod stats;
+mod vars;
+mod watch;
+mod webhook;
+
+use clap::{Parser, Subcommand, ValueEnum};
 use colored::Colorize;
-use config;
-use humantime::parse_duration;
+use config::{parse_duration, AwaitPolicy, Rule, Rules};
 use new_string_template::template::Template;
-use notify_debouncer_mini::{new_debouncer, notify, DebouncedEventKind};
-use std::io::{BufRead, BufReader};
-use std::time::Duration;
+use output::{errln, info, infoln, outln};
+use report::{Outcome, Report};
+use state::StateStore;
+use stats::Stats;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     path::{Path, PathBuf},
 };
 use std::{env, thread};
-use subprocess::Exec;
+use subprocess::{ExitStatus, Redirection};
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    subcommand: Option<Action>,
     /// Config file, ignored if command is given directly
     ///
     /// If none given it'll search the following paths:
@@ -22,46 +52,440 @@ struct Cli {
     /// - "/etc/onchange.toml"
     /// - "~/.config/onchange.toml"
     /// - ".onchange.toml"
+    ///
     /// The later will overwrite the former if same config is present.
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     config: Option<String>,
+    /// Show the rules from the config file and exit
+    #[arg(long, action)]
+    show_config: bool,
     /// Debouncer duration (treat multiple events within this as one)
+    ///
+    /// A rule can set a longer one for its files with `duration` in
+    /// the config.
     #[arg(short='D', long, default_value = "500ms", value_parser=parse_duration)]
     duration: Duration,
+    /// How the debounce window is decided
+    ///
+    /// Fixed fires after the duration from the first event; adaptive
+    /// waits till there are no new events for the duration, up to the
+    /// --debounce-max.
+    #[arg(long, value_enum, default_value = "fixed")]
+    debounce_mode: debounce::Mode,
+    /// Whether a new event restarts the adaptive window of its path
+    /// only or of all the pending paths
+    #[arg(long, value_enum, default_value = "path")]
+    adaptive_scope: debounce::Scope,
+    /// Longest the adaptive debounce waits after the first event
+    #[arg(long, default_value = "5s", value_parser=parse_duration)]
+    debounce_max: Duration,
+    /// What to do with the changes made while the command runs
+    ///
+    /// Queue runs for them as usual, drop ignores them, and coalesce
+    /// runs once more for all of them together after the command
+    /// finishes (in batch mode). Not used with --async or --restart.
+    #[arg(long, value_enum, default_value = "queue")]
+    on_busy: debounce::OnBusy,
     /// Delay duration before execution of the command
+    ///
+    /// A rule can set its own with `delay` in the config.
     #[arg(short, long, default_value = "50us", value_parser=parse_duration)]
     delay: Duration,
+    /// Only allow regular files in the watch list
+    #[arg(long, action, conflicts_with = "dirs_only")]
+    files_only: bool,
+    /// Only allow directories in the watch list
+    #[arg(long, action)]
+    dirs_only: bool,
     /// Watch in Recursive Mode
     #[arg(short, long, action)]
     recursive: bool,
+    /// Directories to not watch in recursive mode, by name or glob
+    ///
+    /// Unlike ignore, the excluded directories are never watched, so
+    /// their events never arrive.
+    #[arg(short = 'x', long)]
+    exclude_dir: Vec<glob::Pattern>,
     /// Render the command but do not run it
     #[arg(short = 'R', long, action)]
     render_only: bool,
     /// Run commands on Async
     #[arg(short, long, action)]
     r#async: bool,
+    /// Exit after the command for the first change finishes, with its
+    /// exit status
+    ///
+    /// The ignored and filtered events don't count; with no command it
+    /// exits with 0 after showing the change. With --async it waits
+    /// for the command to finish.
+    #[arg(long, action, conflicts_with_all = ["trial_run", "initial_run"])]
+    once: bool,
+    /// Clear the terminal before each run, `--clear=all` clears the
+    /// scrollback too
+    ///
+    /// Not done when the stdout isn't a terminal, with --render-only,
+    /// or while another run (with --async) is still going.
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "screen")]
+    clear: Option<output::Clear>,
+    /// Stop the running command before running it again
+    ///
+    /// For the long running commands like servers: the command (and
+    /// what it started) gets SIGTERM, and SIGKILL after the
+    /// `--restart-grace`. The command runs in the background, with or
+    /// without `--async`, so the changes are seen while it runs.
+    #[arg(long, action)]
+    restart: bool,
+    /// Shell and its arguments to run the commands with, the command
+    /// goes after them, e.g. "fish -c" or "pwsh -NoProfile -Command"
+    ///
+    /// Used for the variables command and the checks too, overrides
+    /// `shell` in the config's [global] table; `sh -c` by default.
+    /// It's split like the shell does, so the quotes can keep the
+    /// spaces in a path. The commands in the containers and on the
+    /// `--ssh` host still use `sh -c`.
+    #[arg(long)]
+    shell: Option<String>,
+    /// Container engine for the rules with a `container`, docker or
+    /// podman is found in PATH if not given
+    #[arg(long)]
+    container_engine: Option<String>,
+    /// Time the stopped command has to exit before it's killed
+    #[arg(long, default_value = "2s", value_parser=parse_duration)]
+    restart_grace: Duration,
+    /// Terminate the command if it runs longer than this, e.g. 10m
+    ///
+    /// It gets SIGTERM and then SIGKILL if it's still running a bit
+    /// later, and the run counts as failed. A rule's `timeout`
+    /// overrides it.
+    #[arg(long, value_parser=parse_duration)]
+    timeout: Option<Duration>,
+    /// Keep the run directory of the session ({rundir}) on exit
+    #[arg(long, action)]
+    keep_rundir: bool,
+    /// Threads to filter the events on, for the large bursts of events
+    ///
+    /// Only the filtering is spread over them: the events of a file are
+    /// always filtered in order on the same thread, and the commands
+    /// are rendered and run on the one thread as usual.
+    #[arg(long, default_value_t = 1)]
+    pipeline_threads: usize,
+    /// Append the changes, runs and the output of the commands to the
+    /// file, with the time of each line
+    ///
+    /// The output is still shown as usual, but the commands' output is
+    /// captured (as with --max-output) so it can be logged.
+    #[arg(long)]
+    log: Option<PathBuf>,
+    /// Exit when a command can't be started at all (e.g. the shell
+    /// isn't found), instead of showing the error and going on
+    #[arg(long, action)]
+    fail_on_spawn_error: bool,
+    /// Run the command on the remote host through ssh, can be repeated
+    ///
+    /// The rendered command is run with `sh -c` on each host, the
+    /// variables like {rpath} are still from the local file.
+    #[arg(long)]
+    ssh: Vec<String>,
+    /// Environment variable for the commands as KEY=VALUE, the value
+    /// can use the variables
+    ///
+    /// Can be repeated. The rules' `env` is added on top of these.
+    #[arg(long, value_parser = exec::parse_env)]
+    env: Vec<(String, String)>,
+    /// Directory to run the commands in, a template like "{dir}"
+    ///
+    /// Relative paths are from the current directory, which {pwd}
+    /// still is. Rules can set their own with `cwd`.
+    #[arg(long)]
+    cwd: Option<String>,
+    /// Ring the terminal bell when the command fails (or always)
+    #[arg(
+        long,
+        value_enum,
+        num_args(0..=1),
+        require_equals = true,
+        default_missing_value = "failure"
+    )]
+    bell: Option<output::Cue>,
+    /// Play the sound file instead of ringing the bell
+    ///
+    /// Uses the bell on failures if --bell isn't given, falls back to
+    /// the bell if the sound can't be played.
+    #[arg(long)]
+    sound: Option<PathBuf>,
+    /// Maximum size of the command output to show per run (e.g. 10MB)
+    ///
+    /// The output is captured and anything beyond the limit is
+    /// discarded with a notice.
+    #[arg(long, value_parser=exec::parse_size)]
+    max_output: Option<usize>,
+    /// Maximum size of a value from the variables command
+    ///
+    /// Larger values are truncated with a warning.
+    #[arg(long, value_parser=exec::parse_size, default_value = "64KB")]
+    max_var_size: usize,
     /// Ignore pattern, use unix shell style glob pattern
+    ///
+    /// The pattern is matched with the absolute path, path relative
+    /// to the current directory, and path relative to the watched
+    /// path; matching any of them ignores the change. An extension
+    /// with its dot and nothing else, like ".swp", ignores the files
+    /// ending in it anywhere, as "**/*.swp".
     #[arg(short, long, default_value = "")]
     ignore: Vec<glob::Pattern>,
+    /// Ignore the path exactly as given, without the glob characters
+    /// in it meaning anything; for a directory everything in it
+    #[arg(long)]
+    ignore_literal: Vec<PathBuf>,
+    /// Only run for the paths matching one of these patterns
+    ///
+    /// Matched like the ignore patterns, and checked before them.
+    #[arg(long)]
+    only: Vec<glob::Pattern>,
+    /// Skip the paths with a name starting with `.` below the watched
+    /// path, like `.git/index` or `.DS_Store`
+    ///
+    /// An --only pattern the path matches that names a hidden file or
+    /// directory (like `.env`) lets it through.
+    #[arg(long, action)]
+    ignore_hidden: bool,
+    /// Don't skip the temporary files of the editors
+    ///
+    /// The swap files of vim (`.notes.md.swp`, `4913`), the backups and
+    /// autosaves of emacs (`notes.md~`, `#notes.md#`) and the like are
+    /// skipped by default; more patterns can be given as
+    /// `extra_editor_ignores` in the config's [global] table.
+    #[arg(long, action)]
+    no_ignore_editor_files: bool,
+    /// Warn about the extensions of the changed files no rule is for,
+    /// once for each
+    #[arg(long, action)]
+    warn_unmatched: bool,
+    /// Skip the events for files last modified before this duration
+    ///
+    /// Helps with stale events replayed by network file systems,
+    /// deleted files are never skipped.
+    #[arg(long, value_parser=parse_duration)]
+    fresh_within: Option<Duration>,
     /// Template to get more informations on changed file
     ///
     /// You can use commands with similar template to command that'll
     /// output a list of key:val lines in stdout, that this program
     /// will use as extra variables to populate your command template,
-    /// and change template.
+    /// and change template. The keys are trimmed (keys with spaces
+    /// inside are skipped), and the values are trimmed unless quoted.
     #[arg(short, long)]
     variables_command: Option<String>,
+    /// Show more information, like all the watched paths
+    #[arg(long, action)]
+    verbose: bool,
     /// Show available variables and their values
     #[arg(short = 'V', long)]
     variables: bool,
     /// Template to show informations on file change detection
     #[arg(short, long, default_value = "{path}")]
     template: String,
+    /// Template to show after the command finishes
+    ///
+    /// Along with the usual variables you can use {exit_code},
+    /// {exit_name} ("ok", "failed(2)", "signal(SIGKILL)", "timeout"),
+    /// {duration_ms} and {duration} ("1m 23s").
+    #[arg(long, default_value = "{exit_name} in {duration}")]
+    done_template: String,
+    /// File to save the result of the runs in, to keep them between sessions
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    /// Manifest file with the content hashes of the files, to only run
+    /// for the files whose content changed since their last
+    /// successful run, even across sessions
+    #[arg(long, global = true)]
+    manifest: Option<PathBuf>,
+    /// Which events of the direct children of the watched directories
+    /// to run for: entries (create, remove, rename), writes, or all
+    ///
+    /// With entries or writes, the events deeper in the directories
+    /// are skipped. Rules can override it with `dir_events`.
+    #[arg(long, value_enum, default_value = "all")]
+    dir_events: filter::DirEvents,
+    /// How much of the command to show in the Run line
+    ///
+    /// Full also shows the working directory, the program and its
+    /// arguments, the remote host and the string given to the shell.
+    #[arg(long, value_enum, default_value = "short")]
+    echo: output::Echo,
+    /// Where to show the informational lines like the changes and runs
+    ///
+    /// The output of the commands always goes to stdout.
+    #[arg(long, value_enum, default_value = "stdout")]
+    notify_stream: output::NotifyStream,
+    /// How the changes and the runs are reported
+    ///
+    /// json writes an object per line to stdout, with the variables of
+    /// the changes; the other lines and the output of the commands go
+    /// to stderr then.
+    #[arg(long, alias = "format", value_enum, default_value = "plain")]
+    reporter: report::Mode,
+    /// Only run one instance for the config file (or the directory if
+    /// there's none), exit or replace the one already running
+    #[arg(
+        long,
+        value_enum,
+        num_args(0..=1),
+        require_equals = true,
+        default_missing_value = "exit"
+    )]
+    single_instance: Option<instance::SingleInstance>,
+    /// Journal file to record the runs in, to find the ones interrupted
+    /// by a crash on the next start
+    ///
+    /// The interrupted runs are listed, and the `on_recover` command
+    /// from the config's [global] table is run for each of them.
+    #[arg(long)]
+    journal: Option<PathBuf>,
+    /// Prometheus textfile to write the run metrics to
+    ///
+    /// Written after each run and periodically, for the textfile
+    /// collector of node_exporter.
+    #[arg(long)]
+    metrics_file: Option<PathBuf>,
+    /// URL to POST the results of the runs to, as the JSON objects of
+    /// `--reporter json`
+    ///
+    /// It's sent from its own thread, the results finishing
+    /// within a few seconds go in one request, and the server errors
+    /// are retried a few times.
+    #[arg(long)]
+    webhook: Option<String>,
+    /// Runs to post to the `--webhook`
+    #[arg(long, value_enum, default_value = "all")]
+    webhook_events: webhook::Events,
+    /// Template of a `text` field added to the webhook's objects, for
+    /// the chat webhooks, e.g. '{rule} {exit_name} in {duration}'
+    #[arg(long)]
+    webhook_text: Option<String>,
+    /// Directory to write the final variables of each event to
+    ///
+    /// Each event gets a `<seq>-<name.ext>.toml` file with all the
+    /// variables its command was rendered with, for debugging the
+    /// templates, and the ones of the run are added once it's done.
+    /// Only the latest `--dump-vars-keep` files are kept.
+    #[arg(long)]
+    dump_vars_dir: Option<PathBuf>,
+    /// Number of files to keep in the `--dump-vars-dir`
+    #[arg(long, default_value = "100")]
+    dump_vars_keep: usize,
+    /// Hide the values of the variables matching the pattern in the
+    /// dumps, e.g. 'env.*TOKEN*'
+    #[arg(long)]
+    redact: Vec<glob::Pattern>,
+    /// Show the runs, failures and durations of each rule on exit
+    ///
+    /// Ctrl-C (or SIGTERM) stops watching and prints the summary, a
+    /// second one exits right away.
+    #[arg(long, action)]
+    stats: bool,
+    /// Only show the command for new files on their first change
+    ///
+    /// Files whose first event creates them have their command
+    /// rendered but not run on the first change, it'll run from the
+    /// second change onward. In interactive terminal it'll ask
+    /// whether to run it right away. The paths seen are kept in the
+    /// --state-file.
+    #[arg(long, action)]
+    confirm_new_files: bool,
     /// Trial run
     #[arg(short = 'T', long, action, conflicts_with = "recursive")]
     trial_run: bool,
+    /// Run the command once for each watched path after starting,
+    /// then keep watching
+    ///
+    /// Runs like the trial run does, for all the files in the
+    /// directories with --recursive.
+    #[arg(short = '1', long, action, conflicts_with = "trial_run")]
+    initial_run: bool,
+    /// Only the files modified within this duration in the trial run
+    /// and the initial run, e.g. 1d
+    ///
+    /// Cuts down the work at start in large trees, the live events
+    /// are never filtered by it.
+    #[arg(long, value_parser=parse_duration)]
+    changed_within: Option<Duration>,
+    /// Kind of change for the trial run, to preview the rules'
+    /// commands for that kind
+    #[arg(long, requires = "trial_run")]
+    kind: Option<debounce::Kind>,
+    /// Run the commands once for all the files of a rule changed
+    /// together, with {paths}, {rpaths} and {count}
+    ///
+    /// The other variables are from the first file. It's the default
+    /// for the rules without a `mode`.
+    #[arg(long, action)]
+    batch: bool,
+    /// In batch mode, don't give the batch command the variables of
+    /// the first file (like {name}); a command using them is an error
+    #[arg(long, action)]
+    strict_batch: bool,
+    /// Local time ranges to hold the matched events back in, like
+    /// 09:00-18:00 (comma separated for more)
+    ///
+    /// The changes are run together when the window ends, once per
+    /// file, or as a batch for the rules in batch mode. They're kept
+    /// in the --state-file across restarts; `onchange ctl flush` runs
+    /// them right away.
+    #[arg(long)]
+    defer_window: Option<defer::Window>,
+    /// Kinds of changes to run for, comma separated: create, modify,
+    /// remove, rename (all by default)
+    ///
+    /// Overrides the rules' `events`. A file removed and created again
+    /// in a debounce window (atomic save) counts as modified.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    events: Option<Vec<filter::EventType>>,
+    /// What to do with the variable values that have shell special
+    /// characters (spaces, quotes, `$`, `;` …) in the commands
+    ///
+    /// The values already inside quotes in the template are left as
+    /// they are; fix quotes the others, warn only warns about them.
+    #[arg(long, value_enum, default_value = "warn")]
+    auto_quote: exec::AutoQuote,
+    /// Don't run the variables commands, e.g. for a trial run
+    ///
+    /// The variables they'd give are shown as `<unset:NAME>` in the
+    /// rendered commands, which are not run (implies --render-only).
+    #[arg(long, action)]
+    no_vars: bool,
+    /// File with the paths to watch, one per line
+    ///
+    /// The paths can use `{pwd}` and the environment variables as
+    /// `{env.NAME}`, empty lines and lines starting with `#` are skipped.
+    #[arg(long)]
+    watch_from: Option<PathBuf>,
+    /// Check that the changes in the watched directories are seen, by
+    /// creating and removing a hidden file in each after starting
+    #[arg(long, action)]
+    self_test: bool,
+    /// Keep trying the watch paths that don't exist (yet) at this
+    /// interval, instead of leaving them out
+    ///
+    /// Each one is watched once it appears, and the watched paths that
+    /// are removed are waited for again.
+    #[arg(
+        long,
+        num_args(0..=1),
+        require_equals = true,
+        default_missing_value = "3s",
+        value_parser = parse_duration
+    )]
+    wait_missing: Option<Duration>,
+    /// Treat the watch paths with wildcards (*, ? or [) as glob patterns
+    #[arg(long, action)]
+    glob: bool,
     /// List paths to watch, any number of file is fine
-    #[arg(num_args(1..), required(true))]
+    ///
+    /// The paths are taken literally, with --glob the glob patterns
+    /// (quoted, e.g. 'src/**/*.rs') watch the directory before the
+    /// first wildcard, and only the matching files trigger.
+    #[arg(num_args(1..))]
     watch: Vec<PathBuf>,
     /// Command to run, use single quotes to skip the template braces
     /// properly
@@ -69,271 +493,4021 @@ struct Cli {
     command: Vec<String>,
 }
 
+#[derive(Subcommand)]
+enum Action {
+    /// Check the config file and show the rules in it, with the
+    /// problems found in them
+    Check {
+        /// Fail on the warnings too
+        #[arg(long, value_parser = ["warnings"])]
+        deny: Option<String>,
+    },
+    /// List the rules from the config file in a table
+    Rules {
+        /// Print the rules as json
+        #[arg(short, long, action)]
+        json: bool,
+    },
+    /// Show examples of the commands with the variables they use
+    Examples,
+    /// List the built-in template variables with example values
+    Vars,
+    /// Run the commands for a made up change of the file, exactly like
+    /// a real change would
+    Simulate {
+        /// Changed file
+        path: PathBuf,
+        /// Kind of the change
+        #[arg(long, value_enum, default_value = "modify")]
+        kind: debounce::Kind,
+        /// Run it in this process instead of the instance running for
+        /// the config file (or the directory) with --single-instance
+        #[arg(long, action)]
+        local: bool,
+        /// Render the command but do not run it, with --local
+        #[arg(short = 'R', long, action, requires = "local")]
+        render_only: bool,
+    },
+    /// Control the instance running for the config file (or the
+    /// directory), started with --single-instance
+    Ctl {
+        #[command(subcommand)]
+        action: CtlAction,
+    },
+    /// Manage the manifest file given with --manifest
+    Manifest {
+        #[command(subcommand)]
+        action: ManifestAction,
+    },
+    /// Print the rule and command for the file without watching, exits
+    /// with 1 if nothing would run and 2 if the config can't be loaded
+    Query {
+        /// File to look up
+        path: PathBuf,
+        /// Run the variables command for the rendered command too
+        #[arg(long, action)]
+        with_vars: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum CtlAction {
+    /// Run the events deferred by --defer-window now
+    Flush,
+    /// Print the variables of the latest event as JSON, redacted like
+    /// the --dump-vars-dir
+    VarsDump,
+}
+
+#[derive(Subcommand)]
+enum ManifestAction {
+    /// Remove the entries of the files that don't exist anymore
+    Prune,
+}
+
+/// Interval to write the metrics file at, other than after the runs
+const METRICS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Interval to check for the stop request at, with `--stats` (and
+/// for the end of the run with `--once`)
+const STOP_POLL: Duration = Duration::from_millis(200);
+
+/// Batches smaller than this are filtered on a single thread even with
+/// `--pipeline-threads`, the threads cost more than they save
+const PARALLEL_EVENTS: usize = 256;
+
+/// Events for the same file through other watched paths within this
+/// time are merged into the first one
+const MERGE_WINDOW: Duration = Duration::from_millis(200);
+
+/// Interval to check for the `await_set` files of the held runs at
+const AWAIT_POLL: Duration = Duration::from_millis(100);
+
+/// Data of the session shared with the threads running the commands
+#[derive(Default)]
+struct Session {
+    state: Mutex<StateStore>,
+    stats: Mutex<Stats>,
+    /// Prometheus textfile to write the stats to
+    metrics_file: Option<PathBuf>,
+    /// Journal of the runs, to find the interrupted ones later
+    journal: Option<journal::Journal>,
+    /// Process ids of the running commands with their containers,
+    /// with `--restart`
+    running: Mutex<Vec<(u32, Option<exec::ContainerRun>)>>,
+    /// Cancels the waits of the runs on shutdown
+    cancel: cancel::Token,
+    /// Content hashes of the last successful runs, with `--manifest`
+    manifest: Option<Mutex<manifest::Manifest>>,
+    /// Exit code of the first finished run, for `--once`
+    exit_code: Mutex<Option<i32>>,
+    /// Absolute paths of the files watched directly, with their
+    /// canonical paths the state of their events is kept under
+    watched_files: Vec<(PathBuf, PathBuf)>,
+    webhook: Option<webhook::Webhook>,
+    /// Where the final variables of the events are written to
+    dump: Option<dump::VarsDump>,
+    /// Patterns of the variables whose values are hidden in the dumps
+    redact: Vec<glob::Pattern>,
+    /// Variables of the latest event, for `onchange ctl vars-dump`
+    last_vars: Mutex<Option<BTreeMap<String, String>>>,
+    /// The stdin is a terminal, to ask before running for new files
+    interactive: bool,
+}
+
+impl Session {
+    /// A run finished with the exit code, only the first one is kept
+    fn finish(&self, code: i32) {
+        self.exit_code.lock().unwrap().get_or_insert(code);
+    }
+
+    /// A run has started, it may be over too
+    fn ran(&self) -> bool {
+        self.exit_code.lock().unwrap().is_some() || self.stats.lock().unwrap().in_flight_total() > 0
+    }
+
+    /// Key of the path in the state and the manifest, the same for a
+    /// watched file that's removed and created again, or reached
+    /// through a watched directory too
+    fn state_key(&self, path: &Path) -> PathBuf {
+        let path: PathBuf = path.components().collect();
+        match self.watched_files.iter().find(|(file, _)| *file == path) {
+            Some((_, canonical)) => canonical.clone(),
+            None => path,
+        }
+    }
+
+    /// Keep the variables of the event for `ctl vars-dump` and write
+    /// them to the `--dump-vars-dir`, the file they're written to
+    fn record_vars(&self, map: &HashMap<String, String>) -> Option<PathBuf> {
+        let vars = dump::redacted(map, &self.redact);
+        let file = self.dump.as_ref().map(|d| d.write(&vars));
+        *self.last_vars.lock().unwrap() = Some(vars);
+        file
+    }
+
+    /// Record the variables again with the ones added by the run
+    fn update_vars(&self, file: Option<&Path>, map: &HashMap<String, String>) {
+        let vars = dump::redacted(map, &self.redact);
+        if let (Some(dump), Some(file)) = (&self.dump, file) {
+            dump.update(file, &vars);
+        }
+        *self.last_vars.lock().unwrap() = Some(vars);
+    }
+
+    fn write_metrics(&self) {
+        if let Some(file) = &self.metrics_file {
+            if let Err(e) = self.stats.lock().unwrap().write_metrics(file) {
+                infoln!("{}: writing the metrics file: {}", "Error".bold().red(), e);
+            }
+        }
+    }
+}
+
+/// Variables of the changed file without running the variables command
+fn file_vars(path: &Path, pwd: &Path, rules: &Rules) -> HashMap<String, String> {
+    let mut map = vars::path_vars(path, pwd);
+    // the first matching rewrite (by name) is also {srcpath}
+    let rpath = PathBuf::from(&map["rpath"]);
+    let mut srcpath = String::new();
+    for (name, rewrite) in &rules.rewrites {
+        let value = rewrite
+            .apply(path, &rpath)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if srcpath.is_empty() {
+            srcpath = value.clone();
+        }
+        map.insert(format!("rewrite.{}", name), value);
+    }
+    map.insert("srcpath".to_string(), srcpath);
+    vars::add_aliases(&mut map, &rules.var_aliases);
+    // the named groups of the rule's regex
+    if let Some(rule) = rules.find(&map) {
+        let captures = rule.captures(&map["name.ext"]);
+        map.extend(captures);
+    }
+    map
+}
+
+/// Add the `key:value` lines of the variables command's output to the
+/// map, the values cut to `max_size` bytes
+fn read_var_lines(
+    mut reader: impl BufRead,
+    max_size: usize,
+    verbose: bool,
+    map: &mut HashMap<String, String>,
+) {
+    let mut seen = HashSet::new();
+    let mut line = Vec::new();
+    // the lines that aren't UTF-8 get the replacement characters
+    while reader.read_until(b'\n', &mut line).is_ok_and(|n| n > 0) {
+        let text = String::from_utf8_lossy(&line);
+        let text = text.strip_suffix('\n').unwrap_or(&text);
+        match vars::parse_var_line(text) {
+            Some(Ok((k, mut v))) => {
+                if v.len() > max_size {
+                    v.truncate(vars::floor_char_boundary(&v, max_size));
+                    infoln!(
+                        "{}: value of variable {:?} truncated to {} bytes (--max-var-size)",
+                        "Warning".yellow().bold(),
+                        k,
+                        v.len()
+                    );
+                }
+                // later lines for the same key replace the earlier ones
+                if !seen.insert(k.clone()) && verbose {
+                    infoln!("{}: variable {:?} given again", "Note".dimmed(), k);
+                }
+                map.insert(k, v);
+            }
+            Some(Err(e)) => infoln!("{}: {}", "Warning".yellow().bold(), e),
+            None => (),
+        }
+        line.clear();
+    }
+}
+
+/// All the variables of the changed file, the variables command is
+/// run for the extra ones. The `kinds` of the event are empty when
+/// there's no event (e.g. trial run).
 fn template_vars(
-    path: &PathBuf,
-    pwd: &PathBuf,
-    var_cmd: &Option<String>,
-    conf_map: &HashMap<String, (Option<Template>, Option<Template>)>,
+    path: &Path,
+    kinds: &[notify::EventKind],
+    pwd: &Path,
+    rules: &Rules,
+    args: &Cli,
+    cancel: &cancel::Token,
 ) -> HashMap<String, String> {
-    let mut map: HashMap<String, String> = HashMap::new();
-    map.insert(
-        "name".to_string(),
-        path.file_stem()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string(),
-    );
-    map.insert(
-        "ext".to_string(),
-        path.extension()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string(),
-    );
-    map.insert(
-        "name.ext".to_string(),
-        path.file_name()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string(),
-    );
-    map.insert("pwd".to_string(), pwd.to_string_lossy().to_string());
-    map.insert("path".to_string(), path.to_string_lossy().to_string());
-    map.insert(
-        "rpath".to_string(),
-        pathdiff::diff_paths(path, pwd)
-            .unwrap()
-            .to_string_lossy()
-            .to_string(),
-    );
-    let parent = path.parent().unwrap_or(Path::new("/"));
-    map.insert("dir".to_string(), parent.to_string_lossy().to_string());
-    map.insert(
-        "rdir".to_string(),
-        pathdiff::diff_paths(parent, pwd)
-            .unwrap()
-            .to_string_lossy()
-            .to_string(),
-    );
-    map.insert(
-        "rname".to_string(),
-        if map["rdir"].is_empty() {
-            map["name"].to_string()
-        } else {
-            format!(
-                "{}{}{}",
-                map["rdir"],
-                std::path::MAIN_SEPARATOR,
-                map["name"]
-            )
-        },
-    );
+    let mut map = file_vars(path, pwd, rules);
+    let event = filter::EventType::net(path, kinds)
+        .map(|e| e.to_possible_value().unwrap().get_name().to_string())
+        .unwrap_or_else(|| "any".to_string());
+    map.insert("event".to_string(), event);
+    map.insert("event_raw".to_string(), format!("{:?}", kinds));
 
     // populate it with more variables from the command. If given
     // from CLI use it, otherwise use the one from config.
-    let var_cmd = match var_cmd {
+    let var_cmd = match &args.variables_command {
         Some(cmd) => Some(Template::new(cmd.clone())),
-        None => match conf_map.get(&map["ext"]) {
-            Some((_, Some(templ))) => Some(templ.clone()),
-            _ => None,
-        },
+        None => rules.find(&map).and_then(|rule| rule.extra_variables()),
     };
 
-    if let Some(cmd_t) = var_cmd {
-        let cmd = cmd_t.render_string(&map).unwrap();
-        BufReader::new(Exec::shell(cmd).stream_stdout().unwrap())
-            .lines()
-            .for_each(|s| {
-                if let Some((k, v)) = s.unwrap().split_once(":") {
-                    map.insert(k.trim().to_string(), v.trim().to_string());
-                }
-            });
+    if let Some(cmd_t) = var_cmd.filter(|_| !args.no_vars) {
+        let cmd = match cmd_t.render_string(&map) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                infoln!("{}: variables command: {}", "Error".bold().red(), e);
+                return map;
+            }
+        };
+        let plan = exec::ExecutionPlan::new(&cmd, None);
+        let mut p = match plan.spawn(Redirection::Pipe, Redirection::None) {
+            Ok(p) => p,
+            Err(e) => {
+                infoln!("{}: variables command: {}", "Error".bold().red(), e);
+                return map;
+            }
+        };
+        let stdout = p.stdout.take().unwrap();
+        cancel.guard(p.pid(), plan.group, || {
+            read_var_lines(
+                BufReader::new(stdout),
+                args.max_var_size,
+                args.verbose,
+                &mut map,
+            );
+            p.wait().ok();
+        });
+        // for the aliases of the variables from the command
+        vars::add_aliases(&mut map, &rules.var_aliases);
     }
+    // the `[variables]` from the config, the file's own variables and
+    // the ones from the command win
+    let delimiters = match rules.find(&map) {
+        Some(rule) => rule.template_delimiters.as_ref(),
+        None => rules.delimiters.as_ref(),
+    };
+    let regex = config::variable_regex(delimiters);
+    let config_vars: Vec<(String, String)> = rules
+        .variables
+        .iter()
+        .filter(|(k, _)| !map.contains_key(*k))
+        .map(|(k, v)| {
+            let value = Template::new_regex(v, &regex).render_nofail_string(&map);
+            (k.clone(), value)
+        })
+        .collect();
+    map.extend(config_vars);
     map
 }
 
-fn get_config(conf: &Option<String>) -> Result<config::Config, String> {
-    if let Some(conf_file) = conf {
-        return config::Config::builder()
-            .add_source(config::File::with_name(conf_file))
-            .build()
-            .map_err(|e| e.to_string());
-    }
-    config::Config::builder()
-        .add_source(
-            vec![
-                PathBuf::from("/etc/onchange.toml"),
-                PathBuf::from(format!(
-                    "{}/.config/onchange.toml",
-                    std::env::var("HOME").unwrap_or_default()
-                )),
-                PathBuf::from(".onchange.toml"),
-            ]
+/// With `--no-vars`, the variables used in the templates that aren't
+/// set (as the variables command didn't run) are set to `<unset:NAME>`
+fn unset_placeholders(
+    args: &Cli,
+    rules: &Rules,
+    rule: Option<&Rule>,
+    map: &mut HashMap<String, String>,
+) {
+    if !args.no_vars {
+        return;
+    }
+    let delimiters = match rule {
+        Some(r) => r.template_delimiters.as_ref(),
+        None => rules.delimiters.as_ref(),
+    };
+    let mut templates = vec![args.command.join(" "), args.template.clone()];
+    match rule {
+        Some(r) => {
+            templates.extend(
+                [&r.first_command, &r.check, &r.cwd]
+                    .into_iter()
+                    .flatten()
+                    .cloned(),
+            );
+            templates.extend(r.env.values().cloned());
+            if let Some(cmd) = &r.command {
+                templates.extend(cmd.templates().into_iter().cloned());
+            }
+        }
+        None => templates.extend(rules.default_command.clone()),
+    }
+    for templ in &templates {
+        for var in config::template_variables(templ, delimiters) {
+            map.entry(var.clone())
+                .or_insert_with(|| format!("<unset:{}>", var));
+        }
+    }
+}
+
+/// Variables of the files a command runs for: {paths} and {rpaths}
+/// (shell quoted, space separated) and {count}
+fn paths_vars(paths: &[&Path], pwd: &Path) -> HashMap<String, String> {
+    let quoted = |rel: bool| {
+        paths
             .iter()
-            .filter(|f| f.exists())
-            .map(|f| config::File::from(f.as_path()))
-            .collect::<Vec<config::File<_, _>>>(),
-        )
-        .build()
-        .map_err(|e| e.to_string())
+            .map(|p| {
+                let p = match pathdiff::diff_paths(p, pwd) {
+                    Some(r) if rel => r,
+                    _ => p.to_path_buf(),
+                };
+                exec::shell_quote(&p.to_string_lossy())
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    };
+    HashMap::from([
+        ("paths".to_string(), quoted(false)),
+        ("rpaths".to_string(), quoted(true)),
+        ("count".to_string(), paths.len().to_string()),
+    ])
 }
 
-fn ext_map_from_config<'a>(
-    conf: &'a HashMap<String, HashMap<String, String>>,
-    verbose: bool,
-) -> HashMap<String, (Option<Template>, Option<Template>)> {
-    let mut extmap = HashMap::new();
-    for (k, v) in conf {
-        if verbose {
-            print!("{}: {} ({})", "Rule".blue().bold(), k, v["extensions"],);
-            if let Some(cmd) = v.get("command") {
-                print!(" ⇒ {}", cmd);
-            }
-            println!("");
-        }
-        for ext in v["extensions"].split(" ") {
-            extmap.insert(
-                ext.to_string(),
-                (
-                    v.get("command").map(Template::new),
-                    v.get("extra_variables").map(Template::new),
-                ),
-            );
-        }
+/// Variables about the whole batch of changes detected together:
+/// {batch.count}, {batch.exts}, {batch.roots} and the counts by the
+/// kind of change like {batch.created}; `kinds` has the overall kind
+/// of each path's events
+fn batch_vars(
+    paths: &[&Path],
+    kinds: &HashMap<&Path, debounce::Kind>,
+    roots: &[PathBuf],
+) -> HashMap<String, String> {
+    let paths: BTreeSet<&Path> = paths.iter().copied().collect();
+    let count = |kind: debounce::Kind| {
+        paths
+            .iter()
+            .filter(|p| kinds.get(*p).copied().unwrap_or(debounce::Kind::Modify) == kind)
+            .count()
+            .to_string()
+    };
+    let exts: BTreeSet<String> = paths
+        .iter()
+        .filter_map(|p| p.extension())
+        .map(|e| e.to_string_lossy().to_string())
+        .collect();
+    let batch_roots: BTreeSet<String> = paths
+        .iter()
+        .filter_map(|p| watch::watch_root(p, roots))
+        .map(|r| r.to_string_lossy().to_string())
+        .collect();
+    let mut map = HashMap::new();
+    map.insert("batch.count".to_string(), paths.len().to_string());
+    map.insert("batch.created".to_string(), count(debounce::Kind::Create));
+    map.insert("batch.modified".to_string(), count(debounce::Kind::Modify));
+    map.insert("batch.removed".to_string(), count(debounce::Kind::Remove));
+    map.insert("batch.renamed".to_string(), count(debounce::Kind::Rename));
+    map.insert(
+        "batch.exts".to_string(),
+        exts.into_iter().collect::<Vec<String>>().join(","),
+    );
+    map.insert(
+        "batch.roots".to_string(),
+        batch_roots.into_iter().collect::<Vec<String>>().join(","),
+    );
+    map
+}
+
+/// Ask the user whether to run the command, only when it's interactive
+fn confirm_run(interactive: bool) -> bool {
+    if !interactive {
+        return false;
     }
-    extmap
+    info!("{} [y/N] ", "Run it now?".bold());
+    output::flush();
+    let mut ans = String::new();
+    std::io::stdin().read_line(&mut ans).ok();
+    ans.trim().eq_ignore_ascii_case("y")
 }
 
-fn on_change(args: &Cli, path: &PathBuf, cmd: String, cng: Option<String>) {
+/// Reason to skip the run before it starts: the rule's check failed,
+/// or the file has the same content as in its last successful run.
+/// Else the hash of the content to save for the run, with a manifest
+fn skipped_run(
+    session: &Session,
+    map: &HashMap<String, String>,
+    rule: Option<&Rule>,
+) -> Result<Option<Option<String>>, (Outcome, String)> {
+    if let Some((rule, check)) = rule.and_then(|r| Some((r, r.check()?))) {
+        let check = check.render_nofail_string(map);
+        let invert = rule.check_invert.unwrap_or(false);
+        exec::check(&check, rule.check_timeout(), invert, &session.cancel)
+            .map_err(|reason| (Outcome::Check, reason))?;
+    }
+    let Some(manifest) = &session.manifest else {
+        return Ok(None);
+    };
+    // the hash is taken before the run, so the changes made while it
+    // runs are new ones for the next run
+    let path = Path::new(&map["path"]);
+    let hash = manifest::hash_file(path);
+    if manifest
+        .lock()
+        .unwrap()
+        .is_current(&session.state_key(path), hash.as_deref())
     {
-        if args.ignore.iter().any(|p| p.matches_path(&path)) {
-            return;
+        return Err((
+            Outcome::Unchanged,
+            "unchanged since its last successful run".to_string(),
+        ));
+    }
+    Ok(Some(hash))
+}
+
+/// Run the command for the change; the change template is rendered
+/// with `cng` once the run is classified, so a skipped run shows its
+/// `{action}` (with `--verbose`)
+fn on_change(
+    args: &Cli,
+    session: &Arc<Session>,
+    map: &HashMap<String, String>,
+    cmd: String,
+    cng: &dyn Fn(&HashMap<String, String>) -> Option<String>,
+    rule: Option<&Rule>,
+    new_file: bool,
+) {
+    {
+        let action = if args.command.is_empty() {
+            rule.and_then(|r| r.action.as_ref()).map(|a| a.render(map))
+        } else {
+            None
+        };
+        let cmd = match &action {
+            Some(a) => a.to_string(),
+            None => cmd,
+        };
+        let hash = match cmd.is_empty() {
+            true => Ok(None),
+            false => skipped_run(session, map, rule.filter(|_| args.command.is_empty())),
+        };
+        let skipped_map;
+        let map = match &hash {
+            Err((outcome, _)) => {
+                skipped_map = {
+                    let mut map = map.clone();
+                    map.insert("action".to_string(), outcome.to_string());
+                    map
+                };
+                &skipped_map
+            }
+            Ok(_) => map,
+        };
+        if hash.is_ok() {
+            // clearing while another run prints would lose its output
+            if let Some(clear) = args.clear {
+                if !args.render_only && session.stats.lock().unwrap().in_flight_total() == 0 {
+                    output::clear_screen(clear);
+                }
+            }
         }
-        if let Some(templ) = &cng {
-            println!("{}: {}", "Changed".bold().green(), templ);
+        if hash.is_ok() || args.verbose || args.trial_run {
+            output::report(Report::Detected(change(cng(map), map)));
         }
+        // written again with the variables of the run once it's done
+        let dumped = session.record_vars(map);
 
+        let hash = match hash {
+            Ok(hash) => hash,
+            Err((outcome, reason)) => {
+                if args.verbose || args.trial_run {
+                    output::report(Report::RunSkipped {
+                        subject: cmd,
+                        reason,
+                        outcome,
+                    });
+                }
+                let rule_name = rule.map(|r| r.name.as_str()).unwrap_or_default();
+                session.stats.lock().unwrap().skipped(rule_name, outcome);
+                session.write_metrics();
+                return;
+            }
+        };
         if cmd.is_empty() {
+            session.finish(0);
             return;
         }
-        println!("{}: {}", "Run".bold().red(), cmd);
-        if args.render_only {
-            return;
+        if new_file {
+            infoln!(
+                "{}: {} (new file, it'll run from the next change)",
+                "Preview".bold().yellow(),
+                cmd
+            );
+            if !confirm_run(session.interactive) {
+                return;
+            }
+        }
+        let hosts: Vec<String> = if action.is_some() {
+            vec![]
+        } else {
+            rule.and_then(|r| r.ssh.clone())
+                .unwrap_or_else(|| args.ssh.clone())
+        };
+        let mut on_hosts = if hosts.is_empty() {
+            String::new()
+        } else {
+            format!(" (on {})", hosts.join(", "))
+        };
+        if let Some(c) = rule.and_then(|r| r.container.as_ref()) {
+            if action.is_none() {
+                on_hosts.push_str(&format!(" (in {})", c.image));
+            }
         }
-        let del = args.delay;
-        if args.r#async {
-            thread::spawn(move || {
-                thread::sleep(del);
-                Exec::shell(cmd).join().unwrap();
+        let first_variant = action.is_none()
+            && args.command.is_empty()
+            && map.get("first_run").map(|f| f == "true").unwrap_or(false)
+            && rule.map(|r| r.first_command.is_some()).unwrap_or(false);
+        let detail = format!(
+            "{}{}{}",
+            on_hosts,
+            if first_variant {
+                " (first command)".dimmed().to_string()
+            } else {
+                String::new()
+            },
+            output::description_suffix(rule.and_then(|r| r.description.as_deref()))
+        );
+        // the steps of `commands` are run (and shown) one by one
+        let steps: Option<Vec<String>> = rule
+            .filter(|_| action.is_none() && args.command.is_empty())
+            .and_then(|r| {
+                let first_run = map.get("first_run").is_some_and(|f| f == "true");
+                let regex = config::variable_regex(r.template_delimiters.as_ref());
+                let steps = r.steps(first_run)?;
+                Some(
+                    steps
+                        .iter()
+                        .map(|s| exec::render_shell(s, &regex, map, args.auto_quote))
+                        .collect(),
+                )
+            });
+        let continue_on_error = rule.is_some_and(|r| r.continue_on_error());
+        let fallbacks = steps.is_some() && rule.is_some_and(|r| r.fallbacks());
+        if steps.is_none() {
+            output::report(Report::RunStarted {
+                command: cmd.clone(),
+                detail: detail.clone(),
             });
+        }
+        let hosts: Vec<Option<String>> = if hosts.is_empty() {
+            vec![None]
         } else {
-            thread::sleep(args.delay);
-            Exec::shell(cmd).join().unwrap();
+            hosts.into_iter().map(Some).collect()
+        };
+        let container = match rule.and_then(|r| Some((r, r.container.as_ref()?))) {
+            Some((rule, container)) if action.is_none() => {
+                match exec::container_engine(args.container_engine.as_deref()) {
+                    Ok(engine) => Some(exec::ContainerRun::new(
+                        &engine,
+                        &container.image,
+                        container
+                            .mounts
+                            .iter()
+                            .map(|m| rule.template(m).render_nofail_string(map))
+                            .collect(),
+                        container
+                            .workdir
+                            .as_ref()
+                            .map(|w| rule.template(w).render_nofail_string(map)),
+                    )),
+                    Err(e) => {
+                        infoln!("{}: {}", "Error".bold().red(), e);
+                        return;
+                    }
+                }
+            }
+            _ => None,
+        };
+        // the package root found with the rule's `workdir_marker` wins
+        let pkgroot = rule
+            .filter(|_| args.command.is_empty())
+            .and_then(|r| r.workdir_marker.as_ref())
+            .and(map.get("pkgroot"));
+        let cwd = match (pkgroot, rule.and_then(|r| Some((r, r.cwd.as_ref()?)))) {
+            (Some(root), _) => Some(root.clone()),
+            (None, Some((rule, cwd))) if args.command.is_empty() => {
+                Some(rule.template(cwd).render_nofail_string(map))
+            }
+            _ => args
+                .cwd
+                .as_ref()
+                .map(|cwd| Template::new(cwd).render_nofail_string(map)),
+        };
+        let cwd = match cwd.map(|c| Path::new(&map["pwd"]).join(c)) {
+            Some(dir) if !dir.is_dir() => {
+                infoln!(
+                    "{}: directory {:?} to run in doesn't exist",
+                    "Error".bold().red(),
+                    dir
+                );
+                return;
+            }
+            cwd => cwd,
+        };
+        let mut env: Vec<(String, String)> = args
+            .env
+            .iter()
+            .map(|(k, v)| (k.clone(), Template::new(v).render_nofail_string(map)))
+            .collect();
+        if let Some(rule) = rule.filter(|_| args.command.is_empty()) {
+            for (k, v) in &rule.env {
+                env.retain(|(name, _)| name != k);
+                env.push((k.clone(), rule.template(v).render_nofail_string(map)));
+            }
+        }
+        let cmds = steps.clone().unwrap_or_else(|| vec![cmd.clone()]);
+        // the plans of the steps for each host
+        let plans: Vec<Vec<exec::ExecutionPlan>> = hosts
+            .iter()
+            .map(|h| {
+                cmds.iter()
+                    .map(|cmd| {
+                        let mut plan = exec::ExecutionPlan::new(cmd, h.as_deref());
+                        plan.env = env.clone();
+                        plan.timeout = rule.and_then(|r| r.timeout).or(args.timeout);
+                        if let Some(cwd) = &cwd {
+                            plan.cwd = cwd.clone();
+                        }
+                        match &container {
+                            Some(c) => plan.in_container(c.clone()),
+                            None => plan,
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        if args.echo == output::Echo::Full && action.is_none() {
+            for plan in plans.iter().flatten() {
+                output::print_plan(plan);
+            }
+        }
+        if let Some(e) = plans.iter().flatten().find_map(|p| p.check_size().err()) {
+            infoln!("{}: {}", "Error".bold().red(), e);
+            return;
+        }
+        if args.render_only {
+            return;
+        }
+        if args.restart {
+            let running = session.running.lock().unwrap().clone();
+            for (pid, container) in running {
+                infoln!("{}: previous run (pid {})", "Stop".bold().yellow(), pid);
+                exec::terminate(pid, container.as_ref(), args.restart_grace, || {
+                    session.running.lock().unwrap().iter().any(|r| r.0 == pid)
+                });
+            }
+        }
+        let del = rule.map_or(args.delay, |r| r.delay(args.delay));
+        let debounce = rule.map_or(args.duration, |r| r.duration(args.duration));
+        // the output that goes to the terminal directly can't be logged
+        let max_output = match &args.log {
+            Some(_) => Some(args.max_output.unwrap_or(usize::MAX)),
+            None => args.max_output,
+        };
+        let cue = match (args.bell, &args.sound) {
+            (Some(c), _) => Some(c),
+            (None, Some(_)) => Some(output::Cue::Failure),
+            (None, None) => None,
+        };
+        let done_templ = Some(&args.done_template)
+            .filter(|t| !t.is_empty())
+            .map(Template::new);
+        let rule_name = rule.map(|r| r.name.clone()).unwrap_or_default();
+        for (host, plan) in hosts.into_iter().zip(plans) {
+            let mut map = map.clone();
+            let session = session.clone();
+            let cmd = cmd.clone();
+            let done_templ = done_templ.clone();
+            let webhook_templ = args.webhook_text.as_ref().map(Template::new);
+            let rule_name = rule_name.clone();
+            let action = action.clone();
+            let sound = args.sound.clone();
+            let restart = args.restart;
+            let hash = hash.clone();
+            let detail = detail.clone();
+            let dumped = dumped.clone();
+            let stepped = steps.is_some();
+            let cmds = cmds.clone();
+            let fail_on_spawn_error = args.fail_on_spawn_error;
+            let mut plans = plan;
+            for plan in &mut plans {
+                plan.group |= restart;
+            }
+            session.stats.lock().unwrap().started(&rule_name);
+            let job = move || {
+                if !session.cancel.sleep(del) {
+                    session.stats.lock().unwrap().cancelled(&rule_name);
+                    return;
+                }
+                let journal_id = session
+                    .journal
+                    .as_ref()
+                    .map(|j| j.start(Path::new(&map["path"]), &rule_name, &cmd));
+                let start = Instant::now();
+                let mut attempt = None;
+                let status: Result<exec::RunStatus, String> = match action {
+                    Some(action) => match action.run() {
+                        Ok(_) => Ok(ExitStatus::Exited(0).into()),
+                        Err(e) => {
+                            infoln!("{}: {}", "Error".bold().red(), e);
+                            Ok(ExitStatus::Exited(1).into())
+                        }
+                    },
+                    None if fallbacks => {
+                        let (status, tried) = run_fallbacks(
+                            &session, &cmds, &plans, &detail, max_output, journal_id, restart,
+                        );
+                        attempt = Some(tried);
+                        status
+                    }
+                    None => (|| {
+                        let mut failed = None;
+                        let mut status = ExitStatus::Exited(0).into();
+                        for (step, plan) in cmds.iter().zip(&plans) {
+                            if stepped {
+                                output::report(Report::RunStarted {
+                                    command: step.clone(),
+                                    detail: detail.clone(),
+                                });
+                            }
+                            status = run_plan(&session, plan, max_output, journal_id, restart)?;
+                            if !status.success() {
+                                failed.get_or_insert(status);
+                                if !continue_on_error {
+                                    break;
+                                }
+                            }
+                        }
+                        Ok(failed.unwrap_or(status))
+                    })(),
+                };
+                // the command couldn't be started, that's not a failure of
+                // the command so it isn't counted as a run
+                let status = match status {
+                    Ok(status) => status,
+                    Err(e) => {
+                        infoln!("{}: {}", "Error".bold().red(), e);
+                        if let (Some(j), Some(id)) = (&session.journal, journal_id) {
+                            j.end(id, "spawn error", false);
+                        }
+                        session.stats.lock().unwrap().spawn_error(&rule_name);
+                        session.write_metrics();
+                        if fail_on_spawn_error {
+                            output::flush();
+                            std::process::exit(1);
+                        }
+                        // like the shell for the commands it can't run
+                        session.finish(127);
+                        return;
+                    }
+                };
+                if let Some(attempt) = attempt {
+                    map.insert("attempt".to_string(), attempt.to_string());
+                }
+                if let (Some(j), Some(id)) = (&session.journal, journal_id) {
+                    let name = match attempt {
+                        Some(attempt) => {
+                            format!("{} (attempt {})", exec::exit_name(&status), attempt)
+                        }
+                        None => exec::exit_name(&status),
+                    };
+                    j.end(id, &name, status.output_truncated);
+                }
+                let key = session.state_key(Path::new(&map["path"]));
+                if let (Some(m), Some(hash)) = (&session.manifest, hash) {
+                    if let Err(e) = m.lock().unwrap().finished(&key, hash, status.success()) {
+                        infoln!("{}: saving the manifest: {}", "Error".bold().red(), e);
+                    }
+                }
+                let elapsed = start.elapsed();
+                session.finish(exec::exit_code(&status.status).parse().unwrap_or(1));
+                session
+                    .state
+                    .lock()
+                    .unwrap()
+                    .record_run(&key, &rule_name, &status);
+                let mut stats = session.stats.lock().unwrap();
+                stats.record(&rule_name, elapsed, status.success());
+                let slow = stats.slow_rule(&rule_name, debounce);
+                drop(stats);
+                session.write_metrics();
+                exec::post_run_vars(&mut map, &status, elapsed);
+                session.update_vars(dumped.as_deref(), &map);
+                let message = done_templ.map(|templ| {
+                    let msg = templ.render_nofail_string(&map);
+                    match &host {
+                        Some(host) => format!("[{}] {}", host, msg),
+                        None => msg,
+                    }
+                });
+                let finished = report::Finished {
+                    success: status.success(),
+                    message,
+                    command: cmd,
+                    status: exec::exit_code(&status.status),
+                    exit_name: exec::exit_name(&status),
+                    elapsed,
+                    output_truncated: status.output_truncated,
+                };
+                if let Some(webhook) = &session.webhook {
+                    webhook.send(
+                        report::exit_record(&finished),
+                        finished.success,
+                        webhook_templ.map(|t| t.render_nofail_string(&map)),
+                    );
+                }
+                output::report(Report::RunFinished(finished));
+                if let Some(cue) = cue {
+                    output::completion_cue(cue, sound.as_deref(), status.success());
+                }
+                if let Some(avg) = slow {
+                    output::print_slow_rule_advice(&rule_name, avg, debounce);
+                }
+            };
+            if args.r#async || args.restart {
+                thread::spawn(job);
+            } else {
+                job();
+            }
         }
     }
 }
 
-fn render_command(
-    cmd: &Option<Template>,
-    conf_map: &HashMap<String, (Option<Template>, Option<Template>)>,
-    map: &HashMap<String, String>,
-) -> String {
-    if let Some(templ) = cmd {
-        return templ.render_nofail_string(&map);
+/// Change of the file to report, with all its variables
+fn change(text: Option<String>, map: &HashMap<String, String>) -> report::Change {
+    report::Change {
+        text,
+        path: map["path"].clone(),
+        vars: map.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+    }
+}
+
+/// Run the command of the plan, keeping its pid in the journal and
+/// (with `--restart`) in the running ones while it runs
+fn run_plan(
+    session: &Session,
+    plan: &exec::ExecutionPlan,
+    max_output: Option<usize>,
+    journal_id: Option<u64>,
+    restart: bool,
+) -> Result<exec::RunStatus, String> {
+    let mut pid = None;
+    let status = exec::run(plan, max_output, &session.cancel, |p| {
+        if let (Some(j), Some(id)) = (&session.journal, journal_id) {
+            j.pid(id, p);
+        }
+        if restart {
+            session
+                .running
+                .lock()
+                .unwrap()
+                .push((p, plan.container.clone()));
+            pid = Some(p);
+        }
+    });
+    if let Some(pid) = pid {
+        session.running.lock().unwrap().retain(|r| r.0 != pid);
     }
-    if let Some((Some(templ), _)) = conf_map.get(&(map["ext"].clone())) {
-        return templ.render_nofail_string(&map);
+    status
+}
+
+/// Try the fallback commands in order until one succeeds, the ones
+/// whose program can't be found are skipped; the status is of the last
+/// one that ran (an error if none could), with the number of the last
+/// one tried
+fn run_fallbacks(
+    session: &Session,
+    cmds: &[String],
+    plans: &[exec::ExecutionPlan],
+    detail: &str,
+    max_output: Option<usize>,
+    journal_id: Option<u64>,
+    restart: bool,
+) -> (Result<exec::RunStatus, String>, usize) {
+    let mut last = Err("none of the command_fallbacks could be run".to_string());
+    for (i, (cmd, plan)) in cmds.iter().zip(plans).enumerate() {
+        output::report(Report::RunStarted {
+            command: cmd.clone(),
+            detail: detail.to_string(),
+        });
+        match run_plan(session, plan, max_output, journal_id, restart) {
+            Ok(status) if status.success() => return (Ok(status), i + 1),
+            Ok(status) if !exec::not_found(&status.status) => last = Ok(status),
+            Ok(_) => infoln!(
+                "{}: program not found, trying the next command",
+                "Note".dimmed()
+            ),
+            Err(e) => infoln!("{}: {}, trying the next command", "Note".dimmed(), e),
+        }
     }
-    return String::from("");
+    (last, cmds.len())
 }
 
-fn main() {
-    let args = Cli::parse();
-    let conf_map = if args.command.len() > 0 && args.variables_command.is_some() {
-        HashMap::new()
+/// The commands use `{rundir}`, then it's removed even when stopped
+/// with Ctrl-C (that needs the signal handled)
+fn uses_rundir(args: &Cli, rules: &Rules) -> bool {
+    !args.keep_rundir
+        && if args.command.is_empty() {
+            rules.uses_variable("rundir")
+        } else {
+            config::template_variables(&args.command.join(" "), None)
+                .contains(&"rundir".to_string())
+        }
+}
+
+/// The `{rule}` variable: name of the rule for the file, or `default`
+/// when the default command from the config is used for it
+fn rule_var(cmd: &Option<Template>, rule: Option<&Rule>, rules: &Rules) -> String {
+    match rule {
+        Some(r) => r.name.clone(),
+        None if cmd.is_none() && rules.default_command.is_some() => "default".to_string(),
+        None => String::new(),
+    }
+}
+
+/// `{action}` of the event with its rendered command, the rule's
+/// `action` runs without one
+fn outcome(args: &Cli, rule: Option<&Rule>, cmd: &str) -> Outcome {
+    if cmd.is_empty() && !(args.command.is_empty() && rule.is_some_and(|r| r.action.is_some())) {
+        Outcome::NoRule
     } else {
-        let conf: HashMap<String, HashMap<String, String>> = match get_config(&args.config)
-            .and_then(|c| c.try_deserialize().map_err(|e| e.to_string()))
-        {
-            Ok(conf) => conf,
-            Err(e) => {
-                println!("\n{}: {}", "Error".bold().red(), e);
-                return;
-            }
-        };
-        ext_map_from_config(&conf, args.command.len() == 0)
+        Outcome::Run
+    }
+}
+
+fn render_command(
+    args: &Cli,
+    rule: Option<&Rule>,
+    rules: &Rules,
+    map: &HashMap<String, String>,
+) -> String {
+    if !args.command.is_empty() {
+        let regex = config::variable_regex(None);
+        return exec::render_shell(&args.command.join(" "), &regex, map, args.auto_quote);
+    }
+    let first_run = map.get("first_run").map(|f| f == "true").unwrap_or(false);
+    let kind = map
+        .get("event")
+        .and_then(|e| filter::EventType::from_str(e, false).ok());
+    let (templ, delimiters) = match rule {
+        Some(r) => (r.command(first_run, kind), r.template_delimiters.as_ref()),
+        None => (rules.default_command.as_deref(), rules.delimiters.as_ref()),
+    };
+    match templ {
+        Some(templ) => exec::render_shell(
+            templ,
+            &config::variable_regex(delimiters),
+            map,
+            args.auto_quote,
+        ),
+        None => String::new(),
+    }
+}
+
+/// Run the commands with the shell of `--shell` or the config
+fn use_shell(args: &Cli, rules: &Rules) -> Result<(), String> {
+    match (&args.shell, &rules.shell) {
+        (Some(shell), _) => exec::set_shell(exec::split_shell(shell)?),
+        (None, Some(shell)) => exec::set_shell(shell.argv()?),
+        (None, None) => Ok(()),
+    }
+}
+
+/// Print the rule and the command for the path as `rule=NAME cmd=CMD`,
+/// or `none`, returns the exit code
+fn query(path: &Path, with_vars: bool, args: &Cli) -> i32 {
+    if args.config.is_none() && config::config_files().is_empty() {
+        errln!("{}: no config file found", "Error".bold().red());
+        return 2;
+    }
+    let rules = match config::load_rules(&args.config).and_then(|r| use_shell(args, &r).map(|_| r))
+    {
+        Ok(rules) => rules,
+        Err(e) => {
+            errln!("{}: {}", "Error".bold().red(), e);
+            return 2;
+        }
     };
     let cwd = env::current_dir().unwrap();
-    let cng_templ = if args.template.len() > 0 {
-        Some(Template::new(&args.template))
+    let path = cwd.join(path);
+    let mut map = if with_vars {
+        template_vars(&path, &[], &cwd, &rules, args, &cancel::Token::default())
     } else {
-        None
+        file_vars(&path, &cwd, &rules)
     };
-    let cmd_templ = if args.command.len() > 0 {
-        Some(Template::new(args.command.join(" ")))
-    } else {
-        None
+    let rule = rules.find(&map);
+    map.insert("rule".to_string(), rule_var(&None, rule, &rules));
+    let cmd = render_command(args, rule, &rules, &map);
+    if cmd.is_empty() {
+        outln!("none");
+        return 1;
+    }
+    outln!("rule={} cmd={}", map["rule"], cmd);
+    0
+}
+
+/// List the runs interrupted in the earlier session and run the
+/// `on_recover` command for each of them
+fn recover_runs(runs: &[journal::Interrupted], rules: &Rules) {
+    let templ = rules.on_recover.as_ref().map(Template::new);
+    for run in runs {
+        let started_at = humantime::format_rfc3339_seconds(run.started_at).to_string();
+        let pid = run.pid.map(|p| p.to_string()).unwrap_or_default();
+        infoln!(
+            "{}: {} (rule {:?}, {:?}, started at {}{})",
+            "Interrupted".bold().yellow(),
+            run.command,
+            run.rule,
+            run.path,
+            started_at,
+            if pid.is_empty() {
+                String::new()
+            } else {
+                format!(", pid {}", pid)
+            }
+        );
+        if let Some(templ) = &templ {
+            let map = HashMap::from([
+                ("path".to_string(), run.path.to_string_lossy().to_string()),
+                ("rule".to_string(), run.rule.clone()),
+                ("command".to_string(), run.command.clone()),
+                ("started_at".to_string(), started_at),
+                ("pid".to_string(), pid),
+            ]);
+            let cmd = templ.render_nofail_string(&map);
+            infoln!("{}: {}", "Recover".bold().yellow(), cmd);
+            let start = Instant::now();
+            let status = exec::run(
+                &exec::ExecutionPlan::new(&cmd, None),
+                None,
+                &cancel::Token::default(),
+                |_| (),
+            );
+            match status {
+                Ok(status) if !status.success() => {
+                    output::report(Report::RunFinished(report::Finished {
+                        success: false,
+                        message: Some(exec::exit_name(&status)),
+                        command: cmd,
+                        status: exec::exit_code(&status.status),
+                        exit_name: exec::exit_name(&status),
+                        elapsed: start.elapsed(),
+                        output_truncated: status.output_truncated,
+                    }))
+                }
+                Ok(_) => (),
+                Err(e) => infoln!("{}: {}", "Error".bold().red(), e),
+            }
+        }
+    }
+}
+
+/// Everything needed to handle the debounced events, shared by the
+/// watch loop and the simulate subcommand
+struct Pipeline<'a> {
+    args: &'a Cli,
+    rules: &'a Rules,
+    session: Arc<Session>,
+    filter: filter::Filter,
+    cwd: PathBuf,
+    cmd_templ: Option<Template>,
+    cng_templ: Option<Template>,
+    /// Events held back by the rules with their own debounce
+    held: Mutex<HashMap<String, debounce::Debouncer>>,
+    /// Variables that are the same for all the events: `{watched}`
+    /// and `{watched_file}`
+    static_vars: HashMap<String, String>,
+    /// Recently dispatched files by their canonical path, with the
+    /// form of the path and the time
+    recent: Mutex<HashMap<PathBuf, (PathBuf, Instant)>>,
+    /// Running the deferred events, they're not deferred again
+    flushing: AtomicBool,
+    /// Extensions already warned about with `--warn-unmatched`
+    unmatched: Mutex<HashSet<String>>,
+    /// Paths whose `await_set` wait is over, they aren't held again
+    awaited: Mutex<HashSet<PathBuf>>,
+}
+
+/// Part of the batch a rule with `batch_size` runs for together
+struct Chunk<'a> {
+    index: usize,
+    total: usize,
+    paths: Vec<&'a Path>,
+}
+
+impl Default for Chunk<'_> {
+    fn default() -> Self {
+        Self {
+            index: 0,
+            total: 1,
+            paths: vec![],
+        }
+    }
+}
+
+/// Part an event plays in running a batch mode rule
+enum Batched<'a> {
+    /// Rule runs per file
+    No,
+    /// Runs the rule for all these paths
+    Lead(Vec<&'a Path>),
+    /// Runs with the lead event
+    Member,
+}
+
+/// Chunk of each event, the events of a rule with `batch_size` are
+/// split into chunks of that many in their order
+fn chunks<'e>(events: &[&'e debounce::DebouncedEvent], rules: &[Option<&Rule>]) -> Vec<Chunk<'e>> {
+    let mut groups: HashMap<&str, Vec<&Path>> = HashMap::new();
+    for (event, rule) in events.iter().zip(rules) {
+        if let Some(rule) = rule.filter(|r| r.batch_size.is_some()) {
+            groups.entry(&rule.name).or_default().push(&event.path);
+        }
+    }
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    rules
+        .iter()
+        .map(|rule| {
+            let Some((rule, size)) = rule.and_then(|r| Some((r, r.batch_size?.max(1)))) else {
+                return Chunk::default();
+            };
+            let group = &groups[rule.name.as_str()];
+            let pos = seen.entry(&rule.name).or_default();
+            let index = *pos / size;
+            *pos += 1;
+            Chunk {
+                index,
+                total: group.len().div_ceil(size),
+                paths: group.chunks(size).nth(index).unwrap_or_default().to_vec(),
+            }
+        })
+        .collect()
+}
+
+/// Role of each event in running the batch mode rules: the first
+/// event of the rule (or its chunk) runs it for all their paths
+fn batched<'e>(
+    events: &[&'e debounce::DebouncedEvent],
+    rules: &[Option<&Rule>],
+    chunks: &[Chunk],
+    in_batch: impl Fn(Option<&Rule>) -> bool,
+) -> Vec<Batched<'e>> {
+    let key = |rule: &Option<&Rule>, chunk: &Chunk| {
+        (
+            rule.map(|r| r.name.clone()).unwrap_or_default(),
+            chunk.index,
+        )
     };
+    let mut groups: HashMap<(String, usize), Vec<&Path>> = HashMap::new();
+    for ((event, rule), chunk) in events.iter().zip(rules).zip(chunks) {
+        if in_batch(*rule) {
+            groups
+                .entry(key(rule, chunk))
+                .or_default()
+                .push(&event.path);
+        }
+    }
+    rules
+        .iter()
+        .zip(chunks)
+        .map(|(rule, chunk)| {
+            if !in_batch(*rule) {
+                return Batched::No;
+            }
+            match groups.remove(&key(rule, chunk)) {
+                Some(paths) => Batched::Lead(paths),
+                None => Batched::Member,
+            }
+        })
+        .collect()
+}
 
-    if args.trial_run {
-        for path in &args.watch {
-            // TODO suport recursive flag
+impl Pipeline<'_> {
+    /// Time the next event held by a rule's debounce is ready at
+    fn next_deadline(&self) -> Option<Instant> {
+        let awaiting = self.session.state.lock().unwrap().awaiting_count() > 0;
+        self.held
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|d| d.next_deadline())
+            .chain(awaiting.then(|| Instant::now() + AWAIT_POLL))
+            .min()
+    }
+
+    /// Process the held events whose rule's debounce has ended, and
+    /// the ones whose `await_set` arrived (or timed out)
+    fn release(&self, now: Instant) {
+        let mut events: Vec<debounce::DebouncedEvent> = self
+            .held
+            .lock()
+            .unwrap()
+            .values_mut()
+            .flat_map(|d| d.take_ready(now))
+            .collect();
+        let awaited = self.session.state.lock().unwrap().take_awaited(now);
+        for held in awaited {
+            let missing = self.session.state.lock().unwrap().awaiting_missing(&held);
+            let rule = self.rules.iter().find(|r| r.name == held.rule);
+            if missing.is_empty() || rule.and_then(|r| r.await_policy) != Some(AwaitPolicy::Skip) {
+                self.awaited.lock().unwrap().insert(held.path.clone());
+                events.push(debounce::DebouncedEvent {
+                    path: held.path,
+                    kinds: held.kinds,
+                    synthetic: false,
+                });
+                continue;
+            }
+            self.report_skipped_change(&held.path, rule, Outcome::Await);
+            output::report(Report::RunSkipped {
+                subject: format!("{:?}", held.path),
+                reason: format!(
+                    "{} didn't arrive in time",
+                    missing
+                        .iter()
+                        .map(|p| format!("{:?}", p))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+                outcome: Outcome::Await,
+            });
+            self.session
+                .stats
+                .lock()
+                .unwrap()
+                .skipped(&held.rule, Outcome::Await);
+        }
+        if !events.is_empty() {
+            self.process(&events, true, false);
+        }
+    }
+
+    /// Run the deferred events if the window is over or a flush was
+    /// asked for
+    fn flush_deferred(&self) {
+        let Some(window) = &self.args.defer_window else {
+            return;
+        };
+        let requested = defer::flush_requested();
+        let mut state = self.session.state.lock().unwrap();
+        if state.pending_count() == 0 || (!requested && window.contains(SystemTime::now())) {
+            return;
+        }
+        let events: Vec<debounce::DebouncedEvent> = state
+            .take_pending()
+            .into_iter()
+            .map(|(path, kind)| debounce::DebouncedEvent {
+                path,
+                kinds: vec![kind.event_kind()],
+                synthetic: false,
+            })
+            .collect();
+        drop(state);
+        infoln!(
+            "{}: {} deferred changes",
+            "Flushing".bold().yellow(),
+            events.len()
+        );
+        self.flushing.store(true, Ordering::SeqCst);
+        self.process(&events, true, false);
+        self.flushing.store(false, Ordering::SeqCst);
+    }
+
+    /// Event is of the kinds `--events` (or its rule's `events`) asks
+    /// for, checked before the variables command is run for it
+    fn wanted_event(&self, event: &debounce::DebouncedEvent, rule: Option<&Rule>) -> bool {
+        let wanted = match &self.args.events {
+            Some(events) => events,
+            None => match rule.and_then(|r| r.events.as_ref()) {
+                Some(events) => events,
+                None => return true,
+            },
+        };
+        let types = filter::EventType::of(&event.path, &event.kinds);
+        if types.iter().any(|t| wanted.contains(t)) {
+            return true;
+        }
+        self.report_filtered(
+            &event.path,
+            rule,
+            Outcome::Event,
+            format!(
+                "{} not in the events",
+                types
+                    .iter()
+                    .map(|t| t.to_possible_value().unwrap().get_name().to_string())
+                    .collect::<Vec<String>>()
+                    .join(",")
+            ),
+        );
+        false
+    }
+
+    /// Report the event left out by the filters with `--verbose`, the
+    /// change template renders for it with the variables of its path
+    fn report_filtered(&self, path: &Path, rule: Option<&Rule>, outcome: Outcome, reason: String) {
+        if !self.args.verbose {
+            return;
+        }
+        self.report_skipped_change(path, rule, outcome);
+        output::report(Report::RunSkipped {
+            subject: format!("{:?}", path),
+            reason,
+            outcome,
+        });
+    }
+
+    /// Render the change template for the skipped event with
+    /// `--verbose`, with the variables of its path
+    fn report_skipped_change(&self, path: &Path, rule: Option<&Rule>, outcome: Outcome) {
+        if !self.args.verbose || self.cng_templ.is_none() {
+            return;
+        }
+        let mut map = vars::path_vars(path, &self.cwd);
+        map.extend(self.static_vars.clone());
+        map.insert("action".to_string(), outcome.to_string());
+        let cng = self.render_change(rule, &map);
+        output::report(Report::Detected(change(cng, &map)));
+    }
+
+    /// Render the change template, with the rule's delimiters if it
+    /// has any
+    fn render_change(&self, rule: Option<&Rule>, map: &HashMap<String, String>) -> Option<String> {
+        let templ = self.cng_templ.as_ref()?;
+        Some(match rule.filter(|r| r.template_delimiters.is_some()) {
+            Some(r) => r.template(&self.args.template).render_nofail_string(map),
+            None => templ.render_nofail_string(map),
+        })
+    }
+
+    /// Merge the events of the same file reached through different
+    /// watched paths (e.g. a directory and a file in it), in the batch
+    /// and within `MERGE_WINDOW` of the earlier dispatch
+    fn merge_duplicates(
+        &self,
+        events: &[debounce::DebouncedEvent],
+    ) -> Vec<debounce::DebouncedEvent> {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, (_, t)| now.duration_since(*t) < MERGE_WINDOW);
+        let mut merged: Vec<debounce::DebouncedEvent> = Vec::new();
+        let mut index: HashMap<PathBuf, usize> = HashMap::new();
+        for event in events {
+            let key = watch::canonical_path(&event.path);
+            let first = match index.get(&key) {
+                Some(&i) => {
+                    let first = &mut merged[i];
+                    for kind in &event.kinds {
+                        if !first.kinds.contains(kind) {
+                            first.kinds.push(*kind);
+                        }
+                    }
+                    Some(first.path.clone())
+                }
+                None => recent
+                    .get(&key)
+                    .map(|(path, _)| path.clone())
+                    .filter(|path| *path != event.path),
+            };
+            if let Some(first) = first {
+                if self.args.verbose {
+                    infoln!(
+                        "{}: {:?} (same file as {:?})",
+                        "Merged".dimmed(),
+                        event.path,
+                        first
+                    );
+                }
+                continue;
+            }
+            recent.insert(key.clone(), (event.path.clone(), now));
+            index.insert(key, merged.len());
+            merged.push(event.clone());
+        }
+        merged
+    }
+
+    /// Rule runs once for all its files in the batch, from its `mode`
+    /// or `--batch`
+    fn batch_mode(&self, rule: Option<&Rule>) -> bool {
+        if self.cmd_templ.is_some() {
+            return self.args.batch;
+        }
+        match rule.and_then(|r| r.mode) {
+            Some(mode) => mode == config::RunMode::Batch,
+            None => self.args.batch,
+        }
+    }
+
+    /// Command of the batch rendered without the variables of its first
+    /// file `path`, empty (with an error) if it uses them
+    fn strict_batch_command(
+        &self,
+        rule: Option<&Rule>,
+        map: &HashMap<String, String>,
+        path: &Path,
+    ) -> String {
+        let mut single = file_vars(path, &self.cwd, self.rules);
+        single.retain(|k, _| !config::SESSION_VARIABLES.contains(&k.as_str()));
+        let mut map = map.clone();
+        map.retain(|k, _| !single.contains_key(k));
+        let cmd = render_command(self.args, rule, self.rules, &map);
+        let delimiters = match rule {
+            Some(r) if self.args.command.is_empty() => r.template_delimiters.as_ref(),
+            _ => None,
+        };
+        let used: Vec<String> = config::template_variables(&cmd, delimiters)
+            .into_iter()
+            .filter(|v| single.contains_key(v))
+            .map(|v| format!("{{{}}}", v))
+            .collect();
+        if used.is_empty() {
+            return cmd;
+        }
+        infoln!(
+            "{}: the batch command uses {}, variables of a single file (--strict-batch)",
+            "Error".bold().red(),
+            used.join(", ")
+        );
+        String::new()
+    }
+
+    /// Event is of the kinds `dir_events` (of the rule or the CLI)
+    /// allows
+    fn dir_event_allowed(&self, event: &debounce::DebouncedEvent, rule: Option<&Rule>) -> bool {
+        let dir_events = rule
+            .and_then(|r| r.dir_events)
+            .unwrap_or(self.args.dir_events);
+        if dir_events.allows(&event.path, &event.kinds, &self.filter.roots) {
+            return true;
+        }
+        self.report_filtered(
+            &event.path,
+            rule,
+            Outcome::Event,
+            format!(
+                "not in dir_events={}",
+                dir_events.to_possible_value().unwrap().get_name()
+            ),
+        );
+        false
+    }
+
+    /// Hold the run until the files of the rule's `await_set` arrive,
+    /// false if they already have
+    fn await_set(
+        &self,
+        rule: &Rule,
+        event: &debounce::DebouncedEvent,
+        map: &HashMap<String, String>,
+    ) -> bool {
+        let Some(set) = &rule.await_set else {
+            return false;
+        };
+        if self.awaited.lock().unwrap().remove(&event.path) {
+            return false;
+        }
+        let key = self.session.state_key(&event.path);
+        let members = set
+            .iter()
+            .map(|t| rule.template(t).render_nofail_string(map))
+            .map(|p| self.session.state_key(&self.cwd.join(p)))
+            .filter(|p| *p != key)
+            .collect();
+        let awaiting = state::Awaiting {
+            path: event.path.clone(),
+            kinds: event.kinds.clone(),
+            rule: rule.name.clone(),
+            members,
+            since: SystemTime::now(),
+            until: Instant::now() + rule.await_timeout(),
+        };
+        let mut state = self.session.state.lock().unwrap();
+        let missing = state.awaiting_missing(&awaiting);
+        if missing.is_empty() {
+            return false;
+        }
+        if self.args.verbose {
+            infoln!(
+                "{}: {:?} (rule {} waits for {:?})",
+                "Held".dimmed(),
+                event.path,
+                rule.name,
+                missing
+            );
+        }
+        state.await_set(&key, awaiting);
+        true
+    }
+
+    /// Hold the event if its rule has its own debounce, or a longer
+    /// duration than the global one, for `release`
+    fn hold(&self, rule: &Rule, event: &debounce::DebouncedEvent) -> bool {
+        // the global debounce has already waited for its part
+        let extra = rule.duration(self.args.duration) - self.args.duration;
+        let (mode, wait, reason) = match rule.debounce {
+            Some(quiet) => (debounce::Mode::Adaptive, quiet, "of quiet"),
+            None if !extra.is_zero() => (debounce::Mode::Fixed, extra, "more"),
+            None => return false,
+        };
+        let mut held = self.held.lock().unwrap();
+        let debouncer = held
+            .entry(rule.name.clone())
+            .or_insert_with(|| debounce::Debouncer::new(mode, debounce::Scope::Path, wait, None));
+        for kind in &event.kinds {
+            debouncer.add(event.path.clone(), *kind, Instant::now());
+        }
+        if self.args.verbose {
+            infoln!(
+                "{}: {:?} (rule {} waits for {} {})",
+                "Held".dimmed(),
+                event.path,
+                rule.name,
+                humantime::format_duration(wait),
+                reason
+            );
+        }
+        true
+    }
 
+    /// Rule and the variables of the changed file, with the `extra`
+    /// ones of the batch or the trial run. The `{action}` is added
+    /// once the command is rendered, and the variables of the run
+    /// after it finishes
+    fn final_vars(
+        &self,
+        path: &Path,
+        kinds: &[notify::EventKind],
+        extra: HashMap<String, String>,
+    ) -> (Option<&Rule>, HashMap<String, String>) {
+        let args = self.args;
+        let mut map = template_vars(
+            path,
+            kinds,
+            &self.cwd,
+            self.rules,
+            args,
+            &self.session.cancel,
+        );
+        map.extend(self.static_vars.clone());
+        map.extend(extra);
+        if args.variables {
+            for (k, v) in &map {
+                infoln!("{}{}={}", "V: ".bold(), k, v);
+            }
+        }
+        let rule = self.rules.find(&map);
+        if args.warn_unmatched
+            && rule.is_none()
+            && self.cmd_templ.is_none()
+            && self.rules.default_command.is_none()
+            && self.unmatched.lock().unwrap().insert(map["ext"].clone())
+        {
+            let what = match map["ext"].as_str() {
+                "" => "No rule for the files without an extension".to_string(),
+                ext => format!("No rule for extension '{}'", ext),
+            };
+            infoln!("{} ({})", what.yellow().bold(), map["rpath"]);
+        }
+        if let Some(marker) = rule.and_then(|r| r.workdir_marker.as_ref()) {
+            // a watched file doesn't limit the search
+            let root = watch::watch_root(path, &self.filter.roots).filter(|r| r.is_dir());
+            match vars::package_vars(path, marker, root) {
+                Some(vars) => map.extend(vars),
+                None if args.verbose => infoln!(
+                    "{}: no {} above {:?}, running in the usual directory",
+                    "Note".dimmed(),
+                    marker,
+                    path
+                ),
+                None => (),
+            }
+        }
+        unset_placeholders(args, self.rules, rule, &mut map);
+        map.insert(
+            "rule".to_string(),
+            rule_var(&self.cmd_templ, rule, self.rules),
+        );
+        let rule_name = rule.map(|r| r.name.as_str()).unwrap_or_default();
+        self.session.state.lock().unwrap().run_vars(
+            &self.session.state_key(path),
+            rule_name,
+            &mut map,
+        );
+        let in_flight = self.session.stats.lock().unwrap().in_flight(rule_name);
+        map.insert("in_flight".to_string(), (in_flight > 0).to_string());
+        map.insert("in_flight_count".to_string(), in_flight.to_string());
+        (rule, map)
+    }
+
+    /// Render and run the commands for the paths as if they changed,
+    /// for the trial run and the initial run
+    fn trial(&self, paths: &[PathBuf]) {
+        let args = self.args;
+        let now = SystemTime::now();
+        let mut old = 0;
+        for path in paths {
             // HACK TODO use proper methods to find absolute path, or
             // verify this is good enough
             let path = if path.is_relative() {
-                cwd.join(path)
+                self.cwd.join(path)
             } else {
                 path.clone()
             };
-            let map = template_vars(&path, &cwd, &args.variables_command, &conf_map);
-            let cmd = render_command(&cmd_templ, &conf_map, &map);
-            let cng = cng_templ.as_ref().map(|t| t.render_nofail_string(&map));
-            on_change(&args, &path, cmd, cng)
+            if self.filter.check(&path, now).is_some() {
+                continue;
+            }
+            if let Some(within) = args.changed_within {
+                let mtime = path.metadata().and_then(|m| m.modified());
+                if mtime.is_ok_and(|t| now.duration_since(t).is_ok_and(|age| age > within)) {
+                    old += 1;
+                    continue;
+                }
+            }
+            let kinds: Vec<notify::EventKind> = args.kind.iter().map(|k| k.event_kind()).collect();
+            let mut extra = HashMap::new();
+            if let Some(kind) = args.kind {
+                // the file is there, so a remove can't be seen from it
+                extra.insert(
+                    "event".to_string(),
+                    kind.to_possible_value().unwrap().get_name().to_string(),
+                );
+            }
+            let (rule, mut map) = self.final_vars(&path, &kinds, extra);
+            if args.trial_run {
+                // to see which rule wins when several could apply
+                infoln!(
+                    "{}: {} ⇒ {}",
+                    "Matched".dimmed(),
+                    map["rpath"],
+                    match rule {
+                        Some(rule) if rule.is_catch_all() => {
+                            format!("rule {} (no other rule)", rule.name)
+                        }
+                        Some(Rule {
+                            name,
+                            pattern: Some(p),
+                            ..
+                        }) => format!("rule {} (pattern {})", name, p),
+                        Some(rule) => format!("rule {} (extension {})", rule.name, map["ext"]),
+                        None => "no rule".to_string(),
+                    }
+                );
+            }
+            if let (true, Some(rule)) = (args.trial_run, rule) {
+                infoln!(
+                    "{}: rule {} has duration {} and delay {}",
+                    "Timing".dimmed(),
+                    rule.name,
+                    humantime::format_duration(rule.duration(args.duration)),
+                    humantime::format_duration(rule.delay(args.delay))
+                );
+            }
+            let cmd = render_command(args, rule, self.rules, &map);
+            self.session
+                .state
+                .lock()
+                .unwrap()
+                .mark_rule_run(rule.map(|r| r.name.as_str()).unwrap_or_default());
+            map.insert("action".to_string(), outcome(args, rule, &cmd).to_string());
+            on_change(
+                args,
+                &self.session,
+                &map,
+                cmd,
+                &|map| self.render_change(rule, map),
+                rule,
+                false,
+            )
+        }
+        if old > 0 && args.verbose {
+            infoln!(
+                "{}: {} files not changed within {} were left out",
+                "Note".dimmed(),
+                old,
+                humantime::format_duration(args.changed_within.unwrap_or_default())
+            );
         }
-        return;
     }
-    let (tx, rx) = std::sync::mpsc::channel();
 
-    let mut debouncer = new_debouncer(args.duration, None, tx).unwrap();
+    /// Rule of the event if it passes the filters, `None` if it's
+    /// filtered out; these run for every event before the variables
+    fn admit<'r>(
+        &'r self,
+        event: &debounce::DebouncedEvent,
+        released: bool,
+    ) -> Option<Option<&'r Rule>> {
+        let rule = self.rules.find(&vars::path_vars(&event.path, &self.cwd));
+        if let Some(skip) = self.filter.check(&event.path, SystemTime::now()) {
+            self.report_filtered(&event.path, rule, skip.outcome(), skip.to_string());
+            return None;
+        }
+        let admitted = self.wanted_event(event, rule)
+            && self.dir_event_allowed(event, rule)
+            && (released || self.cmd_templ.is_some() || !rule.is_some_and(|r| self.hold(r, event)));
+        admitted.then_some(rule)
+    }
+
+    /// `admit` for all the events, on `--pipeline-threads` threads for
+    /// the large batches; all the events of a path go to the same
+    /// thread, so they're still seen in order
+    fn admitted(
+        &self,
+        events: &[debounce::DebouncedEvent],
+        released: bool,
+    ) -> Vec<Option<Option<&Rule>>> {
+        let threads = self.args.pipeline_threads.max(1);
+        if threads == 1 || events.len() < PARALLEL_EVENTS {
+            return events.iter().map(|e| self.admit(e, released)).collect();
+        }
+        by_path(events, threads, |e| &e.path, |e| self.admit(e, released))
+    }
 
-    let rm = if args.recursive {
-        notify::RecursiveMode::Recursive
-    } else {
-        notify::RecursiveMode::NonRecursive
+    /// Skip the filtered events and run the commands for the rest as
+    /// a batch, `released` events aren't held by the rule's debounce.
+    /// `all_batch` runs all the rules in batch mode (for the coalesced
+    /// events of `--on-busy`)
+    fn process(&self, events: &[debounce::DebouncedEvent], released: bool, all_batch: bool) {
+        let args = self.args;
+        // commands from the rules that are to be run once per batch
+        let mut batch_cmds = HashSet::new();
+        let events = self.merge_duplicates(events);
+        // the rule is found again with all the variables later, this
+        // one is for the filters that run before the variables command
+        let (events, rules): (Vec<&debounce::DebouncedEvent>, Vec<Option<&Rule>>) = self
+            .admitted(&events, released)
+            .into_iter()
+            .zip(&events)
+            .filter_map(|(rule, event)| Some((event, rule?)))
+            .unzip();
+        if let (Some(window), false) = (&args.defer_window, self.flushing.load(Ordering::SeqCst)) {
+            if window.contains(SystemTime::now()) {
+                let mut state = self.session.state.lock().unwrap();
+                for event in &events {
+                    state.defer(&event.path, &event.kinds);
+                    if args.verbose {
+                        infoln!("{}: {:?}", "Deferred".dimmed(), event.path);
+                    }
+                }
+                return;
+            }
+        }
+        let kinds: HashMap<&Path, debounce::Kind> = events
+            .iter()
+            .map(|e| (e.path.as_path(), debounce::Kind::net(&e.path, &e.kinds)))
+            .collect();
+        let batch = batch_vars(
+            &events
+                .iter()
+                .map(|e| e.path.as_path())
+                .collect::<Vec<&Path>>(),
+            &kinds,
+            &self.filter.roots,
+        );
+        let chunks = chunks(&events, &rules);
+        let batched = batched(&events, &rules, &chunks, |rule| {
+            all_batch || self.batch_mode(rule)
+        });
+        let items = events.into_iter().zip(chunks).zip(batched);
+        items.for_each(|((event, chunk), batched)| {
+            if args.once && self.session.ran() {
+                return;
+            }
+            let mut extra = batch.clone();
+            extra.insert("chunk_index".to_string(), (chunk.index + 1).to_string());
+            extra.insert("chunk_total".to_string(), chunk.total.to_string());
+            if chunk.total > 1 {
+                extra.extend(batch_vars(&chunk.paths, &kinds, &self.filter.roots));
+            }
+            extra.insert("synthetic".to_string(), event.synthetic.to_string());
+            let paths = match &batched {
+                Batched::Lead(paths) => paths.clone(),
+                _ => vec![event.path.as_path()],
+            };
+            extra.extend(paths_vars(&paths, &self.cwd));
+            let (rule, mut map) = self.final_vars(&event.path, &event.kinds, extra);
+            if rule.is_some_and(|r| self.await_set(r, event, &map)) {
+                return;
+            }
+            let mut state_guard = self.session.state.lock().unwrap();
+            let mut cmd = match (&batched, args.strict_batch) {
+                (Batched::Lead(_), true) => self.strict_batch_command(rule, &map, &event.path),
+                _ => render_command(args, rule, self.rules, &map),
+            };
+            state_guard.mark_rule_run(rule.map(|r| r.name.as_str()).unwrap_or_default());
+            if self.cmd_templ.is_none()
+                && rule.map(|r| r.dedupe()).unwrap_or(false)
+                && !batch_cmds.insert((cmd.clone(), chunk.index))
+            {
+                cmd.clear();
+                map.insert("action".to_string(), Outcome::Dedup.to_string());
+            } else {
+                map.insert("action".to_string(), outcome(args, rule, &cmd).to_string());
+            }
+            let key = self.session.state_key(&event.path);
+            let path_state = state_guard.get_mut(&key);
+            path_state.events += 1;
+            path_state.last_event = Some(SystemTime::now());
+            // an atomic save of an old file isn't a create
+            let created = filter::EventType::net(&event.path, &event.kinds)
+                == Some(filter::EventType::Create);
+            let new_file = args.confirm_new_files
+                && state_guard.first_seen(&key, created)
+                && rule.and_then(|r| r.confirm_new).unwrap_or(true);
+            drop(state_guard);
+            if let Batched::Member = batched {
+                // the command runs once for the batch, with the first file
+                self.session.record_vars(&map);
+                let cng = self.render_change(rule, &map);
+                output::report(Report::Detected(change(cng, &map)));
+                return;
+            }
+            on_change(
+                args,
+                &self.session,
+                &map,
+                cmd,
+                &|map| self.render_change(rule, map),
+                rule,
+                new_file,
+            );
+        })
+    }
+}
+
+/// `f` for all the events on the threads, the events of a path go to
+/// the same thread in their order; the results are in the order of
+/// the events
+fn by_path<E: Sync, T: Clone + Send>(
+    events: &[E],
+    threads: usize,
+    path_of: impl Fn(&E) -> &Path + Sync,
+    f: impl Fn(&E) -> T + Sync,
+) -> Vec<T> {
+    let worker_of = |path: &Path| {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        path.hash(&mut hasher);
+        hasher.finish() as usize % threads
     };
-    let watcher = debouncer.watcher();
-    print!("{}: ", "Watching".bold().yellow());
+    // indices of the events of each thread, in their order
+    let mut shares = vec![Vec::new(); threads];
+    for (i, e) in events.iter().enumerate() {
+        shares[worker_of(path_of(e))].push(i);
+    }
+    let mut results = vec![None; events.len()];
+    thread::scope(|s| {
+        let workers: Vec<_> = shares
+            .into_iter()
+            .map(|share| {
+                let f = &f;
+                s.spawn(move || {
+                    share
+                        .into_iter()
+                        .map(|i| (i, f(&events[i])))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (i, result) in worker.join().unwrap() {
+                results[i] = Some(result);
+            }
+        }
+    });
+    results.into_iter().flatten().collect()
+}
+
+/// Watch the path given to watch, recursively with `--recursive` or
+/// a recursive glob pattern for it
+fn watch_path(
+    args: &Cli,
+    filter: &filter::Filter,
+    rwatch: &mut watch::RecursiveWatch,
+    watcher: &mut dyn notify::Watcher,
+    path: &Path,
+) -> notify::Result<()> {
+    let recursive = args.recursive || filter.globs.iter().any(|g| g.recursive && g.base == path);
+    if recursive {
+        rwatch.add(watcher, path).map(|_| ())
+    } else {
+        watcher.watch(path, notify::RecursiveMode::NonRecursive)
+    }
+}
+
+/// Watch the given paths and report them on their own lines; all
+/// the paths are tried before reporting, so an error doesn't cut the
+/// report short. Gives the watched paths and the missing ones waited
+/// for with `--wait-missing`
+fn watch_all(
+    args: &Cli,
+    filter: &filter::Filter,
+    rwatch: &mut watch::RecursiveWatch,
+    watcher: &mut dyn notify::Watcher,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut watching = Vec::new();
+    let mut failed = Vec::new();
+    let mut missing = Vec::new();
     for path in &args.watch {
-        match watcher.watch(path.as_ref(), rm) {
-            Ok(_) => print!("{:?} ", path),
+        match watch_path(args, filter, rwatch, watcher, path) {
+            Ok(_) => watching.push(path.clone()),
+            Err(_) if args.wait_missing.is_some() && !path.exists() => missing.push(path.clone()),
+            Err(e) => failed.push(format!("{:?} ({})", path, e)),
+        }
+    }
+    if !watching.is_empty() {
+        let mut line = format!(
+            "{}: {}",
+            "Watching".bold().yellow(),
+            output::watch_list(&watching, args.verbose)
+        );
+        if args.recursive || rwatch.registered() > 0 {
+            line.push(' ');
+            line.push_str(&output::recursive_summary(
+                rwatch.registered(),
+                rwatch.files,
+                rwatch.excluded,
+            ));
+        }
+        infoln!("{}", line);
+    }
+    if !failed.is_empty() {
+        infoln!("{}: {}", "Not watching".bold().red(), failed.join(", "));
+    }
+    if !missing.is_empty() {
+        infoln!(
+            "{}: {}",
+            "Waiting for".bold().yellow(),
+            output::watch_list(&missing, args.verbose)
+        );
+    }
+    if watching.is_empty() && missing.is_empty() {
+        infoln!(
+            "{}: none of the paths could be watched",
+            "Error".bold().red()
+        );
+    }
+    (watching, missing)
+}
+
+/// Wait again for the watched paths that were removed, and watch the
+/// waited ones that are there now
+fn retry_missing(
+    watching: &mut Vec<PathBuf>,
+    missing: &mut Vec<PathBuf>,
+    verbose: bool,
+    mut watch: impl FnMut(&Path) -> notify::Result<()>,
+) {
+    // their watches went with them
+    let (gone, kept) = watching.drain(..).partition(|p| !p.exists());
+    *watching = kept;
+    for path in gone {
+        infoln!("{}: {:?} (removed)", "Waiting for".bold().yellow(), path);
+        missing.push(path);
+    }
+    let (found, waiting) = missing.drain(..).partition(|p| p.exists());
+    *missing = waiting;
+    for path in found {
+        match watch(&path) {
+            Ok(_) => {
+                infoln!(
+                    "{}: {}",
+                    "Watching".bold().yellow(),
+                    output::watch_list(std::slice::from_ref(&path), verbose)
+                );
+                watching.push(path);
+            }
             Err(e) => {
-                println!("\n{}: {}", "Error".bold().red(), e.to_string());
-                return;
+                if verbose {
+                    infoln!(
+                        "{}: watching {:?} failed ({}), trying again",
+                        "Note".dimmed(),
+                        path,
+                        e
+                    );
+                }
+                missing.push(path);
             }
-        };
+        }
     }
-    println!("");
+}
 
-    for res in rx {
-        match res {
-            Ok(events) => events.iter().for_each(|event| match event.kind {
-                DebouncedEventKind::Any => {
-                    let mut map =
-                        template_vars(&event.path, &cwd, &args.variables_command, &conf_map);
-                    map.insert("event".to_string(), format!("{:?}", event));
-                    if args.variables {
-                        for (k, v) in &map {
-                            println!("{}{}={}", "V: ".bold(), k, v);
-                        }
+/// Key of the instance for `--single-instance`, the config file or the
+/// directory
+fn instance_key(args: &Cli, cwd: &Path) -> PathBuf {
+    match &args.config {
+        Some(conf) => Path::new(conf).canonicalize().unwrap_or(cwd.join(conf)),
+        None => cwd.to_path_buf(),
+    }
+}
+
+fn main() {
+    let _flush = output::FlushOnDrop;
+    let mut args = Cli::parse();
+    // the commands with the placeholders are only to look at
+    args.render_only |= args.no_vars;
+    if args.reporter == report::Mode::Json && args.notify_stream == output::NotifyStream::Stdout {
+        // the stdout is for the reports
+        args.notify_stream = output::NotifyStream::Stderr;
+    }
+    output::set_notify_stream(args.notify_stream);
+    output::set_report_mode(args.reporter);
+    if let Some(file) = &args.log {
+        if let Err(e) = output::set_log(file) {
+            errln!(
+                "{}: can't open the log file {:?}: {}",
+                "Error".bold().red(),
+                file,
+                e
+            );
+            output::flush();
+            std::process::exit(1);
+        }
+    }
+    match &args.subcommand {
+        Some(Action::Check { deny }) => {
+            match config::load_rules(&args.config) {
+                Ok(rules) => {
+                    output::print_rules(&rules);
+                    output::print_static_hints(&rules);
+                    output::print_uppercase_extensions(&rules);
+                    output::print_unreachable_rules(
+                        &rules,
+                        &rules.ignore,
+                        &args.only,
+                        &env::current_dir().unwrap(),
+                    );
+                    let findings = lint::lint(&rules);
+                    for finding in &findings {
+                        outln!("{}\n", finding);
+                    }
+                    let fail_at = if deny.is_some() {
+                        lint::Severity::Warning
+                    } else {
+                        lint::Severity::Error
+                    };
+                    if findings.iter().any(|f| f.severity >= fail_at) {
+                        errln!(
+                            "{}: {} problems found in {} rules",
+                            "Error".bold().red(),
+                            findings.len(),
+                            rules.len()
+                        );
+                        output::flush();
+                        std::process::exit(1);
+                    }
+                    outln!("{}: {} rules", "Config OK".bold().green(), rules.len());
+                }
+                Err(e) => {
+                    errln!("{}: {}", "Error".bold().red(), e);
+                    output::flush();
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Action::Rules { json }) => {
+            match config::load_rules(&args.config) {
+                Ok(rules) if *json => outln!("{}", output::rules_json(&rules)),
+                Ok(rules) => outln!("{}", output::rules_table(&rules)),
+                Err(e) => {
+                    errln!("{}: {}", "Error".bold().red(), e);
+                    output::flush();
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Action::Examples) => {
+            output::print_examples();
+            return;
+        }
+        Some(Action::Vars) => {
+            // the aliases from the config are listed too, if there's one
+            let aliases = config::load_rules(&args.config)
+                .map(|r| r.var_aliases)
+                .unwrap_or_default();
+            outln!("{}", output::vars_table(&aliases));
+            return;
+        }
+        Some(Action::Manifest {
+            action: ManifestAction::Prune,
+        }) => {
+            let Some(file) = &args.manifest else {
+                errln!(
+                    "{}: no manifest given, use --manifest",
+                    "Error".bold().red()
+                );
+                output::flush();
+                std::process::exit(2);
+            };
+            let mut manifest = manifest::Manifest::load(file);
+            match manifest.prune() {
+                Ok(gone) => {
+                    for path in &gone {
+                        outln!("{}: {}", "Pruned".bold().yellow(), path.to_string_lossy());
                     }
-                    let cmd = render_command(&cmd_templ, &conf_map, &map);
-                    let cng = cng_templ.as_ref().map(|t| t.render_nofail_string(&map));
-                    on_change(&args, &event.path, cmd, cng);
+                    outln!(
+                        "{}: {} entries removed, {} left",
+                        "Manifest".bold().green(),
+                        gone.len(),
+                        manifest.len()
+                    );
+                }
+                Err(e) => {
+                    errln!("{}: {:?}: {}", "Error".bold().red(), file, e);
+                    output::flush();
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Action::Query {
+            ref path,
+            with_vars,
+        }) => {
+            let code = query(path, *with_vars, &args);
+            output::flush();
+            std::process::exit(code);
+        }
+        Some(Action::Ctl {
+            action: CtlAction::Flush,
+        }) => {
+            let key = instance_key(&args, &env::current_dir().unwrap());
+            match instance::signal(&key, libc::SIGUSR1) {
+                Ok(pid) => outln!(
+                    "{}: asked instance {} to flush",
+                    "Flush".bold().green(),
+                    pid
+                ),
+                Err(e) => {
+                    errln!("{}: {}", "Error".bold().red(), e);
+                    output::flush();
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Action::Ctl {
+            action: CtlAction::VarsDump,
+        }) => {
+            let key = instance_key(&args, &env::current_dir().unwrap());
+            match control::request(&instance::socket(&key), &control::Request::VarsDump) {
+                Ok(vars) => outln!("{}", serde_json::to_string_pretty(&vars).unwrap()),
+                Err(e) => {
+                    errln!("{}: {}", "Error".bold().red(), e);
+                    output::flush();
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Action::Simulate {
+            ref path,
+            kind,
+            local: false,
+            ..
+        }) => {
+            let cwd = env::current_dir().unwrap();
+            let request = control::Request::Simulate {
+                path: cwd.join(path).components().collect(),
+                kind: *kind,
+            };
+            match control::request(&instance::socket(&instance_key(&args, &cwd)), &request) {
+                Ok(_) => infoln!("{}: {:?}", "Simulated".bold().green(), path),
+                Err(e) => {
+                    errln!("{}: {}", "Error".bold().red(), e);
+                    output::flush();
+                    std::process::exit(1);
                 }
-                _ => (),
-            }),
-            Err(errors) => errors.iter().for_each(|e| println!("Error {:?}", e)),
+            }
+            return;
+        }
+        Some(Action::Simulate {
+            ref path,
+            render_only,
+            ..
+        }) => {
+            args.render_only |= render_only;
+            if args.watch.is_empty() {
+                args.watch.push(path.clone());
+            }
+        }
+        None => (),
+    }
+    let rules = if !args.command.is_empty() && args.variables_command.is_some() {
+        Rules::default()
+    } else {
+        match config::load_rules(&args.config) {
+            Ok(rules) => rules,
+            Err(e) => {
+                infoln!("\n{}: {}", "Error".bold().red(), e);
+                return;
+            }
+        }
+    };
+    if let Err(e) = use_shell(&args, &rules) {
+        infoln!("{}: {}", "Error".bold().red(), e);
+        return;
+    }
+    if args.show_config {
+        output::print_rules(&rules);
+        return;
+    }
+    if args.verbose {
+        output::print_rules(&rules);
+        output::print_normalized(&rules.normalized);
+    }
+    if args.command.is_empty() {
+        output::print_static_hints(&rules);
+        output::print_uppercase_extensions(&rules);
+    }
+    let cwd = env::current_dir().unwrap();
+    let session_vars = vars::session_vars(&cwd);
+    let watch_from = match &args.watch_from {
+        Some(file) => watch::read_watch_file(file)
+            .and_then(|paths| watch::render_paths(&paths, &session_vars, &format!("{:?}", file))),
+        None => Ok(vec![]),
+    };
+    let templated = watch_from.and_then(|mut paths| {
+        paths.extend(watch::render_paths(&rules.watch, &session_vars, "config")?);
+        Ok(paths)
+    });
+    match templated {
+        Ok(paths) => args.watch.extend(paths),
+        Err(e) => {
+            infoln!("{}: {}", "Error".bold().red(), e);
+            return;
+        }
+    }
+    if args.watch.is_empty() {
+        infoln!(
+            "{}: no paths to watch, give them as arguments, with --watch-from, or in the config as `watch` in [global]",
+            "Error".bold().red()
+        );
+        return;
+    }
+    // the globs are watched through their base directories
+    let given = args.watch.clone();
+    let globs = if args.glob {
+        match watch::split_globs(&args.watch, &cwd) {
+            Ok((paths, globs)) => {
+                args.watch = paths;
+                globs
+            }
+            Err(e) => {
+                infoln!("{}: {}", "Error".bold().red(), e);
+                return;
+            }
+        }
+    } else {
+        for path in args
+            .watch
+            .iter()
+            .filter(|p| watch::has_wildcard(p) && !p.exists())
+        {
+            infoln!(
+                "{}: {:?} is taken literally, use --glob for the glob patterns",
+                "Hint".yellow().bold(),
+                path
+            );
+        }
+        vec![]
+    };
+    let cng_templ = if !args.template.is_empty() {
+        Some(Template::new(&args.template))
+    } else {
+        None
+    };
+    let path_kind = if args.files_only {
+        watch::PathKind::Files
+    } else if args.dirs_only {
+        watch::PathKind::Dirs
+    } else {
+        watch::PathKind::Any
+    };
+    // the missing ones are waited for with `--wait-missing`
+    let present: Vec<PathBuf> = args
+        .watch
+        .iter()
+        .filter(|p| args.wait_missing.is_none() || p.exists())
+        .cloned()
+        .collect();
+    if let Err(e) = watch::check_paths(&present, path_kind) {
+        infoln!("{}: {}", "Error".bold().red(), e);
+        return;
+    }
+    let state = match &args.state_file {
+        Some(file) => match StateStore::with_file(file.clone()) {
+            Ok(s) => s,
+            Err(e) => {
+                infoln!("{}: {}", "Error".bold().red(), e);
+                return;
+            }
+        },
+        None => StateStore::default(),
+    };
+    let journal = match &args.journal {
+        Some(file) => match journal::Journal::open(file, &rules.hash) {
+            Ok((journal, interrupted)) => {
+                recover_runs(&interrupted, &rules);
+                Some(journal)
+            }
+            Err(e) => {
+                infoln!("{}: {}", "Error".bold().red(), e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let dump = match &args.dump_vars_dir {
+        Some(dir) => match dump::VarsDump::new(dir, args.dump_vars_keep) {
+            Ok(dump) => Some(dump),
+            Err(e) => {
+                infoln!("{}: {}", "Error".bold().red(), e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let session = Arc::new(Session {
+        state: Mutex::new(state),
+        watched_files: args
+            .watch
+            .iter()
+            .map(|p| cwd.join(p).components().collect::<PathBuf>())
+            .filter(|p| !p.is_dir())
+            .map(|p| (p.clone(), watch::canonical_path(&p)))
+            .collect(),
+        metrics_file: args.metrics_file.clone(),
+        journal,
+        manifest: args
+            .manifest
+            .as_ref()
+            .map(|file| Mutex::new(manifest::Manifest::load(file))),
+        webhook: args
+            .webhook
+            .clone()
+            .map(|url| webhook::Webhook::new(url, args.webhook_events)),
+        dump,
+        redact: args.redact.clone(),
+        interactive: std::io::stdin().is_terminal(),
+        ..Default::default()
+    });
+    if args.metrics_file.is_some() {
+        let session = session.clone();
+        thread::spawn(move || loop {
+            session.write_metrics();
+            thread::sleep(METRICS_INTERVAL);
+        });
+    }
+    let mut ignore = Vec::new();
+    for p in &args.ignore {
+        match filter::bare_extension(p.as_str()) {
+            Some(pattern) => {
+                if args.verbose {
+                    output::print_normalized(&[(p.to_string(), pattern.clone())]);
+                }
+                ignore.push(glob::Pattern::new(&pattern).unwrap());
+            }
+            None => ignore.push(p.clone()),
+        }
+    }
+    ignore.extend(rules.ignore.iter().cloned());
+    for path in &args.ignore_literal {
+        let path: PathBuf = cwd.join(path).components().collect();
+        ignore.push(filter::literal_pattern(&path));
+        ignore.push(filter::literal_contents(&path));
+    }
+    if args.command.is_empty() {
+        output::print_unreachable_rules(&rules, &ignore, &args.only, &cwd);
+    }
+    if let Some(dir) = &args.dump_vars_dir {
+        // nor should writing the dumps
+        let dir: PathBuf = cwd.join(dir).components().collect();
+        ignore.push(filter::literal_contents(&dir));
+    }
+    if let Some(file) = &args.metrics_file {
+        // writing the metrics shouldn't trigger more runs
+        let file: PathBuf = cwd.join(file).components().collect();
+        for f in [stats::metrics_tmp(&file), file] {
+            ignore.push(filter::literal_pattern(&f));
+        }
+    }
+    if let (Some(file), Some(_)) = (&args.state_file, &args.defer_window) {
+        // it's written as the events are deferred
+        let file: PathBuf = cwd.join(file).components().collect();
+        for f in [file.with_extension("tmp"), file] {
+            ignore.push(filter::literal_pattern(&f));
+        }
+    }
+    let watched_file = match watch::WatchedFile::create(&given) {
+        Ok(file) => file,
+        Err(e) => {
+            infoln!(
+                "{}: writing the watched paths file: {}",
+                "Error".bold().red(),
+                e
+            );
+            return;
+        }
+    };
+    ignore.push(filter::literal_pattern(&watched_file.path));
+    let rundir = match watch::RunDir::create(args.keep_rundir) {
+        Ok(dir) => dir,
+        Err(e) => {
+            infoln!(
+                "{}: creating the run directory: {}",
+                "Error".bold().red(),
+                e
+            );
+            return;
+        }
+    };
+    ignore.push(filter::literal_contents(&rundir.path));
+    let session_start = SystemTime::now();
+    let static_vars = HashMap::from([
+        (
+            "rundir".to_string(),
+            rundir.path.to_string_lossy().to_string(),
+        ),
+        (
+            "onchange_version".to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        ),
+        ("config_hash".to_string(), rules.hash.clone()),
+        (
+            "session_start".to_string(),
+            humantime::format_rfc3339_seconds(session_start).to_string(),
+        ),
+        (
+            "watched".to_string(),
+            given
+                .iter()
+                .map(|p| exec::shell_quote(&p.to_string_lossy()))
+                .collect::<Vec<String>>()
+                .join(" "),
+        ),
+        (
+            "watched_file".to_string(),
+            watched_file.path.to_string_lossy().to_string(),
+        ),
+    ]);
+    let filter = filter::Filter {
+        only: args.only.clone(),
+        ignore_hidden: args.ignore_hidden,
+        ignore,
+        editor_files: if args.no_ignore_editor_files {
+            vec![]
+        } else {
+            filter::EDITOR_FILES
+                .iter()
+                .map(|p| glob::Pattern::new(p).unwrap())
+                .chain(rules.editor_ignores.iter().cloned())
+                .collect()
+        },
+        fresh_within: args.fresh_within,
+        cwd: cwd.clone(),
+        roots: args
+            .watch
+            .iter()
+            .map(|p| cwd.join(p).components().collect())
+            .collect(),
+        globs,
+    };
+    let cmd_templ = if !args.command.is_empty() {
+        Some(Template::new(args.command.join(" ")))
+    } else {
+        None
+    };
+    let pipeline = Pipeline {
+        args: &args,
+        rules: &rules,
+        session,
+        filter,
+        cwd,
+        cmd_templ,
+        cng_templ,
+        held: Mutex::new(HashMap::new()),
+        static_vars,
+        recent: Mutex::new(HashMap::new()),
+        flushing: AtomicBool::new(false),
+        unmatched: Mutex::new(HashSet::new()),
+        awaited: Mutex::new(HashSet::new()),
+    };
+    if let Some(Action::Simulate { path, kind, .. }) = &args.subcommand {
+        let path = pipeline.cwd.join(path).components().collect();
+        pipeline.process(
+            &[debounce::DebouncedEvent::simulated(path, kind.event_kind())],
+            true,
+            false,
+        );
+        return;
+    }
+
+    if args.trial_run {
+        pipeline.trial(&args.watch);
+        return;
+    }
+    let (tx, rx) = std::sync::mpsc::channel();
+    let _instance = match args.single_instance {
+        Some(mode) => {
+            let key = instance_key(&args, &pipeline.cwd);
+            match instance::acquire(&key, mode, &args.watch) {
+                Ok(lock) => {
+                    // `onchange ctl flush` shouldn't kill it
+                    defer::flush_on_signal();
+                    let session = pipeline.session.clone();
+                    let tx = tx.clone();
+                    let handle = move |request| match request {
+                        control::Request::VarsDump => {
+                            match session.last_vars.lock().unwrap().as_ref() {
+                                Some(vars) => Ok(serde_json::to_value(vars).unwrap()),
+                                None => Err("no events yet".to_string()),
+                            }
+                        }
+                        control::Request::Simulate { path, kind } => {
+                            let event = control::simulated_event(path, kind);
+                            tx.send(Ok(event))
+                                .map(|_| serde_json::Value::Null)
+                                .map_err(|_| "the instance is stopping".to_string())
+                        }
+                    };
+                    let server = control::Server::start(&instance::socket(&key), handle);
+                    if let Err(e) = &server {
+                        infoln!(
+                            "{}: control socket {}, `onchange ctl` can't reach this instance",
+                            "Warning".yellow().bold(),
+                            e
+                        );
+                    }
+                    Some((lock, server.ok()))
+                }
+                Err(e) => {
+                    infoln!("{}: {}", "Error".bold().red(), e);
+                    output::flush();
+                    std::process::exit(instance::ALREADY_RUNNING);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut watcher = notify::recommended_watcher(tx).unwrap();
+    let mut debouncer = debounce::Debouncer::new(
+        args.debounce_mode,
+        args.adaptive_scope,
+        args.duration,
+        Some(args.debounce_max),
+    );
+
+    let mut rwatch = watch::RecursiveWatch::new(args.exclude_dir.clone());
+    let mut rewatch = watch::Rewatch::new(&args.watch, &pipeline.cwd);
+    let (mut watching, mut missing) = watch_all(&args, &pipeline.filter, &mut rwatch, &mut watcher);
+    if watching.is_empty() && missing.is_empty() {
+        return;
+    }
+    if args.self_test {
+        let dirs: Vec<PathBuf> = watching.iter().filter(|p| p.is_dir()).cloned().collect();
+        let mut received = Vec::new();
+        let result = watch::self_test(&dirs, &rx, |event| received.push(event));
+        output::print_self_test(&result);
+        let now = Instant::now();
+        for event in received {
+            for path in event.paths {
+                debouncer.add(path, event.kind, now);
+            }
+        }
+    }
+    if args.initial_run {
+        let mut paths = vec![];
+        for path in &watching {
+            let glob = pipeline.filter.globs.iter().find(|g| g.base == *path);
+            if args.recursive || glob.is_some_and(|g| g.recursive) {
+                paths.extend(rwatch.files(path, true));
+            } else if glob.is_some() {
+                paths.extend(rwatch.files(path, false));
+            } else {
+                paths.push(path.clone());
+            }
+        }
+        pipeline.trial(&paths);
+    }
+
+    // the restarted commands (and the ones with a timeout) are in their
+    // own process groups, so they don't get the Ctrl-C and have to be
+    // stopped on exit
+    let stop_on_exit = args.stats
+        || args.restart
+        || args.timeout.is_some()
+        || rules.iter().any(|r| r.timeout.is_some())
+        || uses_rundir(&args, &rules);
+    if stop_on_exit {
+        cancel::stop_on_signal();
+    }
+    let mut missing_check = args.wait_missing.map(|interval| Instant::now() + interval);
+    loop {
+        if cancel::stopped() {
+            break;
+        }
+        // the first run is done, and not still going in the background
+        if args.once
+            && pipeline.session.exit_code.lock().unwrap().is_some()
+            && pipeline.session.stats.lock().unwrap().in_flight_total() == 0
+        {
+            break;
+        }
+        // the JSON reports stop when their reader is gone
+        let poll = (stop_on_exit || args.once || args.reporter == report::Mode::Json)
+            .then(|| Instant::now() + STOP_POLL);
+        let defer_poll = args
+            .defer_window
+            .as_ref()
+            .map(|_| Instant::now() + defer::POLL);
+        let deadline = [
+            debouncer.next_deadline(),
+            pipeline.next_deadline(),
+            poll,
+            defer_poll,
+            missing_check,
+            rewatch.next_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let res = match deadline {
+            Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match res {
+            Ok(Ok(event)) if control::is_simulated(&event) => {
+                // not debounced, like `simulate --local`
+                let events: Vec<debounce::DebouncedEvent> = event
+                    .paths
+                    .into_iter()
+                    .map(|path| debounce::DebouncedEvent::simulated(path, event.kind))
+                    .collect();
+                pipeline.process(&events, true, false);
+            }
+            Ok(Ok(event)) => {
+                let now = Instant::now();
+                // the late events of the self-test
+                for path in event
+                    .paths
+                    .into_iter()
+                    .filter(|p| !(args.self_test && watch::is_sentinel(p)))
+                {
+                    rewatch.event(&path, &event.kind, now);
+                    debouncer.add(path, event.kind, now);
+                }
+            }
+            Ok(Err(e)) => infoln!("Error {:?}", e),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        let now = Instant::now();
+        for path in rewatch.retry(&mut watcher, now) {
+            // merged with the event that replaced it, so it runs once
+            debouncer.add(
+                path,
+                notify::EventKind::Modify(notify::event::ModifyKind::Any),
+                now,
+            );
+        }
+        if let Some(interval) = args.wait_missing {
+            if missing_check.is_some_and(|at| Instant::now() >= at) {
+                retry_missing(&mut watching, &mut missing, args.verbose, |path| {
+                    watch_path(&args, &pipeline.filter, &mut rwatch, &mut watcher, path)
+                });
+                missing_check = Some(Instant::now() + interval);
+            }
+        }
+        pipeline.release(Instant::now());
+        pipeline.flush_deferred();
+        let mut events = debouncer.take_ready(Instant::now());
+        if args.once && pipeline.session.ran() {
+            // only the first change is run for
+            events.clear();
+        }
+        let mut follow_up = false;
+        while !events.is_empty() {
+            for event in &events {
+                let recursive = args.recursive
+                    || pipeline
+                        .filter
+                        .globs
+                        .iter()
+                        .any(|g| g.recursive && event.path.starts_with(&g.root));
+                if recursive {
+                    rwatch.update(&mut watcher, &event.path);
+                }
+            }
+            pipeline.process(&events, false, follow_up);
+            // the commands run in the background with these, so there's
+            // no waiting for them
+            if args.r#async || args.restart || args.once || args.on_busy == debounce::OnBusy::Queue
+            {
+                break;
+            }
+            // events received while the commands ran
+            let busy: Vec<notify::Event> = rx.try_iter().filter_map(Result::ok).collect();
+            events = match args.on_busy {
+                debounce::OnBusy::Drop => {
+                    if args.verbose && !busy.is_empty() {
+                        infoln!(
+                            "{}: {} events received while running",
+                            "Dropped".dimmed(),
+                            busy.len()
+                        );
+                    }
+                    vec![]
+                }
+                _ => debounce::coalesce(busy),
+            };
+            follow_up = true;
+        }
+    }
+    pipeline.session.cancel.cancel();
+    let running = pipeline.session.running.lock().unwrap().clone();
+    for (pid, container) in running {
+        exec::terminate(pid, container.as_ref(), args.restart_grace, || {
+            pipeline
+                .session
+                .running
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|r| r.0 == pid)
+        });
+    }
+    if let Some(webhook) = &pipeline.session.webhook {
+        webhook.finish();
+    }
+    if args.stats {
+        output::report(Report::Summary(
+            pipeline.session.stats.lock().unwrap().summary(),
+        ));
+        output::flush();
+    }
+    let code = *pipeline.session.exit_code.lock().unwrap();
+    if let (true, Some(code)) = (args.once, code) {
+        // the exit skips the drops
+        drop(rundir);
+        drop(watched_file);
+        output::flush();
+        std::process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Arguments of the command line, without the program name
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("onchange").chain(args.iter().copied())).unwrap()
+    }
+
+    /// Pipeline for the arguments like `main` makes it, without the
+    /// watcher; the informational lines are only logged
+    fn pipeline<'a>(args: &'a Cli, rules: &'a Rules, session: Session) -> Pipeline<'a> {
+        output::set_notify_stream(output::NotifyStream::None);
+        let cwd = env::current_dir().unwrap();
+        Pipeline {
+            args,
+            rules,
+            session: Arc::new(session),
+            filter: filter::Filter {
+                only: args.only.clone(),
+                ignore_hidden: args.ignore_hidden,
+                ignore: args.ignore.clone(),
+                editor_files: vec![],
+                fresh_within: args.fresh_within,
+                cwd: cwd.clone(),
+                roots: args.watch.iter().map(|p| cwd.join(p)).collect(),
+                globs: vec![],
+            },
+            cwd,
+            cmd_templ: Some(args.command.join(" "))
+                .filter(|c| !c.is_empty())
+                .map(Template::new),
+            cng_templ: Some(&args.template)
+                .filter(|t| !t.is_empty())
+                .map(Template::new),
+            held: Mutex::new(HashMap::new()),
+            static_vars: HashMap::new(),
+            recent: Mutex::new(HashMap::new()),
+            flushing: AtomicBool::new(false),
+            unmatched: Mutex::new(HashSet::new()),
+            awaited: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Empty directory for the test's files
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("onchange-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Debounced event of the path with the kinds
+    fn event(path: &Path, kinds: &[debounce::Kind]) -> debounce::DebouncedEvent {
+        debounce::DebouncedEvent {
+            path: path.to_path_buf(),
+            kinds: kinds.iter().map(|k| k.event_kind()).collect(),
+            synthetic: false,
+        }
+    }
+
+    /// Content of the file the test's commands write to, empty if
+    /// they haven't
+    fn read(file: &Path) -> String {
+        std::fs::read_to_string(file).unwrap_or_default()
+    }
+
+    /// Events of a build writing the files over and over, as
+    /// `(path, sequence)`
+    fn burst(events: usize, files: usize) -> Vec<(PathBuf, usize)> {
+        (0..events)
+            .map(|i| {
+                (
+                    PathBuf::from(format!("/project/target/f{}.o", i % files)),
+                    i,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn events_of_a_path_stay_in_order() {
+        let events = burst(20_000, 300);
+        let seen: Mutex<HashMap<PathBuf, Vec<usize>>> = Mutex::new(HashMap::new());
+        let results = by_path(
+            &events,
+            4,
+            |(path, _)| path,
+            |(path, seq)| {
+                seen.lock()
+                    .unwrap()
+                    .entry(path.clone())
+                    .or_default()
+                    .push(*seq);
+                *seq
+            },
+        );
+        assert_eq!(results, (0..events.len()).collect::<Vec<_>>());
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen.len(), 300);
+        for seqs in seen.values() {
+            assert!(seqs.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn threads_filter_like_one() {
+        let rules = config::rules_from_toml(
+            "[md]\nextensions = \"md\"\ncommand = \"pandoc {name.ext}\"\n\
+             [rs]\nextensions = \"rs\"\n",
+        )
+        .unwrap();
+        // a build writing its files among the sources
+        let events: Vec<debounce::DebouncedEvent> = (0..20_000)
+            .map(|i| {
+                let file = match i % 5 {
+                    0 => format!("/project/src/f{}.rs", i % 300),
+                    1 => format!("/project/notes/n{}.md", i % 200),
+                    2 => format!("/project/.git/objects/o{}", i % 500),
+                    3 => format!("/project/target/f{}.tmp", i % 500),
+                    _ => format!("/project/target/f{}.o", i % 500),
+                };
+                event(Path::new(&file), &[debounce::Kind::Modify])
+            })
+            .collect();
+        let admitted = |threads: &str| {
+            let args = cli(&[
+                "--pipeline-threads",
+                threads,
+                "-i",
+                "**/*.tmp",
+                "-i",
+                "**/.git/**",
+                "/project",
+            ]);
+            let pipeline = pipeline(&args, &rules, Session::default());
+            pipeline
+                .admitted(&events, false)
+                .iter()
+                .map(|a| a.map(|rule| rule.map(|r| r.name.clone())))
+                .collect::<Vec<_>>()
+        };
+        let one = admitted("1");
+        assert_eq!(one.iter().filter(|a| a.is_none()).count(), 8_000);
+        assert_eq!(admitted("4"), one);
+    }
+
+    #[test]
+    fn new_files_run_from_their_second_change() {
+        let dir = temp_dir("confirm");
+        let (new, old, runs) = (dir.join("new.md"), dir.join("old.md"), dir.join("runs"));
+        let state_file = dir.join("state.json");
+        std::fs::write(&old, "").unwrap();
+        let args = cli(&[
+            "--confirm-new-files",
+            dir.to_str().unwrap(),
+            "--",
+            &format!("echo {{name}} >> {}", runs.display()),
+        ]);
+        let rules = Rules::default();
+        let session = || Session {
+            state: Mutex::new(StateStore::with_file(state_file.clone()).unwrap()),
+            ..Default::default()
+        };
+        let pipeline = pipeline(&args, &rules, session());
+        std::fs::write(&new, "").unwrap();
+        pipeline.process(&[event(&new, &[debounce::Kind::Create])], false, false);
+        assert_eq!(read(&runs), "");
+        pipeline.process(&[event(&new, &[debounce::Kind::Modify])], false, false);
+        assert_eq!(read(&runs), "new\n");
+        // after a restart, the atomic save of an old file isn't new
+        drop(pipeline);
+        let pipeline = self::pipeline(&args, &rules, session());
+        let save = [debounce::Kind::Remove, debounce::Kind::Create];
+        pipeline.process(&[event(&old, &save)], false, false);
+        pipeline.process(&[event(&new, &save)], false, false);
+        assert_eq!(read(&runs), "new\nold\nnew\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn previous_run_of_the_path() {
+        let dir = temp_dir("last-run");
+        let (file, ok, runs) = (dir.join("a.md"), dir.join("ok"), dir.join("runs"));
+        std::fs::write(&file, "").unwrap();
+        let args = cli(&[
+            dir.to_str().unwrap(),
+            "--",
+            &format!(
+                "echo '{{last_exit}}|{{last_run_at}}|{{consecutive_failures}}' >> {}; test -e {}",
+                runs.display(),
+                ok.display()
+            ),
+        ]);
+        let rules = Rules::default();
+        let session = || Session {
+            state: Mutex::new(StateStore::with_file(dir.join("state.json")).unwrap()),
+            ..Default::default()
+        };
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let pipeline = pipeline(&args, &rules, session());
+        let modify = [event(&file, &[debounce::Kind::Modify])];
+        pipeline.process(&modify, false, false);
+        pipeline.process(&modify, false, false);
+        std::fs::write(&ok, "").unwrap();
+        // the streak is kept across the restart
+        drop(pipeline);
+        let pipeline = self::pipeline(&args, &rules, session());
+        pipeline.process(&modify, false, false);
+        pipeline.process(&modify, false, false);
+        let runs = read(&runs);
+        let runs: Vec<Vec<&str>> = runs.lines().map(|l| l.split('|').collect()).collect();
+        let exits: Vec<(&str, &str)> = runs.iter().map(|r| (r[0], r[2])).collect();
+        assert_eq!(exits, [("", "0"), ("1", "1"), ("1", "2"), ("0", "0")]);
+        assert_eq!(runs[0][1], "");
+        for run in &runs[1..] {
+            let at = humantime::parse_rfc3339(run[1]).unwrap();
+            assert!(at >= before && at <= SystemTime::now());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn first_run_of_the_rule() {
+        let dir = temp_dir("first-run");
+        let (a, b, runs) = (dir.join("a.md"), dir.join("b.md"), dir.join("runs"));
+        let rules = config::rules_from_toml(&format!(
+            "[md]\nextensions = \"md\"\n\
+             first_command = \"echo clean {{name}} {{first_run}} >> {0}\"\n\
+             command = \"echo build {{name}} {{first_run}} >> {0}\"\n",
+            runs.display()
+        ))
+        .unwrap();
+        let args = cli(&[dir.to_str().unwrap()]);
+        let pipeline = pipeline(&args, &rules, Session::default());
+        for file in [&a, &a, &b] {
+            pipeline.process(&[event(file, &[debounce::Kind::Modify])], false, false);
+        }
+        assert_eq!(read(&runs), "clean a true\nbuild a false\nbuild b false\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bell_on_the_failed_runs() {
+        let dir = temp_dir("bell");
+        let file = dir.join("a.md");
+        let bells = |flags: &[&str], command: &str| {
+            let args = cli(&[flags, &[dir.to_str().unwrap(), "--", command]].concat());
+            let rules = Rules::default();
+            let pipeline = pipeline(&args, &rules, Session::default());
+            let captured = output::capture(output::NotifyStream::Stdout, || {
+                pipeline.process(&[event(&file, &[debounce::Kind::Modify])], false, false);
+            });
+            captured.stdout.matches('\x07').count()
+        };
+        assert_eq!(bells(&["--bell"], "false"), 1);
+        assert_eq!(bells(&["--bell"], "true"), 0);
+        assert_eq!(bells(&["--bell=always"], "true"), 1);
+        assert_eq!(bells(&[], "false"), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn variables_command_output() {
+        let output = b"\xef\xbb\xbfversion: 1.2\r\n\
+            branch:\tmain\t\r\n\
+            title: \"  spaced  \"\n\
+            bad key: x\n\
+            no colon here\n\
+            branch: release\n\
+            bytes: caf\xe9";
+        let mut map = HashMap::new();
+        read_var_lines(&output[..], 100, false, &mut map);
+        let mut vars: Vec<(&str, &str)> =
+            map.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        vars.sort();
+        assert_eq!(
+            vars,
+            [
+                ("branch", "release"),
+                ("bytes", "caf\u{fffd}"),
+                ("title", "  spaced  "),
+                ("version", "1.2"),
+            ]
+        );
+        // cut on the char boundary
+        read_var_lines("long: abcd\u{e9}".as_bytes(), 5, false, &mut map);
+        assert_eq!(map["long"], "abcd");
+    }
+
+    #[test]
+    fn bad_durations_name_the_flag() {
+        for (flag, value) in [("--duration", "soon"), ("--timeout", "5 parsecs")] {
+            let err = Cli::try_parse_from(["onchange", flag, value, "."])
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(err.contains(flag) && err.contains(value), "{}", err);
+        }
+        let cli = Cli::try_parse_from(["onchange", "--duration", "1m30s", "."]).unwrap();
+        assert_eq!(cli.duration, Duration::from_secs(90));
+    }
+
+    #[test]
+    fn default_command_precedence() {
+        let dir = temp_dir("default-command");
+        let runs = dir.join("runs");
+        let rules = config::rules_from_toml(&format!(
+            "[global]\ndefault_command = \"echo default {{rule}} {{name}} >> {0}\"\n\
+             [md]\nextensions = \"md\"\ncommand = \"echo md {{rule}} {{name}} >> {0}\"\n",
+            runs.display()
+        ))
+        .unwrap();
+        let events: Vec<debounce::DebouncedEvent> = ["a.md", "b.txt", "c.log"]
+            .iter()
+            .map(|f| event(&dir.join(f), &[debounce::Kind::Modify]))
+            .collect();
+        let run = |args: &[&str]| {
+            std::fs::remove_file(&runs).ok();
+            let args = cli(&[&["--ignore", "**/*.log", dir.to_str().unwrap()], args].concat());
+            let pipeline = pipeline(&args, &rules, Session::default());
+            pipeline.process(&events, false, false);
+            read(&runs)
+        };
+        // the rule's command, then the default one for the rest;
+        // never for the ignored files
+        assert_eq!(run(&[]), "md md a\ndefault default b\n");
+        // the command given wins over both
+        let cli_command = format!("echo cli [{{rule}}] {{name}} >> {}", runs.display());
+        assert_eq!(run(&["--", &cli_command]), "cli [md] a\ncli [] b\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn notify_stream_split() {
+        colored::control::set_override(false);
+        let dir = temp_dir("notify-stream");
+        let file = dir.join("a.md");
+        let streams = |stream: &str| {
+            let args = cli(&[
+                "--notify-stream",
+                stream,
+                "--max-output",
+                "1MB",
+                "-t",
+                "{name}",
+                dir.to_str().unwrap(),
+                "--",
+                "echo out; echo err >&2",
+            ]);
+            let rules = Rules::default();
+            let pipeline = pipeline(&args, &rules, Session::default());
+            let captured = output::capture(args.notify_stream, || {
+                pipeline.process(&[event(&file, &[debounce::Kind::Modify])], false, false);
+            });
+            // the duration varies
+            let done = regex::Regex::new(r"Done: ok in \S+").unwrap();
+            (
+                done.replace(&captured.stdout, "Done").to_string(),
+                done.replace(&captured.stderr, "Done").to_string(),
+            )
+        };
+        let (run, done) = ("Changed: a\nRun: echo out; echo err >&2\n", "Done\n");
+        // the command's output stays on stdout
+        assert_eq!(
+            streams("stderr"),
+            ("out\nerr\n".to_string(), format!("{}{}", run, done))
+        );
+        assert_eq!(
+            streams("stdout"),
+            (format!("{}out\nerr\n{}", run, done), String::new())
+        );
+        assert_eq!(streams("none"), ("out\nerr\n".to_string(), String::new()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn direct_children_by_dir_events() {
+        use debounce::Kind;
+        let dir = temp_dir("dir-events");
+        let watched = dir.join("watched");
+        std::fs::create_dir_all(watched.join("sub")).unwrap();
+        let runs = dir.join("runs");
+        for file in ["new.txt", "old.txt", "sub/deep.txt", "notes.md"] {
+            std::fs::write(watched.join(file), "").unwrap();
+        }
+        let events = [
+            event(&watched.join("new.txt"), &[Kind::Create]),
+            event(&watched.join("old.txt"), &[Kind::Modify]),
+            event(&watched.join("gone.txt"), &[Kind::Remove]),
+            event(&watched.join("sub/deep.txt"), &[Kind::Modify]),
+            event(&watched.join("notes.md"), &[Kind::Modify]),
+        ];
+        let command = format!("echo {{name}} >> {}", runs.display());
+        // the rule's setting wins over the command line's
+        let rules = config::rules_from_toml(&format!(
+            "[md]\nextensions = \"md\"\ncommand = \"{}\"\ndir_events = \"writes\"\n\
+             [txt]\nextensions = \"txt\"\ncommand = \"{}\"\n",
+            command, command
+        ))
+        .unwrap();
+        let run = |setting: &str| {
+            std::fs::remove_file(&runs).ok();
+            let args = cli(&["--dir-events", setting, watched.to_str().unwrap()]);
+            let pipeline = pipeline(&args, &rules, Session::default());
+            pipeline.process(&events, false, false);
+            read(&runs)
+        };
+        assert_eq!(run("entries"), "new\ngone\nnotes\n");
+        assert_eq!(run("writes"), "old\nnotes\n");
+        assert_eq!(run("all"), "new\nold\ngone\ndeep\nnotes\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rules_with_their_own_debounce() {
+        let dir = temp_dir("rule-debounce");
+        let runs = dir.join("runs");
+        let rules = config::rules_from_toml(&format!(
+            "[tex]\nextensions = \"tex\"\ncommand = \"echo {{name}} >> {0}\"\ndebounce = \"300ms\"\n\
+             [rs]\nextensions = \"rs\"\ncommand = \"echo {{name}} >> {0}\"\n",
+            runs.display()
+        ))
+        .unwrap();
+        let args = cli(&[dir.to_str().unwrap()]);
+        let pipeline = pipeline(&args, &rules, Session::default());
+        let count = |name: &str| read(&runs).lines().filter(|l| *l == name).count();
+        let burst = |files: &[&str]| {
+            for _ in 0..5 {
+                for file in files {
+                    let event = event(&dir.join(file), &[debounce::Kind::Modify]);
+                    pipeline.process(&[event], false, false);
+                }
+                thread::sleep(Duration::from_millis(50));
+                pipeline.release(Instant::now());
+            }
+        };
+        let wait_quiet = || {
+            while let Some(deadline) = pipeline.next_deadline() {
+                thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                pipeline.release(Instant::now());
+            }
+        };
+        burst(&["main.tex", "lib.rs"]);
+        // the tex rule is still waiting for the quiet
+        assert_eq!((count("main"), count("lib")), (0, 5));
+        wait_quiet();
+        assert_eq!((count("main"), count("lib")), (1, 5));
+        // each file has its own quiet period
+        burst(&["main.tex", "ch1.tex"]);
+        wait_quiet();
+        assert_eq!((count("main"), count("ch1")), (2, 1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn aliases_render_like_the_variables() {
+        let rules =
+            config::rules_from_toml("[aliases.vars]\nfile = \"name.ext\"\nfolder = \"dir\"\n")
+                .unwrap();
+        let render = |map: &HashMap<String, String>, templ: &str| {
+            exec::render_shell(
+                templ,
+                &config::variable_regex(None),
+                map,
+                exec::AutoQuote::Fix,
+            )
+        };
+        let aliased = "cp {filename} {basename} {dirname}/{stem}.bak {file} {folder}";
+        let canonical = "cp {name.ext} {name.ext} {dir}/{name}.bak {name.ext} {dir}";
+        let path = Path::new("/project/my notes/a b.md");
+        let map = file_vars(path, Path::new("/project"), &rules);
+        assert_eq!(render(&map, aliased), render(&map, canonical));
+        assert_eq!(
+            render(&map, aliased),
+            "cp 'a b.md' 'a b.md' '/project/my notes'/'a b'.bak 'a b.md' '/project/my notes'"
+        );
+        // the aliases follow the variables command's values too
+        let args = cli(&["--variables-command", "echo name.ext: c.md", "."]);
+        let map = template_vars(
+            path,
+            &[],
+            Path::new("/project"),
+            &rules,
+            &args,
+            &cancel::Token::default(),
+        );
+        assert_eq!(render(&map, aliased), render(&map, canonical));
+        assert_eq!(map["filename"], "c.md");
+        assert_eq!(map["file"], "c.md");
+    }
+
+    #[test]
+    fn overlapping_watches_run_once() {
+        use notify::{RecursiveMode, Watcher};
+        let dir = temp_dir("overlap");
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::os::unix::fs::symlink(&src, dir.join("link")).unwrap();
+        let (file, runs) = (src.join("main.rs"), dir.join("runs"));
+        std::fs::write(&file, "").unwrap();
+        // the directory, and the file again through the link
+        let also = dir.join("link/main.rs");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).unwrap();
+        watcher.watch(&src, RecursiveMode::Recursive).unwrap();
+        watcher.watch(&also, RecursiveMode::NonRecursive).unwrap();
+        std::fs::write(&file, "changed").unwrap();
+        let mut paths = BTreeSet::new();
+        while let Ok(Ok(event)) = rx.recv_timeout(Duration::from_millis(300)) {
+            paths.extend(event.paths);
+        }
+        assert_eq!(paths, BTreeSet::from([also.clone(), file.clone()]));
+
+        let args = cli(&[
+            src.to_str().unwrap(),
+            also.to_str().unwrap(),
+            "--",
+            &format!("echo {{path}} >> {}", runs.display()),
+        ]);
+        let rules = Rules::default();
+        let pipeline = pipeline(&args, &rules, Session::default());
+        let events: Vec<debounce::DebouncedEvent> = paths
+            .iter()
+            .map(|p| event(p, &[debounce::Kind::Modify]))
+            .collect();
+        pipeline.process(&events, false, false);
+        assert_eq!(read(&runs).lines().count(), 1);
+        // the other one coming in the next batch is merged too
+        pipeline.process(&events[1..], false, false);
+        assert_eq!(read(&runs).lines().count(), 1);
+        // but not once the merge window is over
+        thread::sleep(MERGE_WINDOW);
+        pipeline.process(&events[1..], false, false);
+        assert_eq!(read(&runs).lines().count(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn braces_for_other_tools() {
+        let dir = temp_dir("delimiters");
+        let runs = dir.join("runs");
+        let rules = config::rules_from_toml(&format!(
+            r#"[json]
+extensions = "json"
+template_delimiters = ["<%", "%>"]
+command = '''printf '%s\n' <%name%> "$(echo '{{"b": 1}}' | sed 's/b/a/')" '{{a: .b}}' >> {0}'''
+[ci]
+extensions = "yml"
+template_delimiters = ["[[", "]]"]
+command = '''printf '%s\n' [[name.ext]] '${{{{ matrix.os }}}}' '{{name}}' >> {0}'''
+"#,
+            runs.display()
+        ))
+        .unwrap();
+        let args = cli(&[dir.to_str().unwrap()]);
+        let pipeline = pipeline(&args, &rules, Session::default());
+        for file in ["data.json", "build.yml"] {
+            let event = event(&dir.join(file), &[debounce::Kind::Modify]);
+            pipeline.process(&[event], false, false);
+        }
+        assert_eq!(
+            read(&runs),
+            "data\n{\"a\": 1}\n{a: .b}\nbuild.yml\n${{ matrix.os }}\n{name}\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested_rewrites() {
+        let rules = config::rules_from_toml(
+            "[rewrites]\n\
+             gen = { from = \"mirror/gen\", to = \"generated\" }\n\
+             src = { from = \"mirror\", to = \"src\" }\n\
+             system = { from = \"/opt/mirror\", to = \"/opt/src\" }\n",
+        )
+        .unwrap();
+        let args = cli(&["."]);
+        let rewrites = |path: &str| {
+            let map = template_vars(
+                Path::new(path),
+                &[],
+                Path::new("/project"),
+                &rules,
+                &args,
+                &cancel::Token::default(),
+            );
+            Template::new("{rewrite.gen}|{rewrite.src}|{rewrite.system}|{srcpath}")
+                .render_nofail_string(&map)
+        };
+        assert_eq!(
+            rewrites("/project/mirror/gen/a.rs"),
+            "generated/a.rs|src/gen/a.rs||generated/a.rs"
+        );
+        assert_eq!(rewrites("/project/mirror/b.rs"), "|src/b.rs||src/b.rs");
+        assert_eq!(rewrites("/project/mirror"), "|src||src");
+        assert_eq!(
+            rewrites("/opt/mirror/lib/c.rs"),
+            "||/opt/src/lib/c.rs|/opt/src/lib/c.rs"
+        );
+        // only the whole components are prefixes
+        assert_eq!(rewrites("/project/mirrored/d.rs"), "|||");
+        assert_eq!(rewrites("/project/other/mirror/e.rs"), "|||");
+    }
+
+    #[test]
+    fn batch_counts_by_kind() {
+        use debounce::Kind;
+        let dir = temp_dir("batch-kinds");
+        let (old, new, gone) = (dir.join("old.rs"), dir.join("new.md"), dir.join("gone.rs"));
+        std::fs::write(&old, "").unwrap();
+        std::fs::write(&new, "").unwrap();
+        let events = [
+            event(&old, &[Kind::Modify]),
+            event(&new, &[Kind::Create, Kind::Modify]),
+            event(&gone, &[Kind::Modify, Kind::Remove]),
+            // atomic save of an existing file
+            event(&old, &[Kind::Remove, Kind::Create]),
+        ];
+        let kinds: HashMap<&Path, Kind> = events
+            .iter()
+            .map(|e| (e.path.as_path(), Kind::net(&e.path, &e.kinds)))
+            .collect();
+        let paths: Vec<&Path> = events.iter().map(|e| e.path.as_path()).collect();
+        let vars = batch_vars(&paths, &kinds, std::slice::from_ref(&dir));
+        let counts: Vec<&str> = ["count", "created", "modified", "removed", "renamed"]
+            .iter()
+            .map(|k| vars[&format!("batch.{}", k)].as_str())
+            .collect();
+        assert_eq!(counts, ["3", "1", "1", "1", "0"]);
+        assert_eq!(vars["batch.exts"], "md,rs");
+        assert_eq!(vars["batch.roots"], dir.to_string_lossy());
+        // the chunk's counts are of its own paths
+        let vars = batch_vars(&[&gone], &kinds, std::slice::from_ref(&dir));
+        assert_eq!(
+            (vars["batch.count"].as_str(), vars["batch.removed"].as_str()),
+            ("1", "1")
+        );
+        assert_eq!(vars["batch.created"], "0");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mixed_batch_by_rule_mode() {
+        let dir = temp_dir("mixed-batch");
+        let runs = dir.join("runs");
+        let rules = config::rules_from_toml(&format!(
+            "[md]\nextensions = \"md\"\ncommand = \"echo md {{name.ext}} >> {0}\"\nmode = \"per-file\"\n\
+             [rs]\nextensions = \"rs\"\ncommand = \"echo rs {{count}} {{paths}} >> {0}\"\nmode = \"batch\"\n\
+             [txt]\nextensions = \"txt\"\ncommand = \"echo txt {{count}} {{name.ext}} >> {0}\"\n",
+            runs.display()
+        ))
+        .unwrap();
+        // the rules' events come interleaved in the batch
+        let events: Vec<debounce::DebouncedEvent> =
+            ["a.md", "x.rs", "a.txt", "b.md", "y.rs", "b.txt", "z.rs"]
+                .iter()
+                .map(|f| {
+                    std::fs::write(dir.join(f), "").unwrap();
+                    event(&dir.join(f), &[debounce::Kind::Modify])
+                })
+                .collect();
+        let run = |flags: &[&str]| {
+            std::fs::remove_file(&runs).ok();
+            let args = cli(&[flags, &[dir.to_str().unwrap()]].concat());
+            let pipeline = pipeline(&args, &rules, Session::default());
+            pipeline.process(&events, false, false);
+            let mut lines: Vec<String> = read(&runs)
+                .replace(&format!("{}/", dir.display()), "")
+                .lines()
+                .map(String::from)
+                .collect();
+            lines.sort();
+            lines
+        };
+        // the md files run one by one and the rs files once together,
+        // the txt files without a mode follow --batch
+        assert_eq!(
+            run(&[]),
+            [
+                "md a.md",
+                "md b.md",
+                "rs 3 x.rs y.rs z.rs",
+                "txt 1 a.txt",
+                "txt 1 b.txt"
+            ]
+        );
+        assert_eq!(
+            run(&["--batch"]),
+            ["md a.md", "md b.md", "rs 3 x.rs y.rs z.rs", "txt 2 a.txt"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Watcher that can't watch the paths named `locked`
+    struct Locked;
+
+    impl notify::Watcher for Locked {
+        fn new<F: notify::EventHandler>(_: F, _: notify::Config) -> notify::Result<Self> {
+            Ok(Self)
+        }
+
+        fn watch(&mut self, path: &Path, _: notify::RecursiveMode) -> notify::Result<()> {
+            match path.ends_with("locked") {
+                true => Err(notify::Error::generic("permission denied")),
+                false => Ok(()),
+            }
+        }
+
+        fn unwatch(&mut self, _: &Path) -> notify::Result<()> {
+            Ok(())
+        }
+
+        fn kind() -> notify::WatcherKind {
+            notify::WatcherKind::NullWatcher
+        }
+    }
+
+    #[test]
+    fn startup_watch_report() {
+        let dir = temp_dir("startup");
+        for sub in ["a", "b", "locked"] {
+            std::fs::create_dir(dir.join(sub)).unwrap();
+        }
+        let rules = Rules::default();
+        let report = |flags: &[&str], paths: &[&str], notify: output::NotifyStream| {
+            let paths: Vec<String> = paths
+                .iter()
+                .map(|p| dir.join(p).to_string_lossy().into_owned())
+                .collect();
+            let paths: Vec<&str> = paths.iter().map(|p| p.as_str()).collect();
+            let args = cli(&[flags, &paths].concat());
+            let pipeline = pipeline(&args, &rules, Session::default());
+            let mut rwatch = watch::RecursiveWatch::new(args.exclude_dir.clone());
+            let mut watched = 0;
+            let captured = output::capture(notify, || {
+                let (watching, _) = watch_all(&args, &pipeline.filter, &mut rwatch, &mut Locked);
+                watched = watching.len();
+            });
+            let prefix = format!("{}/", dir.display());
+            (
+                watched,
+                captured.stdout.replace(&prefix, ""),
+                captured.stderr.replace(&prefix, ""),
+            )
+        };
+        use output::NotifyStream::{None as Quiet, Stdout};
+        assert_eq!(
+            report(&[], &["a", "b"], Stdout),
+            (2, "Watching: \"a\" \"b\"\n".into(), "".into())
+        );
+        // the failed path doesn't cut the report short
+        assert_eq!(
+            report(&[], &["a", "locked", "b"], Stdout),
+            (
+                2,
+                "Watching: \"a\" \"b\"\nNot watching: \"locked\" (permission denied)\n".into(),
+                "".into()
+            )
+        );
+        assert_eq!(
+            report(&[], &["locked"], Stdout),
+            (
+                0,
+                "Not watching: \"locked\" (permission denied)\n\
+                 Error: none of the paths could be watched\n"
+                    .into(),
+                "".into()
+            )
+        );
+        // nothing is shown, but the paths are watched all the same
+        assert_eq!(
+            report(&[], &["a", "locked", "b"], Quiet),
+            (2, "".into(), "".into())
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn commands_by_kind() {
+        use debounce::Kind;
+        let dir = temp_dir("kind-commands");
+        let runs = dir.join("runs");
+        let log = |what: &str| format!("\"echo {} {{name.ext}} >> {}\"", what, runs.display());
+        let rules = config::rules_from_toml(&format!(
+            "[md]\nextensions = \"md\"\n\
+             command = {{ create = {}, modify = {}, remove = {}, rename = {} }}\n\
+             [txt]\nextensions = \"txt\"\n\
+             command = {{ remove = {}, any = {} }}\n\
+             [rs]\nextensions = \"rs\"\ncommand = {}\n\
+             [log]\nextensions = \"log\"\ncommand = {{ create = {} }}\n",
+            log("created"),
+            log("modified"),
+            log("removed"),
+            log("renamed"),
+            log("gone"),
+            log("other"),
+            log("all"),
+            log("new"),
+        ))
+        .unwrap();
+        let run = |file: &str, kind: Kind| {
+            std::fs::remove_file(&runs).ok();
+            let path = dir.join(file);
+            if kind == Kind::Remove {
+                std::fs::remove_file(&path).ok();
+            } else {
+                std::fs::write(&path, "").unwrap();
+            }
+            let args = cli(&[dir.to_str().unwrap()]);
+            let pipeline = pipeline(&args, &rules, Session::default());
+            pipeline.process(&[event(&path, &[kind])], false, false);
+            read(&runs).trim().to_string()
+        };
+        let kinds = [Kind::Create, Kind::Modify, Kind::Remove, Kind::Rename];
+        let each = |file: &str| kinds.map(|k| run(file, k));
+        assert_eq!(
+            each("a.md"),
+            [
+                "created a.md",
+                "modified a.md",
+                "removed a.md",
+                "renamed a.md"
+            ]
+        );
+        // the kinds without their own command use `any`
+        assert_eq!(
+            each("a.txt"),
+            ["other a.txt", "other a.txt", "gone a.txt", "other a.txt"]
+        );
+        // a plain command is for all of them
+        assert_eq!(each("a.rs"), ["all a.rs"; 4]);
+        // and without `any` the other kinds run nothing
+        assert_eq!(each("a.log"), ["new a.log", "", "", ""]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn in_flight_during_the_run() {
+        let dir = temp_dir("in-flight");
+        let (file, runs) = (dir.join("a.md"), dir.join("runs"));
+        let rules = config::rules_from_toml(&format!(
+            "[md]\nextensions = \"md\"\n\
+             command = \"echo {{in_flight}} {{in_flight_count}} >> {}; sleep 1\"\n",
+            runs.display()
+        ))
+        .unwrap();
+        let args = cli(&["--async", dir.to_str().unwrap()]);
+        let pipeline = pipeline(&args, &rules, Session::default());
+        let in_flight = || pipeline.session.stats.lock().unwrap().in_flight("md");
+        let change = || pipeline.process(&[event(&file, &[debounce::Kind::Modify])], false, false);
+        change();
+        assert_eq!(in_flight(), 1);
+        // changed again while the first one sleeps
+        change();
+        assert_eq!(in_flight(), 2);
+        let wait = || {
+            let start = Instant::now();
+            while in_flight() > 0 {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(20));
+            }
+        };
+        wait();
+        change();
+        wait();
+        // the first two runs may write in either order
+        let runs = read(&runs);
+        let mut lines: Vec<&str> = runs.lines().collect();
+        lines[..2].sort();
+        assert_eq!(lines, ["false 0", "true 1", "false 0"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn initial_run_of_the_recent_files() {
+        let dir = temp_dir("changed-within");
+        let runs = dir.join("runs");
+        let now = SystemTime::now();
+        let ago = |secs: u64| filetime::FileTime::from_system_time(now - Duration::from_secs(secs));
+        let files: Vec<PathBuf> = [("new.md", 60), ("day.md", 20 * 3600), ("old.md", 3 * 86400)]
+            .iter()
+            .map(|(name, age)| {
+                let file = dir.join(name);
+                std::fs::write(&file, "").unwrap();
+                filetime::set_file_mtime(&file, ago(*age)).unwrap();
+                file
+            })
+            .collect();
+        let command = format!("echo {{name.ext}} >> {}", runs.display());
+        let run = |flags: &[&str], live: bool| {
+            std::fs::remove_file(&runs).ok();
+            let args = cli(&[flags, &[dir.to_str().unwrap(), "--", &command]].concat());
+            let rules = Rules::default();
+            let pipeline = pipeline(&args, &rules, Session::default());
+            if live {
+                let events: Vec<debounce::DebouncedEvent> = files
+                    .iter()
+                    .map(|f| event(f, &[debounce::Kind::Modify]))
+                    .collect();
+                pipeline.process(&events, false, false);
+            } else {
+                pipeline.trial(&files);
+            }
+            let mut ran: Vec<String> = read(&runs).lines().map(String::from).collect();
+            ran.sort();
+            ran
+        };
+        assert_eq!(run(&["-1"], false), ["day.md", "new.md", "old.md"]);
+        assert_eq!(
+            run(&["-1", "--changed-within", "1d"], false),
+            ["day.md", "new.md"]
+        );
+        assert_eq!(run(&["-1", "--changed-within", "1h"], false), ["new.md"]);
+        // the live events run whatever their age
+        assert_eq!(
+            run(&["-1", "--changed-within", "1h"], true),
+            ["day.md", "new.md", "old.md"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fallbacks_past_the_missing_programs() {
+        let dir = temp_dir("fallbacks");
+        let runs = dir.join("runs");
+        let log = |what: &str| format!("\"echo {} {{name.ext}} >> {}\"", what, runs.display());
+        let missing = "\"onchange-missing-formatter {name.ext}\"";
+        let rules = config::rules_from_toml(&format!(
+            "[py]\nextensions = \"py\"\ncommand_fallbacks = [{m}, {}, {}]\n\
+             [md]\nextensions = \"md\"\ncommand_fallbacks = [\"false\", {}]\n\
+             [rs]\nextensions = \"rs\"\ncommand_fallbacks = [{}, {}]\n\
+             [sh]\nextensions = \"sh\"\ncommand_fallbacks = [{m}, {m}]\n",
+            log("black"),
+            log("never"),
+            log("after-false"),
+            log("first"),
+            log("never"),
+            m = missing,
+        ))
+        .unwrap();
+        let args = cli(&[
+            "--done-template",
+            "{exit_name} {attempt}",
+            dir.to_str().unwrap(),
+        ]);
+        let run = |file: &str| {
+            std::fs::remove_file(&runs).ok();
+            let pipeline = pipeline(&args, &rules, Session::default());
+            let captured = output::capture(output::NotifyStream::Stdout, || {
+                pipeline.process(
+                    &[event(&dir.join(file), &[debounce::Kind::Modify])],
+                    false,
+                    false,
+                );
+            });
+            let done: Vec<String> = captured
+                .stdout
+                .lines()
+                .filter(|l| {
+                    l.starts_with("Done") || l.starts_with("Failed") || l.starts_with("Error")
+                })
+                .map(String::from)
+                .collect();
+            let code = *pipeline.session.exit_code.lock().unwrap();
+            (read(&runs), done, code)
+        };
+        // the missing program is skipped, the next one works
+        let (ran, done, _) = run("a.py");
+        assert_eq!(ran, "black a.py\n");
+        assert_eq!(done, ["Done: ok 2"]);
+        // a failure tries the next one too
+        let (ran, done, _) = run("a.md");
+        assert_eq!(ran, "after-false a.md\n");
+        assert_eq!(done, ["Done: ok 2"]);
+        let (ran, done, _) = run("a.rs");
+        assert_eq!(ran, "first a.rs\n");
+        assert_eq!(done, ["Done: ok 1"]);
+        // none of them could run, like a command that can't be started
+        let (ran, done, code) = run("a.sh");
+        assert_eq!(ran, "");
+        assert_eq!(done, ["Error: none of the command_fallbacks could be run"]);
+        assert_eq!(code, Some(127));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn action_of_each_outcome() {
+        let dir = temp_dir("actions");
+        let rules = config::rules_from_toml(
+            "[md]\nextensions = \"md\"\ncommand = \"true {name}\"\n\
+             [mk]\nextensions = \"mk\"\ncommand = \"true make\"\n\
+             [chk]\nextensions = \"chk\"\ncommand = \"true {name}\"\ncheck = \"false\"\n\
+             [ev]\nextensions = \"ev\"\ncommand = \"true {name}\"\nevents = [\"create\"]\n\
+             [aw]\nextensions = \"aw\"\ncommand = \"true {name}\"\nawait_set = [\"{dir}/{name}.other\"]\n\
+             await_timeout = \"50ms\"\nawait_policy = \"skip\"\n",
+        )
+        .unwrap();
+        let files = [
+            "a.md", "same.md", "x.mk", "y.mk", "a.chk", "a.ev", "a.aw", "a.txt", "a.log", "old.md",
+        ];
+        for file in files {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        let hours_ago = SystemTime::now() - Duration::from_secs(7200);
+        filetime::set_file_mtime(
+            dir.join("old.md"),
+            filetime::FileTime::from_system_time(hours_ago),
+        )
+        .unwrap();
+        let args = cli(&[
+            "--verbose",
+            "-t",
+            "{action} {name.ext}",
+            "--ignore",
+            "**/*.log",
+            "--fresh-within",
+            "1h",
+            dir.to_str().unwrap(),
+        ]);
+        let session = Session {
+            manifest: Some(Mutex::new(manifest::Manifest::load(&dir.join("manifest")))),
+            ..Default::default()
+        };
+        let pipeline = pipeline(&args, &rules, session);
+        let modified = |files: &[&str]| -> Vec<debounce::DebouncedEvent> {
+            files
+                .iter()
+                .map(|f| event(&dir.join(f), &[debounce::Kind::Modify]))
+                .collect()
+        };
+        let captured = output::capture(output::NotifyStream::Stdout, || {
+            pipeline.process(&modified(&files), false, false);
+            // the same content again
+            pipeline.process(&modified(&["same.md"]), false, false);
+            thread::sleep(Duration::from_millis(100));
+            pipeline.release(Instant::now());
+        });
+        let changed: Vec<&str> = captured
+            .stdout
+            .lines()
+            .filter_map(|l| l.strip_prefix("Changed: "))
+            .collect();
+        // the filtered ones come first, the held one once it's released
+        assert_eq!(
+            changed,
+            [
+                "skip:event a.ev",
+                "skip:ignored a.log",
+                "skip:stale old.md",
+                "run a.md",
+                "run same.md",
+                "run x.mk",
+                "skip:dedup y.mk",
+                "skip:check a.chk",
+                "skip:no-rule a.txt",
+                "skip:unchanged same.md",
+                "skip:await a.aw",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recreated_watched_file_keeps_its_state() {
+        use debounce::Kind;
+        let dir = temp_dir("recreated");
+        std::fs::create_dir(dir.join("real")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+        let file = dir.join("link/out.md");
+        let (runs, counts) = (dir.join("runs"), dir.join("counts"));
+        std::fs::write(&file, "").unwrap();
+        let args = cli(&[
+            file.to_str().unwrap(),
+            "--",
+            &format!(
+                "echo '{{rpath}}|{{dir}}|{{name.ext}}|{{rule}}' >> {}; \
+                 echo '{{file_count}} {{last_exit}}' >> {}",
+                runs.display(),
+                counts.display()
+            ),
+        ]);
+        let rules = Rules::default();
+        let session = Session {
+            watched_files: vec![(file.clone(), watch::canonical_path(&file))],
+            ..Default::default()
+        };
+        let pipeline = pipeline(&args, &rules, session);
+        let change = |kind: Kind| pipeline.process(&[event(&file, &[kind])], false, false);
+        change(Kind::Modify);
+        // the build removes it and writes it again
+        std::fs::remove_file(&file).unwrap();
+        change(Kind::Remove);
+        std::fs::write(&file, "x").unwrap();
+        change(Kind::Create);
+        change(Kind::Modify);
+        assert_eq!(read(&counts), "1 \n2 0\n3 0\n4 0\n");
+        let runs = read(&runs);
+        let commands: Vec<&str> = runs.lines().collect();
+        assert_eq!(commands.len(), 4);
+        assert!(commands.iter().all(|c| *c == commands[0]), "{:?}", commands);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn await_set_in_either_order() {
+        let dir = temp_dir("await-order");
+        let runs = dir.join("runs");
+        let rules = config::rules_from_toml(&format!(
+            "[data]\nextensions = \"json\"\ncommand = \"echo {{name.ext}} >> {runs}\"\n\
+             await_set = [\"{{dir}}/{{name}}.schema\"]\nawait_timeout = \"200ms\"\n\
+             [schema]\nextensions = \"schema\"\ncommand = \"echo {{name.ext}} >> {runs}\"\n\
+             [pair]\nextensions = \"html pdf\"\ncommand = \"echo {{name.ext}} >> {runs}\"\n\
+             await_set = [\"{{dir}}/{{name}}.html\", \"{{dir}}/{{name}}.pdf\"]\n",
+            runs = runs.display()
+        ))
+        .unwrap();
+        // old files, only their events count as arriving
+        let hours_ago =
+            filetime::FileTime::from_system_time(SystemTime::now() - Duration::from_secs(7200));
+        for file in [
+            "a.json", "a.schema", "b.json", "b.schema", "c.json", "d.html", "d.pdf",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+            filetime::set_file_mtime(dir.join(file), hours_ago).unwrap();
+        }
+        let args = cli(&[dir.to_str().unwrap()]);
+        let pipeline = pipeline(&args, &rules, Session::default());
+        let change = |file: &str| {
+            let events = [event(&dir.join(file), &[debounce::Kind::Modify])];
+            pipeline.process(&events, false, false);
+        };
+        let ran = || read(&runs).lines().map(String::from).collect::<Vec<_>>();
+
+        // the file waited for arrives first
+        change("b.schema");
+        change("b.json");
+        assert_eq!(ran(), ["b.schema", "b.json"]);
+
+        // or after the one that waits, which is held until then
+        change("a.json");
+        assert_eq!(ran().len(), 2);
+        change("a.schema");
+        pipeline.release(Instant::now());
+        assert_eq!(ran()[2..], ["a.schema", "a.json"]);
+
+        // files waiting for each other, the second one releases the first
+        change("d.pdf");
+        change("d.html");
+        pipeline.release(Instant::now());
+        assert_eq!(ran()[4..], ["d.html", "d.pdf"]);
+
+        // nothing arrives, it runs after the timeout
+        change("c.json");
+        pipeline.release(Instant::now());
+        assert_eq!(ran().len(), 6);
+        pipeline.release(Instant::now() + Duration::from_millis(300));
+        assert_eq!(ran()[6..], ["c.json"]);
+        assert_eq!(pipeline.session.state.lock().unwrap().awaiting_count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batch_is_run_in_chunks() {
+        let rules = config::rules_from_toml(
+            "[fmt]\nextensions = \"rs\"\ncommand = \"rustfmt {paths}\"\nmode = \"batch\"\n\
+             batch_size = 200\n\
+             [md]\nextensions = \"md\"\ncommand = \"pandoc {name.ext}\"\n",
+        )
+        .unwrap();
+        let rule = |name: &str| rules.iter().find(|r| r.name == name);
+        let events: Vec<debounce::DebouncedEvent> = (0..500)
+            .map(|i| format!("/project/src/f{}.rs", i))
+            .chain(["/project/a.md".to_string()])
+            .map(|p| {
+                debounce::DebouncedEvent::simulated(p.into(), debounce::Kind::Modify.event_kind())
+            })
+            .collect();
+        let events: Vec<&debounce::DebouncedEvent> = events.iter().collect();
+        let mut rules_of = vec![rule("fmt"); 500];
+        // the md event comes in between
+        rules_of.insert(250, rule("md"));
+        let events = [&events[..250], &events[500..], &events[250..500]].concat();
+
+        let chunks = chunks(&events, &rules_of);
+        let batched = batched(&events, &rules_of, &chunks, |r| {
+            r.is_some_and(|r| r.mode == Some(config::RunMode::Batch))
+        });
+        let runs: Vec<(usize, usize, usize, &Path)> = batched
+            .iter()
+            .zip(&chunks)
+            .filter_map(|(b, chunk)| match b {
+                Batched::Lead(paths) => Some((chunk.index, chunk.total, paths.len(), paths[0])),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            runs,
+            [
+                (0, 3, 200, Path::new("/project/src/f0.rs")),
+                (1, 3, 200, Path::new("/project/src/f200.rs")),
+                (2, 3, 100, Path::new("/project/src/f400.rs")),
+            ]
+        );
+        // the chunk's paths are the ones run for, in order
+        for (b, chunk) in batched.iter().zip(&chunks) {
+            if let Batched::Lead(paths) = b {
+                assert_eq!(paths, &chunk.paths);
+            }
         }
+        assert!(matches!(batched[250], Batched::No));
+        assert_eq!((chunks[250].index, chunks[250].total), (0, 1));
     }
 }
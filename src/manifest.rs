@@ -0,0 +1,199 @@
+use crate::output::infoln;
+use colored::Colorize;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Content hashes of the files from their last successful runs, kept
+/// in a file so the unchanged files don't run again after a restart
+pub struct Manifest {
+    file: PathBuf,
+    hashes: BTreeMap<PathBuf, String>,
+}
+
+impl Manifest {
+    /// Load the manifest from the file, a missing file is an empty
+    /// manifest and a corrupt one is rebuilt from scratch
+    pub fn load(file: &Path) -> Self {
+        let hashes = match std::fs::read_to_string(file) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                infoln!(
+                    "{}: manifest {:?} is corrupt ({}), rebuilding it",
+                    "Warning".yellow().bold(),
+                    file,
+                    e
+                );
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Self {
+            file: file.to_path_buf(),
+            hashes,
+        }
+    }
+
+    /// The file's content is the same as in its last successful run
+    pub fn is_current(&self, path: &Path, hash: Option<&str>) -> bool {
+        self.hashes.get(path).map(String::as_str) == hash
+    }
+
+    /// Record the content of the finished run, only the successful
+    /// runs count; `None` for the removed files
+    pub fn finished(
+        &mut self,
+        path: &Path,
+        hash: Option<String>,
+        success: bool,
+    ) -> std::io::Result<()> {
+        if !success {
+            return Ok(());
+        }
+        self.update(path, hash)
+    }
+
+    /// Record the content the run succeeded for, `None` for the
+    /// removed files
+    fn update(&mut self, path: &Path, hash: Option<String>) -> std::io::Result<()> {
+        match hash {
+            Some(hash) => self.hashes.insert(path.to_path_buf(), hash),
+            None => self.hashes.remove(path),
+        };
+        self.save()
+    }
+
+    /// Remove the entries of the files that don't exist anymore,
+    /// returns them
+    pub fn prune(&mut self) -> std::io::Result<Vec<PathBuf>> {
+        let gone: Vec<PathBuf> = self
+            .hashes
+            .keys()
+            .filter(|p| !p.exists())
+            .cloned()
+            .collect();
+        for path in &gone {
+            self.hashes.remove(path);
+        }
+        self.save()?;
+        Ok(gone)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Write to a temporary file and rename it over the manifest, so
+    /// it's never left half written
+    fn save(&self) -> std::io::Result<()> {
+        let tmp = self.file.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&self.hashes)?)?;
+        std::fs::rename(tmp, &self.file)
+    }
+}
+
+/// Start of the FNV-1a hash
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a hash of the file's content, `None` if it can't be read; the
+/// hash has to stay the same across versions, so no `DefaultHasher`
+pub fn hash_file(path: &Path) -> Option<String> {
+    let mut file = std::fs::File::open(path).ok()?;
+    if !file.metadata().ok()?.is_file() {
+        return None;
+    }
+    let mut hash = FNV_OFFSET;
+    let mut buf = [0; 8192];
+    loop {
+        let n = file.read(&mut buf).ok()?;
+        if n == 0 {
+            break;
+        }
+        hash = fnv1a(hash, &buf[..n]);
+    }
+    Some(format!("{:016x}", hash))
+}
+
+/// FNV-1a hash of the bytes, in the same form as `hash_file`
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    format!("{:016x}", fnv1a(FNV_OFFSET, bytes))
+}
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("onchange-manifest-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn manifest_survives_restarts() {
+        let file = temp("restart");
+        let (a, b) = (Path::new("/project/a.md"), Path::new("/project/b.md"));
+        let mut manifest = Manifest::load(&file);
+        assert!(!manifest.is_current(a, Some("1")));
+        manifest.finished(a, Some("1".to_string()), true).unwrap();
+        manifest.finished(b, Some("2".to_string()), true).unwrap();
+        drop(manifest);
+
+        let mut manifest = Manifest::load(&file);
+        assert!(manifest.is_current(a, Some("1")));
+        assert!(!manifest.is_current(a, Some("3")));
+        // removed after its run
+        manifest.finished(b, None, true).unwrap();
+        assert_eq!(Manifest::load(&file).len(), 1);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn only_successful_runs_update_it() {
+        let file = temp("success");
+        let a = Path::new("/project/a.md");
+        let mut manifest = Manifest::load(&file);
+        manifest.finished(a, Some("1".to_string()), true).unwrap();
+        manifest.finished(a, Some("2".to_string()), false).unwrap();
+        assert!(manifest.is_current(a, Some("1")));
+        let manifest = Manifest::load(&file);
+        assert!(manifest.is_current(a, Some("1")));
+        assert!(!manifest.is_current(a, Some("2")));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn corrupt_manifest_is_rebuilt() {
+        let file = temp("corrupt");
+        std::fs::write(&file, "{not json").unwrap();
+        let mut manifest = Manifest::load(&file);
+        assert_eq!(manifest.len(), 0);
+        manifest
+            .finished(Path::new("/a"), Some("1".to_string()), true)
+            .unwrap();
+        assert!(Manifest::load(&file).is_current(Path::new("/a"), Some("1")));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn prune_removes_the_missing_files() {
+        let file = temp("prune");
+        let kept = temp("kept");
+        std::fs::write(&kept, "x").unwrap();
+        let mut manifest = Manifest::load(&file);
+        manifest.finished(&kept, hash_file(&kept), true).unwrap();
+        manifest
+            .finished(Path::new("/nonexistent/a"), Some("1".to_string()), true)
+            .unwrap();
+        assert_eq!(manifest.prune().unwrap(), [PathBuf::from("/nonexistent/a")]);
+        assert_eq!(Manifest::load(&file).len(), 1);
+        assert!(manifest.is_current(&kept, hash_file(&kept).as_deref()));
+        std::fs::remove_file(&file).unwrap();
+        std::fs::remove_file(&kept).unwrap();
+    }
+}
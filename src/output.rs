@@ -0,0 +1,986 @@
+use crate::config::{Rule, Rules};
+use crate::report::{self, Report};
+use crate::vars;
+use crate::watch::FILE_COUNT_CAP;
+use colored::Colorize;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt::Arguments;
+use std::io::{ErrorKind, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+/// Stdout was closed by the reader (broken pipe)
+static STDOUT_CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// Like `println!` but through the printing thread
+macro_rules! outln {
+    () => {
+        $crate::output::print(format_args!(""), true)
+    };
+    ($($arg:tt)*) => {
+        $crate::output::print(format_args!($($arg)*), true)
+    };
+}
+
+/// Like `print!` for the informational lines, goes to the stream
+/// chosen with `--notify-stream`
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::output::print_info(format_args!($($arg)*), false)
+    };
+}
+
+/// Like `println!` for the informational lines, goes to the stream
+/// chosen with `--notify-stream`
+macro_rules! infoln {
+    () => {
+        $crate::output::print_info(format_args!(""), true)
+    };
+    ($($arg:tt)*) => {
+        $crate::output::print_info(format_args!($($arg)*), true)
+    };
+}
+
+/// Like `eprintln!` but through the printing thread, for the errors
+/// that go to stderr whatever the notify stream is
+macro_rules! errln {
+    ($($arg:tt)*) => {
+        $crate::output::print_error(format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {errln, info, infoln, outln};
+
+/// Where the informational lines (changes, runs, status) go
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum NotifyStream {
+    Stdout,
+    Stderr,
+    None,
+}
+
+static NOTIFY_STREAM: OnceLock<NotifyStream> = OnceLock::new();
+
+/// Set the stream for the informational lines, the colors are only
+/// used when that stream is a terminal
+pub fn set_notify_stream(stream: NotifyStream) {
+    NOTIFY_STREAM.set(stream).ok();
+    if stream == NotifyStream::Stderr && std::env::var_os("NO_COLOR").is_none() {
+        colored::control::set_override(std::io::stderr().is_terminal());
+    }
+}
+
+static REPORT_MODE: OnceLock<report::Mode> = OnceLock::new();
+
+/// Set how the changes and runs are reported, before anything is
+pub fn set_report_mode(mode: report::Mode) {
+    REPORT_MODE.set(mode).ok();
+}
+
+/// The stdout is for the JSON reports, the output of the commands goes
+/// to stderr then
+pub fn stdout_reserved() -> bool {
+    REPORT_MODE.get() == Some(&report::Mode::Json)
+}
+
+/// Log file from `--log`, with the part of the last line not written
+/// yet as the lines are timestamped when complete
+struct Log {
+    file: std::fs::File,
+    partial: String,
+}
+
+static LOG: OnceLock<Mutex<Log>> = OnceLock::new();
+
+/// Append everything shown (without the colors) to the file too, with
+/// the time of each line
+pub fn set_log(file: &Path) -> std::io::Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)?;
+    LOG.set(Mutex::new(Log {
+        file,
+        partial: String::new(),
+    }))
+    .ok();
+    Ok(())
+}
+
+/// Write the complete lines of the text to the log file, if any
+fn log(text: &str) {
+    static ANSI: OnceLock<Regex> = OnceLock::new();
+    let Some(log) = LOG.get() else {
+        return;
+    };
+    let ansi = ANSI.get_or_init(|| Regex::new(r"\x1b\[[0-9;?]*[A-Za-z]").unwrap());
+    let mut log = log.lock().unwrap();
+    log.partial.push_str(&ansi.replace_all(text, ""));
+    let Some(end) = log.partial.rfind('\n') else {
+        return;
+    };
+    let lines: String = log.partial.drain(..=end).collect();
+    let time = humantime::format_rfc3339_seconds(std::time::SystemTime::now());
+    let mut out = String::new();
+    for line in lines.lines() {
+        out.push_str(&format!("[{}] {}\n", time, line.trim_start_matches('\r')));
+    }
+    log.file.write_all(out.as_bytes()).ok();
+}
+
+enum Message {
+    Text(String),
+    /// Informational text, for the notify stream
+    Info(String),
+    /// Output of the commands
+    Output(String),
+    /// Error for stderr
+    Error(String),
+    /// For the reporter
+    Report(Report),
+    /// Reply once everything sent before it is written
+    Flush(Sender<()>),
+}
+
+/// All the output goes through a single printing thread, so the
+/// lines from different threads never get mixed
+fn printer() -> &'static Sender<Message> {
+    static PRINTER: OnceLock<Sender<Message>> = OnceLock::new();
+    PRINTER.get_or_init(|| {
+        let (tx, rx) = channel();
+        thread::spawn(move || run_printer(rx));
+        tx
+    })
+}
+
+/// Write the messages till all the senders are gone
+fn run_printer(rx: Receiver<Message>) {
+    let mut reporter = None;
+    for msg in rx {
+        match msg {
+            Message::Text(text) => write_stdout(&text),
+            Message::Info(text) => write_info(&text),
+            Message::Output(text) if stdout_reserved() => write_stderr(&text),
+            Message::Output(text) => write_stdout(&text),
+            Message::Error(text) => write_stderr(&text),
+            Message::Report(r) => {
+                let reporter = reporter.get_or_insert_with(|| {
+                    report::reporter(*REPORT_MODE.get().unwrap_or(&report::Mode::Plain))
+                });
+                report::dispatch(reporter.as_mut(), r);
+            }
+            Message::Flush(done) => {
+                done.send(()).ok();
+            }
+        }
+    }
+}
+
+/// Send the message to the printing thread, false if it's gone
+fn send(msg: Message) -> bool {
+    #[cfg(test)]
+    if let Some(tx) = TEST_PRINTER.with_borrow(|p| p.clone()) {
+        return tx.send(msg).is_ok();
+    }
+    printer().send(msg).is_ok()
+}
+
+/// What the printing thread wrote in the tests, instead of writing it
+/// to the terminal
+#[cfg(test)]
+#[derive(Default, Debug)]
+pub struct Captured {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Printing thread of the test, instead of the global one
+    static TEST_PRINTER: std::cell::RefCell<Option<Sender<Message>>> =
+        const { std::cell::RefCell::new(None) };
+    /// Where the writes go in the test, with its notify stream
+    static CAPTURED: std::cell::RefCell<Option<(NotifyStream, Captured)>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Capture what's written from this thread while `f` runs, with the
+/// informational lines going to `notify`
+#[cfg(test)]
+pub fn capture_writes(notify: NotifyStream, f: impl FnOnce()) -> Captured {
+    CAPTURED.set(Some((notify, Captured::default())));
+    f();
+    CAPTURED.take().unwrap().1
+}
+
+/// Capture what's printed from this thread while `f` runs, through a
+/// printing thread of its own
+#[cfg(test)]
+pub fn capture(notify: NotifyStream, f: impl FnOnce()) -> Captured {
+    let (tx, rx) = channel();
+    let printer = thread::spawn(move || capture_writes(notify, || run_printer(rx)));
+    with_printer(tx, f);
+    printer.join().unwrap()
+}
+
+/// Start a thread that prints through the same printing thread as
+/// this one, for the threads inside `capture`
+#[cfg(test)]
+pub fn spawn(f: impl FnOnce() + Send + 'static) -> thread::JoinHandle<()> {
+    let tx = TEST_PRINTER.with_borrow(|p| p.clone());
+    thread::spawn(move || match tx {
+        Some(tx) => with_printer(tx, f),
+        None => f(),
+    })
+}
+
+/// Send the messages from this thread to `tx` while `f` runs
+#[cfg(test)]
+fn with_printer(tx: Sender<Message>, f: impl FnOnce()) {
+    TEST_PRINTER.set(Some(tx));
+    f();
+    TEST_PRINTER.take();
+}
+
+/// Write the text to the capture of this thread if there's one
+#[cfg(test)]
+fn captured(stream: NotifyStream, text: &str) -> bool {
+    CAPTURED.with_borrow_mut(|c| {
+        let Some((notify, captured)) = c else {
+            return false;
+        };
+        let stream = match stream {
+            NotifyStream::None => *notify,
+            s => s,
+        };
+        match stream {
+            NotifyStream::Stdout => captured.stdout.push_str(text),
+            NotifyStream::Stderr => captured.stderr.push_str(text),
+            NotifyStream::None => (),
+        }
+        true
+    })
+}
+
+/// Send the text to the printing thread
+pub fn print(args: Arguments, newline: bool) {
+    let mut text = args.to_string();
+    if newline {
+        text.push('\n');
+    }
+    send(Message::Text(text));
+}
+
+/// Send the report to the reporter of the printing thread
+pub fn report(report: Report) {
+    send(Message::Report(report));
+}
+
+/// Send the error line to the printing thread
+pub fn print_error(args: Arguments) {
+    send(Message::Error(format!("{}\n", args)));
+}
+
+/// Send the informational text to the printing thread
+pub fn print_info(args: Arguments, newline: bool) {
+    let mut text = args.to_string();
+    if newline {
+        text.push('\n');
+    }
+    send(Message::Info(text));
+}
+
+/// How much `--clear` clears
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Clear {
+    /// The visible screen
+    Screen,
+    /// The screen and the scrollback
+    All,
+}
+
+/// How the terminal is cleared
+#[derive(Debug, PartialEq)]
+enum ClearBy {
+    /// Escape codes, for the terminals that understand them
+    Ansi(&'static str),
+    /// `cls`, for the Windows consoles that don't
+    Cls,
+}
+
+/// How to clear the terminal, nothing if the stdout isn't one or it
+/// can't be cleared
+fn clear_by(clear: Clear, terminal: bool, ansi: bool, windows: bool) -> Option<ClearBy> {
+    if !terminal {
+        return None;
+    }
+    if !ansi {
+        // cls clears the scrollback too
+        return windows.then_some(ClearBy::Cls);
+    }
+    Some(ClearBy::Ansi(match clear {
+        Clear::Screen => "\x1b[2J\x1b[H",
+        Clear::All => "\x1b[3J\x1b[2J\x1b[H",
+    }))
+}
+
+/// Terminal understands the escape codes: the Windows consoles only
+/// in the newer terminals or with an emulation in front of them
+fn ansi_support() -> bool {
+    let term = std::env::var("TERM").ok();
+    if cfg!(windows) {
+        term.is_some()
+            || ["WT_SESSION", "ANSICON", "ConEmuANSI"]
+                .iter()
+                .any(|v| std::env::var_os(v).is_some())
+    } else {
+        term.as_deref() != Some("dumb")
+    }
+}
+
+/// Clear the terminal, nothing if the stdout isn't one
+pub fn clear_screen(clear: Clear) {
+    let terminal = std::io::stdout().is_terminal();
+    match clear_by(clear, terminal, ansi_support(), cfg!(windows)) {
+        Some(ClearBy::Ansi(code)) => {
+            send(Message::Text(code.to_string()));
+        }
+        Some(ClearBy::Cls) => {
+            // after what's already printed
+            flush();
+            std::process::Command::new("cmd")
+                .args(["/C", "cls"])
+                .status()
+                .ok();
+        }
+        None => (),
+    }
+}
+
+/// Send the bytes (e.g. command output) to the printing thread
+pub fn print_bytes(bytes: &[u8]) {
+    let text = String::from_utf8_lossy(bytes).to_string();
+    send(Message::Output(text));
+}
+
+/// Wait till everything sent to the printing thread is written, needed
+/// before anything else writes to the terminal directly
+pub fn flush() {
+    let (tx, rx) = channel();
+    if send(Message::Flush(tx)) {
+        rx.recv().ok();
+    }
+}
+
+/// Flushes the output when dropped, for the end of main
+pub struct FlushOnDrop;
+
+impl Drop for FlushOnDrop {
+    fn drop(&mut self) {
+        flush();
+    }
+}
+
+/// Write the informational text to the notify stream, only from the
+/// printing thread
+pub fn write_info(text: &str) {
+    #[cfg(test)]
+    if captured(NotifyStream::None, text) {
+        return;
+    }
+    match NOTIFY_STREAM.get() {
+        Some(NotifyStream::Stderr) => write_stderr(text),
+        Some(NotifyStream::None) => log(text),
+        _ => write_stdout(text),
+    }
+}
+
+/// Write the text to stderr, only from the printing thread
+pub fn write_stderr(text: &str) {
+    log(text);
+    #[cfg(test)]
+    if captured(NotifyStream::Stderr, text) {
+        return;
+    }
+    let mut stderr = std::io::stderr().lock();
+    stderr.write_all(text.as_bytes()).ok();
+    stderr.flush().ok();
+}
+
+/// Write the text to stdout, only from the printing thread; if the
+/// stdout is closed then stop writing to it and notify about it once
+/// in stderr. The JSON reports have no one to read them then, so it
+/// stops watching instead
+pub fn write_stdout(text: &str) {
+    log(text);
+    #[cfg(test)]
+    if captured(NotifyStream::Stdout, text) {
+        return;
+    }
+    if STDOUT_CLOSED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut stdout = std::io::stdout().lock();
+    let res = stdout
+        .write_all(text.as_bytes())
+        .and_then(|_| stdout.flush());
+    if let Err(e) = res {
+        if e.kind() != ErrorKind::BrokenPipe || STDOUT_CLOSED.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        if stdout_reserved() {
+            crate::cancel::stop();
+        } else {
+            write_stderr(&format!(
+                "{}: stdout is closed, messages will not be shown anymore\n",
+                "Notice".bold().yellow()
+            ));
+        }
+    }
+}
+
+/// One line summary of the rule: name, pattern or extensions, command
+/// and description
+pub fn rule_summary(rule: &Rule) -> String {
+    let mut line = format!("{} ({})", rule.name, rule.selector());
+    if let Some(action) = &rule.action {
+        line.push_str(&format!(" ⇒ {}", action));
+    } else if let Some(cmd) = &rule.command {
+        line.push_str(&format!(" ⇒ {}", cmd));
+    }
+    line.push_str(&description_suffix(rule.description.as_deref()));
+    line
+}
+
+/// Dimmed description to put at the end of a line, empty if there
+/// is no description
+pub fn description_suffix(desc: Option<&str>) -> String {
+    match desc {
+        Some(d) if !d.is_empty() => format!(" {}", format!("— {}", d).dimmed()),
+        _ => String::new(),
+    }
+}
+
+pub fn print_rules(rules: &Rules) {
+    for rule in rules.iter() {
+        infoln!("{}: {}", "Rule".blue().bold(), rule_summary(rule));
+    }
+}
+
+/// Number of watch paths shown before summarizing the rest
+const WATCH_LIST_LEN: usize = 5;
+
+/// List of the watched paths, long lists are shortened unless verbose
+pub fn watch_list(paths: &[PathBuf], verbose: bool) -> String {
+    let shown = if verbose {
+        paths.len()
+    } else {
+        paths.len().min(WATCH_LIST_LEN)
+    };
+    let mut list: Vec<String> = paths[..shown].iter().map(|p| format!("{:?}", p)).collect();
+    if shown < paths.len() {
+        list.push(format!(
+            "… and {} more (use --verbose to list all)",
+            paths.len() - shown
+        ));
+    }
+    list.join(" ")
+}
+
+/// What the recursive watch covers: "(3 directories, 120 files, 2 excluded)"
+pub fn recursive_summary(dirs: usize, files: usize, excluded: usize) -> String {
+    let dirs = match dirs {
+        1 => "1 directory".to_string(),
+        n => format!("{} directories", n),
+    };
+    let files = match files {
+        1 => "1 file".to_string(),
+        n if n > FILE_COUNT_CAP => format!(">{} files", FILE_COUNT_CAP),
+        n => format!("{} files", n),
+    };
+    let mut summary = format!("({}, {}", dirs, files);
+    if excluded > 0 {
+        summary.push_str(&format!(", {} excluded", excluded));
+    }
+    summary.push(')');
+    summary
+}
+
+/// How much of the command is shown when it's run
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Echo {
+    /// Only the rendered command
+    Short,
+    /// Everything about how the command is run
+    Full,
+}
+
+/// Details of how the command is run, below the Run line
+pub fn print_plan(plan: &crate::exec::ExecutionPlan) {
+    for line in plan.describe() {
+        infoln!("    {}", line.dimmed());
+    }
+}
+
+/// When to give the audible cue after the command finishes
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Cue {
+    Failure,
+    Always,
+}
+
+/// Ring the bell or play the sound after the run, playing the sound
+/// falls back to the bell and never affects the run
+pub fn completion_cue(cue: Cue, sound: Option<&Path>, success: bool) {
+    if success && cue == Cue::Failure {
+        return;
+    }
+    let Some(sound) = sound else {
+        info!("\x07");
+        return;
+    };
+    // the players take as long as the sound, that shouldn't hold the
+    // next run
+    let sound = sound.to_path_buf();
+    thread::spawn(move || {
+        if !play_sound(&sound) {
+            info!("\x07");
+        }
+    });
+}
+
+/// Play the sound file with the first available player
+fn play_sound(sound: &Path) -> bool {
+    ["paplay", "aplay", "afplay", "pw-play"]
+        .iter()
+        .any(|player| {
+            subprocess::Exec::cmd(player)
+                .arg(sound)
+                .stdout(subprocess::NullFile)
+                .stderr(subprocess::NullFile)
+                .join()
+                .map(|s| s.success())
+                .unwrap_or(false)
+        })
+}
+
+/// Advice for when the commands take much longer than the debounce
+/// duration, as the changes during the run cause more runs
+pub fn print_slow_rule_advice(rule: &str, avg: Duration, debounce: Duration) {
+    let rule = if rule.is_empty() {
+        "command".to_string()
+    } else {
+        format!("rule {}", rule)
+    };
+    infoln!(
+        "{}: {} takes {} on average, much longer than the debounce duration ({}), consider a longer --duration so a burst of changes doesn't cause many runs",
+        "Advice".yellow().bold(),
+        rule,
+        crate::exec::humanize_duration(avg),
+        humantime::format_duration(debounce),
+    );
+}
+
+/// Hints for the rules whose command is same for every changed file
+pub fn print_static_hints(rules: &Rules) {
+    for hint in static_hints(rules) {
+        infoln!("{}: {}", "Hint".yellow().bold(), hint);
+    }
+}
+
+fn static_hints(rules: &Rules) -> Vec<String> {
+    rules
+        .iter()
+        .filter(|r| r.is_static())
+        .map(|rule| {
+            let note = if rule.dedupe() {
+                "deduplicating its runs within a batch of changes (set `dedupe = false` to disable)"
+            } else {
+                "consider setting `dedupe = true` to run it once per batch of changes"
+            };
+            format!(
+                "rule {} doesn't use any per-file variable, {}",
+                rule.name, note
+            )
+        })
+        .collect()
+}
+
+/// Notes for the entries that were normalized before use
+pub fn print_normalized(normalized: &[(String, String)]) {
+    for (given, used) in normalized {
+        infoln!("{}: normalized '{}' → '{}'", "Note".dimmed(), given, used);
+    }
+}
+
+/// Warnings for the rules that can never run, or have extensions that
+/// never match, because of the ignore (or `--only`) patterns
+pub fn print_unreachable_rules(
+    rules: &Rules,
+    ignore: &[glob::Pattern],
+    only: &[glob::Pattern],
+    cwd: &Path,
+) {
+    for rule in rules.iter() {
+        let (skipped, total) = crate::filter::unreachable_selectors(rule, ignore, only, cwd);
+        if skipped.is_empty() {
+            continue;
+        }
+        if skipped.len() == total {
+            infoln!(
+                "{}: rule {} can never run, all the files for its {} are skipped by the ignore or --only patterns",
+                "Warning".yellow().bold(),
+                rule.name,
+                if rule.pattern.is_some() { "pattern" } else { "extensions" }
+            );
+        } else {
+            infoln!(
+                "{}: rule {} never runs for the extensions ({}), those files are skipped by the ignore or --only patterns",
+                "Warning".yellow().bold(),
+                rule.name,
+                skipped.join(" ")
+            );
+        }
+    }
+}
+
+/// Result of `--self-test`, the directories that gave no events are
+/// named with what could be wrong
+pub fn print_self_test(result: &crate::watch::SelfTest) {
+    for (dir, e) in &result.skipped {
+        infoln!(
+            "{}: can't write in {:?} ({}), not tested",
+            "Note".dimmed(),
+            dir,
+            e
+        );
+    }
+    let tested = result.passed.len() + result.silent.len();
+    if result.silent.is_empty() {
+        infoln!(
+            "{}",
+            format!("Self-test passed ({}/{} paths)", tested, tested)
+                .green()
+                .bold()
+        );
+        return;
+    }
+    infoln!(
+        "{}: no events from {}",
+        format!(
+            "Self-test failed ({}/{} paths)",
+            result.passed.len(),
+            tested
+        )
+        .red()
+        .bold(),
+        result
+            .silent
+            .iter()
+            .map(|p| format!("{:?}", p))
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+    infoln!(
+        "{}: check the paths and their permissions; network and FUSE file systems (like NFS) may not report the changes at all",
+        "Hint".yellow().bold()
+    );
+}
+
+/// Warnings for the rules with uppercase letters in the extensions,
+/// they're matched with the case unless `ignore_case` is set
+pub fn print_uppercase_extensions(rules: &Rules) {
+    if rules.ignore_case {
+        return;
+    }
+    for rule in rules.iter() {
+        let upper: Vec<&str> = rule
+            .extensions()
+            .filter(|e| e.chars().any(char::is_uppercase))
+            .collect();
+        if !upper.is_empty() {
+            infoln!(
+                "{}: rule {} has uppercase extensions ({}), they only match the files with the same case; set `ignore_case = true` in [global] to match any case",
+                "Warning".yellow().bold(),
+                rule.name,
+                upper.join(" ")
+            );
+        }
+    }
+}
+
+/// Table of rules with aligned columns for the `rules` subcommand
+pub fn rules_table(rules: &Rules) -> String {
+    let rows = rules.iter().map(|r| {
+        [
+            r.name.clone(),
+            r.selector(),
+            r.description.clone().unwrap_or("-".to_string()),
+            r.action
+                .as_ref()
+                .map(|a| a.to_string())
+                .or(r.command.as_ref().map(|c| c.to_string()))
+                .unwrap_or("-".to_string()),
+        ]
+    });
+    aligned_table(["RULE", "MATCHES", "DESCRIPTION", "COMMAND"], rows)
+}
+
+/// Table of the built-in variables with their values for a sample
+/// path, for the `vars` subcommand
+pub fn vars_table(aliases: &BTreeMap<String, String>) -> String {
+    let sample = vars::path_vars(Path::new(vars::SAMPLE_PATH), Path::new(vars::SAMPLE_PWD));
+    let rows = vars::VARIABLES.iter().map(|v| {
+        [
+            v.name.to_string(),
+            v.description.to_string(),
+            vars::sample_value(v),
+        ]
+    });
+    let alias_rows = vars::ALIASES
+        .iter()
+        .map(|&(a, v)| (a, v))
+        .chain(aliases.iter().map(|(a, v)| (a.as_str(), v.as_str())))
+        .map(|(alias, var)| {
+            [
+                alias.to_string(),
+                format!("alias of {{{}}}", var),
+                sample.get(var).cloned().unwrap_or_default(),
+            ]
+        });
+    let rows = rows.chain(alias_rows);
+    format!(
+        "Examples are for {:?} changed in {:?}\n\n{}",
+        vars::SAMPLE_PATH,
+        vars::SAMPLE_PWD,
+        aligned_table(["VARIABLE", "DESCRIPTION", "EXAMPLE"], rows)
+    )
+}
+
+/// Table with the columns aligned to the widest cell
+fn aligned_table<const N: usize>(
+    header: [&str; N],
+    rows: impl Iterator<Item = [String; N]>,
+) -> String {
+    let rows: Vec<[String; N]> = std::iter::once(header.map(|h| h.to_string()))
+        .chain(rows)
+        .collect();
+    let mut widths = [0; N];
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(widths)
+                .map(|(cell, w)| format!("{:w$}", cell))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Per-rule totals of the session for `--stats`
+pub fn stats_table(summary: &[crate::stats::RuleSummary]) -> String {
+    if summary.is_empty() {
+        return "No runs".to_string();
+    }
+    aligned_table(
+        ["RULE", "RUNS", "FAILURES", "SUCCESS", "MEAN", "SLOWEST"],
+        summary.iter().map(|s| {
+            [
+                s.rule.clone(),
+                s.runs.to_string(),
+                s.failures.to_string(),
+                format!("{:.0}%", s.success_rate()),
+                crate::exec::humanize_duration(s.mean),
+                crate::exec::humanize_duration(s.slowest),
+            ]
+        }),
+    )
+}
+
+/// Examples for the `examples` subcommand, with the variables they use
+pub fn print_examples() {
+    for (i, example) in vars::EXAMPLES.iter().enumerate() {
+        if i > 0 {
+            outln!();
+        }
+        outln!("{}", example.description.bold());
+        outln!("    {}", example.command);
+        let used = crate::config::template_variables(example.command, None);
+        if !used.is_empty() {
+            outln!("    {}: {}", "variables".dimmed(), used.join(", "));
+        }
+    }
+}
+
+pub fn rules_json(rules: &Rules) -> String {
+    serde_json::to_string_pretty(&rules.iter().collect::<Vec<&Rule>>()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::rules_from_toml;
+
+    const RULES: &str = "[md]\nextensions = \"md markdown\"\ncommand = \"pandoc {name.ext}\"\n\
+        description = \"Render the notes\"\n\
+        [docs]\npattern = \"docs/*.tex\"\ncommand = \"latexmk {name.ext}\"\n\
+        [rs]\nextensions = \"rs\"\n";
+
+    #[test]
+    fn clearing_by_the_terminal() {
+        use Clear::{All, Screen};
+        let ansi = |clear| clear_by(clear, true, true, false);
+        assert_eq!(ansi(Screen), Some(ClearBy::Ansi("\x1b[2J\x1b[H")));
+        assert_eq!(ansi(All), Some(ClearBy::Ansi("\x1b[3J\x1b[2J\x1b[H")));
+        assert_eq!(
+            clear_by(Screen, true, true, true),
+            Some(ClearBy::Ansi("\x1b[2J\x1b[H"))
+        );
+        // the old Windows consoles, without the escape codes
+        assert_eq!(clear_by(Screen, true, false, true), Some(ClearBy::Cls));
+        assert_eq!(clear_by(All, true, false, true), Some(ClearBy::Cls));
+        // a dumb terminal
+        assert_eq!(clear_by(All, true, false, false), None);
+        // not a terminal at all
+        for (ansi, windows) in [(true, false), (false, true)] {
+            assert_eq!(clear_by(All, false, ansi, windows), None);
+        }
+    }
+
+    #[test]
+    fn rules_table_snapshot() {
+        let rules = rules_from_toml(RULES).unwrap();
+        assert_eq!(
+            rules_table(&rules),
+            "RULE  MATCHES      DESCRIPTION       COMMAND\n\
+             docs  docs/*.tex   -                 latexmk {name.ext}\n\
+             md    md markdown  Render the notes  pandoc {name.ext}\n\
+             rs    rs           -                 -"
+        );
+    }
+
+    #[test]
+    fn rule_summaries() {
+        colored::control::set_override(false);
+        let rules = rules_from_toml(RULES).unwrap();
+        let summaries: Vec<String> = rules.iter().map(rule_summary).collect();
+        assert_eq!(
+            summaries,
+            [
+                "docs (docs/*.tex) ⇒ latexmk {name.ext}",
+                "md (md markdown) ⇒ pandoc {name.ext} — Render the notes",
+                "rs (rs)",
+            ]
+        );
+    }
+
+    #[test]
+    fn static_rule_hints() {
+        let rules = rules_from_toml(
+            "[rs]\nextensions = \"rs\"\ncommand = \"cargo build\"\n\
+             [md]\nextensions = \"md\"\ncommand = \"pandoc {name.ext}\"\n\
+             [c]\nextensions = \"c\"\ncommand = \"make -C {pwd}\"\ndedupe = false\n",
+        )
+        .unwrap();
+        assert_eq!(
+            static_hints(&rules),
+            [
+                "rule c doesn't use any per-file variable, consider setting `dedupe = true` to run it once per batch of changes",
+                "rule rs doesn't use any per-file variable, deduplicating its runs within a batch of changes (set `dedupe = false` to disable)",
+            ]
+        );
+    }
+
+    #[test]
+    fn uppercase_extension_warnings() {
+        colored::control::set_override(false);
+        let config = "[rs]\nextensions = \"RS toml\"\ncommand = \"cargo check\"\n\
+                      [md]\nextensions = \"md\"\ncommand = \"pandoc {name.ext}\"\n\
+                      [tex]\nextensions = \"Tex LTX\"\ncommand = \"latexmk\"\n";
+        let warnings = |config: &str| {
+            let rules = rules_from_toml(config).unwrap();
+            capture(NotifyStream::Stdout, || print_uppercase_extensions(&rules)).stdout
+        };
+        assert_eq!(
+            warnings(config),
+            "Warning: rule rs has uppercase extensions (RS), they only match the files with the same case; set `ignore_case = true` in [global] to match any case\n\
+             Warning: rule tex has uppercase extensions (Tex LTX), they only match the files with the same case; set `ignore_case = true` in [global] to match any case\n"
+        );
+        // nothing to warn about when the case doesn't matter
+        assert_eq!(
+            warnings(&format!("[global]\nignore_case = true\n{}", config)),
+            ""
+        );
+    }
+
+    #[test]
+    fn startup_lines() {
+        let paths: Vec<PathBuf> = (1..=500)
+            .map(|i| PathBuf::from(format!("f{}.md", i)))
+            .collect();
+        assert_eq!(watch_list(&paths[..2], false), r#""f1.md" "f2.md""#);
+        assert_eq!(
+            watch_list(&paths, false),
+            r#""f1.md" "f2.md" "f3.md" "f4.md" "f5.md" … and 495 more (use --verbose to list all)"#
+        );
+        assert_eq!(
+            watch_list(&paths[..WATCH_LIST_LEN], false)
+                .matches(".md")
+                .count(),
+            5
+        );
+        assert_eq!(watch_list(&paths, true).matches(".md").count(), 500);
+        assert_eq!(recursive_summary(3, 120, 0), "(3 directories, 120 files)");
+        assert_eq!(
+            recursive_summary(1, 0, 2),
+            "(1 directory, 0 files, 2 excluded)"
+        );
+        assert_eq!(recursive_summary(2, 1, 0), "(2 directories, 1 file)");
+        assert_eq!(
+            recursive_summary(40, FILE_COUNT_CAP + 1, 0),
+            "(40 directories, >10000 files)"
+        );
+    }
+
+    #[test]
+    fn stats_table_snapshot() {
+        use crate::stats::RuleSummary;
+        use std::time::Duration;
+        assert_eq!(stats_table(&[]), "No runs");
+        let summary = [
+            RuleSummary {
+                rule: "deploy".to_string(),
+                runs: 10,
+                failures: 3,
+                mean: Duration::from_millis(83_400),
+                slowest: Duration::from_secs(200),
+            },
+            RuleSummary {
+                rule: "md".to_string(),
+                runs: 1,
+                failures: 0,
+                mean: Duration::from_millis(120),
+                slowest: Duration::from_millis(120),
+            },
+        ];
+        assert_eq!(
+            stats_table(&summary),
+            "RULE    RUNS  FAILURES  SUCCESS  MEAN    SLOWEST\n\
+             deploy  10    3         70%      1m 23s  3m 20s\n\
+             md      1     0         100%     120ms   120ms"
+        );
+    }
+}
@@ -0,0 +1,519 @@
+use crate::output::{write_info, write_stderr, write_stdout};
+use crate::stats::RuleSummary;
+use colored::Colorize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+/// How the changes and the runs are reported
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Mode {
+    /// The usual "Changed:", "Run:" and "Done:" lines
+    Plain,
+    /// Only the failed runs and the summary
+    Quiet,
+    /// One JSON object per line on stdout, the other lines go to
+    /// stderr then
+    Json,
+    /// A single line on stderr updated in place
+    StatusLine,
+}
+
+/// What's done for an event, its `{action}` variable; the filters
+/// classify the events with it before the change template renders
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    /// The command runs for it
+    Run,
+    /// Left out by `--only`, `--ignore`, the hidden files or the
+    /// watched patterns
+    Ignored,
+    /// Last modified before `--fresh-within`
+    Stale,
+    /// Not of the kinds `events` or `dir_events` asks for
+    Event,
+    /// No rule (or no command) for it
+    NoRule,
+    /// Same command as an earlier event of the batch
+    Dedup,
+    /// The check command failed
+    Check,
+    /// Same content as in its last successful run
+    Unchanged,
+    /// The files of its rule's `await_set` didn't all arrive
+    Await,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Run => "run",
+            Self::Ignored => "skip:ignored",
+            Self::Stale => "skip:stale",
+            Self::Event => "skip:event",
+            Self::NoRule => "skip:no-rule",
+            Self::Dedup => "skip:dedup",
+            Self::Check => "skip:check",
+            Self::Unchanged => "skip:unchanged",
+            Self::Await => "skip:await",
+        })
+    }
+}
+
+/// Change of a file to report
+pub struct Change {
+    /// Rendered `--template`, if there's one
+    pub text: Option<String>,
+    pub path: String,
+    /// All the variables of the change
+    pub vars: BTreeMap<String, String>,
+}
+
+/// Finished run to report
+pub struct Finished {
+    pub success: bool,
+    /// Rendered `--done-template`, if there's one
+    pub message: Option<String>,
+    pub command: String,
+    /// Exit code as the shell reports it
+    pub status: String,
+    /// `{exit_name}` of the run, e.g. "failed(2)" or "timeout"
+    pub exit_name: String,
+    pub elapsed: Duration,
+    /// Output was cut at `--max-output`
+    pub output_truncated: bool,
+}
+
+/// Something to report, sent to the reporter of the printing thread
+pub enum Report {
+    Detected(Change),
+    /// Command started, `detail` is shown after it (hosts, description)
+    RunStarted {
+        command: String,
+        detail: String,
+    },
+    RunFinished(Finished),
+    /// The run (or the event) `subject` was skipped for the reason
+    RunSkipped {
+        subject: String,
+        reason: String,
+        outcome: Outcome,
+    },
+    /// Per-rule totals at the end of the session, with `--stats`
+    Summary(Vec<RuleSummary>),
+}
+
+/// Shows the reports, each mode implements it once
+pub trait Reporter: Send {
+    fn event_detected(&mut self, change: &Change);
+    fn run_started(&mut self, command: &str, detail: &str);
+    fn run_finished(&mut self, run: &Finished);
+    fn run_skipped(&mut self, subject: &str, reason: &str, outcome: Outcome);
+    fn session_summary(&mut self, summary: &[RuleSummary]);
+}
+
+/// Reporter for the mode
+pub fn reporter(mode: Mode) -> Box<dyn Reporter> {
+    match mode {
+        Mode::Plain => Box::new(PlainReporter),
+        Mode::Quiet => Box::new(QuietReporter),
+        Mode::Json => Box::new(JsonReporter),
+        Mode::StatusLine => Box::new(StatusLineReporter),
+    }
+}
+
+/// Hand the report to the reporter, the only place they're matched
+pub fn dispatch(reporter: &mut dyn Reporter, report: Report) {
+    match report {
+        Report::Detected(change) => reporter.event_detected(&change),
+        Report::RunStarted { command, detail } => reporter.run_started(&command, &detail),
+        Report::RunFinished(run) => reporter.run_finished(&run),
+        Report::RunSkipped {
+            subject,
+            reason,
+            outcome,
+        } => reporter.run_skipped(&subject, &reason, outcome),
+        Report::Summary(summary) => reporter.session_summary(&summary),
+    }
+}
+
+/// The "Done:" (or "Failed:") line, if there's a message to show
+fn done_line(run: &Finished) -> Option<String> {
+    let message = run.message.as_ref()?;
+    Some(if run.success {
+        format!("{}: {}\n", "Done".bold().green(), message)
+    } else {
+        format!("{}: {}\n", "Failed".bold().red(), message)
+    })
+}
+
+pub struct PlainReporter;
+
+impl Reporter for PlainReporter {
+    fn event_detected(&mut self, change: &Change) {
+        if let Some(text) = &change.text {
+            write_info(&format!("{}: {}\n", "Changed".bold().green(), text));
+        }
+    }
+
+    fn run_started(&mut self, command: &str, detail: &str) {
+        write_info(&format!("{}: {}{}\n", "Run".bold().red(), command, detail));
+    }
+
+    fn run_finished(&mut self, run: &Finished) {
+        if let Some(line) = done_line(run) {
+            write_info(&line);
+        }
+    }
+
+    fn run_skipped(&mut self, subject: &str, reason: &str, _: Outcome) {
+        write_info(&format!(
+            "{}: {} ({})\n",
+            "Skipped".dimmed(),
+            subject,
+            reason
+        ));
+    }
+
+    fn session_summary(&mut self, summary: &[RuleSummary]) {
+        write_info("\n");
+        write_stdout(&format!("{}\n", crate::output::stats_table(summary)));
+    }
+}
+
+pub struct QuietReporter;
+
+impl Reporter for QuietReporter {
+    fn event_detected(&mut self, _: &Change) {}
+
+    fn run_started(&mut self, _: &str, _: &str) {}
+
+    fn run_finished(&mut self, run: &Finished) {
+        if let (false, Some(line)) = (run.success, done_line(run)) {
+            write_info(&line);
+        }
+    }
+
+    fn run_skipped(&mut self, _: &str, _: &str, _: Outcome) {}
+
+    fn session_summary(&mut self, summary: &[RuleSummary]) {
+        PlainReporter.session_summary(summary);
+    }
+}
+
+/// JSON object of the finished run, also posted to the webhook
+pub fn exit_record(run: &Finished) -> serde_json::Value {
+    json!({
+        "type": "exit",
+        "command": run.command,
+        "status": run.status.parse::<i64>().ok(),
+        "success": run.success,
+        "exit_name": run.exit_name,
+        "elapsed_ms": run.elapsed.as_millis() as u64,
+        "output_truncated": run.output_truncated,
+    })
+}
+
+/// Counts of the change's batch by the kind of change, for the JSON
+/// change record
+fn batch_record(vars: &BTreeMap<String, String>) -> serde_json::Value {
+    let count = |kind: &str| {
+        vars.get(&format!("batch.{}", kind))
+            .and_then(|c| c.parse::<u64>().ok())
+    };
+    json!({
+        "count": count("count"),
+        "created": count("created"),
+        "modified": count("modified"),
+        "removed": count("removed"),
+        "renamed": count("renamed"),
+    })
+}
+
+pub struct JsonReporter;
+
+impl JsonReporter {
+    fn write(&self, value: serde_json::Value) {
+        write_stdout(&format!("{}\n", value));
+    }
+}
+
+impl Reporter for JsonReporter {
+    fn event_detected(&mut self, change: &Change) {
+        self.write(json!({
+            "type": "change",
+            "path": change.path,
+            "vars": change.vars,
+            "batch": batch_record(&change.vars),
+        }));
+    }
+
+    fn run_started(&mut self, command: &str, _: &str) {
+        self.write(json!({"type": "run", "command": command}));
+    }
+
+    fn run_finished(&mut self, run: &Finished) {
+        self.write(exit_record(run));
+    }
+
+    fn run_skipped(&mut self, subject: &str, reason: &str, outcome: Outcome) {
+        self.write(json!({
+            "type": "skipped",
+            "subject": subject,
+            "reason": reason,
+            "action": outcome.to_string(),
+        }));
+    }
+
+    fn session_summary(&mut self, summary: &[RuleSummary]) {
+        let rules: Vec<serde_json::Value> = summary
+            .iter()
+            .map(|s| {
+                json!({
+                    "rule": s.rule,
+                    "runs": s.runs,
+                    "failures": s.failures,
+                    "mean_ms": s.mean.as_millis() as u64,
+                    "slowest_ms": s.slowest.as_millis() as u64,
+                })
+            })
+            .collect();
+        self.write(json!({"type": "summary", "rules": rules}));
+    }
+}
+
+pub struct StatusLineReporter;
+
+impl StatusLineReporter {
+    /// Replace the line with the text
+    fn show(&self, text: String) {
+        write_stderr(&format!("\r\x1b[K{}", text));
+    }
+}
+
+impl Reporter for StatusLineReporter {
+    fn event_detected(&mut self, change: &Change) {
+        let text = change.text.as_ref().unwrap_or(&change.path);
+        self.show(format!("{}: {}", "Changed".bold().green(), text));
+    }
+
+    fn run_started(&mut self, command: &str, _: &str) {
+        self.show(format!("{}: {}", "Running".bold().yellow(), command));
+    }
+
+    fn run_finished(&mut self, run: &Finished) {
+        if let Some(line) = done_line(run) {
+            self.show(line.trim_end().to_string());
+        }
+    }
+
+    fn run_skipped(&mut self, _: &str, _: &str, _: Outcome) {}
+
+    fn session_summary(&mut self, summary: &[RuleSummary]) {
+        write_stderr("\n");
+        PlainReporter.session_summary(summary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{capture_writes, Captured, NotifyStream};
+
+    fn finished(output_truncated: bool) -> Finished {
+        Finished {
+            success: false,
+            message: None,
+            command: "yes".to_string(),
+            status: "143".to_string(),
+            exit_name: "timeout".to_string(),
+            elapsed: Duration::from_millis(300),
+            output_truncated,
+        }
+    }
+
+    /// Reports of a session: a run that passes, a skipped change, a
+    /// run that fails and the summary
+    fn session() -> Vec<Report> {
+        let change = |path: &str| {
+            Report::Detected(Change {
+                text: Some(path.to_string()),
+                path: format!("/project/{}", path),
+                vars: [("rule", "md"), ("batch.count", "1")]
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            })
+        };
+        let done = |success: bool, message: &str| {
+            Report::RunFinished(Finished {
+                success,
+                message: Some(message.to_string()),
+                command: "pandoc a.md".to_string(),
+                status: if success { "0" } else { "1" }.to_string(),
+                exit_name: if success { "ok" } else { "failed(1)" }.to_string(),
+                elapsed: Duration::from_millis(40),
+                output_truncated: false,
+            })
+        };
+        let started = || Report::RunStarted {
+            command: "pandoc a.md".to_string(),
+            detail: " (Render the notes)".to_string(),
+        };
+        vec![
+            change("a.md"),
+            started(),
+            done(true, "ok"),
+            Report::RunSkipped {
+                subject: "/project/b.md".to_string(),
+                reason: "unchanged".to_string(),
+                outcome: Outcome::Unchanged,
+            },
+            change("a.md"),
+            started(),
+            done(false, "failed(1)"),
+            Report::Summary(vec![RuleSummary {
+                rule: "md".to_string(),
+                runs: 2,
+                failures: 1,
+                mean: Duration::from_millis(40),
+                slowest: Duration::from_millis(40),
+            }]),
+        ]
+    }
+
+    /// What the reporter of the mode writes for the session
+    fn reported(mode: Mode, notify: NotifyStream) -> Captured {
+        colored::control::set_override(false);
+        capture_writes(notify, || {
+            let mut reporter = reporter(mode);
+            for report in session() {
+                dispatch(reporter.as_mut(), report);
+            }
+        })
+    }
+
+    const SUMMARY: &str = "RULE  RUNS  FAILURES  SUCCESS  MEAN  SLOWEST\n\
+                           md    2     1         50%      40ms  40ms\n";
+
+    #[test]
+    fn plain_reports() {
+        let lines = "Changed: a.md\n\
+                     Run: pandoc a.md (Render the notes)\n\
+                     Done: ok\n\
+                     Skipped: /project/b.md (unchanged)\n\
+                     Changed: a.md\n\
+                     Run: pandoc a.md (Render the notes)\n\
+                     Failed: failed(1)\n\
+                     \n";
+        let out = reported(Mode::Plain, NotifyStream::Stdout);
+        assert_eq!(out.stdout, format!("{}{}", lines, SUMMARY));
+        assert_eq!(out.stderr, "");
+        // the lines follow the notify stream, the table stays
+        let out = reported(Mode::Plain, NotifyStream::Stderr);
+        assert_eq!((out.stdout.as_str(), out.stderr.as_str()), (SUMMARY, lines));
+        let out = reported(Mode::Plain, NotifyStream::None);
+        assert_eq!((out.stdout.as_str(), out.stderr.as_str()), (SUMMARY, ""));
+    }
+
+    #[test]
+    fn quiet_reports() {
+        let out = reported(Mode::Quiet, NotifyStream::Stdout);
+        assert_eq!(out.stdout, format!("Failed: failed(1)\n\n{}", SUMMARY));
+        assert_eq!(out.stderr, "");
+    }
+
+    #[test]
+    fn json_reports() {
+        let out = reported(Mode::Json, NotifyStream::Stderr);
+        assert_eq!(out.stderr, "");
+        let records: Vec<serde_json::Value> = out
+            .stdout
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let types: Vec<&str> = records
+            .iter()
+            .map(|r| r["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            types,
+            ["change", "run", "exit", "skipped", "change", "run", "exit", "summary"]
+        );
+        assert_eq!(records[0]["path"], "/project/a.md");
+        assert_eq!(records[0]["vars"]["rule"], "md");
+        assert_eq!(records[0]["batch"]["count"], 1);
+        assert_eq!(records[1], json!({"type": "run", "command": "pandoc a.md"}));
+        assert_eq!(
+            (&records[2]["success"], &records[6]["exit_name"]),
+            (&json!(true), &json!("failed(1)"))
+        );
+        assert_eq!(
+            records[3],
+            json!({
+                "type": "skipped",
+                "subject": "/project/b.md",
+                "reason": "unchanged",
+                "action": "skip:unchanged",
+            })
+        );
+        assert_eq!(
+            records[7],
+            json!({"type": "summary", "rules": [
+                {"rule": "md", "runs": 2, "failures": 1, "mean_ms": 40, "slowest_ms": 40}
+            ]})
+        );
+    }
+
+    #[test]
+    fn status_line_reports() {
+        let out = reported(Mode::StatusLine, NotifyStream::Stdout);
+        // each report replaces the line, the skip leaves it as it is
+        assert_eq!(
+            out.stderr,
+            "\r\x1b[KChanged: a.md\
+             \r\x1b[KRunning: pandoc a.md\
+             \r\x1b[KDone: ok\
+             \r\x1b[KChanged: a.md\
+             \r\x1b[KRunning: pandoc a.md\
+             \r\x1b[KFailed: failed(1)\n"
+        );
+        assert_eq!(out.stdout, format!("\n{}", SUMMARY));
+    }
+
+    #[test]
+    fn exit_record_has_the_truncation() {
+        assert_eq!(
+            exit_record(&finished(true)),
+            json!({
+                "type": "exit",
+                "command": "yes",
+                "status": 143,
+                "success": false,
+                "exit_name": "timeout",
+                "elapsed_ms": 300,
+                "output_truncated": true,
+            })
+        );
+        assert_eq!(exit_record(&finished(false))["output_truncated"], false);
+    }
+
+    #[test]
+    fn batch_breakdown_of_the_change() {
+        let vars: BTreeMap<String, String> = [
+            ("batch.count", "4"),
+            ("batch.created", "1"),
+            ("batch.modified", "2"),
+            ("batch.removed", "1"),
+            ("batch.renamed", "0"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(
+            batch_record(&vars),
+            json!({"count": 4, "created": 1, "modified": 2, "removed": 1, "renamed": 0})
+        );
+    }
+}
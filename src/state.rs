@@ -0,0 +1,339 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::debounce::Kind;
+use crate::exec;
+use crate::output::infoln;
+use colored::Colorize;
+
+/// The `await_set` files count as arrived if they changed this long
+/// before the event that waits for them
+const AWAIT_RECENT: Duration = Duration::from_secs(10);
+
+/// State kept for a changed path during the session
+#[derive(Default)]
+pub struct PathState {
+    /// Number of change events seen for the path
+    pub events: usize,
+    /// Time of the last change event of the path
+    pub last_event: Option<SystemTime>,
+    /// Run of the path held for its rule's `await_set`
+    pub awaiting: Option<Awaiting>,
+}
+
+/// Run held until the other files of its rule's `await_set` arrive
+pub struct Awaiting {
+    /// Path of the event, to process it again
+    pub path: PathBuf,
+    pub kinds: Vec<notify::EventKind>,
+    pub rule: String,
+    /// State keys of the files waited for
+    pub members: Vec<PathBuf>,
+    /// Time of the event, the files have to be about as new
+    pub since: SystemTime,
+    /// Time to stop waiting at
+    pub until: Instant,
+}
+
+impl Awaiting {
+    /// The files have arrived, or it's been waiting too long
+    fn done(&self, paths: &HashMap<PathBuf, PathState>, now: Instant) -> bool {
+        now >= self.until || self.missing(paths).is_empty()
+    }
+
+    /// Files that haven't arrived: no recent event and not modified
+    /// recently
+    pub fn missing(&self, paths: &HashMap<PathBuf, PathState>) -> Vec<&PathBuf> {
+        let recent = |t: SystemTime| t + AWAIT_RECENT >= self.since;
+        self.members
+            .iter()
+            .filter(|m| {
+                let event = paths.get(*m).and_then(|s| s.last_event);
+                let mtime = m.metadata().and_then(|meta| meta.modified()).ok();
+                !(event.is_some_and(recent) || mtime.is_some_and(recent))
+            })
+            .collect()
+    }
+}
+
+/// Result of the previous runs of a rule for a path
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct RunState {
+    pub path: PathBuf,
+    pub rule: String,
+    pub last_exit: String,
+    pub last_run_at: Option<SystemTime>,
+    pub consecutive_failures: usize,
+}
+
+/// Content of the state file, it used to be only the runs
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Saved {
+    Runs(Vec<RunState>),
+    Full {
+        runs: Vec<RunState>,
+        #[serde(default)]
+        pending: BTreeMap<PathBuf, Kind>,
+        #[serde(default)]
+        seen: BTreeSet<PathBuf>,
+    },
+}
+
+/// Per path state of the session
+#[derive(Default)]
+pub struct StateStore {
+    paths: HashMap<PathBuf, PathState>,
+    runs: HashMap<(PathBuf, String), RunState>,
+    /// Rules that have run in this session
+    rules_run: HashSet<String>,
+    /// Events deferred by `--defer-window`, with the overall change to
+    /// the path
+    pending: BTreeMap<PathBuf, Kind>,
+    /// Paths with events seen with `--confirm-new-files`, in this
+    /// session or the earlier ones
+    seen: BTreeSet<PathBuf>,
+    /// File to save the run states to, so they survive restarts
+    file: Option<PathBuf>,
+}
+
+impl StateStore {
+    /// Load the state from the file if it exists, the runs are saved
+    /// to it after that
+    pub fn with_file(file: PathBuf) -> Result<Self, String> {
+        let mut store = Self::default();
+        if file.exists() {
+            let saved: Saved = std::fs::read_to_string(&file)
+                .map_err(|e| e.to_string())
+                .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+                .map_err(|e| format!("{}: {}", file.to_string_lossy(), e))?;
+            let runs = match saved {
+                Saved::Runs(runs) => runs,
+                Saved::Full {
+                    runs,
+                    pending,
+                    seen,
+                } => {
+                    store.pending = pending;
+                    store.seen = seen;
+                    runs
+                }
+            };
+            for run in runs {
+                store.runs.insert((run.path.clone(), run.rule.clone()), run);
+            }
+        }
+        store.file = Some(file);
+        Ok(store)
+    }
+
+    pub fn get_mut(&mut self, path: &Path) -> &mut PathState {
+        self.paths.entry(path.to_path_buf()).or_default()
+    }
+
+    /// Record that the path had an event, true if it's a new file: one
+    /// never seen before that the event `created`
+    pub fn first_seen(&mut self, path: &Path, created: bool) -> bool {
+        if !self.seen.insert(path.to_path_buf()) {
+            return false;
+        }
+        self.save_or_warn();
+        created
+    }
+
+    /// Rule hasn't run yet in this session
+    pub fn first_run(&self, rule: &str) -> bool {
+        !self.rules_run.contains(rule)
+    }
+
+    pub fn mark_rule_run(&mut self, rule: &str) {
+        self.rules_run.insert(rule.to_string());
+    }
+
+    /// Add the variables about the previous runs of the rule for the
+    /// path: {first_run}, {last_exit}, {last_run_at},
+    /// {consecutive_failures}, and its changes: {file_count}
+    pub fn run_vars(&self, path: &Path, rule: &str, map: &mut HashMap<String, String>) {
+        map.insert("first_run".to_string(), self.first_run(rule).to_string());
+        // this change isn't counted yet
+        let events = self.paths.get(path).map(|s| s.events).unwrap_or_default();
+        map.insert("file_count".to_string(), (events + 1).to_string());
+        let run = self
+            .runs
+            .get(&(path.to_path_buf(), rule.to_string()))
+            .cloned()
+            .unwrap_or_default();
+        map.insert("last_exit".to_string(), run.last_exit);
+        map.insert(
+            "last_run_at".to_string(),
+            run.last_run_at
+                .map(|t| humantime::format_rfc3339_seconds(t).to_string())
+                .unwrap_or_default(),
+        );
+        map.insert(
+            "consecutive_failures".to_string(),
+            run.consecutive_failures.to_string(),
+        );
+    }
+
+    /// Record the finished run of the rule for the path
+    pub fn record_run(&mut self, path: &Path, rule: &str, status: &exec::RunStatus) {
+        let run = self
+            .runs
+            .entry((path.to_path_buf(), rule.to_string()))
+            .or_insert_with(|| RunState {
+                path: path.to_path_buf(),
+                rule: rule.to_string(),
+                ..Default::default()
+            });
+        run.last_exit = exec::exit_code(&status.status);
+        run.last_run_at = Some(SystemTime::now());
+        if status.success() {
+            run.consecutive_failures = 0;
+        } else {
+            run.consecutive_failures += 1;
+        }
+        self.save_or_warn();
+    }
+
+    /// Defer the event for the path until the window ends, merged with
+    /// the one already deferred for it
+    pub fn defer(&mut self, path: &Path, kinds: &[notify::EventKind]) {
+        let mut all: Vec<notify::EventKind> = self
+            .pending
+            .get(path)
+            .map(|k| vec![k.event_kind()])
+            .unwrap_or_default();
+        all.extend(kinds);
+        self.pending
+            .insert(path.to_path_buf(), Kind::net(path, &all));
+        self.save_or_warn();
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Files of the `await_set` that haven't arrived for the run
+    pub fn awaiting_missing(&self, awaiting: &Awaiting) -> Vec<PathBuf> {
+        awaiting.missing(&self.paths).into_iter().cloned().collect()
+    }
+
+    /// Hold the run of the path until its `await_set` arrives, another
+    /// event of the path keeps the time it's waited until. The path has
+    /// arrived for the runs that wait for it.
+    pub fn await_set(&mut self, key: &Path, mut awaiting: Awaiting) {
+        let state = self.get_mut(key);
+        state.last_event = Some(awaiting.since);
+        if let Some(held) = &state.awaiting {
+            awaiting.until = held.until;
+        }
+        state.awaiting = Some(awaiting);
+    }
+
+    pub fn awaiting_count(&self) -> usize {
+        self.paths.values().filter(|s| s.awaiting.is_some()).count()
+    }
+
+    /// Take the held runs whose files have all arrived or that waited
+    /// too long
+    pub fn take_awaited(&mut self, now: Instant) -> Vec<Awaiting> {
+        let done: Vec<PathBuf> = self
+            .paths
+            .iter()
+            .filter(|(_, s)| {
+                s.awaiting
+                    .as_ref()
+                    .is_some_and(|a| a.done(&self.paths, now))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        done.iter()
+            .filter_map(|key| self.paths.get_mut(key)?.awaiting.take())
+            .collect()
+    }
+
+    /// Take all the deferred events to run them
+    pub fn take_pending(&mut self) -> Vec<(PathBuf, Kind)> {
+        let pending = std::mem::take(&mut self.pending);
+        if !pending.is_empty() {
+            self.save_or_warn();
+        }
+        pending.into_iter().collect()
+    }
+
+    fn save_or_warn(&self) {
+        if let Err(e) = self.save() {
+            infoln!("{}: saving the state file: {}", "Error".bold().red(), e);
+        }
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let file = match &self.file {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        let saved = Saved::Full {
+            runs: self.runs.values().cloned().collect(),
+            pending: self.pending.clone(),
+            seen: self.seen.clone(),
+        };
+        let tmp = file.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string(&saved)?)?;
+        std::fs::rename(tmp, file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deferred_events_survive_restarts() {
+        let dir = std::env::temp_dir().join(format!("onchange-defer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("state.json");
+        let (a, b, gone) = (dir.join("a.md"), dir.join("b.md"), dir.join("gone.md"));
+        std::fs::write(&a, "").unwrap();
+        std::fs::write(&b, "").unwrap();
+
+        let mut state = StateStore::with_file(file.clone()).unwrap();
+        state.defer(&b, &[Kind::Modify.event_kind()]);
+        state.defer(&gone, &[Kind::Create.event_kind()]);
+        state.defer(&a, &[Kind::Create.event_kind()]);
+        // merged with the one deferred before
+        state.defer(&a, &[Kind::Modify.event_kind()]);
+        state.defer(&gone, &[Kind::Remove.event_kind()]);
+        drop(state);
+
+        let mut state = StateStore::with_file(file.clone()).unwrap();
+        assert_eq!(state.pending_count(), 3);
+        // drained in the order of the paths, each once
+        assert_eq!(
+            state.take_pending(),
+            [(a, Kind::Create), (b, Kind::Modify), (gone, Kind::Remove)]
+        );
+        assert_eq!(StateStore::with_file(file).unwrap().pending_count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn paths_are_new_until_seen() {
+        let dir = std::env::temp_dir().join(format!("onchange-seen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("state.json");
+        let (new, old) = (dir.join("new.md"), dir.join("old.md"));
+        let mut state = StateStore::with_file(file.clone()).unwrap();
+        assert!(state.first_seen(&new, true));
+        assert!(!state.first_seen(&new, true));
+        // the first event of an old file doesn't create it
+        assert!(!state.first_seen(&old, false));
+        drop(state);
+        let mut state = StateStore::with_file(file).unwrap();
+        assert!(!state.first_seen(&new, true));
+        assert!(!state.first_seen(&old, true));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,388 @@
+use crate::report::Outcome;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of recent runs used for the average duration
+const WINDOW: usize = 10;
+/// Runs needed before giving any advice
+const MIN_RUNS: usize = 3;
+/// Advise when the average run takes this many times the debounce duration
+const SLOW_FACTOR: u32 = 10;
+
+/// Statistics of the runs of a rule
+#[derive(Default)]
+struct RuleStats {
+    /// Durations of the recent runs
+    durations: VecDeque<Duration>,
+    runs: usize,
+    failures: usize,
+    /// Runs skipped after the rule was found, by the reason
+    skipped: BTreeMap<Outcome, usize>,
+    /// Runs whose command couldn't be started
+    spawn_errors: usize,
+    last_run: Option<SystemTime>,
+    /// Runs started but not finished yet
+    in_flight: usize,
+    /// Duration of all the runs
+    total: Duration,
+    slowest: Duration,
+}
+
+/// Totals of a rule over the whole session
+pub struct RuleSummary {
+    pub rule: String,
+    pub runs: usize,
+    pub failures: usize,
+    pub mean: Duration,
+    pub slowest: Duration,
+}
+
+impl RuleSummary {
+    /// Percentage of the successful runs
+    pub fn success_rate(&self) -> f64 {
+        if self.runs == 0 {
+            return 0.0;
+        }
+        (self.runs - self.failures) as f64 * 100.0 / self.runs as f64
+    }
+}
+
+/// Statistics of the runs in the session
+#[derive(Default)]
+pub struct Stats {
+    rules: BTreeMap<String, RuleStats>,
+    advised: HashSet<String>,
+}
+
+impl Stats {
+    pub fn started(&mut self, rule: &str) {
+        self.rules.entry(rule.to_string()).or_default().in_flight += 1;
+    }
+
+    /// Number of runs of the rule started but not finished yet
+    pub fn in_flight(&self, rule: &str) -> usize {
+        self.rules.get(rule).map(|s| s.in_flight).unwrap_or(0)
+    }
+
+    /// Number of runs of all the rules not finished yet
+    pub fn in_flight_total(&self) -> usize {
+        self.rules.values().map(|s| s.in_flight).sum()
+    }
+
+    /// Run of the rule was cancelled before it started
+    pub fn cancelled(&mut self, rule: &str) {
+        let stats = self.rules.entry(rule.to_string()).or_default();
+        stats.in_flight = stats.in_flight.saturating_sub(1);
+    }
+
+    pub fn skipped(&mut self, rule: &str, outcome: Outcome) {
+        let stats = self.rules.entry(rule.to_string()).or_default();
+        *stats.skipped.entry(outcome).or_default() += 1;
+    }
+
+    /// Command of the rule's run couldn't be started, it's not a run
+    pub fn spawn_error(&mut self, rule: &str) {
+        let stats = self.rules.entry(rule.to_string()).or_default();
+        stats.spawn_errors += 1;
+        stats.in_flight = stats.in_flight.saturating_sub(1);
+    }
+
+    pub fn record(&mut self, rule: &str, elapsed: Duration, success: bool) {
+        let stats = self.rules.entry(rule.to_string()).or_default();
+        if stats.durations.len() == WINDOW {
+            stats.durations.pop_front();
+        }
+        stats.durations.push_back(elapsed);
+        stats.total += elapsed;
+        stats.slowest = stats.slowest.max(elapsed);
+        stats.runs += 1;
+        if !success {
+            stats.failures += 1;
+        }
+        stats.last_run = Some(SystemTime::now());
+        stats.in_flight = stats.in_flight.saturating_sub(1);
+    }
+
+    /// Totals of the rules that have run, by rule name
+    pub fn summary(&self) -> Vec<RuleSummary> {
+        self.rules
+            .iter()
+            .filter(|(_, s)| s.runs > 0)
+            .map(|(rule, s)| RuleSummary {
+                rule: rule.clone(),
+                runs: s.runs,
+                failures: s.failures,
+                mean: s.total / s.runs as u32,
+                slowest: s.slowest,
+            })
+            .collect()
+    }
+
+    /// Average duration of the recent runs of the rule
+    pub fn average(&self, rule: &str) -> Option<Duration> {
+        let durations = &self.rules.get(rule)?.durations;
+        if durations.is_empty() {
+            return None;
+        }
+        Some(durations.iter().sum::<Duration>() / durations.len() as u32)
+    }
+
+    /// Average duration of the rule if it's much longer than the
+    /// debounce duration, only once per rule
+    pub fn slow_rule(&mut self, rule: &str, debounce: Duration) -> Option<Duration> {
+        if self.advised.contains(rule) || self.rules.get(rule)?.durations.len() < MIN_RUNS {
+            return None;
+        }
+        let avg = self.average(rule)?;
+        if avg > debounce * SLOW_FACTOR {
+            self.advised.insert(rule.to_string());
+            Some(avg)
+        } else {
+            None
+        }
+    }
+
+    /// Metrics in the prometheus text format, labeled by rule
+    pub fn prometheus(&self) -> String {
+        type Metric = fn(&RuleStats) -> String;
+        let metrics: [(&str, &str, &str, Metric); 6] = [
+            ("runs_total", "counter", "Number of runs", |s| {
+                s.runs.to_string()
+            }),
+            ("failures_total", "counter", "Number of failed runs", |s| {
+                s.failures.to_string()
+            }),
+            (
+                "spawn_errors_total",
+                "counter",
+                "Number of runs whose command couldn't be started",
+                |s| s.spawn_errors.to_string(),
+            ),
+            (
+                "last_run_timestamp",
+                "gauge",
+                "Unix time the last run finished",
+                |s| {
+                    s.last_run
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs_f64())
+                        .unwrap_or_default()
+                        .to_string()
+                },
+            ),
+            (
+                "last_duration_seconds",
+                "gauge",
+                "Duration of the last run",
+                |s| {
+                    s.durations
+                        .back()
+                        .map(|d| d.as_secs_f64())
+                        .unwrap_or_default()
+                        .to_string()
+                },
+            ),
+            (
+                "queue_depth",
+                "gauge",
+                "Runs started but not finished",
+                |s| s.in_flight.to_string(),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            writeln!(text, "# HELP onchange_{} {}", name, help).unwrap();
+            writeln!(text, "# TYPE onchange_{} {}", name, kind).unwrap();
+            for (rule, stats) in &self.rules {
+                writeln!(
+                    text,
+                    "onchange_{}{{rule=\"{}\"}} {}",
+                    name,
+                    escape_label(rule),
+                    value(stats)
+                )
+                .unwrap();
+            }
+        }
+        writeln!(text, "# HELP onchange_skipped_total Number of runs skipped").unwrap();
+        writeln!(text, "# TYPE onchange_skipped_total counter").unwrap();
+        for (rule, stats) in &self.rules {
+            for (outcome, n) in &stats.skipped {
+                writeln!(
+                    text,
+                    "onchange_skipped_total{{rule=\"{}\",action=\"{}\"}} {}",
+                    escape_label(rule),
+                    outcome,
+                    n
+                )
+                .unwrap();
+            }
+        }
+        text
+    }
+
+    /// Write the metrics to the file, through a temporary file so the
+    /// reader never sees a partial file
+    pub fn write_metrics(&self, file: &Path) -> std::io::Result<()> {
+        let tmp = metrics_tmp(file);
+        std::fs::write(&tmp, self.prometheus())?;
+        std::fs::rename(tmp, file)
+    }
+}
+
+/// Temporary file the metrics are written to before the rename
+pub fn metrics_tmp(file: &Path) -> PathBuf {
+    let mut tmp = file.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// Escape the label value for the prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn slow_rules_are_advised_once() {
+        let debounce = Duration::from_millis(100);
+        let mut stats = Stats::default();
+        let ms = Duration::from_millis;
+        // slow, but not enough runs to tell yet
+        for _ in 1..MIN_RUNS {
+            stats.record("build", ms(5000), true);
+            assert_eq!(stats.slow_rule("build", debounce), None);
+        }
+        stats.record("build", ms(2000), true);
+        assert_eq!(stats.slow_rule("build", debounce), Some(ms(4000)));
+        // only once per rule
+        stats.record("build", ms(9000), true);
+        assert_eq!(stats.slow_rule("build", debounce), None);
+
+        // not above the factor
+        for _ in 0..MIN_RUNS {
+            stats.record("fmt", debounce * SLOW_FACTOR, true);
+        }
+        assert_eq!(stats.slow_rule("fmt", debounce), None);
+        // till the recent runs get slower; the failures count too
+        for _ in 0..WINDOW {
+            stats.record("fmt", ms(1500), false);
+        }
+        assert_eq!(stats.slow_rule("fmt", debounce), Some(ms(1500)));
+        assert_eq!(stats.slow_rule("unknown", debounce), None);
+    }
+
+    /// Samples of the prometheus text as `name{labels}` to value, the
+    /// label values unescaped; and the `# TYPE` of each metric
+    fn parse(text: &str) -> (HashMap<String, f64>, HashMap<String, String>) {
+        let mut samples = HashMap::new();
+        let mut types = HashMap::new();
+        for line in text.lines() {
+            if let Some(t) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = t.split_once(' ').unwrap();
+                types.insert(name.to_string(), kind.to_string());
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            let (name, labels) = series.split_once('{').unwrap();
+            let mut unescaped = String::new();
+            let mut chars = labels.strip_suffix('}').unwrap().chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next().unwrap() {
+                        'n' => unescaped.push('\n'),
+                        c => unescaped.push(c),
+                    },
+                    c => unescaped.push(c),
+                }
+            }
+            samples.insert(format!("{}{{{}}}", name, unescaped), value.parse().unwrap());
+        }
+        (samples, types)
+    }
+
+    #[test]
+    fn metrics_file_parses() {
+        let mut stats = Stats::default();
+        for success in [true, false] {
+            stats.started("tex");
+            stats.record("tex", Duration::from_millis(1500), success);
+        }
+        stats.started("tex");
+        stats.skipped("tex", Outcome::Dedup);
+        stats.started("odd \"name\"\\\n");
+        stats.spawn_error("odd \"name\"\\\n");
+        let file = std::env::temp_dir().join(format!("onchange-{}.prom", std::process::id()));
+        stats.write_metrics(&file).unwrap();
+        assert!(!metrics_tmp(&file).exists());
+        let (samples, types) = parse(&std::fs::read_to_string(&file).unwrap());
+        std::fs::remove_file(&file).unwrap();
+
+        let tex = |name: &str| samples[&format!("onchange_{}{{rule=\"tex\"}}", name)];
+        assert_eq!(tex("runs_total"), 2.0);
+        assert_eq!(tex("failures_total"), 1.0);
+        assert_eq!(tex("last_duration_seconds"), 1.5);
+        assert_eq!(tex("queue_depth"), 1.0);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert!((now.as_secs_f64() - tex("last_run_timestamp")).abs() < 60.0);
+        assert_eq!(
+            samples["onchange_skipped_total{rule=\"tex\",action=\"skip:dedup\"}"],
+            1.0
+        );
+        assert_eq!(
+            samples["onchange_spawn_errors_total{rule=\"odd \"name\"\\\n\"}"],
+            1.0
+        );
+        assert_eq!(types["onchange_runs_total"], "counter");
+        assert_eq!(types["onchange_queue_depth"], "gauge");
+        assert_eq!(samples.len(), 2 * 6 + 1);
+    }
+
+    #[test]
+    fn totals_of_the_rules() {
+        let mut stats = Stats::default();
+        let runs = [
+            ("deploy", 4_000, true),
+            ("deploy", 9_000, false),
+            ("tex", 500, true),
+            ("deploy", 2_000, true),
+        ];
+        for (rule, ms, success) in runs {
+            stats.started(rule);
+            stats.record(rule, Duration::from_millis(ms), success);
+        }
+        // never ran
+        stats.skipped("md", Outcome::NoRule);
+        let summary = stats.summary();
+        let totals: Vec<(&str, usize, usize, u128, u128)> = summary
+            .iter()
+            .map(|s| {
+                (
+                    s.rule.as_str(),
+                    s.runs,
+                    s.failures,
+                    s.mean.as_millis(),
+                    s.slowest.as_millis(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            totals,
+            [("deploy", 3, 1, 5_000, 9_000), ("tex", 1, 0, 500, 500)]
+        );
+        assert_eq!(summary[0].success_rate().round(), 67.0);
+        assert_eq!(summary[1].success_rate(), 100.0);
+    }
+}
@@ -0,0 +1,585 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// A built-in template variable
+pub struct Variable {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Value from the changed file's path and the current directory,
+    /// `None` for the variables set from elsewhere
+    pub value: Option<fn(&Path, &Path) -> String>,
+    /// Example value for the variables without `value`
+    pub sample: &'static str,
+}
+
+/// All the built-in variables, the per-file ones are computed from
+/// here in `template_vars`
+pub const VARIABLES: &[Variable] = &[
+    Variable {
+        name: "path",
+        description: "full path of the changed file",
+        value: Some(|path, _| path.to_string_lossy().to_string()),
+        sample: "",
+    },
+    Variable {
+        name: "rpath",
+        description: "relative path of the changed file wrt PWD",
+        value: Some(relative),
+        sample: "",
+    },
+    Variable {
+        name: "dir",
+        description: "directory (parent) of the changed file (absolute)",
+        value: Some(|path, _| parent(path).to_string_lossy().to_string()),
+        sample: "",
+    },
+    Variable {
+        name: "rdir",
+        description: "directory (parent) of the changed file (relative)",
+        value: Some(|path, pwd| relative(parent(path), pwd)),
+        sample: "",
+    },
+    Variable {
+        name: "name",
+        description: "filename of the changed file (without extension)",
+        value: Some(|path, _| split_name(&file_name(path)).0.to_string()),
+        sample: "",
+    },
+    Variable {
+        name: "ext",
+        description: "extension of the changed file (excludes `.`)",
+        value: Some(|path, _| split_name(&file_name(path)).1.to_string()),
+        sample: "",
+    },
+    Variable {
+        name: "name.ext",
+        description: "name and extension of the changed file",
+        value: Some(|path, _| file_name(path)),
+        sample: "",
+    },
+    Variable {
+        name: "rname",
+        description: "relative path of the changed file without extension",
+        value: Some(|path, pwd| {
+            let rdir = relative(parent(path), pwd);
+            let name = file_name(path);
+            let name = split_name(&name).0;
+            if rdir.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}{}{}", rdir, std::path::MAIN_SEPARATOR, name)
+            }
+        }),
+        sample: "",
+    },
+    Variable {
+        name: "pwd",
+        description: "current directory onchange was started in",
+        value: Some(|_, pwd| pwd.to_string_lossy().to_string()),
+        sample: "",
+    },
+    Variable {
+        name: "watched",
+        description: "shell quoted watched paths, as given",
+        value: None,
+        sample: "'src' 'Cargo.toml'",
+    },
+    Variable {
+        name: "watched_file",
+        description: "file with the watched paths, one per line",
+        value: None,
+        sample: "/tmp/onchange-watched-1234",
+    },
+    Variable {
+        name: "rundir",
+        description: "scratch directory of the session, removed on exit",
+        value: None,
+        sample: "/tmp/onchange-run-1234",
+    },
+    Variable {
+        name: "onchange_version",
+        description: "version of onchange",
+        value: None,
+        sample: env!("CARGO_PKG_VERSION"),
+    },
+    Variable {
+        name: "config_hash",
+        description: "hash of the config's settings, empty without a config",
+        value: None,
+        sample: "5f0e3c1a9b2d7e44",
+    },
+    Variable {
+        name: "session_start",
+        description: "time onchange was started at",
+        value: None,
+        sample: "2024-03-01T09:30:00Z",
+    },
+    Variable {
+        name: "pkgroot",
+        description: "nearest directory of the file with the rule's workdir_marker in it",
+        value: None,
+        sample: "/home/user/repo/crates/core",
+    },
+    Variable {
+        name: "pkgrel",
+        description: "path of the file relative to {pkgroot}",
+        value: None,
+        sample: "src/lib.rs",
+    },
+    Variable {
+        name: "event",
+        description: "change to the file: create, modify, remove, rename or any",
+        value: None,
+        sample: "modify",
+    },
+    Variable {
+        name: "event_raw",
+        description: "the raw events from the watcher, for debugging",
+        value: None,
+        sample: "[Modify(Data(Content))]",
+    },
+    Variable {
+        name: "rule",
+        description: "rule for the changed file, \"default\" for default_command",
+        value: None,
+        sample: "rust",
+    },
+    Variable {
+        name: "rewrite.NAME",
+        description: "path with the prefix of the rewrite NAME swapped",
+        value: None,
+        sample: "mirror/main.rs",
+    },
+    Variable {
+        name: "srcpath",
+        description: "path from the first matching rewrite (by name)",
+        value: None,
+        sample: "mirror/main.rs",
+    },
+    Variable {
+        name: "synthetic",
+        description: "\"true\" for the events from the simulate subcommand",
+        value: None,
+        sample: "false",
+    },
+    Variable {
+        name: "action",
+        description: "run, or skip:<reason> for the events shown with --verbose (--template only)",
+        value: None,
+        sample: "run",
+    },
+    Variable {
+        name: "paths",
+        description: "shell quoted paths the command runs for (batch mode)",
+        value: None,
+        sample: "'/home/user/project/src/main.rs'",
+    },
+    Variable {
+        name: "rpaths",
+        description: "shell quoted relative paths the command runs for",
+        value: None,
+        sample: "'src/main.rs'",
+    },
+    Variable {
+        name: "count",
+        description: "number of files the command runs for",
+        value: None,
+        sample: "1",
+    },
+    Variable {
+        name: "batch.count",
+        description: "number of files changed together in this batch",
+        value: None,
+        sample: "3",
+    },
+    Variable {
+        name: "batch.created",
+        description: "number of files created in the batch",
+        value: None,
+        sample: "1",
+    },
+    Variable {
+        name: "batch.modified",
+        description: "number of files modified in the batch",
+        value: None,
+        sample: "2",
+    },
+    Variable {
+        name: "batch.removed",
+        description: "number of files removed in the batch",
+        value: None,
+        sample: "0",
+    },
+    Variable {
+        name: "batch.renamed",
+        description: "number of files renamed in the batch",
+        value: None,
+        sample: "0",
+    },
+    Variable {
+        name: "batch.exts",
+        description: "comma separated unique extensions in the batch",
+        value: None,
+        sample: "rs,toml",
+    },
+    Variable {
+        name: "batch.roots",
+        description: "comma separated watched paths touched by the batch",
+        value: None,
+        sample: "/home/user/project/src",
+    },
+    Variable {
+        name: "chunk_index",
+        description: "number of the chunk of the batch, with batch_size",
+        value: None,
+        sample: "1",
+    },
+    Variable {
+        name: "chunk_total",
+        description: "number of chunks the batch is split into",
+        value: None,
+        sample: "1",
+    },
+    Variable {
+        name: "first_run",
+        description: "\"true\" if the rule hasn't run yet in this session",
+        value: None,
+        sample: "false",
+    },
+    Variable {
+        name: "in_flight",
+        description: "\"true\" if a run of the rule is still running",
+        value: None,
+        sample: "false",
+    },
+    Variable {
+        name: "in_flight_count",
+        description: "number of the rule's runs still running",
+        value: None,
+        sample: "0",
+    },
+    Variable {
+        name: "file_count",
+        description: "number of changes of the file in this session, this one included",
+        value: None,
+        sample: "3",
+    },
+    Variable {
+        name: "last_exit",
+        description: "exit code of the previous run for the file",
+        value: None,
+        sample: "0",
+    },
+    Variable {
+        name: "last_run_at",
+        description: "time of the previous run for the file",
+        value: None,
+        sample: "2024-01-01T10:00:00Z",
+    },
+    Variable {
+        name: "consecutive_failures",
+        description: "number of failed runs in a row for the file",
+        value: None,
+        sample: "0",
+    },
+    Variable {
+        name: "exit_code",
+        description: "exit code of the command (--done-template only)",
+        value: None,
+        sample: "0",
+    },
+    Variable {
+        name: "exit_name",
+        description: "readable exit status (--done-template only)",
+        value: None,
+        sample: "ok",
+    },
+    Variable {
+        name: "attempt",
+        description: "number of the command_fallbacks entry that ran last (--done-template only)",
+        value: None,
+        sample: "1",
+    },
+    Variable {
+        name: "duration_ms",
+        description: "run time of the command in ms (--done-template only)",
+        value: None,
+        sample: "1520",
+    },
+    Variable {
+        name: "duration",
+        description: "readable run time of the command (--done-template only)",
+        value: None,
+        sample: "1s",
+    },
+];
+
+/// Other names for the built-in variables, as `(alias, variable)`
+pub const ALIASES: &[(&str, &str)] = &[
+    ("filename", "name.ext"),
+    ("basename", "name.ext"),
+    ("dirname", "dir"),
+    ("stem", "name"),
+];
+
+/// Variable the built-in name stands for, itself for the variables
+/// and the original for the aliases
+pub fn builtin_target(name: &str) -> Option<&'static str> {
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map(|(_, var)| *var)
+        .or_else(|| VARIABLES.iter().find(|v| v.name == name).map(|v| v.name))
+}
+
+/// Add the aliases (built-in and the given ones) of the variables in
+/// the map, next to the originals
+pub fn add_aliases(map: &mut HashMap<String, String>, aliases: &BTreeMap<String, String>) {
+    let all = ALIASES
+        .iter()
+        .map(|&(a, v)| (a, v))
+        .chain(aliases.iter().map(|(a, v)| (a.as_str(), v.as_str())));
+    for (alias, var) in all {
+        if let Some(value) = map.get(var) {
+            map.insert(alias.to_string(), value.clone());
+        }
+    }
+}
+
+/// `{pkgroot}` and `{pkgrel}` of the path: its nearest directory (from
+/// its own one up) with the marker file, and the path relative to it.
+/// The search stops at the root, `None` if there's no such directory.
+pub fn package_vars(
+    path: &Path,
+    marker: &str,
+    root: Option<&Path>,
+) -> Option<HashMap<String, String>> {
+    let pkgroot: PathBuf = parent(path)
+        .ancestors()
+        .take_while(|dir| root.is_none_or(|r| dir.starts_with(r)))
+        .find(|dir| dir.join(marker).exists())?
+        .components()
+        .collect();
+    Some(HashMap::from([
+        ("pkgrel".to_string(), relative(path, &pkgroot)),
+        ("pkgroot".to_string(), pkgroot.to_string_lossy().to_string()),
+    ]))
+}
+
+/// Variables that stay the same for the whole session, `{pwd}` and
+/// the environment variables as `{env.NAME}`
+pub fn session_vars(pwd: &Path) -> HashMap<String, String> {
+    let mut map: HashMap<String, String> = std::env::vars()
+        .map(|(k, v)| (format!("env.{}", k), v))
+        .collect();
+    map.insert("pwd".to_string(), pwd.to_string_lossy().to_string());
+    map
+}
+
+/// Parse a `key:value` line from the variables command.
+///
+/// A byte order mark and the trailing carriage return are removed, the
+/// key is trimmed, and the value is trimmed unless it's quoted with `"`
+/// or `'`, then the quotes are removed and the spaces inside are kept.
+/// Keys with whitespace inside are an error, as the templates can't
+/// use them. Lines without `:` or with an empty key are ignored.
+pub fn parse_var_line(line: &str) -> Option<Result<(String, String), String>> {
+    let line = line.trim_start_matches('\u{feff}').trim_end_matches('\r');
+    let (key, value) = line.split_once(':')?;
+    let key = key.trim();
+    if key.is_empty() {
+        return None;
+    }
+    if key.contains(char::is_whitespace) {
+        return Some(Err(format!(
+            "variable {:?} has whitespace in its name",
+            key
+        )));
+    }
+    let value = value.trim();
+    let value = ['"', '\'']
+        .iter()
+        .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+        .unwrap_or(value);
+    Some(Ok((key.to_string(), value.to_string())))
+}
+
+/// Path used for the example values in `onchange vars`
+pub const SAMPLE_PATH: &str = "/home/user/project/src/main.rs";
+/// Current directory used for the example values in `onchange vars`
+pub const SAMPLE_PWD: &str = "/home/user/project";
+
+/// Variables of the changed file that only depend on its path
+pub fn path_vars(path: &Path, pwd: &Path) -> HashMap<String, String> {
+    let mut map = VARIABLES
+        .iter()
+        .filter_map(|v| Some((v.name.to_string(), v.value?(path, pwd))))
+        .collect();
+    add_aliases(&mut map, &BTreeMap::new());
+    map
+}
+
+/// Example value of the variable for the sample path
+pub fn sample_value(var: &Variable) -> String {
+    match var.value {
+        Some(value) => value(Path::new(SAMPLE_PATH), Path::new(SAMPLE_PWD)),
+        None => var.sample.to_string(),
+    }
+}
+
+/// Split the file name into name and extension at the last dot.
+///
+/// A leading dot is part of the name, so ".env" has no extension and
+/// ".env.local" is ".env" with "local" extension; "archive.tar.gz" is
+/// "archive.tar" with "gz" extension.
+pub fn split_name(file_name: &str) -> (&str, &str) {
+    match file_name.rsplit_once('.') {
+        Some((name, ext)) if !name.is_empty() => (name, ext),
+        _ => (file_name, ""),
+    }
+}
+
+/// Largest index up to `max` that is on a char boundary of the string
+pub fn floor_char_boundary(s: &str, max: usize) -> usize {
+    (0..=max.min(s.len()))
+        .rev()
+        .find(|&i| s.is_char_boundary(i))
+        .unwrap_or(0)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+fn parent(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new("/"))
+}
+
+fn relative(path: &Path, pwd: &Path) -> String {
+    pathdiff::diff_paths(path, pwd)
+        .unwrap()
+        .to_string_lossy()
+        .to_string()
+}
+
+/// A runnable example for `onchange examples`
+pub struct Example {
+    pub description: &'static str,
+    pub command: &'static str,
+}
+
+pub const EXAMPLES: &[Example] = &[
+    Example {
+        description: "Rebuild the PDF when the LaTeX file changes",
+        command: "onchange paper.tex -- latexmk -pdf {name.ext}",
+    },
+    Example {
+        description: "Mirror the changed files to a server",
+        command: "onchange -r src -- rsync -a {rpath} server:backup/{rdir}/",
+    },
+    Example {
+        description: "Run the tests of the package the changed file is in",
+        command: "onchange -r . --files-only -- go test ./{rdir}",
+    },
+    Example {
+        description: "Show what changed together, without running anything",
+        command: "onchange -r . -t '{batch.count} files changed: {rpath}'",
+    },
+    Example {
+        description: "Build on changes and report how long it took",
+        command: "onchange -r src --done-template '{exit_name} in {duration}' -- cargo build",
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variable_lines() {
+        let ok = |k: &str, v: &str| Some(Ok((k.to_string(), v.to_string())));
+        assert_eq!(parse_var_line("name: value"), ok("name", "value"));
+        assert_eq!(parse_var_line("\u{feff}name:value\r"), ok("name", "value"));
+        assert_eq!(parse_var_line("\tname\t:\tvalue\t"), ok("name", "value"));
+        assert_eq!(parse_var_line("name: ' a b '"), ok("name", " a b "));
+        assert_eq!(parse_var_line("name: \"a\" b\""), ok("name", "a\" b"));
+        assert_eq!(parse_var_line("url: http://x:80"), ok("url", "http://x:80"));
+        assert_eq!(parse_var_line("empty:"), ok("empty", ""));
+        assert!(matches!(parse_var_line("a name: x"), Some(Err(_))));
+        assert_eq!(parse_var_line("no colon"), None);
+        assert_eq!(parse_var_line(" : value"), None);
+        assert_eq!(parse_var_line(""), None);
+    }
+
+    #[test]
+    fn dotfile_names() {
+        let cases = [
+            (".gitignore", ".gitignore", ""),
+            (".env.local", ".env", "local"),
+            ("archive.tar.gz", "archive.tar", "gz"),
+            ("README", "README", ""),
+            ("notes.", "notes", ""),
+        ];
+        for (file, name, ext) in cases {
+            assert_eq!(split_name(file), (name, ext), "{}", file);
+            let vars = path_vars(
+                &Path::new("/project/docs").join(file),
+                Path::new("/project"),
+            );
+            assert_eq!(vars["name"], name);
+            assert_eq!(vars["ext"], ext);
+            assert_eq!(vars["name.ext"], file);
+            assert_eq!(vars["rname"], format!("docs/{}", name));
+        }
+    }
+
+    #[test]
+    fn nearest_package_root() {
+        let repo = std::env::temp_dir().join(format!("onchange-monorepo-{}", std::process::id()));
+        for dir in [
+            "packages/web/src/pages",
+            "packages/api/src",
+            "tools/scripts",
+        ] {
+            std::fs::create_dir_all(repo.join(dir)).unwrap();
+        }
+        for marker in [
+            "package.json",
+            "packages/web/package.json",
+            "packages/api/package.json",
+        ] {
+            std::fs::write(repo.join(marker), "{}").unwrap();
+        }
+        let pkg = |file: &str, root: Option<&Path>| {
+            package_vars(&repo.join(file), "package.json", root)
+                .map(|m| (m["pkgroot"].clone(), m["pkgrel"].clone()))
+        };
+        let root = |dir: &str| repo.join(dir).to_string_lossy().to_string();
+        assert_eq!(
+            pkg("packages/web/src/pages/index.tsx", None),
+            Some((root("packages/web"), "src/pages/index.tsx".to_string()))
+        );
+        assert_eq!(
+            pkg("packages/api/package.json", None),
+            Some((root("packages/api"), "package.json".to_string()))
+        );
+        // no package of its own, the repo's is the nearest
+        assert_eq!(
+            pkg("tools/scripts/release.sh", None),
+            Some((
+                repo.to_string_lossy().to_string(),
+                "tools/scripts/release.sh".to_string()
+            ))
+        );
+        // the search stops at the watch root
+        assert_eq!(
+            pkg("tools/scripts/release.sh", Some(&repo.join("tools"))),
+            None
+        );
+        assert_eq!(package_vars(&repo.join("a.rs"), "Cargo.toml", None), None);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+}
@@ -0,0 +1,684 @@
+use colored::Colorize;
+use new_string_template::template::Template;
+use notify::{RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+/// Prefix of the sentinel files `--self-test` creates
+const SENTINEL: &str = ".onchange-self-test-";
+
+/// Time the events of the sentinel files have to arrive in
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Interval to look for the replaced watched files at
+const REWATCH_POLL: Duration = Duration::from_millis(50);
+
+/// Time the replaced watched file has to appear again in, the editors
+/// write the new file right after moving the old one away
+const REWATCH_FOR: Duration = Duration::from_secs(2);
+
+/// Watches the files watched directly again after they're replaced
+/// (like the atomic saves of the editors: a temporary file renamed
+/// over the file), as their watch goes with the old file
+pub struct Rewatch {
+    /// Watched files, as given and their absolute path
+    files: Vec<(PathBuf, PathBuf)>,
+    /// Files waited for by their index, with the time to give up at
+    pending: Vec<(usize, Instant)>,
+}
+
+/// Recursive watch done by walking the directories ourself, so the
+/// excluded directories are never registered with the watcher
+pub struct RecursiveWatch {
+    exclude: Vec<glob::Pattern>,
+    registered: HashSet<PathBuf>,
+    /// Number of directories skipped due to exclusion
+    pub excluded: usize,
+    /// Number of files in the watched directories, counted up to
+    /// `FILE_COUNT_CAP`
+    pub files: usize,
+}
+
+/// File with the watched paths one per line for `{watched_file}`,
+/// removed when dropped
+pub struct WatchedFile {
+    pub path: PathBuf,
+}
+
+impl WatchedFile {
+    pub fn create(paths: &[PathBuf]) -> std::io::Result<Self> {
+        let (path, mut file) = fresh_temp("onchange-watched", |path| {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+        })?;
+        let mut contents = String::new();
+        for p in paths {
+            contents.push_str(&p.to_string_lossy());
+            contents.push('\n');
+        }
+        if let Err(e) = file.write_all(contents.as_bytes()) {
+            std::fs::remove_file(&path).ok();
+            return Err(e);
+        }
+        Ok(Self { path })
+    }
+}
+
+impl Drop for WatchedFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Scratch directory of the session for `{rundir}`, removed with its
+/// contents when dropped unless it's to be kept
+pub struct RunDir {
+    pub path: PathBuf,
+    pub keep: bool,
+}
+
+impl RunDir {
+    pub fn create(keep: bool) -> std::io::Result<Self> {
+        let (path, _) = fresh_temp("onchange-run", |path| {
+            DirBuilder::new().mode(0o700).create(path)
+        })?;
+        Ok(Self { path, keep })
+    }
+}
+
+/// Make a new entry in the temp directory with `create`, trying the
+/// next name when one exists; an existing one could be anyone's, so
+/// it's never reused
+fn fresh_temp<T>(
+    prefix: &str,
+    create: impl Fn(&Path) -> std::io::Result<T>,
+) -> std::io::Result<(PathBuf, T)> {
+    let stem = format!("{}-{}", prefix, std::process::id());
+    let mut n = 0;
+    loop {
+        let path = std::env::temp_dir().join(match n {
+            0 => stem.clone(),
+            n => format!("{}.{}", stem, n),
+        });
+        match create(&path) {
+            Ok(made) => return Ok((path, made)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && n < 100 => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl Drop for RunDir {
+    fn drop(&mut self) {
+        if self.keep {
+            crate::output::infoln!(
+                "{}: kept the run directory {:?}",
+                "Note".dimmed(),
+                self.path
+            );
+        } else {
+            std::fs::remove_dir_all(&self.path).ok();
+        }
+    }
+}
+
+/// Outcome of `--self-test` for the watched directories
+#[derive(Default)]
+pub struct SelfTest {
+    /// The events of their sentinel arrived
+    pub passed: Vec<PathBuf>,
+    /// Nothing arrived from them
+    pub silent: Vec<PathBuf>,
+    /// The sentinel couldn't be written, with the reason
+    pub skipped: Vec<(PathBuf, String)>,
+}
+
+/// Sentinel file of `--self-test`, its events never run anything
+pub fn is_sentinel(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|n| n.to_string_lossy().starts_with(SENTINEL))
+}
+
+/// Create and remove a sentinel file in each of the watched
+/// directories and wait for their events; the other events received
+/// meanwhile are given to `other`
+pub fn self_test(
+    dirs: &[PathBuf],
+    rx: &Receiver<notify::Result<notify::Event>>,
+    mut other: impl FnMut(notify::Event),
+) -> SelfTest {
+    let mut result = SelfTest::default();
+    let mut waiting: HashMap<String, PathBuf> = HashMap::new();
+    for (i, dir) in dirs.iter().enumerate() {
+        let name = format!("{}{}-{}", SENTINEL, std::process::id(), i);
+        let sentinel = dir.join(&name);
+        match std::fs::write(&sentinel, "") {
+            Ok(_) => {
+                std::fs::remove_file(&sentinel).ok();
+                waiting.insert(name, dir.clone());
+            }
+            Err(e) => result.skipped.push((dir.clone(), e.to_string())),
+        }
+    }
+    let end = Instant::now() + SELF_TEST_TIMEOUT;
+    while !waiting.is_empty() {
+        let left = end.saturating_duration_since(Instant::now());
+        let Ok(res) = rx.recv_timeout(left) else {
+            break;
+        };
+        let Ok(event) = res else {
+            continue;
+        };
+        if !event.paths.iter().any(|p| is_sentinel(p)) {
+            other(event);
+            continue;
+        }
+        for path in &event.paths {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if let Some(dir) = waiting.remove(name.as_ref()) {
+                result.passed.push(dir);
+            }
+        }
+    }
+    result.silent = waiting.into_values().collect();
+    result.silent.sort();
+    result
+}
+
+/// Watched path the changed path is from, the deepest one if the
+/// watched paths are nested
+pub fn watch_root<'a>(path: &Path, roots: &'a [PathBuf]) -> Option<&'a Path> {
+    roots
+        .iter()
+        .filter(|r| path.starts_with(r))
+        .max_by_key(|r| r.components().count())
+        .map(|r| r.as_path())
+}
+
+/// Kind of paths that are allowed to be watched
+#[derive(Clone, Copy, PartialEq)]
+pub enum PathKind {
+    Any,
+    Files,
+    Dirs,
+}
+
+/// Same path for all the ways to reach the file, through the symlinks
+/// or the `.` and `..` components; only lexically for the removed files
+pub fn canonical_path(path: &Path) -> PathBuf {
+    path.canonicalize()
+        .unwrap_or_else(|_| path.components().collect())
+}
+
+/// Check the paths to watch exist and are of the allowed kind
+pub fn check_paths(paths: &[PathBuf], kind: PathKind) -> Result<(), String> {
+    for path in paths {
+        let meta = path.metadata().map_err(|e| format!("{:?}: {}", path, e))?;
+        match kind {
+            PathKind::Files if !meta.is_file() => {
+                return Err(format!("{:?} is not a regular file (--files-only)", path))
+            }
+            PathKind::Dirs if !meta.is_dir() => {
+                return Err(format!("{:?} is not a directory (--dirs-only)", path))
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// A glob pattern given as a watch path, the directory before its
+/// first wildcard is watched and the events are matched to the pattern
+pub struct GlobWatch {
+    /// Directory to watch, as given
+    pub base: PathBuf,
+    /// Absolute path of the base, for comparing with the watch roots
+    pub root: PathBuf,
+    /// Absolute pattern to match the changed paths with
+    pub pattern: glob::Pattern,
+    /// Pattern goes into the subdirectories of the base
+    pub recursive: bool,
+}
+
+impl GlobWatch {
+    pub fn matches(&self, path: &Path) -> bool {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        let path: PathBuf = path.components().collect();
+        self.pattern.matches_path_with(&path, options)
+    }
+}
+
+/// Path has the glob wildcards in it
+pub fn has_wildcard(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Split the glob patterns from the watch paths, they are replaced by
+/// their base directories in the returned paths
+pub fn split_globs(
+    paths: &[PathBuf],
+    cwd: &Path,
+) -> Result<(Vec<PathBuf>, Vec<GlobWatch>), String> {
+    let mut plain = Vec::new();
+    let mut globs = Vec::new();
+    for path in paths {
+        if !has_wildcard(path) {
+            plain.push(path.clone());
+            continue;
+        }
+        let abs: PathBuf = cwd.join(path).components().collect();
+        let pattern = glob::Pattern::new(&abs.to_string_lossy())
+            .map_err(|e| format!("watch pattern {:?}: {}", path, e))?;
+        let base: PathBuf = path
+            .components()
+            .take_while(|c| !has_wildcard(Path::new(c)))
+            .collect();
+        let rest = path.components().count() - base.components().count();
+        let base = if base.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            base
+        };
+        globs.push(GlobWatch {
+            root: cwd.join(&base).components().collect(),
+            recursive: rest > 1 || path.to_string_lossy().contains("**"),
+            base,
+            pattern,
+        });
+    }
+    let mut paths = plain.clone();
+    for glob in &globs {
+        if !paths.contains(&glob.base) {
+            paths.push(glob.base.clone());
+        }
+    }
+    // the bases watched as plain paths too aren't filtered
+    globs.retain(|g| !plain.contains(&g.base));
+    Ok((paths, globs))
+}
+
+/// Watch paths from the config or the `--watch-from` file rendered
+/// with the session variables, `source` is for the error messages
+pub fn render_paths(
+    entries: &[String],
+    vars: &HashMap<String, String>,
+    source: &str,
+) -> Result<Vec<PathBuf>, String> {
+    entries
+        .iter()
+        .map(|entry| {
+            if let Some(var) = crate::config::template_variables(entry, None)
+                .into_iter()
+                .find(|v| !vars.contains_key(v))
+            {
+                return Err(format!(
+                    "watch path {:?} from {}: unknown variable {{{}}}",
+                    entry, source, var
+                ));
+            }
+            Ok(PathBuf::from(
+                Template::new(entry).render_nofail_string(vars),
+            ))
+        })
+        .collect()
+}
+
+/// Watch paths from the file, one per line, empty lines and the lines
+/// starting with `#` are skipped
+pub fn read_watch_file(file: &Path) -> Result<Vec<String>, String> {
+    let text = std::fs::read_to_string(file).map_err(|e| format!("{:?}: {}", file, e))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+impl Rewatch {
+    /// Watch the paths that are files again when they're replaced, the
+    /// absolute ones are the event paths
+    pub fn new(paths: &[PathBuf], cwd: &Path) -> Self {
+        let files = paths
+            .iter()
+            .filter(|p| !p.is_dir())
+            .map(|p| (p.clone(), cwd.join(p).components().collect()))
+            .collect();
+        Self {
+            files,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The event may have taken away the watch of the file, it's
+    /// watched again once the file is back
+    pub fn event(&mut self, path: &Path, kind: &notify::EventKind, now: Instant) {
+        use notify::event::ModifyKind;
+        use notify::EventKind;
+        if !matches!(
+            kind,
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+        ) {
+            return;
+        }
+        let path: PathBuf = path.components().collect();
+        for (i, (_, abs)) in self.files.iter().enumerate() {
+            if *abs == path && !self.pending.iter().any(|(p, _)| *p == i) {
+                self.pending.push((i, now + REWATCH_FOR));
+            }
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        (!self.pending.is_empty()).then(|| Instant::now() + REWATCH_POLL)
+    }
+
+    /// Watch the replaced files that are there again, their absolute
+    /// paths are returned as they've changed; the ones that don't come
+    /// back in time are given up on
+    pub fn retry(&mut self, watcher: &mut dyn Watcher, now: Instant) -> Vec<PathBuf> {
+        let mut back = Vec::new();
+        self.pending.retain(|&(i, until)| {
+            let (given, abs) = &self.files[i];
+            if given.exists() && watcher.watch(given, RecursiveMode::NonRecursive).is_ok() {
+                back.push(abs.clone());
+                return false;
+            }
+            now < until
+        });
+        back
+    }
+}
+
+/// Stop counting the files after this many
+pub const FILE_COUNT_CAP: usize = 10000;
+
+impl RecursiveWatch {
+    pub fn new(exclude: Vec<glob::Pattern>) -> Self {
+        Self {
+            exclude,
+            registered: HashSet::new(),
+            excluded: 0,
+            files: 0,
+        }
+    }
+
+    /// Directory matches one of the exclude patterns by its name or path
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        self.exclude
+            .iter()
+            .any(|p| p.matches(&name) || p.matches_path(path))
+    }
+
+    pub fn registered(&self) -> usize {
+        self.registered.len()
+    }
+
+    /// Watch the directory and all its subdirectories that aren't
+    /// excluded, returns the number of newly registered directories
+    pub fn add(&mut self, watcher: &mut dyn Watcher, root: &Path) -> notify::Result<usize> {
+        if !root.is_dir() {
+            watcher.watch(root, RecursiveMode::NonRecursive)?;
+            return Ok(0);
+        }
+        let mut count = 0;
+        let mut excluded = 0;
+        // counting stops past the cap, it's only shown as ">N files"
+        let mut files = self.files;
+        let walker = WalkDir::new(root).into_iter().filter_entry(|e| {
+            if !e.file_type().is_dir() {
+                if files <= FILE_COUNT_CAP {
+                    files += 1;
+                }
+                return false;
+            }
+            // the root is watched even if it matches
+            if e.depth() > 0 && self.is_excluded(e.path()) {
+                excluded += 1;
+                return false;
+            }
+            true
+        });
+        let mut dirs = vec![];
+        for entry in walker.filter_map(|e| e.ok()) {
+            dirs.push(entry.into_path());
+        }
+        for dir in dirs {
+            if self.registered.contains(&dir) {
+                continue;
+            }
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+            self.registered.insert(dir);
+            count += 1;
+        }
+        self.excluded += excluded;
+        self.files = files;
+        Ok(count)
+    }
+
+    /// Files in the directory (or the file itself), in the
+    /// subdirectories that aren't excluded too if `recursive`
+    pub fn files(&self, root: &Path, recursive: bool) -> Vec<PathBuf> {
+        if !root.is_dir() {
+            return vec![root.to_path_buf()];
+        }
+        WalkDir::new(root)
+            .max_depth(if recursive { usize::MAX } else { 1 })
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|e| {
+                e.depth() == 0 || !(e.file_type().is_dir() && self.is_excluded(e.path()))
+            })
+            .filter_map(|e| e.ok())
+            .filter(|e| !e.file_type().is_dir())
+            .map(|e| e.into_path())
+            .collect()
+    }
+
+    /// Update the watches from the changed path: new directories are
+    /// registered and the removed ones are forgotten
+    pub fn update(&mut self, watcher: &mut dyn Watcher, path: &Path) {
+        if path.is_dir() {
+            if !self.registered.contains(path) && !self.is_excluded(path) {
+                self.add(watcher, path).ok();
+            }
+        } else if !path.exists() {
+            self.registered.retain(|d| !d.starts_with(path));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn run_dir_is_never_reused() {
+        // a directory left by someone else under the expected name
+        let planted = std::env::temp_dir().join(format!("onchange-run-{}", std::process::id()));
+        std::fs::create_dir_all(&planted).unwrap();
+        let rundir = RunDir::create(false).unwrap();
+        assert_ne!(rundir.path, planted);
+        let mode = std::fs::metadata(&rundir.path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+        drop(rundir);
+        std::fs::remove_dir(&planted).ok();
+    }
+
+    #[test]
+    fn watched_file_is_never_reused() {
+        let watched = WatchedFile::create(&[PathBuf::from("/a")]).unwrap();
+        let other = WatchedFile::create(&[PathBuf::from("/b")]).unwrap();
+        assert_ne!(watched.path, other.path);
+        assert_eq!(std::fs::read_to_string(&watched.path).unwrap(), "/a\n");
+        let mode = std::fs::metadata(&other.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    /// Watcher that only keeps the paths it was asked to watch
+    #[derive(Default)]
+    struct Recorder(Vec<PathBuf>);
+
+    impl Watcher for Recorder {
+        fn new<F: notify::EventHandler>(_: F, _: notify::Config) -> notify::Result<Self> {
+            Ok(Self::default())
+        }
+
+        fn watch(&mut self, path: &Path, _: RecursiveMode) -> notify::Result<()> {
+            self.0.push(path.to_path_buf());
+            Ok(())
+        }
+
+        fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+            self.0.retain(|p| p != path);
+            Ok(())
+        }
+
+        fn kind() -> notify::WatcherKind {
+            notify::WatcherKind::NullWatcher
+        }
+    }
+
+    fn tree(name: &str, dirs: &[&str], files: &[&str]) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("onchange-watch-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&root).ok();
+        for d in dirs {
+            std::fs::create_dir_all(root.join(d)).unwrap();
+        }
+        for f in files {
+            std::fs::write(root.join(f), "").unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn excluded_directories_arent_registered() {
+        let root = tree(
+            "exclude",
+            &["src/bin", "docs", ".git/objects/ab", "target/debug/deps"],
+            &["src/main.rs", ".git/HEAD", "target/debug/deps/a.o"],
+        );
+        let exclude = vec![
+            glob::Pattern::new(".git").unwrap(),
+            glob::Pattern::new("target").unwrap(),
+        ];
+        let mut rwatch = RecursiveWatch::new(exclude);
+        let mut watcher = Recorder::default();
+        // root, src, src/bin and docs
+        assert_eq!(rwatch.add(&mut watcher, &root).unwrap(), 4);
+        assert_eq!(rwatch.registered(), 4);
+        assert_eq!(rwatch.excluded, 2);
+        assert_eq!(rwatch.files, 1);
+        assert!(watcher
+            .0
+            .iter()
+            .all(|p| !p.starts_with(root.join(".git")) && !p.starts_with(root.join("target"))));
+
+        // adding it again registers nothing new
+        assert_eq!(rwatch.add(&mut watcher, &root).unwrap(), 0);
+        assert_eq!(rwatch.registered(), 4);
+
+        // a new directory is picked up, an excluded one isn't
+        std::fs::create_dir_all(root.join("docs/img")).unwrap();
+        std::fs::create_dir_all(root.join("docs/target")).unwrap();
+        rwatch.update(&mut watcher, &root.join("docs/img"));
+        rwatch.update(&mut watcher, &root.join("docs/target"));
+        assert_eq!(rwatch.registered(), 5);
+
+        // and the removed ones are forgotten along with their children
+        std::fs::remove_dir_all(root.join("src")).unwrap();
+        rwatch.update(&mut watcher, &root.join("src"));
+        assert_eq!(rwatch.registered(), 3);
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn watch_list_of_one_kind() {
+        let root = tree("kinds", &["docs", "src"], &["a.md", "b.md"]);
+        let (file, other, dir) = (root.join("a.md"), root.join("b.md"), root.join("docs"));
+        let mixed = [file.clone(), dir.clone(), other.clone()];
+        assert!(check_paths(&mixed, PathKind::Any).is_ok());
+        assert_eq!(
+            check_paths(&mixed, PathKind::Files).unwrap_err(),
+            format!("{:?} is not a regular file (--files-only)", dir)
+        );
+        assert_eq!(
+            check_paths(&mixed, PathKind::Dirs).unwrap_err(),
+            format!("{:?} is not a directory (--dirs-only)", file)
+        );
+        assert!(check_paths(&[file, other], PathKind::Files).is_ok());
+        assert!(check_paths(&[dir, root.join("src")], PathKind::Dirs).is_ok());
+        // the missing path is named whatever the kind
+        let missing = root.join("c.md");
+        for kind in [PathKind::Any, PathKind::Files, PathKind::Dirs] {
+            let err = check_paths(std::slice::from_ref(&missing), kind).unwrap_err();
+            assert!(err.starts_with(&format!("{:?}: ", missing)), "{}", err);
+        }
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn watch_paths_from_the_environment() {
+        let root = tree("env", &["site/content/posts"], &[]);
+        std::env::set_var("ONCHANGE_TEST_PROJECTS", &root);
+        let list = root.join("watch-list");
+        std::fs::write(
+            &list,
+            "# per machine\n{env.ONCHANGE_TEST_PROJECTS}/site/content\n\n",
+        )
+        .unwrap();
+        let vars = crate::vars::session_vars(&root);
+        let entries = read_watch_file(&list).unwrap();
+        let paths = render_paths(&entries, &vars, "the list").unwrap();
+        assert_eq!(paths, [root.join("site/content")]);
+        // and it's the resolved path that's watched
+        let mut rwatch = RecursiveWatch::new(vec![]);
+        let mut watcher = Recorder::default();
+        rwatch.add(&mut watcher, &paths[0]).unwrap();
+        assert_eq!(
+            watcher.0,
+            [root.join("site/content"), root.join("site/content/posts")]
+        );
+        let err = render_paths(
+            &["{env.ONCHANGE_TEST_UNSET}/site".to_string()],
+            &vars,
+            "config",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "watch path \"{env.ONCHANGE_TEST_UNSET}/site\" from config: unknown variable {env.ONCHANGE_TEST_UNSET}"
+        );
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn file_count_stops_at_the_cap() {
+        let root = tree("cap", &["a", "b"], &["a/1", "a/2", "b/3", "b/4"]);
+        let mut rwatch = RecursiveWatch::new(vec![]);
+        rwatch.files = FILE_COUNT_CAP - 1;
+        rwatch.add(&mut Recorder::default(), &root).unwrap();
+        assert_eq!(rwatch.files, FILE_COUNT_CAP + 1);
+        // the directories are still all watched
+        assert_eq!(rwatch.registered(), 3);
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,261 @@
+use crate::output::infoln;
+use colored::Colorize;
+use serde_json::{json, Value};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Results finishing this soon after the first one of the batch are
+/// sent with it, so the rapid runs don't hit the rate limits
+const BATCH_WINDOW: Duration = Duration::from_secs(3);
+
+/// Delays before the retries of a request the server failed (5xx)
+const BACKOFF: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(4),
+];
+
+/// Time a single request can take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs that are posted to the webhook
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Events {
+    /// Only the failed runs
+    Failures,
+    /// All the runs
+    All,
+}
+
+/// Posts the results of the runs to a URL from its own thread, so the
+/// runs never wait for the network
+pub struct Webhook {
+    events: Events,
+    sender: Mutex<Option<Sender<Value>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Webhook {
+    pub fn new(url: String, events: Events) -> Self {
+        let (tx, rx) = mpsc::channel::<Value>();
+        let thread = thread::spawn(move || {
+            let mut batch = Vec::new();
+            let mut deadline: Option<Instant> = None;
+            loop {
+                let received = match deadline {
+                    Some(d) => rx.recv_timeout(d.saturating_duration_since(Instant::now())),
+                    None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(record) => {
+                        deadline.get_or_insert(Instant::now() + BATCH_WINDOW);
+                        batch.push(record);
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        deliver(&url, std::mem::take(&mut batch), &BACKOFF);
+                        deadline = None;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        if !batch.is_empty() {
+                            deliver(&url, batch, &BACKOFF);
+                        }
+                        return;
+                    }
+                }
+            }
+        });
+        Self {
+            events,
+            sender: Mutex::new(Some(tx)),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    /// Queue the run's record, `text` is the rendered `--webhook-text`
+    pub fn send(&self, mut record: Value, success: bool, text: Option<String>) {
+        if success && self.events == Events::Failures {
+            return;
+        }
+        if let Some(text) = text {
+            record["text"] = Value::String(text);
+        }
+        if let Some(tx) = self.sender.lock().unwrap().as_ref() {
+            tx.send(record).ok();
+        }
+    }
+
+    /// Send the queued results and wait for them to be delivered
+    pub fn finish(&self) {
+        self.sender.lock().unwrap().take();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Post the batch, a single record as it is and more of them in a
+/// "batch" object with their texts joined for the chat webhooks
+fn deliver(url: &str, mut batch: Vec<Value>, backoff: &[Duration]) {
+    let body = if batch.len() == 1 {
+        batch.pop().unwrap()
+    } else {
+        let texts: Vec<&str> = batch.iter().filter_map(|r| r["text"].as_str()).collect();
+        let mut body = json!({"type": "batch", "records": batch});
+        if !texts.is_empty() {
+            body["text"] = Value::String(texts.join("\n"));
+        }
+        body
+    };
+    let body = body.to_string();
+    let mut retries = backoff.iter();
+    loop {
+        match post(url, &body) {
+            Ok(status) if status < 400 => return,
+            Ok(status) if status >= 500 && retries.len() > 0 => {
+                thread::sleep(*retries.next().unwrap());
+            }
+            Ok(status) => {
+                infoln!(
+                    "{}: webhook responded with HTTP {}, the results weren't delivered",
+                    "Warning".yellow().bold(),
+                    status
+                );
+                return;
+            }
+            Err(e) => {
+                infoln!(
+                    "{}: webhook couldn't be reached: {}",
+                    "Warning".yellow().bold(),
+                    e
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// POST the JSON body, the HTTP status of the response
+fn post(url: &str, body: &str) -> Result<u16, String> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(REQUEST_TIMEOUT))
+        .http_status_as_error(false)
+        .build()
+        .into();
+    agent
+        .post(url)
+        .header("Content-Type", "application/json")
+        .send(body)
+        .map(|resp| resp.status().as_u16())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Server answering the requests with `statuses` in order, the
+    /// bodies it received
+    fn server(statuses: Vec<u16>) -> (String, JoinHandle<Vec<Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let thread = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((k, v)) = line.split_once(':') {
+                        if k.eq_ignore_ascii_case("content-length") {
+                            length = v.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(serde_json::from_slice(&body).unwrap());
+                write!(
+                    &stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+            bodies
+        });
+        (url, thread)
+    }
+
+    const NO_WAIT: [Duration; 3] = [Duration::ZERO; 3];
+
+    #[test]
+    fn single_record_is_posted_as_is() {
+        let (url, srv) = server(vec![200]);
+        deliver(&url, vec![json!({"rule": "tex", "exit_code": 1})], &NO_WAIT);
+        let bodies = srv.join().unwrap();
+        assert_eq!(bodies, vec![json!({"rule": "tex", "exit_code": 1})]);
+    }
+
+    #[test]
+    fn batch_joins_the_texts() {
+        let (url, srv) = server(vec![204]);
+        let batch = vec![
+            json!({"rule": "a", "text": "a failed"}),
+            json!({"rule": "b"}),
+            json!({"rule": "c", "text": "c failed"}),
+        ];
+        deliver(&url, batch.clone(), &NO_WAIT);
+        let bodies = srv.join().unwrap();
+        assert_eq!(
+            bodies,
+            vec![json!({"type": "batch", "records": batch, "text": "a failed\nc failed"})]
+        );
+    }
+
+    #[test]
+    fn server_errors_are_retried() {
+        let (url, srv) = server(vec![500, 503, 200]);
+        deliver(&url, vec![json!({"rule": "a"})], &NO_WAIT);
+        assert_eq!(srv.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn retries_stop_after_the_backoff() {
+        let (url, srv) = server(vec![500, 500]);
+        let backoff = [Duration::from_millis(50)];
+        let start = Instant::now();
+        deliver(&url, vec![json!({"rule": "a"})], &backoff);
+        assert!(start.elapsed() >= backoff[0]);
+        assert_eq!(srv.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let (url, srv) = server(vec![404]);
+        deliver(&url, vec![json!({"rule": "a"})], &NO_WAIT);
+        assert_eq!(srv.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn webhook_sends_only_failures() {
+        let (url, srv) = server(vec![200]);
+        let hook = Webhook::new(url, Events::Failures);
+        hook.send(json!({"rule": "ok"}), true, None);
+        hook.send(json!({"rule": "bad"}), false, Some("bad failed".into()));
+        hook.finish();
+        assert_eq!(
+            srv.join().unwrap(),
+            vec![json!({"rule": "bad", "text": "bad failed"})]
+        );
+    }
+}
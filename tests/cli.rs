@@ -0,0 +1,442 @@
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Empty directory for the test to watch
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("onchange-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn json_reports_stop_when_the_reader_is_gone() {
+    let dir = temp_dir("epipe");
+    let file = dir.join("a.txt");
+    std::fs::write(&file, "").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_onchange"))
+        .args(["--reporter", "json", "--duration", "50ms"])
+        .arg(&dir)
+        .args(["--", "echo {name}"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // keeps changing the file, as the watcher may not be ready yet
+    let done = Arc::new(AtomicBool::new(false));
+    let toucher = {
+        let (done, file) = (done.clone(), file.clone());
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                std::fs::write(&file, "x").unwrap();
+                thread::sleep(Duration::from_millis(200));
+            }
+        })
+    };
+    // like `| head -1`
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    assert!(line.contains("\"type\":\"change\""), "{}", line);
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            child.kill().ok();
+            panic!("still running after its stdout was closed");
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    done.store(true, Ordering::SeqCst);
+    toucher.join().unwrap();
+    assert!(status.success());
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+/// Info file of the single instance in the runtime directory, once
+/// it's written
+fn instance_info(runtime: &std::path::Path) -> Option<String> {
+    std::fs::read_dir(runtime)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .find(|e| e.path().extension().is_some_and(|x| x == "info"))
+        .and_then(|e| std::fs::read_to_string(e.path()).ok())
+        .filter(|text| !text.is_empty())
+}
+
+/// Wait till the info file has the pid
+fn wait_for_instance(runtime: &std::path::Path, pid: u32) {
+    let start = Instant::now();
+    while !instance_info(runtime).is_some_and(|i| i.contains(&format!("\"pid\":{},", pid))) {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "instance {} didn't start",
+            pid
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn single_instance_across_processes() {
+    let dir = temp_dir("instance");
+    let runtime = dir.join("runtime");
+    std::fs::create_dir_all(&runtime).unwrap();
+    let onchange = |mode: &str, extra: &[&str]| {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_onchange"));
+        cmd.current_dir(&dir)
+            .env("XDG_RUNTIME_DIR", &runtime)
+            .arg(format!("--single-instance={}", mode))
+            .args(extra)
+            .args([".", "--", "true"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        cmd
+    };
+    // --stats handles the signal to stop, the info file has to go anyway
+    let mut first = onchange("exit", &["--stats"]).spawn().unwrap();
+    wait_for_instance(&runtime, first.id());
+
+    let second = onchange("exit", &[]).output().unwrap();
+    assert_eq!(second.status.code(), Some(3));
+    let text = String::from_utf8_lossy(&second.stdout);
+    assert!(
+        text.contains(&format!(
+            "another instance is running for {:?} (pid {},",
+            dir,
+            first.id()
+        )),
+        "{}",
+        text
+    );
+
+    let mut third = onchange("replace", &[]).spawn().unwrap();
+    wait_for_instance(&runtime, third.id());
+    let start = Instant::now();
+    while first.try_wait().unwrap().is_none() {
+        assert!(start.elapsed() < Duration::from_secs(10), "not replaced");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    unsafe { libc::kill(third.id() as i32, libc::SIGTERM) };
+    third.wait().unwrap();
+    assert_eq!(instance_info(&runtime), None);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn query_exit_codes() {
+    let dir = temp_dir("query");
+    let query = |cwd: &std::path::Path, path: &str| {
+        let out = Command::new(env!("CARGO_BIN_EXE_onchange"))
+            .current_dir(cwd)
+            // no config from the user's home
+            .env("HOME", &dir)
+            .args(["query", path])
+            .output()
+            .unwrap();
+        (
+            out.status.code(),
+            String::from_utf8_lossy(&out.stdout).trim().to_string(),
+            String::from_utf8_lossy(&out.stderr).to_string(),
+        )
+    };
+    let (code, _, err) = query(&dir, "a.md");
+    assert_eq!(code, Some(2));
+    assert!(err.contains("no config file found"), "{}", err);
+
+    std::fs::write(
+        dir.join(".onchange.toml"),
+        "[md]\nextensions = \"md\"\ncommand = \"pandoc {name.ext}\"\n",
+    )
+    .unwrap();
+    let (code, out, _) = query(&dir, "notes/a.md");
+    assert_eq!((code, out.as_str()), (Some(0), "rule=md cmd=pandoc a.md"));
+    let (code, out, _) = query(&dir, "src/main.rs");
+    assert_eq!((code, out.as_str()), (Some(1), "none"));
+
+    std::fs::write(dir.join(".onchange.toml"), "[md]\nextensions = [\n").unwrap();
+    let (code, _, err) = query(&dir, "notes/a.md");
+    assert_eq!(code, Some(2));
+    assert!(err.contains("Error"), "{}", err);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn watched_paths_include_the_watch_list() {
+    let dir = temp_dir("watched");
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::create_dir_all(dir.join("b c")).unwrap();
+    std::fs::write(dir.join("list"), "# from the list\nb c\n").unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_onchange"))
+        .current_dir(&dir)
+        .env("HOME", &dir)
+        .args(["--trial-run", "--watch-from", "list", "a", "--"])
+        .arg("printf '%s\\n' {watched} > args.out; cp {watched_file} file.out")
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    // the arguments first, then the ones from the list
+    let read = |f: &str| std::fs::read_to_string(dir.join(f)).unwrap();
+    assert_eq!(read("args.out"), "a\nb c\n");
+    assert_eq!(read("file.out"), "a\nb c\n");
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn variables_command_isnt_run_to_look() {
+    let dir = temp_dir("no-vars");
+    let marker = dir.join("marker");
+    std::fs::write(dir.join("a.md"), "").unwrap();
+    std::fs::write(
+        dir.join(".onchange.toml"),
+        format!(
+            "[md]\nextensions = \"md\"\ncommand = \"echo {{version}}\"\n\
+             extra_variables = \"touch {}; echo version: 1\"\n",
+            marker.display()
+        ),
+    )
+    .unwrap();
+    let onchange = |args: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_onchange"))
+            .current_dir(&dir)
+            .env("HOME", &dir)
+            .args(args)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&out.stdout).to_string()
+    };
+    let out = onchange(&["--trial-run", "--no-vars", "a.md"]);
+    assert!(out.contains("echo <unset:version>"), "{}", out);
+    onchange(&["check"]);
+    let out = onchange(&["query", "a.md"]);
+    assert!(out.contains("rule=md"), "{}", out);
+    assert!(!marker.exists());
+    // it's spawned when asked for
+    let out = onchange(&["query", "--with-vars", "a.md"]);
+    assert!(out.contains("cmd=echo 1"), "{}", out);
+    assert!(marker.exists());
+    std::fs::remove_file(&marker).unwrap();
+    onchange(&["--trial-run", "a.md"]);
+    assert!(marker.exists());
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn glob_characters_in_the_watched_names() {
+    let dir = temp_dir("literal-names");
+    for sub in ["photos [2024]", "photos 2", "raw*", "skip [x]"] {
+        std::fs::create_dir(dir.join(sub)).unwrap();
+    }
+    let mut child = Command::new(env!("CARGO_BIN_EXE_onchange"))
+        .current_dir(&dir)
+        .env("HOME", &dir)
+        .args(["--duration", "50ms", "--ignore-literal", "skip [x]"])
+        .args(["photos [2024]", "raw*", "skip [x]", "photos 2"])
+        .args(["--", "echo ran {name.ext}"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let (tx, rx) = std::sync::mpsc::channel();
+    let reader = thread::spawn(move || {
+        for line in stdout.lines().map_while(Result::ok) {
+            tx.send(line).ok();
+        }
+    });
+    // the files in the ignored directory are written along with the
+    // others, so they'd be seen by the time the others are
+    let files = ["photos [2024]/a.jpg", "raw*/b.cr2", "skip [x]/c.txt"];
+    let mut lines = Vec::new();
+    let start = Instant::now();
+    while !["ran a.jpg", "ran b.cr2"]
+        .iter()
+        .all(|l| lines.iter().any(|line| line == l))
+    {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "missed the changes: {:?}",
+            lines
+        );
+        for file in files {
+            std::fs::write(dir.join(file), "x").unwrap();
+        }
+        thread::sleep(Duration::from_millis(200));
+        lines.extend(rx.try_iter());
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    reader.join().unwrap();
+    lines.extend(rx.try_iter());
+    // the names aren't patterns: "photos [2024]" isn't "photos 2", and
+    // there's no hint to use --glob for the ones that are there
+    let watching = lines.iter().find(|l| l.starts_with("Watching:")).unwrap();
+    assert_eq!(
+        watching,
+        "Watching: \"photos [2024]\" \"raw*\" \"skip [x]\" \"photos 2\""
+    );
+    assert!(!lines
+        .iter()
+        .any(|l| l.contains("c.txt") || l.starts_with("Hint")));
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn shell_that_cant_be_started() {
+    let dir = temp_dir("spawn-error");
+    let file = dir.join("a.md");
+    std::fs::write(&file, "").unwrap();
+    // output of onchange changing the file till it exits, or till it
+    // has shown `errors` errors
+    let run = |flags: &[&str], errors: usize| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_onchange"))
+            .current_dir(&dir)
+            .env("HOME", &dir)
+            .args(["--duration", "50ms", "--shell", "/nonexistent"])
+            .args(flags)
+            .args(["a.md", "--", "echo hi"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+        let reader = thread::spawn(move || {
+            for line in stdout.lines().map_while(Result::ok) {
+                tx.send(line).ok();
+            }
+        });
+        let mut lines = Vec::new();
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break status.code();
+            }
+            if lines
+                .iter()
+                .filter(|l: &&String| l.starts_with("Error"))
+                .count()
+                >= errors
+            {
+                child.kill().unwrap();
+                child.wait().unwrap();
+                break None;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "{:?}", lines);
+            std::fs::write(&file, "x").unwrap();
+            thread::sleep(Duration::from_millis(200));
+            lines.extend(rx.try_iter());
+        };
+        reader.join().unwrap();
+        lines.extend(rx.try_iter());
+        (status, lines)
+    };
+    let error = "Error: can't start \"/nonexistent\" (No such file or directory (os error 2))";
+    // the error is shown and onchange keeps watching
+    let (status, lines) = run(&[], 2);
+    assert_eq!(status, None);
+    assert_eq!(
+        lines.iter().filter(|l| *l == error).count(),
+        2,
+        "{:?}",
+        lines
+    );
+    // like the shell for the commands it can't find
+    let (status, lines) = run(&["--once"], usize::MAX);
+    assert_eq!(status, Some(127));
+    assert_eq!(
+        lines.iter().filter(|l| *l == error).count(),
+        1,
+        "{:?}",
+        lines
+    );
+    let (status, lines) = run(&["--fail-on-spawn-error"], usize::MAX);
+    assert_eq!(status, Some(1));
+    assert_eq!(
+        &lines[lines.len() - 3..],
+        [
+            &format!("Changed: {}", file.display()),
+            "Run: echo hi",
+            error
+        ]
+    );
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn self_test_of_the_watched_dirs() {
+    let dir = temp_dir("self-test");
+    let ok = dir.join("ok");
+    std::fs::create_dir(&ok).unwrap();
+    // root writes in the read-only directories all the same
+    let unwritable = if unsafe { libc::geteuid() } == 0 {
+        PathBuf::from("/proc")
+    } else {
+        use std::os::unix::fs::PermissionsExt;
+        let ro = dir.join("ro");
+        std::fs::create_dir(&ro).unwrap();
+        std::fs::set_permissions(&ro, std::fs::Permissions::from_mode(0o555)).unwrap();
+        ro
+    };
+    let mut child = Command::new(env!("CARGO_BIN_EXE_onchange"))
+        .current_dir(&dir)
+        .env("HOME", &dir)
+        .args(["--self-test", "--duration", "50ms"])
+        .arg(&ok)
+        .arg(&unwritable)
+        .args(["--", "echo ran {name.ext}"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let (tx, rx) = std::sync::mpsc::channel();
+    let reader = thread::spawn(move || {
+        for line in stdout.lines().map_while(Result::ok) {
+            tx.send(line).ok();
+        }
+    });
+    let mut lines = Vec::new();
+    while !lines.iter().any(|l: &String| l.starts_with("Self-test")) {
+        let line = rx.recv_timeout(Duration::from_secs(10));
+        lines.push(line.expect("no self-test result"));
+    }
+    // the sentinels don't run the command
+    thread::sleep(Duration::from_millis(500));
+    child.kill().unwrap();
+    child.wait().unwrap();
+    reader.join().unwrap();
+    lines.extend(rx.try_iter());
+    assert!(
+        lines.contains(&format!(
+            "Note: can't write in {:?} ({}), not tested",
+            unwritable,
+            if unwritable.starts_with("/proc") {
+                "No such file or directory (os error 2)"
+            } else {
+                "Permission denied (os error 13)"
+            }
+        )),
+        "{:?}",
+        lines
+    );
+    assert!(
+        lines.contains(&"Self-test passed (1/1 paths)".to_string()),
+        "{:?}",
+        lines
+    );
+    assert!(!lines
+        .iter()
+        .any(|l| l.starts_with("Changed") || l.starts_with("ran")));
+    std::fs::remove_dir_all(&dir).ok();
+}